@@ -3,17 +3,18 @@
 //! This module provides utilities for sending requests to the daemon and receiving responses
 //! over a Unix domain socket with robust error handling, timeouts, and reconnection logic.
 
-use crate::{Request, Response};
+use crate::{decode_frame, decode_frame_with_header, encode_frame, encode_frame_with_header, FrameError, FrameHeader, Request, Response, WireFormat};
 use bincode;
 use serde::{Serialize, de::DeserializeOwned};
 
 use std::io;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::time::Duration;
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::UnixStream;
-
+use tokio::sync::Mutex;
 use tokio::time::timeout;
 
 /// Errors that can occur during IPC communication
@@ -56,6 +57,12 @@ pub enum IpcError {
     #[error("Connection closed unexpectedly")]
     ConnectionClosed,
 
+    #[error("Received an empty message")]
+    EmptyMessage,
+
+    #[error("invalid frame: {0}")]
+    Frame(#[from] FrameError),
+
     #[error("Other error: {0}")]
     Other(String),
 }
@@ -66,7 +73,7 @@ pub const DEFAULT_SOCKET_PATH: &str = "/run/razermapper.sock";
 /// Default timeout for operations (in milliseconds)
 pub const DEFAULT_TIMEOUT_MS: u64 = 5000;
 
-/// Maximum message size (1MB)
+/// Default maximum message size (1MB), matching the daemon's default `max_message_bytes`
 pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
 
 /// Maximum number of reconnection attempts
@@ -75,6 +82,10 @@ pub const DEFAULT_MAX_RETRIES: u32 = 3;
 /// Delay between reconnection attempts (in milliseconds)
 pub const DEFAULT_RETRY_DELAY_MS: u64 = 1000;
 
+/// Cached connections found closed in a row before a persistent client
+/// decides the daemon doesn't keep connections alive
+const KEEP_ALIVE_STRIKES: u32 = 2;
+
 /// IPC client with connection management and error handling
 #[derive(Debug)]
 pub struct IpcClient {
@@ -82,7 +93,18 @@ pub struct IpcClient {
     timeout: Duration,
     max_retries: u32,
     retry_delay: Duration,
-    // stream: Option<Mutex<UnixStream>>, // Unused for now, will be implemented later
+    /// Largest request or response frame accepted, in bytes
+    max_message_size: usize,
+    /// Connection kept open by `send_cached` for reuse across requests
+    stream: Mutex<Option<UnixStream>>,
+    /// Whether `send` goes through the cached connection
+    persistent: bool,
+    /// Cleared once the daemon is seen closing every connection after one reply
+    keep_alive: AtomicBool,
+    /// Cached connections the daemon closed since one was last reused
+    closed_connections: AtomicU32,
+    /// Id given to the next request, echoed by the daemon in its reply and logs
+    next_request_id: AtomicU64,
 }
 
 impl IpcClient {
@@ -98,9 +120,33 @@ impl IpcClient {
             timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
             max_retries: DEFAULT_MAX_RETRIES,
             retry_delay: Duration::from_millis(DEFAULT_RETRY_DELAY_MS),
+            max_message_size: MAX_MESSAGE_SIZE,
+            stream: Mutex::new(None),
+            persistent: false,
+            keep_alive: AtomicBool::new(true),
+            closed_connections: AtomicU32::new(0),
+            next_request_id: AtomicU64::new(1),
         }
     }
 
+    /// Create a client whose `send` keeps one connection open to the default socket
+    ///
+    /// See `with_persistent_connection`.
+    pub fn persistent() -> Self {
+        Self::new().with_persistent_connection(true)
+    }
+
+    /// Have `send` reuse a cached connection instead of connecting for every request
+    ///
+    /// Suits clients that poll the daemon often. Requests from several tasks
+    /// take turns on the one connection, which is replaced transparently when
+    /// it fails. Daemons that close the connection after each reply are
+    /// detected, and the client goes back to a connection per request.
+    pub fn with_persistent_connection(mut self, persistent: bool) -> Self {
+        self.persistent = persistent;
+        self
+    }
+
     /// Set the timeout for operations
     pub fn with_timeout(mut self, timeout_ms: u64) -> Self {
         self.timeout = Duration::from_millis(timeout_ms);
@@ -114,6 +160,16 @@ impl IpcClient {
         self
     }
 
+    /// Set the largest request or response frame, in bytes
+    ///
+    /// Raise it together with the daemon's `security.max_message_bytes` to
+    /// transfer very large profiles; the daemon rejects bigger requests with
+    /// `ErrorKind::MessageTooLarge`.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
     /// Check if the daemon is running by attempting to connect to its socket
     pub async fn is_daemon_running(&self) -> bool {
         match UnixStream::connect(&self.socket_path).await {
@@ -144,10 +200,17 @@ impl IpcClient {
 
     /// Send a request to the daemon and wait for a response with reconnection logic
     pub async fn send(&self, request: &Request) -> Result<Response, IpcError> {
+        if self.persistent && self.keep_alive.load(Ordering::SeqCst) {
+            return self.send_cached(request).await;
+        }
         self.send_with_retries(request, self.max_retries).await
     }
 
     /// Send a request with a specific number of retries
+    ///
+    /// Requests that aren't idempotent are only retried when they never made
+    /// it onto the connection, so a failure after the daemon may have acted
+    /// on them (such as a lost reply) is returned instead of running them twice.
     pub async fn send_with_retries(&self, request: &Request, max_retries: u32) -> Result<Response, IpcError> {
         let mut attempts = 0;
         let mut last_error = None;
@@ -157,6 +220,7 @@ impl IpcClient {
                 Ok(mut stream) => {
                     match self.send_with_stream(&mut stream, request).await {
                         Ok(response) => return Ok(response),
+                        Err(e) if !may_resend(request, &e) => return Err(e),
                         Err(e) => {
                             last_error = Some(e);
                             if attempts < max_retries {
@@ -180,19 +244,90 @@ impl IpcClient {
         Err(last_error.unwrap_or(IpcError::Other("Unknown error".to_string())))
     }
 
+    /// Send a request over a cached connection, keeping it open for later calls
+    ///
+    /// The daemon serves any number of requests per connection, so sequences
+    /// like devices, then macros, then status avoid reconnecting each time. If
+    /// the cached connection turns out to be dead, it is replaced by a fresh
+    /// one and the request is sent once more, unless the daemon may already
+    /// have received a request that isn't idempotent.
+    pub async fn send_cached(&self, request: &Request) -> Result<Response, IpcError> {
+        let mut cached = self.stream.lock().await;
+
+        // A connection the daemon already closed would swallow the request
+        if cached.as_ref().is_some_and(|stream| !connection_is_open(stream)) {
+            *cached = None;
+            let closed = self.closed_connections.fetch_add(1, Ordering::SeqCst) + 1;
+            if closed >= KEEP_ALIVE_STRIKES && self.keep_alive.swap(false, Ordering::SeqCst) {
+                tracing::info!("Daemon closes connections after each reply, using a connection per request");
+            }
+        }
+
+        if let Some(stream) = cached.as_mut() {
+            match self.send_with_stream(stream, request).await {
+                Ok(response) => {
+                    self.closed_connections.store(0, Ordering::SeqCst);
+                    return Ok(response);
+                }
+                Err(e) => {
+                    *cached = None;
+                    if !may_resend(request, &e) {
+                        return Err(e);
+                    }
+                    tracing::debug!("Cached connection failed: {}, reconnecting", e);
+                }
+            }
+        }
+
+        let mut stream = self.connect().await?;
+        let response = self.send_with_stream(&mut stream, request).await?;
+        *cached = Some(stream);
+        Ok(response)
+    }
+
+    /// Drop the cached connection used by `send_cached`
+    pub async fn disconnect(&self) {
+        self.stream.lock().await.take();
+    }
+
+    /// Subscribe to live input events from the daemon
+    ///
+    /// Pass a device path to only receive events from that device. The returned
+    /// subscription keeps its own connection open until it is dropped.
+    pub async fn subscribe_events(&self, device_path: Option<String>) -> Result<EventSubscription, IpcError> {
+        let mut stream = self.connect().await?;
+
+        match self.send_with_stream(&mut stream, &Request::SubscribeEvents { device_path }).await? {
+            Response::Ack => Ok(EventSubscription {
+                stream,
+                max_message_size: self.max_message_size,
+            }),
+            response => match response.as_error() {
+                Some((_, message)) => Err(IpcError::Other(message.to_string())),
+                None => Err(IpcError::InvalidResponse),
+            },
+        }
+    }
+
     /// Send a request using an existing stream
+    ///
+    /// Each request gets the next request id, which the reply has to echo.
     async fn send_with_stream(&self, stream: &mut UnixStream, request: &Request) -> Result<Response, IpcError> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        tracing::debug!("Sending request #{}: {:?}", request_id, request);
+
         // Serialize the request
-        let serialized = bincode::serialize(request)
+        let header = FrameHeader { format: WireFormat::Bincode, request_id: Some(request_id) };
+        let serialized = encode_frame_with_header(request, header)
             .map_err(|e| IpcError::Serialization(e.to_string()))?;
 
         // Check message size
-        if serialized.len() > MAX_MESSAGE_SIZE {
-            return Err(IpcError::MessageTooLarge(serialized.len(), MAX_MESSAGE_SIZE));
+        if serialized.len() > self.max_message_size {
+            return Err(IpcError::MessageTooLarge(serialized.len(), self.max_message_size));
         }
 
         // Send the request with timeout
-        if let Err(_) = timeout(self.timeout, async {
+        match timeout(self.timeout, async {
             // Write the length of the message first (4 bytes little endian)
             let len = serialized.len() as u32;
             stream.write_all(&len.to_le_bytes()).await?;
@@ -203,28 +338,23 @@ impl IpcClient {
 
             Ok::<(), io::Error>(())
         }).await {
-            return Err(IpcError::OperationTimeout(self.timeout.as_millis() as u64));
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(IpcError::Send(e)),
+            Err(_) => return Err(IpcError::OperationTimeout(self.timeout.as_millis() as u64)),
         }
 
         // Read the response with timeout
         let response = timeout(self.timeout, async {
-            // Read the response length first
-            let mut len_bytes = [0u8; 4];
-            stream.read_exact(&mut len_bytes).await?;
-            let response_len = u32::from_le_bytes(len_bytes) as usize;
-
-            // Validate response length
-            if response_len > MAX_MESSAGE_SIZE {
-                return Err(IpcError::MessageTooLarge(response_len, MAX_MESSAGE_SIZE));
-            }
+            let buffer = read_frame(stream, self.max_message_size).await?;
 
-            // Read the response
-            let mut buffer = vec![0u8; response_len];
-            stream.read_exact(&mut buffer).await?;
-
-            // Deserialize the response
-            bincode::deserialize(&buffer)
-                .map_err(|e| IpcError::Serialization(e.to_string()))
+            // Deserialize the response; daemons that predate request ids don't echo one
+            let (response, header): (Response, FrameHeader) = decode_frame_with_header(&buffer)?;
+            if header.request_id.is_some_and(|id| id != request_id) {
+                tracing::warn!("Reply for request #{} arrived for request #{}", header.request_id.unwrap_or_default(), request_id);
+                return Err(IpcError::InvalidResponse);
+            }
+            tracing::debug!("Received response #{}", request_id);
+            Ok(response)
         }).await;
 
         match response {
@@ -235,6 +365,72 @@ impl IpcClient {
     }
 }
 
+/// Read one length-prefixed frame of at most `max_message_size` bytes
+///
+/// A peer that closes before the frame is complete gives
+/// `IpcError::ConnectionClosed`, and a zero length prefix
+/// `IpcError::EmptyMessage`, since no valid frame is empty.
+async fn read_frame(stream: &mut UnixStream, max_message_size: usize) -> Result<Vec<u8>, IpcError> {
+    let closed_or_receive = |e: io::Error| match e.kind() {
+        io::ErrorKind::UnexpectedEof => IpcError::ConnectionClosed,
+        _ => IpcError::Receive(e),
+    };
+
+    let mut len_bytes = [0u8; 4];
+    stream.read_exact(&mut len_bytes).await.map_err(closed_or_receive)?;
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    if len == 0 {
+        return Err(IpcError::EmptyMessage);
+    }
+    if len > max_message_size {
+        return Err(IpcError::MessageTooLarge(len, max_message_size));
+    }
+
+    let mut buffer = vec![0u8; len];
+    stream.read_exact(&mut buffer).await.map_err(closed_or_receive)?;
+    Ok(buffer)
+}
+
+/// Whether the daemon still has its end of a cached connection open
+///
+/// Reads without waiting: an idle live connection has nothing to read, while
+/// a closed one reads end of file. Unexpected data also counts as closed,
+/// since the connection is out of step with its requests.
+fn connection_is_open(stream: &UnixStream) -> bool {
+    let mut byte = [0u8; 1];
+    matches!(stream.try_read(&mut byte), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+}
+
+/// Whether a request that failed with `error` can safely be sent again
+///
+/// A failed write means the daemon never saw a complete request, since it
+/// only acts on whole frames; anything later may have been carried out.
+fn may_resend(request: &Request, error: &IpcError) -> bool {
+    matches!(error, IpcError::Send(_)) || request.is_idempotent()
+}
+
+/// Live input event stream returned by `IpcClient::subscribe_events`
+#[derive(Debug)]
+pub struct EventSubscription {
+    stream: UnixStream,
+    max_message_size: usize,
+}
+
+impl EventSubscription {
+    /// Wait for the next event pushed by the daemon
+    ///
+    /// Events arrive as regular length-prefixed `Response` frames:
+    /// `Response::InputEvent`, `Response::DevicesChanged` or
+    /// `Response::DeviceUngrabbed`. There is no timeout since input can be idle for
+    /// a long time; `IpcError::ConnectionClosed` is returned once the daemon
+    /// ends the stream.
+    pub async fn next_event(&mut self) -> Result<Response, IpcError> {
+        let buffer = read_frame(&mut self.stream, self.max_message_size).await?;
+        let (response, _) = decode_frame(&buffer)?;
+        Ok(response)
+    }
+}
+
 /// Send a request to the daemon using the default client
 ///
 /// # Arguments
@@ -265,7 +461,7 @@ pub async fn send(request: &Request) -> Result<Response, IpcError> {
 /// Send a request to the razermapper daemon
 ///
 /// This function connects to the daemon socket at /run/razermapper.sock,
-/// serializes the request using bincode, sends it with a length prefix,
+/// serializes the request as a bincode frame, sends it with a length prefix,
 /// and returns the deserialized response.
 ///
 /// # Arguments
@@ -286,7 +482,8 @@ pub async fn send_request(req: &Request) -> Result<Response, IpcError> {
     .map_err(IpcError::Connect)?;
 
     // Serialize the request
-    let serialized = bincode::serialize(req).map_err(IpcError::Serialize)?;
+    let serialized = encode_frame(req, WireFormat::Bincode)
+        .map_err(|e| IpcError::Serialization(e.to_string()))?;
 
     // Check message size
     if serialized.len() > MAX_MESSAGE_SIZE {
@@ -311,35 +508,17 @@ pub async fn send_request(req: &Request) -> Result<Response, IpcError> {
     .map_err(|_| IpcError::Timeout)?
     .map_err(IpcError::Send)?;
 
-    // Read the length prefix of the response
-    let mut response_len_bytes = [0u8; 4];
-    timeout(
-        Duration::from_secs(2),
-        stream.read_exact(&mut response_len_bytes)
-    )
-    .await
-    .map_err(|_| IpcError::Timeout)?
-    .map_err(IpcError::Receive)?;
-
-    let response_len = u32::from_le_bytes(response_len_bytes) as usize;
-
-    // Check response size
-    if response_len > MAX_MESSAGE_SIZE {
-        return Err(IpcError::MessageTooLarge(response_len, MAX_MESSAGE_SIZE));
-    }
-
-    // Read the response payload
-    let mut response_buffer = vec![0u8; response_len];
-    timeout(
+    // Read the length-prefixed response
+    let response_buffer = timeout(
         Duration::from_secs(2),
-        stream.read_exact(&mut response_buffer)
+        read_frame(&mut stream, MAX_MESSAGE_SIZE)
     )
     .await
-    .map_err(|_| IpcError::Timeout)?
-    .map_err(IpcError::Receive)?;
+    .map_err(|_| IpcError::Timeout)??;
 
     // Deserialize and return the response
-    bincode::deserialize(&response_buffer).map_err(IpcError::Deserialize)
+    let (response, _) = decode_frame(&response_buffer)?;
+    Ok(response)
 }
 
 /// Send a request to the daemon at a specific socket path
@@ -406,8 +585,10 @@ pub fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, IpcError> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Request, Response, DeviceInfo, Action, KeyCombo, MacroEntry};
+    use crate::{Request, Response, DeviceInfo, Action, KeyCombo, MacroEntry, TriggerEdge};
     use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
     use tempfile::TempDir;
     use tokio::net::UnixListener;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -443,8 +624,8 @@ mod tests {
                         }
 
                         // Deserialize the request
-                        let request: Request = match bincode::deserialize(&msg_buf) {
-                            Ok(req) => req,
+                        let request: Request = match decode_frame(&msg_buf) {
+                            Ok((req, _)) => req,
                             Err(_) => return,
                         };
 
@@ -458,6 +639,8 @@ mod tests {
                                         vendor_id: 0x1234,
                                         product_id: 0x5678,
                                         phys: "usb-0000:00:14.0-1/input0".to_string(),
+                                        capabilities: crate::DeviceCapabilities::default(),
+                                        stable_id: String::new(),
                                     }
                                 ];
                                 Response::Devices(devices)
@@ -477,6 +660,10 @@ mod tests {
                                         ],
                                         device_id: None,
                                         enabled: true,
+                                        cooldown_ms: 0,
+                                        trigger_on: TriggerEdge::Press,
+                                        repeat_while_held: false,
+                                        tags: vec![],
                                     }
                                 ];
                                 Response::Macros(macros)
@@ -489,11 +676,11 @@ mod tests {
                                     macros_count: 1,
                                 }
                             },
-                            _ => Response::Error("Unsupported request in test".to_string()),
+                            _ => Response::error(crate::ErrorKind::InvalidRequest, "Unsupported request in test"),
                         };
 
                         // Send the response
-                        let response_bytes = bincode::serialize(&response).unwrap();
+                        let response_bytes = encode_frame(&response, WireFormat::Bincode).unwrap();
                         let len = response_bytes.len() as u32;
 
                         if let Err(_) = stream.write_all(&len.to_le_bytes()).await {
@@ -524,16 +711,19 @@ mod tests {
         assert_eq!(client.timeout, Duration::from_millis(DEFAULT_TIMEOUT_MS));
         assert_eq!(client.max_retries, DEFAULT_MAX_RETRIES);
         assert_eq!(client.retry_delay, Duration::from_millis(DEFAULT_RETRY_DELAY_MS));
+        assert_eq!(client.max_message_size, MAX_MESSAGE_SIZE);
 
         let custom_path = "/tmp/test.sock";
         let custom_client = IpcClient::with_socket_path(custom_path)
             .with_timeout(10000)
-            .with_retry_params(5, 2000);
+            .with_retry_params(5, 2000)
+            .with_max_message_size(16 * 1024 * 1024);
 
         assert_eq!(custom_client.socket_path, custom_path);
         assert_eq!(custom_client.timeout, Duration::from_millis(10000));
         assert_eq!(custom_client.max_retries, 5);
         assert_eq!(custom_client.retry_delay, Duration::from_millis(2000));
+        assert_eq!(custom_client.max_message_size, 16 * 1024 * 1024);
     }
 
     #[tokio::test]
@@ -556,6 +746,10 @@ mod tests {
             ],
             device_id: Some("test_device".to_string()),
             enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
         };
 
         let serialized = serialize(&macro_entry).unwrap();
@@ -622,6 +816,188 @@ mod tests {
         } else {
             panic!("Expected Devices response");
         }
+
+        // Requests and responses above the client's limit are refused
+        let small_client = IpcClient::with_socket_path(&socket_path_str)
+            .with_retry_params(0, 0)
+            .with_max_message_size(16);
+        let long_name = Request::ExecuteMacro { name: "x".repeat(32), speed: crate::DEFAULT_REPLAY_SPEED };
+        assert!(matches!(small_client.send(&long_name).await, Err(IpcError::MessageTooLarge(_, 16))));
+        assert!(matches!(small_client.send(&Request::GetDevices).await, Err(IpcError::MessageTooLarge(_, 16))));
+    }
+
+    #[tokio::test]
+    async fn test_send_cached_reuses_connection() {
+        let temp_dir = TempDir::new().unwrap();
+        let socket_path = temp_dir.path().join("keepalive.sock");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+
+        // Accept a single connection and answer every request sent over it
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut served = 0;
+            loop {
+                let mut len_buf = [0u8; 4];
+                if stream.read_exact(&mut len_buf).await.is_err() {
+                    return served;
+                }
+                let mut msg_buf = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+                stream.read_exact(&mut msg_buf).await.unwrap();
+                let (_request, _): (Request, _) = decode_frame(&msg_buf).unwrap();
+
+                let response_bytes = encode_frame(&Response::Ack, WireFormat::Bincode).unwrap();
+                stream.write_all(&(response_bytes.len() as u32).to_le_bytes()).await.unwrap();
+                stream.write_all(&response_bytes).await.unwrap();
+                served += 1;
+            }
+        });
+
+        let client = IpcClient::with_socket_path(&socket_path)
+            .with_timeout(1000)
+            .with_retry_params(0, 10);
+
+        for _ in 0..3 {
+            let response = client.send_cached(&Request::GetStatus).await.unwrap();
+            assert!(matches!(response, Response::Ack));
+        }
+
+        client.disconnect().await;
+        assert_eq!(server.await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_and_empty_frames() {
+        let temp_dir = TempDir::new().unwrap();
+        let socket_path = temp_dir.path().join("truncated.sock");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+
+        // Read each request, then answer with a broken reply and hang up:
+        // a body cut short, an empty frame, and a length prefix cut short
+        let replies: Vec<Vec<u8>> = vec![
+            [&100u32.to_le_bytes()[..], &[0u8; 10]].concat(),
+            0u32.to_le_bytes().to_vec(),
+            vec![7, 0],
+        ];
+        tokio::spawn(async move {
+            for reply in replies {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut len_buf = [0u8; 4];
+                stream.read_exact(&mut len_buf).await.unwrap();
+                let mut msg_buf = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+                stream.read_exact(&mut msg_buf).await.unwrap();
+                stream.write_all(&reply).await.unwrap();
+            }
+        });
+
+        let client = IpcClient::with_socket_path(&socket_path)
+            .with_timeout(1000)
+            .with_retry_params(0, 10);
+
+        assert!(matches!(client.send(&Request::GetStatus).await, Err(IpcError::ConnectionClosed)));
+        assert!(matches!(client.send(&Request::GetStatus).await, Err(IpcError::EmptyMessage)));
+        assert!(matches!(client.send(&Request::GetStatus).await, Err(IpcError::ConnectionClosed)));
+    }
+
+    #[tokio::test]
+    async fn test_send_cached_does_not_repeat_unsafe_requests() {
+        let temp_dir = TempDir::new().unwrap();
+        let socket_path = temp_dir.path().join("flaky.sock");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let received = Arc::new(AtomicUsize::new(0));
+
+        // Answer the first request on each connection, then drop the
+        // connection after reading the second without replying
+        let server_received = Arc::clone(&received);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let received = Arc::clone(&server_received);
+                tokio::spawn(async move {
+                    for answered in [true, false] {
+                        let mut len_buf = [0u8; 4];
+                        if stream.read_exact(&mut len_buf).await.is_err() {
+                            return;
+                        }
+                        let mut msg_buf = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+                        stream.read_exact(&mut msg_buf).await.unwrap();
+                        received.fetch_add(1, Ordering::SeqCst);
+                        if answered {
+                            let response_bytes = encode_frame(&Response::Ack, WireFormat::Bincode).unwrap();
+                            stream.write_all(&(response_bytes.len() as u32).to_le_bytes()).await.unwrap();
+                            stream.write_all(&response_bytes).await.unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        let client = IpcClient::with_socket_path(&socket_path)
+            .with_timeout(1000)
+            .with_retry_params(0, 10);
+
+        // The daemon may have started the macro before the reply was lost
+        assert!(client.send_cached(&Request::GetStatus).await.is_ok());
+        let execute = Request::ExecuteMacro { name: "once".to_string(), speed: crate::DEFAULT_REPLAY_SPEED };
+        assert!(client.send_cached(&execute).await.is_err());
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+
+        // A lost reply to a read-only request is fetched again
+        assert!(client.send_cached(&Request::GetStatus).await.is_ok());
+        assert!(client.send_cached(&Request::GetStatus).await.is_ok());
+        assert_eq!(received.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_persistent_client() {
+        let temp_dir = TempDir::new().unwrap();
+        let socket_path = temp_dir.path().join("persistent.sock");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+
+        // A daemon that keeps the connection open serves every request on it
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut served = 0;
+            loop {
+                let mut len_buf = [0u8; 4];
+                if stream.read_exact(&mut len_buf).await.is_err() {
+                    return served;
+                }
+                let mut msg_buf = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+                stream.read_exact(&mut msg_buf).await.unwrap();
+                let response_bytes = encode_frame(&Response::Ack, WireFormat::Bincode).unwrap();
+                stream.write_all(&(response_bytes.len() as u32).to_le_bytes()).await.unwrap();
+                stream.write_all(&response_bytes).await.unwrap();
+                served += 1;
+            }
+        });
+
+        let client = IpcClient::with_socket_path(&socket_path)
+            .with_persistent_connection(true)
+            .with_timeout(1000)
+            .with_retry_params(0, 10);
+        for _ in 0..3 {
+            assert!(matches!(client.send(&Request::GetStatus).await.unwrap(), Response::Ack));
+        }
+        client.disconnect().await;
+        assert_eq!(server.await.unwrap(), 3);
+        assert!(client.keep_alive.load(Ordering::SeqCst));
+
+        // The mock daemon closes each connection after one reply
+        let socket_path = temp_dir.path().join("oneshot.sock");
+        let socket_path_str = socket_path.to_string_lossy().to_string();
+        let daemon_path = socket_path_str.clone();
+        tokio::spawn(async move { mock_daemon(&daemon_path).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let client = IpcClient::with_socket_path(&socket_path_str)
+            .with_persistent_connection(true)
+            .with_timeout(1000)
+            .with_retry_params(0, 10);
+        for _ in 0..4 {
+            assert!(matches!(client.send(&Request::GetStatus).await.unwrap(), Response::Status { .. }));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!client.keep_alive.load(Ordering::SeqCst));
     }
 
     #[tokio::test]
@@ -665,6 +1041,8 @@ mod tests {
                 vendor_id: 0x1532,
                 product_id: 0x0221,
                 phys: "usb-0000:00:14.0-1/input0".to_string(),
+                capabilities: crate::DeviceCapabilities::default(),
+                stable_id: String::new(),
             }
         ];
         let response = Response::Devices(devices.clone());
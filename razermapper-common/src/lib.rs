@@ -1,6 +1,6 @@
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 // Re-export common dependencies
 pub use serde;
@@ -11,6 +11,25 @@ pub use tracing;
 // IPC client module
 pub mod ipc_client;
 
+/// Version of the IPC protocol, exchanged in the `Hello` handshake
+///
+/// Bump it whenever `Request` or `Response` change in a way older peers
+/// would decode differently, such as adding or reordering variants.
+pub const PROTOCOL_VERSION: u32 = 38;
+
+/// Replay speed that plays a macro's delays as recorded
+pub const DEFAULT_REPLAY_SPEED: f32 = 1.0;
+
+/// Slowest and fastest replay speeds the daemon accepts
+pub const REPLAY_SPEED_RANGE: std::ops::RangeInclusive<f32> = 0.01..=100.0;
+
+fn default_replay_speed() -> f32 {
+    DEFAULT_REPLAY_SPEED
+}
+
+/// Environment variable overriding the daemon's socket path, read by both the daemon and the GUI
+pub const SOCKET_ENV_VAR: &str = "RAZERMAPPER_SOCKET";
+
 /// Information about a connected input device
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceInfo {
@@ -19,6 +38,80 @@ pub struct DeviceInfo {
     pub vendor_id: u16,
     pub product_id: u16,
     pub phys: String,
+    /// What kinds of events the device reports; empty when talking to older daemons
+    #[serde(default)]
+    pub capabilities: DeviceCapabilities,
+    /// Identifier that survives event node renumbering, see `stable_device_id`
+    #[serde(default)]
+    pub stable_id: String,
+}
+
+/// USB vendor id of Razer devices
+pub const RAZER_VENDOR_ID: u16 = 0x1532;
+
+/// Identify a device by vendor, product and physical location rather than its
+/// event node, whose number changes across reboots and replugs
+pub fn stable_device_id(vendor_id: u16, product_id: u16, phys: &str) -> String {
+    format!("{:04x}:{:04x}:{}", vendor_id, product_id, phys)
+}
+
+impl DeviceInfo {
+    /// Whether the device is made by Razer
+    pub fn is_razer(&self) -> bool {
+        self.vendor_id == RAZER_VENDOR_ID
+    }
+
+    /// Whether a macro's device restriction refers to this device, by stable id
+    /// or by the event node older configs used
+    pub fn matches_device_id(&self, device_id: &str) -> bool {
+        device_id == self.stable_id || Path::new(device_id) == self.path
+    }
+}
+
+/// Event types an input device reports, read from the kernel rather than guessed from its name
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceCapabilities {
+    /// Sends key or button events
+    pub has_keys: bool,
+    /// Sends relative axis events, i.e. moves a pointer or scrolls
+    pub has_rel: bool,
+    /// Has indicator LEDs such as Caps Lock
+    pub has_leds: bool,
+    /// Number of key and button codes it can send
+    pub key_count: usize,
+}
+
+/// Lowest DPI accepted by `SetDpi`
+pub const MIN_DPI: u16 = 100;
+
+/// Highest DPI accepted by `SetDpi`. OpenRazer doesn't report a per-model
+/// maximum, so this covers the fastest Razer sensors.
+pub const MAX_DPI: u16 = 45_000;
+
+/// Fewest key codes a device needs to count as a keyboard; mice report a handful of buttons
+pub const KEYBOARD_MIN_KEYS: usize = 20;
+
+/// Rough kind of an input device, derived from its capabilities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Keyboard,
+    Mouse,
+    /// Both a keyboard and a pointer, like a mouse with a keypad on one interface
+    Combo,
+    Other,
+}
+
+impl DeviceCapabilities {
+    /// Classify the device by the keys and axes it reports
+    pub fn kind(&self) -> DeviceKind {
+        let keyboard = self.has_keys && self.key_count >= KEYBOARD_MIN_KEYS;
+        match (keyboard, self.has_rel) {
+            (true, true) => DeviceKind::Combo,
+            (true, false) => DeviceKind::Keyboard,
+            (false, true) => DeviceKind::Mouse,
+            (false, false) => DeviceKind::Other,
+        }
+    }
 }
 
 impl fmt::Display for DeviceInfo {
@@ -31,12 +124,20 @@ impl fmt::Display for DeviceInfo {
 /// Represents a key combination for macro triggers
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct KeyCombo {
-    pub keys: Vec<u16>, // Key codes
+    pub keys: Vec<u16>, // Key codes, including mouse button codes such as BTN_SIDE
     pub modifiers: Vec<u16>, // Modifier key codes
 }
 
+impl KeyCombo {
+    /// Combo of the keys pressed, with Ctrl, Shift, Alt and Meta sorted into `modifiers`
+    pub fn from_pressed(pressed: Vec<u16>) -> Self {
+        let (modifiers, keys) = pressed.into_iter().partition(|key| MODIFIER_KEYS.contains(key));
+        KeyCombo { keys, modifiers }
+    }
+}
+
 /// Different actions that can be executed by a macro
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Action {
     /// Key press with optional key code
     KeyPress(u16),
@@ -54,27 +155,456 @@ pub enum Action {
     MouseRelease(u16),
     /// Mouse move relative
     MouseMove(i32, i32),
+    /// Mouse move to an absolute screen position in pixels
+    MouseMoveAbsolute(i32, i32),
     /// Mouse scroll
     MouseScroll(i32),
+    /// Repeat a sequence of actions a number of times
+    Repeat {
+        count: u32,
+        actions: Vec<Action>,
+    },
+    /// Delay for a duration picked uniformly from `min_ms..=max_ms` each time
+    /// the action runs, so repeated macros don't have perfectly regular timing
+    RandomDelay {
+        min_ms: u32,
+        max_ms: u32,
+    },
+    /// Run the actions of another macro, looked up by name when this action runs
+    CallMacro(String),
+    /// Run `then` if the key is held down when this action is reached, otherwise `else_`
+    ///
+    /// The key is checked against the live keyboard state at execution time,
+    /// not when the macro was triggered, so a macro can branch on a modifier
+    /// such as Shift being held.
+    IfKeyHeld {
+        key_code: u16,
+        then: Vec<Action>,
+        #[serde(rename = "else")]
+        else_: Vec<Action>,
+    },
+    /// Press and release a key, holding it for the daemon's tap delay
+    KeyTap(u16),
+    /// Press and release a mouse button, holding it for the daemon's tap delay
+    MouseClick(u16),
+    /// Press all keys in one input frame, hold them for the tap delay, then
+    /// release them all in one frame
+    Chord(Vec<u16>),
+    /// Pause until the key is pressed on an input device, failing the run if
+    /// `timeout_ms` passes first; without a timeout it waits until stopped
+    ///
+    /// Only presses made after the action is reached count. Presses are seen
+    /// while a recording is in progress too, and are recorded as usual.
+    WaitForKey {
+        key_code: u16,
+        timeout_ms: Option<u32>,
+    },
+    /// Run a command like `Execute` and use what it prints to stdout
+    ExecuteCapture {
+        command: String,
+        into: CaptureTarget,
+    },
 }
 
-/// Macro definition with name, trigger combo, and actions
+impl Action {
+    /// Replace key code `from` with `to` in this action and the actions it
+    /// contains, returning how many actions changed
+    ///
+    /// Mouse buttons are key codes too, so `BTN_*` codes are remapped alike.
+    pub fn remap_key_code(&mut self, from: u16, to: u16) -> usize {
+        let swap = |code: &mut u16| {
+            let matched = *code == from;
+            if matched {
+                *code = to;
+            }
+            matched
+        };
+        match self {
+            Action::KeyPress(code)
+            | Action::KeyRelease(code)
+            | Action::KeyTap(code)
+            | Action::MousePress(code)
+            | Action::MouseRelease(code)
+            | Action::MouseClick(code)
+            | Action::WaitForKey { key_code: code, .. } => usize::from(swap(code)),
+            Action::Chord(codes) => {
+                let changed = codes.iter_mut().fold(false, |changed, code| swap(code) | changed);
+                usize::from(changed)
+            }
+            Action::Repeat { actions, .. } => remap_actions(actions, from, to),
+            Action::IfKeyHeld { key_code, then, else_ } => {
+                usize::from(swap(key_code)) + remap_actions(then, from, to) + remap_actions(else_, from, to)
+            }
+            _ => 0,
+        }
+    }
+}
+
+fn remap_actions(actions: &mut [Action], from: u16, to: u16) -> usize {
+    actions.iter_mut().map(|action| action.remap_key_code(from, to)).sum()
+}
+
+/// Which macros a `RemapKeyCode` request rewrites
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RemapScope {
+    /// Every macro the daemon has
+    All,
+    /// Only the macro with this name
+    Macro(String),
+}
+
+/// What an `ExecuteCapture` action does with the command's output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureTarget {
+    /// Type the output, without its trailing newline
+    Type,
+    /// Write the output to the daemon log
+    Log,
+}
+
+/// Lighting effect for a device's LEDs, as offered by OpenRazer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedEffect {
+    /// A single steady color
+    Static(u8, u8, u8),
+    /// A color fading in and out
+    Breathing { color: (u8, u8, u8) },
+    /// Cycling through every color
+    Spectrum,
+    /// A rainbow sweeping across the device
+    Wave { direction: WaveDirection },
+    /// Keys lighting up in a color when pressed
+    Reactive { color: (u8, u8, u8) },
+}
+
+impl LedEffect {
+    /// Short name of the effect for messages, e.g. "breathing"
+    pub fn name(&self) -> &'static str {
+        match self {
+            LedEffect::Static(..) => "static",
+            LedEffect::Breathing { .. } => "breathing",
+            LedEffect::Spectrum => "spectrum",
+            LedEffect::Wave { .. } => "wave",
+            LedEffect::Reactive { .. } => "reactive",
+        }
+    }
+}
+
+/// Direction a wave effect sweeps in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WaveDirection {
+    Left,
+    Right,
+}
+
+/// An issued authentication token, identified without revealing the secret
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenInfo {
+    /// Stable identifier derived from the token
+    pub id: String,
+    /// Expiry time in seconds since the Unix epoch
+    pub expires_at: u64,
+}
+
+/// How often a macro has run and how long its runs took
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MacroMetric {
+    pub name: String,
+    pub total_executions: u64,
+    pub average_duration: std::time::Duration,
+    /// When the latest run started, in milliseconds since the Unix epoch
+    pub last_run_ts: u64,
+    /// Runs aborted for going over the engine's action or delay limits
+    #[serde(default)]
+    pub failed_executions: u64,
+}
+
+impl MacroMetric {
+    /// Metrics for a macro that hasn't run yet
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            total_executions: 0,
+            average_duration: std::time::Duration::ZERO,
+            last_run_ts: 0,
+            failed_executions: 0,
+        }
+    }
+
+    /// Add a run that started at `started_ts` and took `duration`
+    pub fn record(&mut self, duration: std::time::Duration, started_ts: u64) {
+        let previous_total = self.average_duration.as_nanos() * self.total_executions as u128;
+        self.total_executions += 1;
+        let average_nanos = (previous_total + duration.as_nanos()) / self.total_executions as u128;
+        self.average_duration = std::time::Duration::from_nanos(average_nanos as u64);
+        self.last_run_ts = self.last_run_ts.max(started_ts);
+    }
+
+    /// Count a run that was aborted before finishing its actions
+    pub fn record_failure(&mut self) {
+        self.failed_executions += 1;
+    }
+}
+
+/// Highest key code the Linux input subsystem defines (KEY_MAX)
+pub const KEY_MAX: u16 = 0x2ff;
+
+/// Key codes of the Ctrl, Shift, Alt and Meta keys on both sides of the keyboard
+pub const MODIFIER_KEYS: [u16; 8] = [29, 97, 42, 54, 56, 100, 125, 126];
+
+/// Number of mouse buttons the injector can press (1=left, 2=right, 3=middle, ...)
+pub const MOUSE_BUTTON_COUNT: u16 = 8;
+
+/// Event code of the left mouse button (BTN_LEFT); the other buttons follow it
+/// in the order right, middle, side, extra, forward, back, task
+pub const BTN_LEFT: u16 = 0x110;
+
+/// Whether an input event code is a mouse button rather than a keyboard key
+///
+/// Mouse buttons arrive as key events, so they can be used in a `KeyCombo`
+/// like any key.
+pub fn is_mouse_button(code: u16) -> bool {
+    (BTN_LEFT..BTN_LEFT + MOUSE_BUTTON_COUNT).contains(&code)
+}
+
+/// Most actions a macro may perform once its Repeat blocks are expanded
+pub const MAX_EXPANDED_ACTIONS: u64 = 1_000_000;
+
+/// Default cap on expanded actions the daemon accepts in a macro
+pub const DEFAULT_MAX_MACRO_ACTIONS: u64 = 100_000;
+
+/// Default cap on the total delay of a macro, ten minutes
+pub const DEFAULT_MAX_TOTAL_DELAY_MS: u64 = 600_000;
+
+/// Macro definition with name, trigger combo, and actions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MacroEntry {
     pub name: String,
     pub trigger: KeyCombo,
     pub actions: Vec<Action>,
-    pub device_id: Option<String>, // Optional device restriction
+    pub device_id: Option<String>, // Optional device restriction, a stable device id
     pub enabled: bool,
+    /// Milliseconds after a triggered run starts during which the trigger is ignored
+    #[serde(default)]
+    pub cooldown_ms: u32,
+    /// Whether pressing the trigger, letting go of it, or both starts the macro
+    #[serde(default)]
+    pub trigger_on: TriggerEdge,
+    /// Keep playing the actions over while the trigger combo is held
+    #[serde(default)]
+    pub repeat_while_held: bool,
+    /// Labels for grouping macros, e.g. "work" or "games"
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Edge of a trigger combo that starts a macro
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerEdge {
+    /// When the last key of the combo goes down
+    #[default]
+    Press,
+    /// When the first key of the held combo is let go
+    Release,
+    /// On both the press and the release
+    Both,
+}
+
+impl TriggerEdge {
+    /// Whether a macro with this edge starts on a press (`true`) or release (`false`)
+    pub fn fires_on(self, pressed: bool) -> bool {
+        match self {
+            TriggerEdge::Press => pressed,
+            TriggerEdge::Release => !pressed,
+            TriggerEdge::Both => true,
+        }
+    }
+}
+
+impl MacroEntry {
+    /// Check that the trigger and actions only use values the injector can act on
+    ///
+    /// Only the hard `MAX_EXPANDED_ACTIONS` ceiling is applied; the daemon
+    /// checks its configured caps with `validate_with_limits`.
+    pub fn validate(&self) -> Result<(), String> {
+        self.validate_with_limits(MAX_EXPANDED_ACTIONS, u64::MAX)
+    }
+
+    /// Like `validate`, also refusing macros over the given action count or total delay
+    pub fn validate_with_limits(&self, max_actions: u64, max_total_delay_ms: u64) -> Result<(), String> {
+        for &code in self.trigger.keys.iter().chain(&self.trigger.modifiers) {
+            validate_key_code(code).map_err(|e| format!("Invalid trigger: {}", e))?;
+        }
+        if self.tags.iter().any(|tag| tag.trim().is_empty()) {
+            return Err("Tags must not be blank".to_string());
+        }
+        validate_actions(&self.actions)?;
+
+        let max_actions = max_actions.min(MAX_EXPANDED_ACTIONS);
+        let expanded = expanded_action_count(&self.actions);
+        if expanded > max_actions {
+            return Err(format!(
+                "macro expands to {} actions, more than the limit of {}",
+                expanded, max_actions
+            ));
+        }
+        let delay = expanded_delay_ms(&self.actions);
+        if delay > max_total_delay_ms {
+            return Err(format!(
+                "macro delays for up to {} ms, more than the limit of {} ms",
+                delay, max_total_delay_ms
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the macro carries `tag`, ignoring case
+    pub fn has_tag(&self, tag: &str) -> bool {
+        let tag = tag.to_lowercase();
+        self.tags.iter().any(|t| t.to_lowercase() == tag)
+    }
+
+    /// Replace key code `from` with `to` in the trigger and the actions,
+    /// returning whether the trigger changed and how many actions did
+    ///
+    /// A trigger that ends up naming a key twice keeps it once.
+    pub fn remap_key_code(&mut self, from: u16, to: u16) -> (bool, usize) {
+        let mut trigger_changed = false;
+        for codes in [&mut self.trigger.keys, &mut self.trigger.modifiers] {
+            if codes.contains(&from) {
+                trigger_changed = true;
+                let mut seen = Vec::with_capacity(codes.len());
+                for code in codes.drain(..) {
+                    let code = if code == from { to } else { code };
+                    if !seen.contains(&code) {
+                        seen.push(code);
+                    }
+                }
+                *codes = seen;
+            }
+        }
+        (trigger_changed, remap_actions(&mut self.actions, from, to))
+    }
+
+    /// Indented JSON, for files kept under version control or edited by hand
+    pub fn to_json_pretty(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// Parse a macro written by `to_json_pretty`; the result isn't validated
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+/// Number of actions a sequence performs with its Repeat blocks expanded
+///
+/// IfKeyHeld counts its longer branch and CallMacro counts as one action,
+/// since the called macro is checked on its own. Saturates at `u64::MAX`.
+pub fn expanded_action_count(actions: &[Action]) -> u64 {
+    actions.iter().fold(0u64, |total, action| {
+        let count = match action {
+            // An iteration always costs something, even with nothing in it
+            Action::Repeat { count, actions } => (*count as u64).saturating_mul(expanded_action_count(actions).max(1)),
+            Action::IfKeyHeld { then, else_, .. } => expanded_action_count(then).max(expanded_action_count(else_)),
+            _ => 1,
+        };
+        total.saturating_add(count)
+    })
+}
+
+/// Longest time a sequence spends in Delay and RandomDelay actions, in milliseconds
+///
+/// Repeat blocks are expanded, IfKeyHeld counts its longer branch and
+/// RandomDelay its maximum. Saturates at `u64::MAX`.
+pub fn expanded_delay_ms(actions: &[Action]) -> u64 {
+    actions.iter().fold(0u64, |total, action| {
+        let delay = match action {
+            Action::Delay(ms) => *ms as u64,
+            Action::RandomDelay { max_ms, .. } => *max_ms as u64,
+            Action::Repeat { count, actions } => (*count as u64).saturating_mul(expanded_delay_ms(actions)),
+            Action::IfKeyHeld { then, else_, .. } => expanded_delay_ms(then).max(expanded_delay_ms(else_)),
+            _ => 0,
+        };
+        total.saturating_add(delay)
+    })
+}
+
+fn validate_actions(actions: &[Action]) -> Result<(), String> {
+    for action in actions {
+        match action {
+            Action::KeyPress(code) | Action::KeyRelease(code) | Action::KeyTap(code) => validate_key_code(*code)?,
+            Action::Chord(keys) if keys.is_empty() => {
+                return Err("Chord action has no keys".to_string());
+            }
+            Action::Chord(keys) => {
+                for &code in keys {
+                    validate_key_code(code)?;
+                }
+            }
+            Action::MousePress(button) | Action::MouseRelease(button) | Action::MouseClick(button)
+                if *button == 0 || *button > MOUSE_BUTTON_COUNT =>
+            {
+                return Err(format!("mouse button {} is out of range (1-{})", button, MOUSE_BUTTON_COUNT));
+            }
+            Action::Execute(command) | Action::ExecuteCapture { command, .. } if command.trim().is_empty() => {
+                return Err("Execute action has an empty command".to_string());
+            }
+            Action::CallMacro(name) if name.trim().is_empty() => {
+                return Err("CallMacro action has an empty macro name".to_string());
+            }
+            Action::RandomDelay { min_ms, max_ms } if min_ms > max_ms => {
+                return Err(format!("random delay minimum {} ms is above its maximum {} ms", min_ms, max_ms));
+            }
+            Action::Repeat { actions, .. } if actions.is_empty() => {
+                return Err("Repeat action has no actions to repeat".to_string());
+            }
+            Action::Repeat { actions, .. } => validate_actions(actions)?,
+            Action::WaitForKey { timeout_ms: Some(0), .. } => {
+                return Err("WaitForKey action has a zero timeout".to_string());
+            }
+            Action::WaitForKey { key_code, .. } => validate_key_code(*key_code)?,
+            Action::IfKeyHeld { key_code, then, else_ } => {
+                validate_key_code(*key_code)?;
+                validate_actions(then)?;
+                validate_actions(else_)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Check that a key code is one the Linux input subsystem defines
+pub fn validate_key_code(code: u16) -> Result<(), String> {
+    if code == 0 || code > KEY_MAX {
+        return Err(format!("key code {} is out of range (1-{})", code, KEY_MAX));
+    }
+    Ok(())
 }
 
 /// IPC Requests from GUI to Daemon
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
+    /// Version handshake, sent first by clients that want to check compatibility
+    ///
+    /// Kept as the first variant so every protocol version decodes it the same way.
+    Hello {
+        client_version: u32,
+    },
+
     /// List all available devices
     GetDevices,
 
-    /// Set a macro for a device
+    /// Create a macro, or replace the one with the same name, answered with `MacroSet`
+    ///
+    /// The device must be present to create a macro, but not to update one,
+    /// so macros for an unplugged device can still be edited.
     SetMacro {
         device_path: String,
         macro_entry: MacroEntry,
@@ -83,11 +613,30 @@ pub enum Request {
     /// List all configured macros
     ListMacros,
 
+    /// Fetch a single macro by name
+    GetMacro {
+        name: String,
+    },
+
     /// Delete a macro by name
     DeleteMacro {
         name: String,
     },
 
+    /// Enable or disable a macro without deleting it
+    SetMacroEnabled {
+        name: String,
+        enabled: bool,
+    },
+
+    /// Revoke an authentication token by the id shown in `ListTokens` (root only)
+    RevokeToken {
+        id: String,
+    },
+
+    /// List issued authentication tokens (root only)
+    ListTokens,
+
     /// Reload configuration from disk
     ReloadConfig,
 
@@ -98,17 +647,37 @@ pub enum Request {
     },
 
     /// Start recording a macro
+    ///
+    /// With `capture_trigger` the keys pressed before the first release become
+    /// the macro's trigger, and only what follows is recorded as its actions.
     RecordMacro {
         device_path: String,
         name: String,
+        #[serde(default)]
+        capture_trigger: bool,
     },
 
     /// Stop recording a macro
     StopRecording,
 
+    /// Wait for the next key combination pressed on a grabbed device
+    ///
+    /// The daemon replies with `TriggerCaptured` once a key of the combination
+    /// is released, or with an error if nothing was pressed within `timeout_ms`.
+    CaptureTrigger {
+        timeout_ms: u64,
+    },
+
     /// Test a macro execution
+    ///
+    /// With `simulate` set nothing is injected; the daemon replies with a
+    /// `MacroTrace` of the actions the macro would perform. `speed` divides
+    /// every delay when the macro is actually run, so 2.0 plays it twice as fast.
     TestMacro {
         name: String,
+        simulate: bool,
+        #[serde(default = "default_replay_speed")]
+        speed: f32,
     },
 
     /// Get daemon status and version
@@ -127,6 +696,18 @@ pub enum Request {
     /// List available profiles
     ListProfiles,
 
+    /// Fetch a saved profile with all of its macros, e.g. to share it as a file
+    ExportProfile {
+        name: String,
+    },
+
+    /// Save a profile received from a client, replacing an existing one of the
+    /// same name only if `overwrite` is set
+    ImportProfile {
+        profile: Profile,
+        overwrite: bool,
+    },
+
     /// Delete a profile
     DeleteProfile {
         name: String,
@@ -142,9 +723,31 @@ pub enum Request {
         token: String,
     },
 
-    /// Execute a macro by name
+    /// Execute a macro by name, dividing its delays by `speed`
     ExecuteMacro {
         name: String,
+        #[serde(default = "default_replay_speed")]
+        speed: f32,
+    },
+
+    /// Stop a currently executing macro by name
+    StopMacro {
+        name: String,
+    },
+
+    /// Stop all currently executing macros
+    StopAllMacros,
+
+    /// Subscribe to live input events from grabbed devices
+    ///
+    /// The daemon replies with `Response::Ack` and then keeps the connection
+    /// open, pushing one length-prefixed `Response::InputEvent` frame per event
+    /// until the client disconnects. `device_path` limits events to one device.
+    /// `Response::DevicesChanged` and `Response::DeviceUngrabbed` frames are sent
+    /// whenever devices are plugged in, removed, or lose their grab, regardless
+    /// of the filter.
+    SubscribeEvents {
+        device_path: Option<String>,
     },
 
     /// Grab a device exclusively for input interception
@@ -156,6 +759,94 @@ pub enum Request {
     UngrabDevice {
         device_path: String,
     },
+
+    /// Inspect one device: its capabilities, grab status and event counters
+    GetDeviceDetails {
+        device_path: String,
+    },
+
+    /// Execution counts and durations of the macros that have run
+    GetMetrics,
+
+    /// Copy a macro under a new name, keeping its trigger, actions, device
+    /// restriction and enabled flag
+    DuplicateMacro {
+        source: String,
+        new_name: String,
+    },
+
+    /// Get the discovered devices made by Razer, leaving out other keyboards,
+    /// mice and virtual devices that `GetDevices` includes
+    GetRazerDevices,
+
+    /// Shut the daemon down gracefully, as SIGTERM would
+    ///
+    /// Needs an authenticated connection when token auth is enabled, and is
+    /// refused otherwise unless `security.allow_remote_shutdown` is set.
+    /// The daemon answers `Ack` and closes the connection before cleaning up.
+    Shutdown,
+
+    /// Grab every discovered device that isn't grabbed yet
+    ///
+    /// A device that can't be grabbed doesn't stop the others; the reply is
+    /// `GrabResults`.
+    GrabAllDevices,
+
+    /// Release every grabbed device, answered with `GrabResults`
+    UngrabAllDevices,
+
+    /// Drop these key codes from a device's input before macros see them
+    ///
+    /// Replaces the device's previous list; an empty list stops filtering.
+    /// The list outlives grabs, so it applies the next time the device is grabbed.
+    SetDeviceIgnoreKeys {
+        device_path: String,
+        keys: Vec<u16>,
+    },
+
+    /// List the macros carrying a tag, compared ignoring case
+    ListMacrosByTag {
+        tag: String,
+    },
+
+    /// Change how many triggered macros may run at once, answered with
+    /// `EngineConfig`. Must be at least 1; lasts until the daemon restarts.
+    SetMaxConcurrent {
+        n: usize,
+    },
+
+    /// Current macro engine settings, answered with `EngineConfig`
+    GetEngineConfig,
+
+    /// Start a lighting effect on a device; `LedSet` is the static case
+    LedEffect {
+        device_path: String,
+        effect: LedEffect,
+    },
+
+    /// Read the charge of a wireless device, answered with `Battery`
+    GetBatteryLevel {
+        device_path: String,
+    },
+
+    /// Read a mouse's sensor resolution, answered with `Dpi`
+    GetDpi {
+        device_path: String,
+    },
+
+    /// Set a mouse's X and Y sensor resolution, each within `MIN_DPI..=MAX_DPI`
+    SetDpi {
+        device_path: String,
+        dpi: (u16, u16),
+    },
+
+    /// Replace key code `from` with `to` in the triggers and actions of the
+    /// macros in `scope`, answered with `KeyCodeRemapped`
+    RemapKeyCode {
+        from: u16,
+        to: u16,
+        scope: RemapScope,
+    },
 }
 
 /// Status information structure
@@ -170,12 +861,24 @@ pub struct StatusInfo {
 /// IPC Responses from Daemon to GUI
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Response {
+    /// Handshake reply carrying the daemon's package version
+    /// (major * 10000 + minor * 100 + patch) and its `PROTOCOL_VERSION`
+    ///
+    /// Kept as the first variant so every protocol version decodes it the same way.
+    Hello {
+        daemon_version: u32,
+        protocol: u32,
+    },
+
     /// List of discovered devices
     Devices(Vec<DeviceInfo>),
 
     /// List of configured macros
     Macros(Vec<MacroEntry>),
 
+    /// A single macro
+    Macro(MacroEntry),
+
     /// Acknowledgment of successful operation
     Ack,
 
@@ -205,6 +908,7 @@ pub enum Response {
     ProfileLoaded {
         name: String,
         macros_count: usize,
+        description: Option<String>,
     },
 
     /// Profile save confirmation
@@ -213,7 +917,13 @@ pub enum Response {
         macros_count: usize,
     },
 
-    /// Error response
+    /// A full profile, in reply to `ExportProfile`
+    ProfileData(Profile),
+
+    /// Error response without a category
+    ///
+    /// The daemon sends `ErrorCode` instead; this is kept so clients can still
+    /// understand older daemons. `Response::as_error` handles both.
     Error(String),
 
     /// Authentication token
@@ -221,13 +931,244 @@ pub enum Response {
 
     /// Authentication successful
     Authenticated,
+
+    /// Issued authentication tokens
+    Tokens(Vec<TokenInfo>),
+
+    /// Number of executing macros that were stopped
+    Stopped(usize),
+
+    /// Actions a simulated macro would perform, each with its offset from the start
+    MacroTrace(Vec<(Action, std::time::Duration)>),
+
+    /// Key combination captured for use as a macro trigger
+    TriggerCaptured(KeyCombo),
+
+    /// Configuration and macros were reloaded from disk
+    ConfigReloaded {
+        macros_count: usize,
+    },
+
+    /// Live input event pushed to subscribed clients
+    InputEvent {
+        device_path: String,
+        key_code: u16,
+        pressed: bool,
+    },
+
+    /// Updated device list pushed to subscribed clients after a hotplug change
+    DevicesChanged(Vec<DeviceInfo>),
+
+    /// Grab released because the device disappeared or failed, pushed to subscribed clients
+    DeviceUngrabbed {
+        device_path: String,
+    },
+
+    /// Failed request, categorized so clients don't have to match on the message
+    ErrorCode {
+        code: ErrorKind,
+        message: String,
+    },
+
+    /// A single device, in reply to `GetDeviceDetails`
+    ///
+    /// `events_seen` counts the input events read from the device while it was
+    /// grabbed and `last_event_ts` is when the latest one arrived, in
+    /// milliseconds since the Unix epoch.
+    DeviceDetails {
+        info: DeviceInfo,
+        grabbed: bool,
+        events_seen: u64,
+        last_event_ts: Option<u64>,
+    },
+
+    /// Per-macro execution metrics, in reply to `GetMetrics`
+    Metrics(Vec<MacroMetric>),
+
+    /// Each device path touched by `GrabAllDevices` or `UngrabAllDevices`
+    /// and whether the grab or release succeeded
+    GrabResults(Vec<(String, bool)>),
+
+    /// Macro engine settings that can be read or changed at runtime
+    EngineConfig {
+        max_concurrent_macros: usize,
+        default_delay: u32,
+    },
+
+    /// Charge of a wireless device in percent and whether it is charging
+    Battery {
+        percent: u8,
+        charging: bool,
+    },
+
+    /// Sensor resolution of a mouse along X and Y
+    Dpi {
+        x: u16,
+        y: u16,
+    },
+
+    /// How many macros a `RemapKeyCode` changed, and how many of their actions
+    KeyCodeRemapped {
+        macros: usize,
+        actions: usize,
+    },
+
+    /// A `SetMacro` succeeded; `created` is false when it replaced an existing macro
+    MacroSet {
+        created: bool,
+    },
+}
+
+/// Why a request failed, carried by `Response::ErrorCode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorKind {
+    /// The named macro, device, profile or token doesn't exist
+    NotFound,
+    /// Something with that name already exists
+    AlreadyExists,
+    /// The connection must authenticate with a valid token first
+    AuthRequired,
+    /// The client isn't allowed to make this request
+    PermissionDenied,
+    /// The request can't be handled in its current form
+    InvalidRequest,
+    /// The macro or one of its actions failed validation
+    InvalidMacro,
+    /// The device or engine is already in use, e.g. grabbed or recording
+    DeviceBusy,
+    /// A daemon component the request needs isn't running
+    Unavailable,
+    /// Nothing happened before the request's timeout
+    Timeout,
+    /// The client sent too many requests
+    RateLimited,
+    /// Client and daemon speak different protocol versions
+    VersionMismatch,
+    /// The daemon failed to carry out a valid request
+    Internal,
+    /// The request frame is larger than the daemon's `max_message_bytes`
+    MessageTooLarge,
+}
+
+impl Request {
+    /// Whether running the request twice has the same effect as running it once
+    ///
+    /// Clients only resend these after a connection fails mid-request, since
+    /// the daemon may already have carried out the first attempt.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Request::Hello { .. }
+            | Request::GetDevices
+            | Request::GetRazerDevices
+            | Request::GetDeviceDetails { .. }
+            | Request::ListMacros
+            | Request::GetMacro { .. }
+            | Request::SetMacro { .. }
+            | Request::SetMacroEnabled { .. }
+            | Request::ListTokens
+            | Request::ReloadConfig
+            | Request::LedSet { .. }
+            | Request::LedEffect { .. }
+            | Request::GetStatus
+            | Request::GetMetrics
+            | Request::ListProfiles
+            | Request::ExportProfile { .. }
+            | Request::Authenticate { .. }
+            | Request::UngrabDevice { .. }
+            | Request::UngrabAllDevices
+            | Request::SetDeviceIgnoreKeys { .. }
+            | Request::ListMacrosByTag { .. }
+            | Request::SetMaxConcurrent { .. }
+            | Request::GetEngineConfig
+            | Request::GetBatteryLevel { .. }
+            | Request::GetDpi { .. }
+            | Request::SetDpi { .. }
+            | Request::RemapKeyCode { .. } => true,
+            Request::TestMacro { simulate, .. } => *simulate,
+            _ => false,
+        }
+    }
+}
+
+impl Response {
+    /// Build an `ErrorCode` response
+    pub fn error(code: ErrorKind, message: impl Into<String>) -> Self {
+        Response::ErrorCode {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// The category and message if this is an error response
+    ///
+    /// Uncategorized `Error` responses from older daemons count as `Internal`.
+    pub fn as_error(&self) -> Option<(ErrorKind, &str)> {
+        match self {
+            Response::ErrorCode { code, message } => Some((*code, message)),
+            Response::Error(message) => Some((ErrorKind::Internal, message)),
+            _ => None,
+        }
+    }
 }
 
 /// Profile structure for organizing macros
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Profile {
     pub name: String,
+    /// Written sorted by name, so saved and exported profiles diff cleanly
+    #[serde(serialize_with = "serialize_sorted")]
     pub macros: std::collections::HashMap<String, MacroEntry>,
+    /// What the profile is for, shown when it is loaded
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Who made the profile, for profiles shared between users
+    #[serde(default)]
+    pub author: Option<String>,
+    /// When the profile was first saved, in unix seconds
+    #[serde(default)]
+    pub created_at: Option<u64>,
+}
+
+impl Profile {
+    /// Check that the name is usable as a file name and every macro is valid
+    pub fn validate(&self) -> Result<(), String> {
+        validate_profile_name(&self.name)?;
+        for macro_entry in self.macros.values() {
+            macro_entry.validate().map_err(|e| format!("macro '{}': {}", macro_entry.name, e))?;
+        }
+        Ok(())
+    }
+
+    /// Indented JSON with macros sorted by name, so exports diff cleanly
+    pub fn to_json_pretty(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// Parse a profile written by `to_json_pretty`; the result isn't validated
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+/// Serialize a map in key order rather than hash order
+fn serialize_sorted<K, V, S>(map: &std::collections::HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Serialize + Ord,
+    V: Serialize,
+    S: serde::Serializer,
+{
+    map.iter().collect::<std::collections::BTreeMap<_, _>>().serialize(serializer)
+}
+
+/// Check that a profile name can be used as a file name inside the profiles directory
+pub fn validate_profile_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("profile name is empty".to_string());
+    }
+    if name.starts_with('.') || name.contains(['/', '\\', '\0']) {
+        return Err(format!("profile name '{}' is not a valid file name", name));
+    }
+    Ok(())
 }
 
 /// Serialization helpers for the IPC protocol
@@ -242,6 +1183,132 @@ pub fn deserialize<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T, bincode
     bincode::deserialize(bytes)
 }
 
+/// Encoding of an IPC message
+///
+/// Every frame on the socket is a 4-byte little-endian length followed by
+/// that many bytes of payload. The first payload byte selects the encoding
+/// of the rest of the payload: `0x00` for bincode (used by the Rust clients)
+/// or `0x01` for JSON (for clients written in other languages). The daemon
+/// answers in the encoding the request used.
+///
+/// With `REQUEST_ID_FLAG` set in the first byte, an 8-byte little-endian
+/// request id follows it, before the message. The daemon echoes the id in
+/// its reply and logs it, so client and daemon logs can be matched up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    #[default]
+    Bincode,
+    Json,
+}
+
+impl WireFormat {
+    /// Format selected by the first byte of a frame payload
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x00 => Some(WireFormat::Bincode),
+            0x01 => Some(WireFormat::Json),
+            _ => None,
+        }
+    }
+
+    /// Byte that starts a frame payload in this format
+    pub fn as_byte(self) -> u8 {
+        match self {
+            WireFormat::Bincode => 0x00,
+            WireFormat::Json => 0x01,
+        }
+    }
+}
+
+/// Bit of a frame's format byte saying a request id follows it
+pub const REQUEST_ID_FLAG: u8 = 0x80;
+
+/// How a frame is encoded and which request it belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameHeader {
+    pub format: WireFormat,
+    /// Id the client gave the request, echoed in the reply
+    pub request_id: Option<u64>,
+}
+
+impl From<WireFormat> for FrameHeader {
+    fn from(format: WireFormat) -> Self {
+        Self { format, request_id: None }
+    }
+}
+
+impl FrameHeader {
+    /// Parse the format byte and request id that start a frame payload,
+    /// returning the header and how many bytes it took
+    pub fn parse(payload: &[u8]) -> Result<(Self, usize), FrameError> {
+        let (&first, rest) = payload.split_first().ok_or(FrameError::Empty)?;
+        let format = WireFormat::from_byte(first & !REQUEST_ID_FLAG).ok_or(FrameError::UnknownFormat(first))?;
+        if first & REQUEST_ID_FLAG == 0 {
+            return Ok((format.into(), 1));
+        }
+        let id_bytes: [u8; 8] = rest.get(..8).and_then(|bytes| bytes.try_into().ok()).ok_or(FrameError::Truncated)?;
+        Ok((Self { format, request_id: Some(u64::from_le_bytes(id_bytes)) }, 9))
+    }
+
+    /// Format byte and request id to start a frame payload with
+    pub fn to_bytes(self) -> Vec<u8> {
+        match self.request_id {
+            Some(id) => {
+                let mut bytes = vec![self.format.as_byte() | REQUEST_ID_FLAG];
+                bytes.extend_from_slice(&id.to_le_bytes());
+                bytes
+            }
+            None => vec![self.format.as_byte()],
+        }
+    }
+}
+
+/// Errors decoding an IPC frame payload
+#[derive(Debug, thiserror::Error)]
+pub enum FrameError {
+    #[error("empty frame")]
+    Empty,
+    #[error("unknown wire format 0x{0:02x}")]
+    UnknownFormat(u8),
+    #[error("frame ends inside its request id")]
+    Truncated,
+    #[error("bincode error: {0}")]
+    Bincode(#[from] bincode::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Encode a message as a frame payload: the format byte, then the message
+pub fn encode_frame<T: Serialize>(msg: &T, format: WireFormat) -> Result<Vec<u8>, FrameError> {
+    encode_frame_with_header(msg, format.into())
+}
+
+/// Encode a message as a frame payload, tagged with the header's request id if it has one
+pub fn encode_frame_with_header<T: Serialize>(msg: &T, header: FrameHeader) -> Result<Vec<u8>, FrameError> {
+    let mut payload = header.to_bytes();
+    match header.format {
+        WireFormat::Bincode => payload.extend_from_slice(&bincode::serialize(msg)?),
+        WireFormat::Json => payload.extend_from_slice(&serde_json::to_vec(msg)?),
+    }
+    Ok(payload)
+}
+
+/// Decode a frame payload, returning the message and the format it was sent in
+pub fn decode_frame<T: DeserializeOwned>(payload: &[u8]) -> Result<(T, WireFormat), FrameError> {
+    decode_frame_with_header(payload).map(|(msg, header)| (msg, header.format))
+}
+
+/// Decode a frame payload, returning the message and its format and request id
+pub fn decode_frame_with_header<T: DeserializeOwned>(payload: &[u8]) -> Result<(T, FrameHeader), FrameError> {
+    let (header, header_len) = FrameHeader::parse(payload)?;
+    let body = &payload[header_len..];
+    let msg = match header.format {
+        WireFormat::Bincode => bincode::deserialize(body)?,
+        WireFormat::Json => serde_json::from_slice(body)?,
+    };
+    Ok((msg, header))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -254,6 +1321,88 @@ mod tests {
         assert!(matches!(deserialized, Request::GetDevices));
     }
 
+    #[test]
+    fn test_profile_validation() {
+        let mut profile = Profile {
+            name: "shared".to_string(),
+            macros: std::collections::HashMap::new(),
+            description: None,
+            author: None,
+            created_at: None,
+        };
+        assert!(profile.validate().is_ok());
+
+        for bad_name in ["", "../etc/passwd", ".hidden", "a/b"] {
+            profile.name = bad_name.to_string();
+            assert!(profile.validate().is_err(), "accepted profile name {:?}", bad_name);
+        }
+
+        profile.name = "shared".to_string();
+        profile.macros.insert("bad".to_string(), MacroEntry {
+            name: "bad".to_string(),
+            trigger: KeyCombo { keys: vec![KEY_MAX + 1], modifiers: vec![] },
+            actions: vec![],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        });
+        let err = profile.validate().unwrap_err();
+        assert!(err.contains("macro 'bad'"), "{}", err);
+    }
+
+    #[test]
+    fn test_frame_formats() {
+        let request = Request::TestMacro { name: "Test".to_string(), simulate: true, speed: DEFAULT_REPLAY_SPEED };
+        for format in [WireFormat::Bincode, WireFormat::Json] {
+            let payload = encode_frame(&request, format).unwrap();
+            assert_eq!(payload[0], format.as_byte());
+            let (decoded, decoded_format): (Request, _) = decode_frame(&payload).unwrap();
+            assert_eq!(decoded_format, format);
+            assert!(matches!(decoded, Request::TestMacro { simulate: true, .. }));
+        }
+
+        // Hand-written JSON, as a third-party client would send it
+        let (request, _): (Request, _) = decode_frame(b"\x01\"GetStatus\"").unwrap();
+        assert!(matches!(request, Request::GetStatus));
+        // Leaving out the replay speed plays the macro as recorded
+        let (request, _): (Request, _) = decode_frame(b"\x01{\"ExecuteMacro\":{\"name\":\"Reload\"}}").unwrap();
+        assert!(matches!(request, Request::ExecuteMacro { speed, .. } if speed == DEFAULT_REPLAY_SPEED));
+
+        assert!(matches!(decode_frame::<Request>(b""), Err(FrameError::Empty)));
+        assert!(matches!(decode_frame::<Request>(b"\x07"), Err(FrameError::UnknownFormat(7))));
+
+        // JSON object keys must be strings, so this map can't be encoded
+        let unencodable: std::collections::HashMap<(u8, u8), u8> = [((1, 2), 3)].into_iter().collect();
+        assert!(matches!(encode_frame(&unencodable, WireFormat::Json), Err(FrameError::Json(_))));
+    }
+
+    #[test]
+    fn test_frame_request_ids() {
+        for format in [WireFormat::Bincode, WireFormat::Json] {
+            let header = FrameHeader { format, request_id: Some(42) };
+            let payload = encode_frame_with_header(&Request::GetStatus, header).unwrap();
+            assert_eq!(payload[0], format.as_byte() | REQUEST_ID_FLAG);
+
+            let (request, decoded): (Request, _) = decode_frame_with_header(&payload).unwrap();
+            assert!(matches!(request, Request::GetStatus));
+            assert_eq!(decoded, header);
+            // Callers that don't care about ids still read tagged frames
+            let (_, decoded_format): (Request, _) = decode_frame(&payload).unwrap();
+            assert_eq!(decoded_format, format);
+        }
+
+        // Frames without an id decode as before
+        let payload = encode_frame(&Request::GetStatus, WireFormat::Json).unwrap();
+        let (_, header): (Request, _) = decode_frame_with_header(&payload).unwrap();
+        assert_eq!(header, FrameHeader { format: WireFormat::Json, request_id: None });
+
+        assert!(matches!(decode_frame::<Request>(b"\x80\x01\x02"), Err(FrameError::Truncated)));
+        assert!(matches!(decode_frame::<Request>(b"\x87"), Err(FrameError::UnknownFormat(0x87))));
+    }
+
     #[test]
     fn test_macro_entry_serialization() {
         let macro_entry = MacroEntry {
@@ -269,11 +1418,251 @@ mod tests {
             ],
             device_id: Some("test_device".to_string()),
             enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
         };
 
         let serialized = serialize(&macro_entry);
         let deserialized: MacroEntry = deserialize(&serialized).unwrap();
         assert_eq!(deserialized.name, "Test Macro");
         assert_eq!(deserialized.trigger.keys, vec![30, 40]);
+        assert_eq!(deserialized.cooldown_ms, 0);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let entry = macro_with(vec![30], vec![Action::KeyTap(30), Action::Delay(50)]);
+        let json = entry.to_json_pretty().unwrap();
+        assert!(json.contains("\n  \"name\": \"Test Macro\""));
+        assert_eq!(MacroEntry::from_json(&json).unwrap(), entry);
+
+        let mut profile = Profile {
+            name: "work".to_string(),
+            macros: Default::default(),
+            description: Some("Shortcuts for the office".to_string()),
+            author: None,
+            created_at: Some(1_700_000_000),
+        };
+        for name in ["b", "a"] {
+            profile.macros.insert(name.to_string(), MacroEntry { name: name.to_string(), ..entry.clone() });
+        }
+        let json = profile.to_json_pretty().unwrap();
+        assert!(json.find("\"a\":").unwrap() < json.find("\"b\":").unwrap());
+        assert_eq!(Profile::from_json(&json).unwrap(), profile);
+
+        profile.author = Some("Sam".to_string());
+        profile.description = None;
+        assert_eq!(Profile::from_json(&profile.to_json_pretty().unwrap()).unwrap(), profile);
+        assert!(Profile::from_json("{\"name\": 3}").is_err());
+    }
+
+    #[test]
+    fn test_macro_cooldown_defaults_and_rejects_negative() {
+        let json = r#"{"name":"m","trigger":{"keys":[30],"modifiers":[]},"actions":[],"device_id":null,"enabled":true}"#;
+        let entry: MacroEntry = serde_json::from_str(json).unwrap();
+        assert_eq!(entry.cooldown_ms, 0);
+
+        let negative = json.replace(r#""enabled":true"#, r#""enabled":true,"cooldown_ms":-5"#);
+        assert!(serde_json::from_str::<MacroEntry>(&negative).is_err());
+    }
+
+    #[test]
+    fn test_macro_tags() {
+        let json = r#"{"name":"m","trigger":{"keys":[30],"modifiers":[]},"actions":[],"device_id":null,"enabled":true}"#;
+        let mut entry: MacroEntry = serde_json::from_str(json).unwrap();
+        assert!(entry.tags.is_empty());
+
+        entry.tags = vec!["Work".to_string()];
+        assert!(entry.has_tag("work"));
+        assert!(!entry.has_tag("games"));
+
+        let mut blank = macro_with(vec![30], vec![Action::KeyPress(30)]);
+        blank.tags = vec!["work".to_string(), " ".to_string()];
+        assert!(blank.validate().unwrap_err().contains("blank"));
+    }
+
+    #[test]
+    fn test_if_key_held_serialization() {
+        let action = Action::IfKeyHeld {
+            key_code: 42,
+            then: vec![Action::Type("A".to_string())],
+            else_: vec![Action::Repeat { count: 2, actions: vec![Action::KeyPress(30)] }],
+        };
+
+        let deserialized: Action = deserialize(&serialize(&action)).unwrap();
+        assert_eq!(deserialized, action);
+
+        // Text formats spell the fallback branch as plain `else`
+        let json = serde_json::to_value(&action).unwrap();
+        assert_eq!(json["IfKeyHeld"]["key_code"], 42);
+        assert!(json["IfKeyHeld"]["else"].is_array());
+        assert_eq!(serde_json::from_value::<Action>(json).unwrap(), action);
+    }
+
+    #[test]
+    fn test_is_mouse_button() {
+        assert!(is_mouse_button(BTN_LEFT));
+        assert!(is_mouse_button(275)); // BTN_SIDE
+        assert!(is_mouse_button(276)); // BTN_EXTRA
+        assert!(!is_mouse_button(30)); // KEY_A
+        assert!(!is_mouse_button(BTN_LEFT + MOUSE_BUTTON_COUNT));
+
+        // Buttons are valid trigger codes
+        assert!(macro_with(vec![275], vec![Action::KeyPress(30)]).validate().is_ok());
+    }
+
+    #[test]
+    fn test_device_kind() {
+        let mouse = DeviceCapabilities { has_keys: true, has_rel: true, has_leds: false, key_count: 8 };
+        assert_eq!(mouse.kind(), DeviceKind::Mouse);
+
+        // Named like a keyboard or not, what it reports decides
+        let keyboard = DeviceCapabilities { has_keys: true, has_rel: false, has_leds: true, key_count: 120 };
+        assert_eq!(keyboard.kind(), DeviceKind::Keyboard);
+
+        let combo = DeviceCapabilities { has_rel: true, ..keyboard };
+        assert_eq!(combo.kind(), DeviceKind::Combo);
+        assert_eq!(DeviceCapabilities::default().kind(), DeviceKind::Other);
+
+        // JSON from a daemon without capabilities still decodes
+        let json = r#"{"name":"Razer Huntsman","path":"/dev/input/event3","vendor_id":5426,"product_id":537,"phys":""}"#;
+        let device: DeviceInfo = serde_json::from_str(json).unwrap();
+        assert_eq!(device.capabilities, DeviceCapabilities::default());
+    }
+
+    #[test]
+    fn test_macro_metric_average() {
+        let mut metric = MacroMetric::new("Reload");
+        metric.record(std::time::Duration::from_millis(100), 1_000);
+        metric.record(std::time::Duration::from_millis(300), 3_000);
+        // A run that finished late but started earlier doesn't move last_run_ts back
+        metric.record(std::time::Duration::from_millis(200), 2_000);
+
+        assert_eq!(metric.total_executions, 3);
+        assert_eq!(metric.average_duration, std::time::Duration::from_millis(200));
+        assert_eq!(metric.last_run_ts, 3_000);
+
+        metric.record_failure();
+        assert_eq!(metric.failed_executions, 1);
+        assert_eq!(metric.total_executions, 3);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let response = Response::error(ErrorKind::NotFound, "Macro not found: Reload");
+        let (decoded, _): (Response, _) = decode_frame(&encode_frame(&response, WireFormat::Bincode).unwrap()).unwrap();
+        assert_eq!(decoded.as_error(), Some((ErrorKind::NotFound, "Macro not found: Reload")));
+
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["ErrorCode"]["code"], "NotFound");
+
+        assert_eq!(Response::Error("old".to_string()).as_error(), Some((ErrorKind::Internal, "old")));
+        assert_eq!(Response::Ack.as_error(), None);
+    }
+
+    fn macro_with(trigger_keys: Vec<u16>, actions: Vec<Action>) -> MacroEntry {
+        MacroEntry {
+            name: "Test Macro".to_string(),
+            trigger: KeyCombo {
+                keys: trigger_keys,
+                modifiers: vec![],
+            },
+            actions,
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        }
+    }
+
+    #[test]
+    fn test_macro_validation() {
+        let valid = macro_with(vec![30], vec![
+            Action::KeyPress(30),
+            Action::MousePress(1),
+            Action::Execute("echo hi".to_string()),
+            Action::Repeat { count: 2, actions: vec![Action::KeyRelease(KEY_MAX)] },
+        ]);
+        assert!(valid.validate().is_ok());
+
+        let err = macro_with(vec![30], vec![Action::KeyPress(4464)]).validate().unwrap_err();
+        assert!(err.contains("key code 4464"));
+
+        assert!(macro_with(vec![30], vec![Action::KeyTap(30), Action::MouseClick(1)]).validate().is_ok());
+        assert!(macro_with(vec![30], vec![Action::KeyTap(0)]).validate().is_err());
+        assert!(macro_with(vec![30], vec![Action::MouseClick(0)]).validate().is_err());
+        assert!(macro_with(vec![30], vec![Action::Chord(vec![30, 31, 42])]).validate().is_ok());
+        assert!(macro_with(vec![30], vec![Action::Chord(vec![30, 0])]).validate().is_err());
+        let err = macro_with(vec![30], vec![Action::Chord(vec![])]).validate().unwrap_err();
+        assert!(err.contains("no keys"));
+        assert!(macro_with(vec![30], vec![Action::WaitForKey { key_code: 28, timeout_ms: None }]).validate().is_ok());
+        assert!(macro_with(vec![30], vec![Action::WaitForKey { key_code: 0, timeout_ms: Some(500) }]).validate().is_err());
+        let err = macro_with(vec![30], vec![Action::WaitForKey { key_code: 28, timeout_ms: Some(0) }]).validate().unwrap_err();
+        assert!(err.contains("zero timeout"));
+
+        let err = macro_with(vec![30], vec![Action::MousePress(999)]).validate().unwrap_err();
+        assert!(err.contains("mouse button 999"));
+
+        let err = macro_with(vec![30], vec![Action::Execute("  ".to_string())]).validate().unwrap_err();
+        assert!(err.contains("empty command"));
+
+        let nested = vec![Action::Repeat { count: 3, actions: vec![Action::MouseRelease(0)] }];
+        assert!(macro_with(vec![30], nested).validate().is_err());
+
+        let err = macro_with(vec![30], vec![Action::RandomDelay { min_ms: 50, max_ms: 10 }]).validate().unwrap_err();
+        assert!(err.contains("random delay minimum 50 ms"));
+        assert!(macro_with(vec![30], vec![Action::RandomDelay { min_ms: 10, max_ms: 10 }]).validate().is_ok());
+
+        let err = macro_with(vec![30], vec![Action::CallMacro(String::new())]).validate().unwrap_err();
+        assert!(err.contains("empty macro name"));
+
+        let conditional = vec![Action::IfKeyHeld { key_code: 42, then: vec![], else_: vec![Action::KeyPress(0)] }];
+        assert!(macro_with(vec![30], conditional).validate().is_err());
+
+        let err = macro_with(vec![0], vec![Action::KeyPress(30)]).validate().unwrap_err();
+        assert!(err.starts_with("Invalid trigger"));
+
+        let empty = vec![Action::Repeat { count: u32::MAX, actions: vec![Action::Repeat { count: u32::MAX, actions: vec![] }] }];
+        let err = macro_with(vec![30], empty).validate().unwrap_err();
+        assert!(err.contains("no actions to repeat"));
+
+        let huge = vec![Action::Repeat { count: 1_000, actions: vec![Action::Repeat { count: 1_001, actions: vec![Action::KeyPress(30)] }] }];
+        let err = macro_with(vec![30], huge).validate().unwrap_err();
+        assert!(err.contains("expands to 1001000 actions"));
+
+        let at_limit = vec![Action::Repeat { count: 1_000, actions: vec![Action::Repeat { count: 1_000, actions: vec![Action::KeyPress(30)] }] }];
+        assert!(macro_with(vec![30], at_limit).validate().is_ok());
+
+        let repeat = |actions| Action::Repeat { count: u32::MAX, actions };
+        let saturating = vec![repeat(vec![repeat(vec![repeat(vec![Action::KeyPress(30)])])])];
+        assert_eq!(expanded_action_count(&saturating), u64::MAX);
+    }
+
+    #[test]
+    fn test_macro_limits() {
+        let taps = vec![Action::Repeat { count: 100, actions: vec![Action::KeyTap(30), Action::Delay(50)] }];
+        let entry = macro_with(vec![30], taps);
+        assert_eq!(expanded_delay_ms(&entry.actions), 5_000);
+        assert!(entry.validate_with_limits(200, 5_000).is_ok());
+
+        let err = entry.validate_with_limits(199, 5_000).unwrap_err();
+        assert!(err.contains("expands to 200 actions, more than the limit of 199"));
+        let err = entry.validate_with_limits(200, 4_999).unwrap_err();
+        assert!(err.contains("delays for up to 5000 ms"));
+
+        // The longer branch and the longest random delay count
+        let conditional = vec![
+            Action::IfKeyHeld { key_code: 42, then: vec![Action::Delay(10)], else_: vec![Action::Delay(30)] },
+            Action::RandomDelay { min_ms: 5, max_ms: 20 },
+        ];
+        assert_eq!(expanded_delay_ms(&conditional), 50);
+
+        // A configured cap can't raise the hard ceiling
+        let huge = vec![Action::Repeat { count: 1_000, actions: vec![Action::Repeat { count: 1_001, actions: vec![Action::KeyPress(30)] }] }];
+        assert!(macro_with(vec![30], huge).validate_with_limits(u64::MAX, u64::MAX).is_err());
     }
 }
@@ -1,12 +1,12 @@
 use iced::{
     widget::{
-        button, column, container, row, text, text_input, scrollable,
+        button, checkbox, column, container, pick_list, row, slider, text, text_input, scrollable,
         horizontal_rule, vertical_rule, Column, Space,
     },
     Element, Length, Subscription, Theme, Application, Command,
-    Alignment,
+    Alignment, Color,
 };
-use razermapper_common::{DeviceInfo, MacroEntry};
+use razermapper_common::{is_mouse_button, Action, CaptureTarget, DeviceCapabilities, DeviceInfo, DeviceKind, KeyCombo, LedEffect, MacroEntry, MacroMetric, Profile, Response, WaveDirection, BTN_LEFT, MAX_DPI, MIN_DPI, SOCKET_ENV_VAR};
 use std::path::PathBuf;
 use std::collections::{VecDeque, HashMap, HashSet};
 use std::time::{Duration, Instant};
@@ -23,6 +23,61 @@ use std::time::{Duration, Instant};
 // const DANGER_RED: Color = Color::from_rgb(1.0, 0.231, 0.188); // #FF3B30
 // const WARNING_YELLOW: Color = Color::from_rgb(1.0, 0.722, 0.0); // #FFB800
 
+/// LED color as red, green and blue channels
+pub type Rgb = (u8, u8, u8);
+
+/// Lighting effects offered by the LED picker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedPreset {
+    Static,
+    Breathing,
+    Spectrum,
+    WaveLeft,
+    WaveRight,
+    Reactive,
+}
+
+impl LedPreset {
+    pub const ALL: [LedPreset; 6] = [
+        LedPreset::Static,
+        LedPreset::Breathing,
+        LedPreset::Spectrum,
+        LedPreset::WaveLeft,
+        LedPreset::WaveRight,
+        LedPreset::Reactive,
+    ];
+
+    /// The effect to send, in the picked color for effects that have one
+    pub fn effect(self, (r, g, b): Rgb) -> LedEffect {
+        match self {
+            LedPreset::Static => LedEffect::Static(r, g, b),
+            LedPreset::Breathing => LedEffect::Breathing { color: (r, g, b) },
+            LedPreset::Spectrum => LedEffect::Spectrum,
+            LedPreset::WaveLeft => LedEffect::Wave { direction: WaveDirection::Left },
+            LedPreset::WaveRight => LedEffect::Wave { direction: WaveDirection::Right },
+            LedPreset::Reactive => LedEffect::Reactive { color: (r, g, b) },
+        }
+    }
+
+    /// Whether the effect uses the picked color
+    pub fn uses_color(self) -> bool {
+        matches!(self, LedPreset::Static | LedPreset::Breathing | LedPreset::Reactive)
+    }
+}
+
+impl std::fmt::Display for LedPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LedPreset::Static => "Static",
+            LedPreset::Breathing => "Breathing",
+            LedPreset::Spectrum => "Spectrum",
+            LedPreset::WaveLeft => "Wave (left)",
+            LedPreset::WaveRight => "Wave (right)",
+            LedPreset::Reactive => "Reactive",
+        })
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Tab {
     Devices,
@@ -40,6 +95,8 @@ pub struct Notification {
 pub struct State {
     pub devices: Vec<DeviceInfo>,
     pub macros: Vec<MacroEntry>,
+    /// Execution metrics of the macros that have run, refreshed with the macro list
+    pub macro_metrics: Vec<MacroMetric>,
     pub selected_device: Option<usize>,
     pub status: String,
     pub status_history: VecDeque<String>,
@@ -48,18 +105,42 @@ pub struct State {
     pub recording_macro_name: Option<String>,
     pub daemon_connected: bool,
     pub new_macro_name: String,
+    pub macro_filter: String,
     pub socket_path: PathBuf,
     pub recently_updated_macros: HashMap<String, Instant>,
     pub grabbed_devices: HashSet<String>,
+    /// Input events the daemon has read from each grabbed device
+    pub device_events: HashMap<String, u64>,
+    /// Charge percent and charging flag of each wireless device
+    pub battery_levels: HashMap<String, (u8, bool)>,
     pub profile_name: String,
+    pub profiles: Vec<String>,
+    /// YAML file profiles are exported to and imported from
+    pub profile_file: String,
+    pub import_overwrite: bool,
+    /// List only devices made by Razer rather than every input device
+    pub razer_only: bool,
     pub active_tab: Tab,
     pub notifications: VecDeque<Notification>,
     pub recording_pulse: bool,
+    /// Copy of the macro open in the editor, saved back on SaveEditedMacro
+    pub editing_macro: Option<MacroEntry>,
+    /// Tags field of the editor as typed, split into the macro's tags on each edit
+    pub editing_tags: String,
+    pub capturing_trigger: bool,
+    /// Color picked for the selected device's LEDs, applied on ApplyLed
+    pub led_color: Rgb,
+    /// Effect picked for the selected device's LEDs, applied on ApplyLed
+    pub led_preset: LedPreset,
+    /// DPI typed for the selected mouse, applied to both axes on ApplyDpi
+    pub dpi_input: String,
 }
 
 impl Default for State {
     fn default() -> Self {
-        let socket_path = if cfg!(target_os = "linux") {
+        let socket_path = if let Some(path) = std::env::var_os(SOCKET_ENV_VAR).filter(|path| !path.is_empty()) {
+            PathBuf::from(path)
+        } else if cfg!(target_os = "linux") {
             PathBuf::from("/run/razermapper/razermapper.sock")
         } else if cfg!(target_os = "macos") {
             PathBuf::from("/tmp/razermapper.sock")
@@ -69,6 +150,7 @@ impl Default for State {
         State {
             devices: Vec::new(),
             macros: Vec::new(),
+            macro_metrics: Vec::new(),
             selected_device: None,
             status: "Initializing...".to_string(),
             status_history: VecDeque::with_capacity(10),
@@ -77,13 +159,26 @@ impl Default for State {
             recording_macro_name: None,
             daemon_connected: false,
             new_macro_name: String::new(),
+            macro_filter: String::new(),
             socket_path,
             recently_updated_macros: HashMap::new(),
             grabbed_devices: HashSet::new(),
+            device_events: HashMap::new(),
+            battery_levels: HashMap::new(),
             profile_name: "default".to_string(),
+            profiles: Vec::new(),
+            profile_file: String::new(),
+            import_overwrite: false,
+            razer_only: false,
             active_tab: Tab::Devices,
             notifications: VecDeque::with_capacity(5),
             recording_pulse: false,
+            editing_macro: None,
+            editing_tags: String::new(),
+            capturing_trigger: false,
+            led_color: (0, 255, 0),
+            led_preset: LedPreset::Static,
+            dpi_input: String::new(),
         }
     }
 }
@@ -100,7 +195,24 @@ pub enum Message {
     UngrabDevice(String),
     DeviceGrabbed(Result<String, String>),
     DeviceUngrabbed(Result<String, String>),
+    GrabAllDevices,
+    UngrabAllDevices,
+    AllDevicesGrabbed(Result<Vec<(String, bool)>, String>),
+    AllDevicesUngrabbed(Result<Vec<(String, bool)>, String>),
+    DeviceDetailsLoaded(String, Result<(bool, u64), String>),
+    DevicesChanged(Vec<DeviceInfo>),
+    DeviceGrabLost(String),
+    ToggleRazerOnly(bool),
     SelectDevice(usize),
+    UpdateLedColor(Rgb),
+    UpdateLedPreset(LedPreset),
+    BatteryLoaded(String, Result<(u8, bool), String>),
+    DpiLoaded(Result<(u16, u16), String>),
+    UpdateDpiInput(String),
+    ApplyDpi(String),
+    DpiApplied(Result<(String, u16), String>),
+    ApplyLed(String),
+    LedApplied(Result<String, String>),
 
     // Macro Recording
     UpdateMacroName(String),
@@ -111,27 +223,70 @@ pub enum Message {
 
     // Macro Management
     LoadMacros,
+    UpdateMacroFilter(String),
     MacrosLoaded(Result<Vec<MacroEntry>, String>),
     PlayMacro(String),
     MacroPlayed(Result<String, String>),
+    StopMacro(String),
+    MacroStopped(Result<String, String>),
+    StopAllMacros,
+    AllMacrosStopped(Result<usize, String>),
     DeleteMacro(String),
     MacroDeleted(Result<String, String>),
+    DuplicateMacro(String),
+    MacroDuplicated(Result<MacroEntry, String>),
+    ToggleMacroEnabled(String, bool),
+    MacroEnabledChanged(Result<(String, bool), String>),
+    LoadMetrics,
+    MetricsLoaded(Result<Vec<MacroMetric>, String>),
+
+    // Macro Editor
+    EditMacro(String),
+    UpdateAction(usize, Action),
+    MoveAction(usize, usize),
+    RemoveAction(usize),
+    UpdateMacroTags(String),
+    SaveEditedMacro,
+    CancelEdit,
+    MacroSaved(Result<MacroEntry, String>),
+    CaptureTrigger,
+    TriggerCaptured(Result<KeyCombo, String>),
 
     // Profile Management
+    LoadProfilesList,
+    ProfilesListed(Result<Vec<String>, String>),
+    SelectProfile(String),
+    LoadNamedProfile(String),
+    DeleteProfile(String),
+    ProfileDeleted(Result<String, String>),
     UpdateProfileName(String),
+    UpdateProfileFile(String),
+    ToggleImportOverwrite(bool),
+    ExportProfile,
+    ProfileExported(Result<String, String>),
+    ImportProfile,
+    ProfileImported(Result<(String, usize), String>),
     SaveProfile,
     ProfileSaved(Result<(String, usize), String>),
     LoadProfile,
-    ProfileLoaded(Result<(String, usize), String>),
+    ProfileLoaded(Result<(String, usize, Option<String>), String>),
 
     // Status
     CheckDaemonConnection,
     DaemonStatusChanged(bool),
+    DaemonIncompatible(String),
 
     // UI
     TickAnimations,
 }
 
+/// How long the daemon waits for a trigger combination to be pressed
+const TRIGGER_CAPTURE_TIMEOUT_MS: u64 = 10_000;
+
+/// How long to wait before subscribing to device events again after the
+/// daemon went away or refused the subscription
+const EVENT_RESUBSCRIBE_DELAY: Duration = Duration::from_secs(2);
+
 // Reserved for future use
 #[allow(dead_code)]
 pub enum _FutureMessage {
@@ -149,6 +304,7 @@ impl Application for State {
         let initial_commands = Command::batch([
             Command::perform(async { Message::CheckDaemonConnection }, |msg| msg),
             Command::perform(async { Message::LoadDevices }, |msg| msg),
+            Command::perform(async { Message::LoadProfilesList }, |msg| msg),
         ]);
         (initial_state, initial_commands)
     }
@@ -169,6 +325,94 @@ impl Application for State {
             }
             Message::SelectDevice(idx) => {
                 self.selected_device = Some(idx);
+                self.dpi_input.clear();
+                let Some(device) = self.devices.get(idx).filter(|d| has_adjustable_dpi(d)) else {
+                    return Command::none();
+                };
+                let socket_path = self.socket_path.clone();
+                let device_path = device.path.to_string_lossy().to_string();
+                Command::perform(
+                    async move {
+                        let client = crate::ipc::IpcClient::new(socket_path);
+                        client.get_dpi(&device_path).await
+                    },
+                    Message::DpiLoaded,
+                )
+            }
+            Message::DpiLoaded(Ok((x, _))) => {
+                self.dpi_input = x.to_string();
+                Command::none()
+            }
+            Message::DpiLoaded(Err(e)) => {
+                self.add_notification(&format!("Could not read DPI: {}", e), true);
+                Command::none()
+            }
+            Message::UpdateDpiInput(value) => {
+                self.dpi_input = value;
+                Command::none()
+            }
+            Message::ApplyDpi(device_path) => {
+                let Ok(dpi) = self.dpi_input.trim().parse::<u16>() else {
+                    self.add_notification("Enter the DPI as a whole number", true);
+                    return Command::none();
+                };
+                let socket_path = self.socket_path.clone();
+                Command::perform(
+                    async move {
+                        let client = crate::ipc::IpcClient::new(socket_path);
+                        client.set_dpi(&device_path, (dpi, dpi)).await.map(|_| (device_path, dpi))
+                    },
+                    Message::DpiApplied,
+                )
+            }
+            Message::DpiApplied(Ok((device_path, dpi))) => {
+                self.add_notification(&format!("DPI {} applied to {}", dpi, device_path), false);
+                Command::none()
+            }
+            Message::DpiApplied(Err(e)) => {
+                self.add_notification(&format!("DPI update failed: {}", e), true);
+                Command::none()
+            }
+            Message::UpdateLedColor(color) => {
+                self.led_color = color;
+                Command::none()
+            }
+            Message::BatteryLoaded(device_path, Ok(level)) => {
+                self.battery_levels.insert(device_path, level);
+                Command::none()
+            }
+            Message::BatteryLoaded(device_path, Err(_)) => {
+                // Wired devices have no battery, so there is nothing to report
+                self.battery_levels.remove(&device_path);
+                Command::none()
+            }
+            Message::UpdateLedPreset(preset) => {
+                self.led_preset = preset;
+                Command::none()
+            }
+            Message::ApplyLed(device_path) => {
+                let socket_path = self.socket_path.clone();
+                let effect = self.led_preset.effect(self.led_color);
+                Command::perform(
+                    async move {
+                        let client = crate::ipc::IpcClient::new(socket_path);
+                        client.set_led_effect(&device_path, effect).await.map(|_| device_path)
+                    },
+                    Message::LedApplied,
+                )
+            }
+            Message::LedApplied(Ok(device_path)) => {
+                let message = if self.led_preset.uses_color() {
+                    let (r, g, b) = self.led_color;
+                    format!("LED {} #{:02X}{:02X}{:02X} applied to {}", self.led_preset, r, g, b, device_path)
+                } else {
+                    format!("LED {} applied to {}", self.led_preset, device_path)
+                };
+                self.add_notification(&message, false);
+                Command::none()
+            }
+            Message::LedApplied(Err(e)) => {
+                self.add_notification(&format!("LED update failed: {}", e), true);
                 Command::none()
             }
             Message::CheckDaemonConnection => {
@@ -176,11 +420,22 @@ impl Application for State {
                 Command::perform(
                     async move {
                         let client = crate::ipc::IpcClient::new(socket_path);
-                        client.connect().await.is_ok()
+                        if client.connect().await.is_err() {
+                            return Message::DaemonStatusChanged(false);
+                        }
+                        match client.check_protocol().await {
+                            Ok(()) => Message::DaemonStatusChanged(true),
+                            Err(e) => Message::DaemonIncompatible(e),
+                        }
                     },
-                    Message::DaemonStatusChanged,
+                    |msg| msg,
                 )
             }
+            Message::DaemonIncompatible(reason) => {
+                self.daemon_connected = false;
+                self.add_notification(&format!("Incompatible daemon: {}", reason), true);
+                Command::none()
+            }
             Message::DaemonStatusChanged(connected) => {
                 self.daemon_connected = connected;
                 if connected {
@@ -192,11 +447,12 @@ impl Application for State {
             }
             Message::LoadDevices => {
                 let socket_path = self.socket_path.clone();
+                let razer_only = self.razer_only;
                 self.loading = true;
                 Command::perform(
                     async move {
                         let client = crate::ipc::IpcClient::new(socket_path);
-                        client.get_devices().await.map_err(|e| e.to_string())
+                        client.get_devices(razer_only).await.map_err(|e| e.to_string())
                     },
                     Message::DevicesLoaded,
                 )
@@ -206,7 +462,11 @@ impl Application for State {
                 self.devices = devices;
                 self.loading = false;
                 self.add_notification(&format!("Found {} devices", count), false);
-                Command::perform(async { Message::LoadMacros }, |msg| msg)
+
+                Command::batch([
+                    Command::perform(async { Message::LoadMacros }, |msg| msg),
+                    self.read_battery_levels(),
+                ])
             }
             Message::DevicesLoaded(Err(e)) => {
                 self.loading = false;
@@ -227,12 +487,30 @@ impl Application for State {
                 let count = macros.len();
                 self.macros = macros;
                 self.add_notification(&format!("Loaded {} macros", count), false);
-                Command::none()
+                Command::perform(async { Message::LoadMetrics }, |msg| msg)
             }
             Message::MacrosLoaded(Err(e)) => {
                 self.add_notification(&format!("Error loading macros: {}", e), true);
                 Command::none()
             }
+            Message::LoadMetrics => {
+                let socket_path = self.socket_path.clone();
+                Command::perform(
+                    async move {
+                        let client = crate::ipc::IpcClient::new(socket_path);
+                        client.get_metrics().await
+                    },
+                    Message::MetricsLoaded,
+                )
+            }
+            Message::MetricsLoaded(Ok(metrics)) => {
+                self.macro_metrics = metrics;
+                Command::none()
+            }
+            Message::MetricsLoaded(Err(e)) => {
+                self.add_notification(&format!("Error loading macro stats: {}", e), true);
+                Command::none()
+            }
             Message::PlayMacro(macro_name) => {
                 let socket_path = self.socket_path.clone();
                 let name = macro_name.clone();
@@ -252,6 +530,47 @@ impl Application for State {
                 self.add_notification(&format!("Failed to play: {}", e), true);
                 Command::none()
             }
+            Message::StopMacro(macro_name) => {
+                let socket_path = self.socket_path.clone();
+                let name = macro_name.clone();
+                Command::perform(
+                    async move {
+                        let client = crate::ipc::IpcClient::new(socket_path);
+                        client.stop_macro(&name).await.map(|_| name).map_err(|e| e.to_string())
+                    },
+                    Message::MacroStopped,
+                )
+            }
+            Message::MacroStopped(Ok(name)) => {
+                self.add_notification(&format!("Stopped macro: {}", name), false);
+                Command::none()
+            }
+            Message::MacroStopped(Err(e)) => {
+                self.add_notification(&format!("Failed to stop: {}", e), true);
+                Command::none()
+            }
+            Message::StopAllMacros => {
+                let socket_path = self.socket_path.clone();
+                Command::perform(
+                    async move {
+                        let client = crate::ipc::IpcClient::new(socket_path);
+                        client.stop_all_macros().await.map_err(|e| e.to_string())
+                    },
+                    Message::AllMacrosStopped,
+                )
+            }
+            Message::AllMacrosStopped(Ok(count)) => {
+                self.add_notification(&format!("Stopped {} running macros", count), false);
+                Command::none()
+            }
+            Message::AllMacrosStopped(Err(e)) => {
+                self.add_notification(&format!("Failed to stop macros: {}", e), true);
+                Command::none()
+            }
+            Message::UpdateMacroFilter(filter) => {
+                self.macro_filter = filter;
+                Command::none()
+            }
             Message::UpdateMacroName(name) => {
                 self.new_macro_name = name;
                 Command::none()
@@ -343,6 +662,164 @@ impl Application for State {
                 self.add_notification(&format!("Delete failed: {}", e), true);
                 Command::none()
             }
+            Message::DuplicateMacro(macro_name) => {
+                let socket_path = self.socket_path.clone();
+                let new_name = copy_name(&macro_name, &self.macros);
+                Command::perform(
+                    async move {
+                        let client = crate::ipc::IpcClient::new(socket_path);
+                        client.duplicate_macro(&macro_name, &new_name).await.map_err(|e| e.to_string())
+                    },
+                    Message::MacroDuplicated,
+                )
+            }
+            Message::MacroDuplicated(Ok(macro_entry)) => {
+                let name = macro_entry.name.clone();
+                self.macros.retain(|m| m.name != name);
+                self.macros.push(macro_entry);
+                self.recently_updated_macros.insert(name.clone(), Instant::now());
+                self.add_notification(&format!("Duplicated as: {}", name), false);
+                Command::none()
+            }
+            Message::MacroDuplicated(Err(e)) => {
+                self.add_notification(&format!("Duplicate failed: {}", e), true);
+                Command::none()
+            }
+            Message::ToggleMacroEnabled(macro_name, enabled) => {
+                let socket_path = self.socket_path.clone();
+                let name = macro_name.clone();
+                Command::perform(
+                    async move {
+                        let client = crate::ipc::IpcClient::new(socket_path);
+                        client.set_macro_enabled(&name, enabled).await.map(|_| (name, enabled)).map_err(|e| e.to_string())
+                    },
+                    Message::MacroEnabledChanged,
+                )
+            }
+            Message::MacroEnabledChanged(Ok((name, enabled))) => {
+                if let Some(macro_entry) = self.macros.iter_mut().find(|m| m.name == name) {
+                    macro_entry.enabled = enabled;
+                }
+                let verb = if enabled { "Enabled" } else { "Disabled" };
+                self.add_notification(&format!("{}: {}", verb, name), false);
+                Command::none()
+            }
+            Message::MacroEnabledChanged(Err(e)) => {
+                self.add_notification(&format!("Failed to update macro: {}", e), true);
+                Command::none()
+            }
+            Message::EditMacro(macro_name) => {
+                self.editing_macro = self.macros.iter().find(|m| m.name == macro_name).cloned();
+                self.editing_tags = self.editing_macro.as_ref().map(|m| m.tags.join(", ")).unwrap_or_default();
+                self.active_tab = Tab::Macros;
+                Command::none()
+            }
+            Message::UpdateAction(idx, action) => {
+                if let Some(slot) = self.editing_macro.as_mut().and_then(|m| m.actions.get_mut(idx)) {
+                    *slot = action;
+                }
+                Command::none()
+            }
+            Message::MoveAction(from, to) => {
+                if let Some(macro_entry) = self.editing_macro.as_mut() {
+                    if from < macro_entry.actions.len() && to < macro_entry.actions.len() {
+                        let action = macro_entry.actions.remove(from);
+                        macro_entry.actions.insert(to, action);
+                    }
+                }
+                Command::none()
+            }
+            Message::RemoveAction(idx) => {
+                if let Some(macro_entry) = self.editing_macro.as_mut() {
+                    if idx < macro_entry.actions.len() {
+                        macro_entry.actions.remove(idx);
+                    }
+                }
+                Command::none()
+            }
+            Message::UpdateMacroTags(value) => {
+                if let Some(macro_entry) = self.editing_macro.as_mut() {
+                    macro_entry.tags = parse_tags(&value);
+                }
+                self.editing_tags = value;
+                Command::none()
+            }
+            Message::SaveEditedMacro => {
+                let Some(macro_entry) = self.editing_macro.clone() else {
+                    return Command::none();
+                };
+                // The daemon files macros under a device, so prefer the one the
+                // macro is bound to and fall back to the selected device
+                let device_path = macro_entry.device_id.clone()
+                    .or_else(|| self.selected_device
+                        .and_then(|idx| self.devices.get(idx))
+                        .or_else(|| self.devices.first())
+                        .map(|d| d.path.to_string_lossy().to_string()));
+                let Some(device_path) = device_path else {
+                    self.add_notification("No device to save the macro for", true);
+                    return Command::none();
+                };
+
+                let socket_path = self.socket_path.clone();
+                Command::perform(
+                    async move {
+                        let client = crate::ipc::IpcClient::new(socket_path);
+                        let name = macro_entry.name.clone();
+                        client.set_macro(&device_path, macro_entry).await?;
+                        // Show what the daemon stored rather than our local copy
+                        client.get_macro(&name).await
+                    },
+                    Message::MacroSaved,
+                )
+            }
+            Message::CaptureTrigger => {
+                if self.grabbed_devices.is_empty() {
+                    self.add_notification("Grab a device first", true);
+                    return Command::none();
+                }
+                self.capturing_trigger = true;
+                self.add_notification("Press the trigger key combination...", false);
+                let socket_path = self.socket_path.clone();
+                Command::perform(
+                    async move {
+                        let client = crate::ipc::IpcClient::new(socket_path);
+                        client.capture_trigger(TRIGGER_CAPTURE_TIMEOUT_MS).await
+                    },
+                    Message::TriggerCaptured,
+                )
+            }
+            Message::TriggerCaptured(Ok(combo)) => {
+                self.capturing_trigger = false;
+                self.add_notification(&format!("Trigger set to {}", describe_combo(&combo)), false);
+                if let Some(macro_entry) = self.editing_macro.as_mut() {
+                    macro_entry.trigger = combo;
+                }
+                Command::none()
+            }
+            Message::TriggerCaptured(Err(e)) => {
+                self.capturing_trigger = false;
+                self.add_notification(&format!("Trigger not set: {}", e), true);
+                Command::none()
+            }
+            Message::CancelEdit => {
+                self.editing_macro = None;
+                Command::none()
+            }
+            Message::MacroSaved(Ok(macro_entry)) => {
+                let name = macro_entry.name.clone();
+                match self.macros.iter_mut().find(|m| m.name == name) {
+                    Some(existing) => *existing = macro_entry,
+                    None => self.macros.push(macro_entry),
+                }
+                self.editing_macro = None;
+                self.recently_updated_macros.insert(name.clone(), Instant::now());
+                self.add_notification(&format!("Saved macro: {}", name), false);
+                Command::none()
+            }
+            Message::MacroSaved(Err(e)) => {
+                self.add_notification(&format!("Save failed: {}", e), true);
+                Command::none()
+            }
             Message::SaveProfile => {
                 if self.profile_name.trim().is_empty() {
                     self.add_notification("Enter a profile name", true);
@@ -360,12 +837,120 @@ impl Application for State {
             }
             Message::ProfileSaved(Ok((name, count))) => {
                 self.add_notification(&format!("Saved '{}' ({} macros)", name, count), false);
-                Command::none()
+                Command::perform(async { Message::LoadProfilesList }, |msg| msg)
             }
             Message::ProfileSaved(Err(e)) => {
                 self.add_notification(&format!("Save failed: {}", e), true);
                 Command::none()
             }
+            Message::UpdateProfileFile(path) => {
+                self.profile_file = path;
+                Command::none()
+            }
+            Message::ToggleImportOverwrite(overwrite) => {
+                self.import_overwrite = overwrite;
+                Command::none()
+            }
+            Message::ExportProfile => {
+                if self.profile_name.trim().is_empty() || self.profile_file.trim().is_empty() {
+                    self.add_notification("Enter a profile name and a file to export to", true);
+                    return Command::none();
+                }
+                let socket_path = self.socket_path.clone();
+                let name = self.profile_name.clone();
+                let path = expand_home(self.profile_file.trim());
+                Command::perform(
+                    async move {
+                        let client = crate::ipc::IpcClient::new(socket_path);
+                        let profile = client.export_profile(&name).await?;
+                        let yaml = serde_yaml::to_string(&profile).map_err(|e| e.to_string())?;
+                        tokio::fs::write(&path, yaml).await.map_err(|e| format!("{}: {}", path.display(), e))?;
+                        Ok(path.display().to_string())
+                    },
+                    Message::ProfileExported,
+                )
+            }
+            Message::ProfileExported(Ok(path)) => {
+                self.add_notification(&format!("Exported '{}' to {}", self.profile_name, path), false);
+                Command::none()
+            }
+            Message::ProfileExported(Err(e)) => {
+                self.add_notification(&format!("Export failed: {}", e), true);
+                Command::none()
+            }
+            Message::ImportProfile => {
+                if self.profile_file.trim().is_empty() {
+                    self.add_notification("Enter a file to import from", true);
+                    return Command::none();
+                }
+                let socket_path = self.socket_path.clone();
+                let path = expand_home(self.profile_file.trim());
+                let overwrite = self.import_overwrite;
+                Command::perform(
+                    async move {
+                        let yaml = tokio::fs::read_to_string(&path).await.map_err(|e| format!("{}: {}", path.display(), e))?;
+                        let profile: Profile = serde_yaml::from_str(&yaml).map_err(|e| format!("{}: {}", path.display(), e))?;
+                        let client = crate::ipc::IpcClient::new(socket_path);
+                        client.import_profile(profile, overwrite).await
+                    },
+                    Message::ProfileImported,
+                )
+            }
+            Message::ProfileImported(Ok((name, count))) => {
+                self.add_notification(&format!("Imported '{}' ({} macros)", name, count), false);
+                self.profile_name = name;
+                Command::perform(async { Message::LoadProfilesList }, |msg| msg)
+            }
+            Message::ProfileImported(Err(e)) => {
+                self.add_notification(&format!("Import failed: {}", e), true);
+                Command::none()
+            }
+            Message::LoadProfilesList => {
+                let socket_path = self.socket_path.clone();
+                Command::perform(
+                    async move {
+                        let client = crate::ipc::IpcClient::new(socket_path);
+                        client.list_profiles().await
+                    },
+                    Message::ProfilesListed,
+                )
+            }
+            Message::ProfilesListed(Ok(mut profiles)) => {
+                profiles.sort();
+                self.profiles = profiles;
+                Command::none()
+            }
+            Message::ProfilesListed(Err(e)) => {
+                self.add_notification(&format!("Error listing profiles: {}", e), true);
+                Command::none()
+            }
+            Message::SelectProfile(name) => {
+                self.profile_name = name;
+                Command::none()
+            }
+            Message::LoadNamedProfile(name) => {
+                self.profile_name = name;
+                self.update(Message::LoadProfile)
+            }
+            Message::DeleteProfile(profile_name) => {
+                let socket_path = self.socket_path.clone();
+                let name = profile_name.clone();
+                Command::perform(
+                    async move {
+                        let client = crate::ipc::IpcClient::new(socket_path);
+                        client.delete_profile(&name).await.map(|_| name)
+                    },
+                    Message::ProfileDeleted,
+                )
+            }
+            Message::ProfileDeleted(Ok(name)) => {
+                self.add_notification(&format!("Deleted profile: {}", name), false);
+                Command::perform(async { Message::LoadProfilesList }, |msg| msg)
+            }
+            Message::ProfileDeleted(Err(e)) => {
+                self.add_notification(&format!("Delete failed: {}", e), true);
+                Command::none()
+            }
             Message::LoadProfile => {
                 if self.profile_name.trim().is_empty() {
                     self.add_notification("Enter a profile name to load", true);
@@ -381,8 +966,12 @@ impl Application for State {
                     Message::ProfileLoaded,
                 )
             }
-            Message::ProfileLoaded(Ok((name, count))) => {
-                self.add_notification(&format!("Loaded '{}' ({} macros)", name, count), false);
+            Message::ProfileLoaded(Ok((name, count, description))) => {
+                let message = match description {
+                    Some(description) => format!("Loaded '{}' ({} macros): {}", name, count, description),
+                    None => format!("Loaded '{}' ({} macros)", name, count),
+                };
+                self.add_notification(&message, false);
                 Command::perform(async { Message::LoadMacros }, |msg| msg)
             }
             Message::ProfileLoaded(Err(e)) => {
@@ -403,6 +992,31 @@ impl Application for State {
                         break;
                     }
                 }
+                // Refresh the event counters so users can see a grab intercepting input
+                let socket_path = &self.socket_path;
+                Command::batch(self.grabbed_devices.iter().map(|device_path| {
+                    let socket_path = socket_path.clone();
+                    let device_path = device_path.clone();
+                    Command::perform(
+                        async move {
+                            let client = crate::ipc::IpcClient::new(socket_path);
+                            let details = client.get_device_details(&device_path).await;
+                            (device_path, details)
+                        },
+                        |(device_path, details)| Message::DeviceDetailsLoaded(device_path, details),
+                    )
+                }))
+            }
+            Message::DeviceDetailsLoaded(device_path, Ok((grabbed, events_seen))) => {
+                if grabbed {
+                    self.device_events.insert(device_path, events_seen);
+                } else {
+                    self.device_events.remove(&device_path);
+                }
+                Command::none()
+            }
+            Message::DeviceDetailsLoaded(_, Err(_)) => {
+                // Polled twice a second, so keep the last count instead of notifying every failure
                 Command::none()
             }
             Message::GrabDevice(device_path) => {
@@ -441,6 +1055,7 @@ impl Application for State {
             }
             Message::DeviceUngrabbed(Ok(device_path)) => {
                 self.grabbed_devices.remove(&device_path);
+                self.device_events.remove(&device_path);
                 self.add_notification("Device released", false);
                 Command::none()
             }
@@ -448,6 +1063,86 @@ impl Application for State {
                 self.add_notification(&format!("Release failed: {}", e), true);
                 Command::none()
             }
+            Message::GrabAllDevices => {
+                let socket_path = self.socket_path.clone();
+                Command::perform(
+                    async move { crate::ipc::IpcClient::new(socket_path).grab_all_devices().await },
+                    Message::AllDevicesGrabbed,
+                )
+            }
+            Message::UngrabAllDevices => {
+                let socket_path = self.socket_path.clone();
+                Command::perform(
+                    async move { crate::ipc::IpcClient::new(socket_path).ungrab_all_devices().await },
+                    Message::AllDevicesUngrabbed,
+                )
+            }
+            Message::AllDevicesGrabbed(Ok(results)) => {
+                let failed = results.iter().filter(|(_, grabbed)| !grabbed).count();
+                for (device_path, grabbed) in results {
+                    if grabbed {
+                        self.grabbed_devices.insert(device_path);
+                    }
+                }
+                if failed > 0 {
+                    self.add_notification(&format!("{} devices could not be grabbed", failed), true);
+                } else {
+                    self.add_notification("All devices grabbed", false);
+                }
+                Command::none()
+            }
+            Message::AllDevicesUngrabbed(Ok(results)) => {
+                for (device_path, released) in results {
+                    if released {
+                        self.grabbed_devices.remove(&device_path);
+                        self.device_events.remove(&device_path);
+                    }
+                }
+                self.add_notification("All devices released", false);
+                Command::none()
+            }
+            Message::AllDevicesGrabbed(Err(e)) => {
+                self.add_notification(&format!("Grab failed: {}", e), true);
+                Command::none()
+            }
+            Message::AllDevicesUngrabbed(Err(e)) => {
+                self.add_notification(&format!("Release failed: {}", e), true);
+                Command::none()
+            }
+            Message::DevicesChanged(devices) => {
+                // Grabs on unplugged devices are gone with the device
+                let present: HashSet<String> = devices.iter()
+                    .map(|device| device.path.to_string_lossy().to_string())
+                    .collect();
+                self.grabbed_devices.retain(|path| present.contains(path));
+                self.device_events.retain(|path, _| present.contains(path));
+                self.battery_levels.retain(|path, _| present.contains(path));
+
+                let selected_path = self.selected_device
+                    .and_then(|idx| self.devices.get(idx))
+                    .map(|device| device.path.clone());
+                self.devices = devices.into_iter()
+                    .filter(|device| !self.razer_only || device.is_razer())
+                    .collect();
+                self.selected_device = selected_path
+                    .and_then(|path| self.devices.iter().position(|device| device.path == path));
+
+                self.add_notification(&format!("Devices changed - {} devices", self.devices.len()), false);
+                // A newly plugged in wireless device shows its charge right away
+                self.read_battery_levels()
+            }
+            Message::ToggleRazerOnly(razer_only) => {
+                self.razer_only = razer_only;
+                self.selected_device = None;
+                Command::perform(async { Message::LoadDevices }, |msg| msg)
+            }
+            Message::DeviceGrabLost(device_path) => {
+                if self.grabbed_devices.remove(&device_path) {
+                    self.device_events.remove(&device_path);
+                    self.add_notification(&format!("Lost grab on {}", device_path), true);
+                }
+                Command::none()
+            }
         }
     }
 
@@ -474,11 +1169,69 @@ impl Application for State {
     }
 
     fn subscription(&self) -> Subscription<Message> {
-        iced::time::every(Duration::from_millis(500)).map(|_| Message::TickAnimations)
+        Subscription::batch([
+            iced::time::every(Duration::from_millis(500)).map(|_| Message::TickAnimations),
+            device_events(self.socket_path.clone()),
+        ])
     }
 }
 
+/// Device notifications pushed by the daemon, resubscribing whenever the
+/// connection drops
+fn device_events(socket_path: PathBuf) -> Subscription<Message> {
+    iced::subscription::unfold(
+        ("device-events", socket_path.clone()),
+        None,
+        move |subscription| {
+            let socket_path = socket_path.clone();
+            async move {
+                let mut subscription = subscription;
+                loop {
+                    let mut events = match subscription.take() {
+                        Some(events) => events,
+                        None => match crate::ipc::IpcClient::new(socket_path.clone()).subscribe_device_events().await {
+                            Ok(events) => events,
+                            Err(_) => {
+                                tokio::time::sleep(EVENT_RESUBSCRIBE_DELAY).await;
+                                continue;
+                            }
+                        },
+                    };
+
+                    match events.next_event().await {
+                        Ok(Response::DevicesChanged(devices)) => {
+                            return (Message::DevicesChanged(devices), Some(events));
+                        }
+                        Ok(Response::DeviceUngrabbed { device_path }) => {
+                            return (Message::DeviceGrabLost(device_path), Some(events));
+                        }
+                        Ok(_) => subscription = Some(events),
+                        Err(_) => tokio::time::sleep(EVENT_RESUBSCRIBE_DELAY).await,
+                    }
+                }
+            }
+        },
+    )
+}
+
 impl State {
+    /// Ask the daemon for the battery of every listed Razer device, the only
+    /// ones that can report one through OpenRazer
+    fn read_battery_levels(&self) -> Command<Message> {
+        Command::batch(self.devices.iter().filter(|d| d.is_razer()).map(|device| {
+            let socket_path = self.socket_path.clone();
+            let device_path = device.path.to_string_lossy().to_string();
+            Command::perform(
+                async move {
+                    let client = crate::ipc::IpcClient::new(socket_path);
+                    let result = client.get_battery_level(&device_path).await;
+                    (device_path, result)
+                },
+                |(device_path, result)| Message::BatteryLoaded(device_path, result),
+            )
+        }))
+    }
+
     fn add_notification(&mut self, message: &str, is_error: bool) {
         self.notifications.push_back(Notification {
             message: message.to_string(),
@@ -571,7 +1324,10 @@ impl State {
     fn view_main_content(&self) -> Element<'_, Message> {
         let content = match self.active_tab {
             Tab::Devices => self.view_devices_tab(),
-            Tab::Macros => self.view_macros_tab(),
+            Tab::Macros => match &self.editing_macro {
+                Some(macro_entry) => self.view_macro_editor(macro_entry),
+                None => self.view_macros_tab(),
+            },
             Tab::Profiles => self.view_profiles_tab(),
         };
 
@@ -586,6 +1342,17 @@ impl State {
         let header = row![
             text("DEVICES").size(24),
             Space::with_width(Length::Fill),
+            checkbox("Razer only", self.razer_only)
+                .on_toggle(Message::ToggleRazerOnly),
+            Space::with_width(12),
+            button("Grab All")
+                .on_press(Message::GrabAllDevices)
+                .style(iced::theme::Button::Secondary),
+            Space::with_width(8),
+            button("Release All")
+                .on_press(Message::UngrabAllDevices)
+                .style(iced::theme::Button::Secondary),
+            Space::with_width(8),
             button("Reload")
                 .on_press(Message::LoadDevices)
                 .style(iced::theme::Button::Secondary),
@@ -623,17 +1390,15 @@ impl State {
         let is_grabbed = self.grabbed_devices.contains(&device_path);
         let is_selected = self.selected_device == Some(idx);
 
-        let icon = if device.name.to_lowercase().contains("mouse") {
-            "🖱️"
-        } else if device.name.to_lowercase().contains("keyboard") {
-            "⌨️"
-        } else {
-            "🎮"
-        };
+        let icon = device_icon(device);
 
         let status_badge = if is_grabbed {
+            let label = match self.device_events.get(&device_path) {
+                Some(events_seen) => format!("GRABBED · events: {}", events_seen),
+                None => "GRABBED".to_string(),
+            };
             container(
-                text("GRABBED").size(10)
+                text(label).size(10)
             )
             .padding([4, 8])
             .style(iced::theme::Container::Box)
@@ -641,6 +1406,15 @@ impl State {
             container(text("").size(10))
         };
 
+        let battery_badge = match self.battery_levels.get(&device_path) {
+            Some((percent, charging)) => container(
+                text(format!("{} {}%", if *charging { "⚡" } else { "🔋" }, percent)).size(10)
+            )
+            .padding([4, 8])
+            .style(iced::theme::Container::Box),
+            None => container(text("").size(10)),
+        };
+
         let action_button = if is_grabbed {
             button("Release")
                 .on_press(Message::UngrabDevice(device_path.clone()))
@@ -653,7 +1427,7 @@ impl State {
 
         let select_indicator = if is_selected { "▶ " } else { "" };
 
-        let card_content = column![
+        let mut card_content = column![
             row![
                 text(icon).size(28),
                 Space::with_width(12),
@@ -665,6 +1439,8 @@ impl State {
                     )).size(11),
                 ],
                 Space::with_width(Length::Fill),
+                battery_badge,
+                Space::with_width(8),
                 status_badge,
             ]
             .align_items(Alignment::Center),
@@ -679,6 +1455,15 @@ impl State {
         ]
         .spacing(8);
 
+        // Older daemons don't report capabilities, so keep offering the picker to them
+        let has_leds = device.capabilities.has_leds || device.capabilities == DeviceCapabilities::default();
+        if is_selected && has_adjustable_dpi(device) {
+            card_content = card_content.push(self.view_dpi_control(device_path.clone()));
+        }
+        if is_selected && has_leds {
+            card_content = card_content.push(self.view_led_picker(device_path));
+        }
+
         container(card_content)
             .padding(16)
             .width(Length::Fill)
@@ -686,11 +1471,86 @@ impl State {
             .into()
     }
 
+    fn view_dpi_control(&self, device_path: String) -> Element<'_, Message> {
+        container(
+            row![
+                text("DPI").size(12),
+                text_input(&format!("{}-{}", MIN_DPI, MAX_DPI), &self.dpi_input)
+                    .on_input(Message::UpdateDpiInput)
+                    .on_submit(Message::ApplyDpi(device_path.clone()))
+                    .padding(6)
+                    .size(12)
+                    .width(100),
+                button("Apply DPI")
+                    .on_press(Message::ApplyDpi(device_path))
+                    .style(iced::theme::Button::Primary),
+            ]
+            .spacing(8)
+            .align_items(Alignment::Center)
+        )
+        .padding(12)
+        .width(Length::Fill)
+        .into()
+    }
+
+    fn view_led_picker(&self, device_path: String) -> Element<'_, Message> {
+        let (r, g, b) = self.led_color;
+
+        let channel = |label: &'static str, value: u8, update: fn(Rgb, u8) -> Rgb| {
+            let color = self.led_color;
+            row![
+                text(label).size(12).width(20),
+                slider(0..=255, value, move |v| Message::UpdateLedColor(update(color, v))),
+                text(format!("{:3}", value)).size(12).width(30),
+            ]
+            .spacing(8)
+            .align_items(Alignment::Center)
+        };
+
+        let swatch = container(Space::new(48, 48))
+            .style(move |_theme: &Theme| container::Appearance {
+                background: Some(Color::from_rgb8(r, g, b).into()),
+                ..Default::default()
+            });
+
+        container(
+            row![
+                column![
+                    text("LED EFFECT").size(12),
+                    pick_list(&LedPreset::ALL[..], Some(self.led_preset), Message::UpdateLedPreset)
+                        .text_size(12),
+                    channel("R", r, |(_, g, b), v| (v, g, b)),
+                    channel("G", g, |(r, _, b), v| (r, v, b)),
+                    channel("B", b, |(r, g, _), v| (r, g, v)),
+                ]
+                .spacing(6)
+                .width(Length::Fill),
+                Space::with_width(16),
+                column![
+                    swatch,
+                    button("Apply LED")
+                        .on_press(Message::ApplyLed(device_path))
+                        .style(iced::theme::Button::Primary),
+                ]
+                .spacing(8)
+                .align_items(Alignment::Center),
+            ]
+            .align_items(Alignment::Center)
+        )
+        .padding(12)
+        .width(Length::Fill)
+        .into()
+    }
+
     fn view_macros_tab(&self) -> Element<'_, Message> {
         let header = row![
             text("MACROS").size(24),
             Space::with_width(Length::Fill),
             text(format!("{} total", self.macros.len())).size(14),
+            Space::with_width(12),
+            button("⏹ Stop All")
+                .on_press(Message::StopAllMacros)
+                .style(iced::theme::Button::Destructive),
         ]
         .align_items(Alignment::Center);
 
@@ -704,12 +1564,55 @@ impl State {
             Space::with_height(20),
             text("MACRO LIBRARY").size(18),
             Space::with_height(10),
+            text_input("Search macros by name, tag or typed text...", &self.macro_filter)
+                .on_input(Message::UpdateMacroFilter)
+                .padding(10)
+                .size(13),
             macro_list,
+            Space::with_height(20),
+            self.view_metrics_panel(),
         ]
         .spacing(10)
         .into()
     }
 
+    fn view_metrics_panel(&self) -> Element<'_, Message> {
+        let header = row![
+            text("MACRO STATS").size(18),
+            Space::with_width(Length::Fill),
+            button("↻ Refresh")
+                .on_press(Message::LoadMetrics)
+                .style(iced::theme::Button::Secondary),
+        ]
+        .align_items(Alignment::Center);
+
+        if self.macro_metrics.is_empty() {
+            return column![header, text("No macros have run yet").size(12)]
+                .spacing(8)
+                .into();
+        }
+
+        let mut rows: Column<Message> = column![].spacing(4);
+        for metric in &self.macro_metrics {
+            rows = rows.push(row![
+                text(&metric.name).size(12).width(Length::FillPortion(3)),
+                text(format!("{} runs", metric.total_executions)).size(12).width(Length::FillPortion(1)),
+                text(format!("avg {} ms", metric.average_duration.as_millis())).size(12).width(Length::FillPortion(1)),
+                text(format!("last {}", format_elapsed_since(metric.last_run_ts))).size(12).width(Length::FillPortion(1)),
+            ]);
+        }
+
+        column![
+            header,
+            container(scrollable(rows).height(150))
+                .padding(12)
+                .width(Length::Fill)
+                .style(iced::theme::Container::Box),
+        ]
+        .spacing(8)
+        .into()
+    }
+
     fn view_recording_panel(&self) -> Element<'_, Message> {
         let name_input = text_input("Enter macro name (e.g., 'Quick Reload')", &self.new_macro_name)
             .on_input(Message::UpdateMacroName)
@@ -807,9 +1710,24 @@ impl State {
             .into();
         }
 
+        let filter = self.macro_filter.trim().to_lowercase();
+        let matching: Vec<&MacroEntry> = self.macros.iter()
+            .filter(|m| macro_matches(m, &filter))
+            .collect();
+
+        if matching.is_empty() {
+            return container(
+                text(format!("No macros match '{}'", self.macro_filter.trim())).size(14)
+            )
+            .padding(20)
+            .width(Length::Fill)
+            .center_x()
+            .into();
+        }
+
         let mut list: Column<Message> = column![].spacing(8);
 
-        for macro_entry in &self.macros {
+        for macro_entry in matching {
             let is_recent = self.recently_updated_macros.contains_key(&macro_entry.name);
             let name_prefix = if is_recent { "★ " } else { "⚡ " };
 
@@ -818,10 +1736,11 @@ impl State {
                     column![
                         text(format!("{}{}", name_prefix, macro_entry.name)).size(15),
                         text(format!(
-                            "{} actions | {} trigger keys | {}",
+                            "{} actions | {} trigger keys | {}{}",
                             macro_entry.actions.len(),
                             macro_entry.trigger.keys.len(),
-                            if macro_entry.enabled { "enabled" } else { "disabled" }
+                            if macro_entry.enabled { "enabled" } else { "disabled" },
+                            describe_tags(&macro_entry.tags),
                         )).size(11),
                     ]
                     .spacing(4),
@@ -829,6 +1748,18 @@ impl State {
                     button("▶ Test")
                         .on_press(Message::PlayMacro(macro_entry.name.clone()))
                         .style(iced::theme::Button::Secondary),
+                    button("⏹")
+                        .on_press(Message::StopMacro(macro_entry.name.clone()))
+                        .style(iced::theme::Button::Secondary),
+                    button("✏ Edit")
+                        .on_press(Message::EditMacro(macro_entry.name.clone()))
+                        .style(iced::theme::Button::Secondary),
+                    button("⧉ Duplicate")
+                        .on_press(Message::DuplicateMacro(macro_entry.name.clone()))
+                        .style(iced::theme::Button::Secondary),
+                    button(if macro_entry.enabled { "Disable" } else { "Enable" })
+                        .on_press(Message::ToggleMacroEnabled(macro_entry.name.clone(), !macro_entry.enabled))
+                        .style(iced::theme::Button::Secondary),
                     button("🗑")
                         .on_press(Message::DeleteMacro(macro_entry.name.clone()))
                         .style(iced::theme::Button::Destructive),
@@ -846,6 +1777,132 @@ impl State {
         scrollable(list).height(300).into()
     }
 
+    fn view_macro_editor(&self, macro_entry: &MacroEntry) -> Element<'_, Message> {
+        let header = row![
+            text(format!("EDIT: {}", macro_entry.name)).size(24),
+            Space::with_width(Length::Fill),
+            button("Cancel")
+                .on_press(Message::CancelEdit)
+                .style(iced::theme::Button::Secondary),
+            Space::with_width(8),
+            button("💾 Save")
+                .on_press(Message::SaveEditedMacro)
+                .style(iced::theme::Button::Primary),
+        ]
+        .align_items(Alignment::Center);
+
+        let trigger_button = if self.capturing_trigger {
+            button("Press keys now...").style(iced::theme::Button::Secondary)
+        } else {
+            button("⌨ Set Trigger")
+                .on_press(Message::CaptureTrigger)
+                .style(iced::theme::Button::Secondary)
+        };
+
+        let trigger_row = container(
+            row![
+                text("Trigger").size(14),
+                Space::with_width(12),
+                text(describe_combo(&macro_entry.trigger)).size(14),
+                Space::with_width(Length::Fill),
+                trigger_button,
+            ]
+            .align_items(Alignment::Center)
+        )
+        .padding(12)
+        .width(Length::Fill)
+        .style(iced::theme::Container::Box);
+
+        let tags_row = row![
+            text("Tags").size(14),
+            Space::with_width(12),
+            text_input("work, games, ...", &self.editing_tags)
+                .on_input(Message::UpdateMacroTags)
+                .padding(6)
+                .size(13),
+        ]
+        .align_items(Alignment::Center);
+
+        let last = macro_entry.actions.len().saturating_sub(1);
+        let mut list: Column<Message> = column![].spacing(6);
+
+        for (idx, action) in macro_entry.actions.iter().enumerate() {
+            let editor: Element<'_, Message> = match action {
+                Action::Delay(ms) => {
+                    let ms = *ms;
+                    row![
+                        text("Wait").size(13),
+                        text_input("ms", &ms.to_string())
+                            .on_input(move |value| {
+                                // Keep the old value while the field holds something unparsable
+                                let ms = if value.trim().is_empty() { 0 } else { value.trim().parse().unwrap_or(ms) };
+                                Message::UpdateAction(idx, Action::Delay(ms))
+                            })
+                            .padding(6)
+                            .size(13)
+                            .width(100),
+                        text("ms").size(13),
+                    ]
+                    .spacing(8)
+                    .align_items(Alignment::Center)
+                    .into()
+                }
+                Action::Type(typed) => row![
+                    text("Type").size(13),
+                    text_input("Text to type", typed)
+                        .on_input(move |value| Message::UpdateAction(idx, Action::Type(value)))
+                        .padding(6)
+                        .size(13),
+                ]
+                .spacing(8)
+                .align_items(Alignment::Center)
+                .into(),
+                other => text(describe_action(other)).size(13).into(),
+            };
+
+            let action_row = row![
+                text(format!("{}.", idx + 1)).size(12).width(30),
+                container(editor).width(Length::Fill),
+                button("▲")
+                    .on_press_maybe((idx > 0).then(|| Message::MoveAction(idx, idx - 1)))
+                    .style(iced::theme::Button::Text),
+                button("▼")
+                    .on_press_maybe((idx < last).then(|| Message::MoveAction(idx, idx + 1)))
+                    .style(iced::theme::Button::Text),
+                button("🗑")
+                    .on_press(Message::RemoveAction(idx))
+                    .style(iced::theme::Button::Destructive),
+            ]
+            .spacing(8)
+            .align_items(Alignment::Center);
+
+            list = list.push(
+                container(action_row)
+                    .padding(8)
+                    .width(Length::Fill)
+                    .style(iced::theme::Container::Box)
+            );
+        }
+
+        if macro_entry.actions.is_empty() {
+            list = list.push(text("No actions left - a macro needs at least one to be saved").size(12));
+        }
+
+        column![
+            header,
+            Space::with_height(20),
+            trigger_row,
+            Space::with_height(10),
+            tags_row,
+            Space::with_height(10),
+            text(format!("{} actions", macro_entry.actions.len())).size(14),
+            Space::with_height(10),
+            list,
+        ]
+        .spacing(10)
+        .into()
+    }
+
     fn view_profiles_tab(&self) -> Element<'_, Message> {
         let header = text("PROFILES").size(24);
 
@@ -887,6 +1944,76 @@ impl State {
         ]
         .spacing(4);
 
+        let profile_list = if self.profiles.is_empty() {
+            column![text("No saved profiles yet").size(12)]
+        } else {
+            let mut list: Column<Message> = column![].spacing(6);
+            for name in &self.profiles {
+                let is_selected = *name == self.profile_name;
+                let select_indicator = if is_selected { "▶ " } else { "" };
+
+                let profile_row = container(
+                    row![
+                        button(text(format!("{}{}", select_indicator, name)).size(14))
+                            .on_press(Message::SelectProfile(name.clone()))
+                            .style(iced::theme::Button::Text)
+                            .width(Length::Fill),
+                        button("Load")
+                            .on_press(Message::LoadNamedProfile(name.clone()))
+                            .style(iced::theme::Button::Secondary),
+                        button("🗑")
+                            .on_press(Message::DeleteProfile(name.clone()))
+                            .style(iced::theme::Button::Destructive),
+                    ]
+                    .spacing(8)
+                    .align_items(Alignment::Center)
+                )
+                .padding(8)
+                .width(Length::Fill)
+                .style(iced::theme::Container::Box);
+
+                list = list.push(profile_row);
+            }
+            list
+        };
+
+        let share_panel = column![
+            text("SHARE PROFILES").size(16),
+            Space::with_height(12),
+            text_input("Profile file (e.g. ~/macros.yaml)", &self.profile_file)
+                .on_input(Message::UpdateProfileFile)
+                .padding(12)
+                .size(14),
+            Space::with_height(12),
+            row![
+                button("Export to file")
+                    .on_press(Message::ExportProfile)
+                    .style(iced::theme::Button::Secondary),
+                Space::with_width(10),
+                button("Import from file")
+                    .on_press(Message::ImportProfile)
+                    .style(iced::theme::Button::Secondary),
+                Space::with_width(16),
+                checkbox("Overwrite existing profile", self.import_overwrite)
+                    .on_toggle(Message::ToggleImportOverwrite)
+                    .size(16),
+            ]
+            .align_items(Alignment::Center),
+        ];
+
+        let saved_profiles = column![
+            row![
+                text("SAVED PROFILES").size(16),
+                Space::with_width(Length::Fill),
+                button("Refresh")
+                    .on_press(Message::LoadProfilesList)
+                    .style(iced::theme::Button::Secondary),
+            ]
+            .align_items(Alignment::Center),
+            Space::with_height(12),
+            profile_list,
+        ];
+
         let panel_content = column![
             text("SAVE / LOAD CONFIGURATION").size(16),
             Space::with_height(16),
@@ -908,6 +2035,16 @@ impl State {
                 .padding(20)
                 .width(Length::Fill)
                 .style(iced::theme::Container::Box),
+            Space::with_height(20),
+            container(share_panel)
+                .padding(20)
+                .width(Length::Fill)
+                .style(iced::theme::Container::Box),
+            Space::with_height(20),
+            container(saved_profiles)
+                .padding(20)
+                .width(Length::Fill)
+                .style(iced::theme::Container::Box),
         ]
         .spacing(10)
         .into()
@@ -946,3 +2083,149 @@ impl State {
         .into()
     }
 }
+
+/// How long ago a Unix timestamp in milliseconds was, e.g. "5m ago"
+fn format_elapsed_since(timestamp_ms: u64) -> String {
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let seconds = now_ms.saturating_sub(timestamp_ms) / 1000;
+    match seconds {
+        0..=59 => format!("{}s ago", seconds),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
+
+/// Icon for a device card, from the capabilities the daemon reported
+fn device_icon(device: &DeviceInfo) -> &'static str {
+    match device.capabilities.kind() {
+        DeviceKind::Keyboard => "⌨️",
+        DeviceKind::Mouse => "🖱️",
+        DeviceKind::Combo => "🖱️⌨️",
+        // Daemons that don't report capabilities leave only the name to go by
+        DeviceKind::Other if device.name.to_lowercase().contains("mouse") => "🖱️",
+        DeviceKind::Other if device.name.to_lowercase().contains("keyboard") => "⌨️",
+        DeviceKind::Other => "🎮",
+    }
+}
+
+/// Short human readable description of an action for the macro editor
+fn describe_action(action: &Action) -> String {
+    match action {
+        Action::KeyPress(key) => format!("Press key {}", key),
+        Action::KeyRelease(key) => format!("Release key {}", key),
+        Action::Delay(ms) => format!("Wait {} ms", ms),
+        Action::RandomDelay { min_ms, max_ms } => format!("Wait {}-{} ms", min_ms, max_ms),
+        Action::Execute(command) => format!("Run `{}`", command),
+        Action::Type(typed) => format!("Type \"{}\"", typed),
+        Action::MousePress(mouse_button) => format!("Press mouse button {}", mouse_button),
+        Action::MouseRelease(mouse_button) => format!("Release mouse button {}", mouse_button),
+        Action::KeyTap(key) => format!("Tap key {}", key),
+        Action::MouseClick(mouse_button) => format!("Click mouse button {}", mouse_button),
+        Action::Chord(keys) => format!("Press keys {:?} together", keys),
+        Action::MouseMove(x, y) => format!("Move mouse by ({}, {})", x, y),
+        Action::MouseMoveAbsolute(x, y) => format!("Move mouse to ({}, {})", x, y),
+        Action::MouseScroll(amount) => format!("Scroll {}", amount),
+        Action::Repeat { count, actions } => format!("Repeat {} times ({} actions)", count, actions.len()),
+        Action::CallMacro(name) => format!("Run macro \"{}\"", name),
+        Action::IfKeyHeld { key_code, then, else_ } => format!(
+            "If key {} is held ({} actions, otherwise {})", key_code, then.len(), else_.len()
+        ),
+        Action::WaitForKey { key_code, timeout_ms: Some(ms) } => format!("Wait for key {} (up to {} ms)", key_code, ms),
+        Action::WaitForKey { key_code, timeout_ms: None } => format!("Wait for key {}", key_code),
+        Action::ExecuteCapture { command, into: CaptureTarget::Type } => format!("Run `{}` and type its output", command),
+        Action::ExecuteCapture { command, into: CaptureTarget::Log } => format!("Run `{}` and log its output", command),
+    }
+}
+
+/// Key codes of a trigger, modifiers first, e.g. "29 + 30" or "42 + Mouse Side"
+fn describe_combo(combo: &KeyCombo) -> String {
+    if combo.keys.is_empty() && combo.modifiers.is_empty() {
+        return "none".to_string();
+    }
+    combo.modifiers.iter()
+        .chain(combo.keys.iter())
+        .map(|&code| describe_input_code(code))
+        .collect::<Vec<_>>()
+        .join(" + ")
+}
+
+/// Mouse buttons by name, keyboard keys by code
+fn describe_input_code(code: u16) -> String {
+    const BUTTON_NAMES: [&str; 8] = ["Left", "Right", "Middle", "Side", "Extra", "Forward", "Back", "Task"];
+    if is_mouse_button(code) {
+        format!("Mouse {}", BUTTON_NAMES[(code - BTN_LEFT) as usize])
+    } else {
+        code.to_string()
+    }
+}
+
+/// Whether a macro's name, or text it types or runs, contains the lowercase filter
+fn macro_matches(macro_entry: &MacroEntry, filter: &str) -> bool {
+    fn actions_match(actions: &[Action], filter: &str) -> bool {
+        actions.iter().any(|action| match action {
+            Action::Type(text)
+            | Action::Execute(text)
+            | Action::ExecuteCapture { command: text, .. }
+            | Action::CallMacro(text) => text.to_lowercase().contains(filter),
+            Action::Repeat { actions, .. } => actions_match(actions, filter),
+            Action::IfKeyHeld { then, else_, .. } => actions_match(then, filter) || actions_match(else_, filter),
+            _ => false,
+        })
+    }
+
+    filter.is_empty()
+        || macro_entry.name.to_lowercase().contains(filter)
+        || macro_entry.tags.iter().any(|tag| tag.to_lowercase().contains(filter))
+        || actions_match(&macro_entry.actions, filter)
+}
+
+/// Whether a device may have a DPI setting: a Razer pointer, or a Razer
+/// device found through OpenRazer whose capabilities are unknown
+fn has_adjustable_dpi(device: &DeviceInfo) -> bool {
+    device.is_razer() && matches!(
+        device.capabilities.kind(),
+        DeviceKind::Mouse | DeviceKind::Combo | DeviceKind::Other
+    )
+}
+
+/// Split a comma separated tags field, dropping blank entries
+fn parse_tags(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// " | #work #games" for the macro list, or nothing for an untagged macro
+fn describe_tags(tags: &[String]) -> String {
+    if tags.is_empty() {
+        return String::new();
+    }
+    let tags: Vec<String> = tags.iter().map(|tag| format!("#{}", tag)).collect();
+    format!(" | {}", tags.join(" "))
+}
+
+/// First of "<name> copy", "<name> copy 2", ... not taken by another macro
+fn copy_name(name: &str, macros: &[MacroEntry]) -> String {
+    let taken = |candidate: &str| macros.iter().any(|m| m.name == candidate);
+    let mut candidate = format!("{} copy", name);
+    let mut n = 2;
+    while taken(&candidate) {
+        candidate = format!("{} copy {}", name, n);
+        n += 1;
+    }
+    candidate
+}
+
+/// Expand a leading `~/` to the user's home directory
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
@@ -3,7 +3,7 @@
 //! This module provides a simplified interface for the GUI to communicate
 //! with the razermapper daemon using the common IPC client.
 
-use razermapper_common::{ipc_client, DeviceInfo, MacroEntry, Request, Response};
+use razermapper_common::{ipc_client, DeviceInfo, ErrorKind, KeyCombo, LedEffect, MacroEntry, MacroMetric, Profile, Request, Response, DEFAULT_REPLAY_SPEED, PROTOCOL_VERSION};
 use std::path::PathBuf;
 // Import removed as it's not used
 
@@ -26,35 +26,91 @@ impl GuiIpcClient {
         }
     }
 
+    /// Check that the daemon speaks the same protocol version as this GUI
+    pub async fn check_protocol(&self) -> Result<(), String> {
+        let request = Request::Hello {
+            client_version: PROTOCOL_VERSION,
+        };
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::Hello { protocol, .. }) if protocol == PROTOCOL_VERSION => Ok(()),
+            Ok(Response::Hello { protocol, .. }) => Err(format!(
+                "Daemon speaks protocol version {}, GUI speaks version {}", protocol, PROTOCOL_VERSION
+            )),
+            Ok(other) => match other.as_error() {
+                Some((code, message)) => Err(error_message(code, message)),
+                None => Err("Daemon does not support version negotiation - it may be outdated".to_string()),
+            },
+            Err(e) => Err(format!("Failed to check daemon version: {}", e)),
+        }
+    }
+
     /// Get list of available devices
-    pub async fn get_devices(&self) -> Result<Vec<DeviceInfo>, String> {
-        let request = Request::GetDevices;
+    ///
+    /// With `razer_only` set, devices made by other vendors are left out.
+    pub async fn get_devices(&self, razer_only: bool) -> Result<Vec<DeviceInfo>, String> {
+        let request = if razer_only { Request::GetRazerDevices } else { Request::GetDevices };
         match ipc_client::send_to_path(&request, &self.socket_path).await {
             Ok(Response::Devices(devices)) => Ok(devices),
-            Ok(_) => Err("Unexpected response".to_string()),
+            Ok(other) => Err(unexpected(other)),
             Err(e) => Err(format!("Failed to get devices: {}", e)),
         }
     }
 
+    /// Whether a device is grabbed and how many input events the daemon has read from it
+    pub async fn get_device_details(&self, device_path: &str) -> Result<(bool, u64), String> {
+        let request = Request::GetDeviceDetails {
+            device_path: device_path.to_string(),
+        };
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::DeviceDetails { grabbed, events_seen, .. }) => Ok((grabbed, events_seen)),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to get device details: {}", e)),
+        }
+    }
+
     /// Get list of configured macros
     pub async fn list_macros(&self) -> Result<Vec<MacroEntry>, String> {
         let request = Request::ListMacros;
         match ipc_client::send_to_path(&request, &self.socket_path).await {
             Ok(Response::Macros(macros)) => Ok(macros),
-            Ok(_) => Err("Unexpected response".to_string()),
+            Ok(other) => Err(unexpected(other)),
             Err(e) => Err(format!("Failed to list macros: {}", e)),
         }
     }
 
+    /// Get execution counts and durations of the macros that have run
+    pub async fn get_metrics(&self) -> Result<Vec<MacroMetric>, String> {
+        let request = Request::GetMetrics;
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::Metrics(metrics)) => Ok(metrics),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to get macro metrics: {}", e)),
+        }
+    }
+
+    /// Fetch a single macro by name
+    pub async fn get_macro(&self, name: &str) -> Result<MacroEntry, String> {
+        let request = Request::GetMacro {
+            name: name.to_string(),
+        };
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::Macro(macro_entry)) => Ok(macro_entry),
+            Ok(Response::ErrorCode { code: ErrorKind::NotFound, .. }) => Err(format!("Macro '{}' no longer exists", name)),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to get macro: {}", e)),
+        }
+    }
+
     /// Start recording a macro for a device
     pub async fn start_recording_macro(&self, device_path: &str, name: &str) -> Result<(), String> {
         let request = Request::RecordMacro {
             device_path: device_path.to_string(),
             name: name.to_string(),
+            capture_trigger: false,
         };
         match ipc_client::send_to_path(&request, &self.socket_path).await {
             Ok(Response::RecordingStarted { .. }) => Ok(()),
-            Ok(_) => Err("Unexpected response".to_string()),
+            Ok(other) => Err(unexpected(other)),
             Err(e) => Err(format!("Failed to start recording: {}", e)),
         }
     }
@@ -64,7 +120,7 @@ impl GuiIpcClient {
         let request = Request::StopRecording;
         match ipc_client::send_to_path(&request, &self.socket_path).await {
             Ok(Response::RecordingStopped { macro_entry }) => Ok(macro_entry),
-            Ok(_) => Err("Unexpected response".to_string()),
+            Ok(other) => Err(unexpected(other)),
             Err(e) => Err(format!("Failed to stop recording: {}", e)),
         }
     }
@@ -76,23 +132,101 @@ impl GuiIpcClient {
         };
         match ipc_client::send_to_path(&request, &self.socket_path).await {
             Ok(Response::Ack) => Ok(()),
-            Ok(_) => Err("Unexpected response".to_string()),
+            Ok(other) => Err(unexpected(other)),
             Err(e) => Err(format!("Failed to delete macro: {}", e)),
         }
     }
 
+    /// Copy a macro under a new name, returning the copy the daemon stored
+    pub async fn duplicate_macro(&self, source: &str, new_name: &str) -> Result<MacroEntry, String> {
+        let request = Request::DuplicateMacro {
+            source: source.to_string(),
+            new_name: new_name.to_string(),
+        };
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::Macro(macro_entry)) => Ok(macro_entry),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to duplicate macro: {}", e)),
+        }
+    }
+
+    /// Create a macro or replace the one with the same name, returning
+    /// whether it was created
+    pub async fn set_macro(&self, device_path: &str, macro_entry: MacroEntry) -> Result<bool, String> {
+        let request = Request::SetMacro {
+            device_path: device_path.to_string(),
+            macro_entry,
+        };
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::MacroSet { created }) => Ok(created),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to save macro: {}", e)),
+        }
+    }
+
+    /// Wait for the user to press a key combination on a grabbed device
+    pub async fn capture_trigger(&self, timeout_ms: u64) -> Result<KeyCombo, String> {
+        let request = Request::CaptureTrigger { timeout_ms };
+        // The daemon holds the reply until the keys are pressed, so allow for the
+        // full capture window and don't retry a capture the user already sat through
+        let client = ipc_client::IpcClient::with_socket_path(&self.socket_path)
+            .with_timeout(timeout_ms + ipc_client::DEFAULT_TIMEOUT_MS);
+        match client.send_with_retries(&request, 0).await {
+            Ok(Response::TriggerCaptured(combo)) => Ok(combo),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to capture trigger: {}", e)),
+        }
+    }
+
+    /// Enable or disable a macro
+    pub async fn set_macro_enabled(&self, name: &str, enabled: bool) -> Result<(), String> {
+        let request = Request::SetMacroEnabled {
+            name: name.to_string(),
+            enabled,
+        };
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::Ack) => Ok(()),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to update macro: {}", e)),
+        }
+    }
+
     /// Test a macro execution
     pub async fn test_macro(&self, name: &str) -> Result<(), String> {
         let request = Request::TestMacro {
             name: name.to_string(),
+            simulate: false,
+            speed: DEFAULT_REPLAY_SPEED,
         };
         match ipc_client::send_to_path(&request, &self.socket_path).await {
             Ok(Response::Ack) => Ok(()),
-            Ok(_) => Err("Unexpected response".to_string()),
+            Ok(other) => Err(unexpected(other)),
             Err(e) => Err(format!("Failed to test macro: {}", e)),
         }
     }
 
+    /// Stop a currently executing macro
+    pub async fn stop_macro(&self, name: &str) -> Result<(), String> {
+        let request = Request::StopMacro {
+            name: name.to_string(),
+        };
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::Ack) => Ok(()),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to stop macro: {}", e)),
+        }
+    }
+
+    /// Stop all currently executing macros, returning how many were stopped
+    pub async fn stop_all_macros(&self) -> Result<usize, String> {
+        let request = Request::StopAllMacros;
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::Stopped(count)) => Ok(count),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to stop macros: {}", e)),
+        }
+    }
+
     /// Save current macros to a profile
     pub async fn save_profile(&self, name: &str) -> Result<(String, usize), String> {
         let request = Request::SaveProfile {
@@ -100,23 +234,117 @@ impl GuiIpcClient {
         };
         match ipc_client::send_to_path(&request, &self.socket_path).await {
             Ok(Response::ProfileSaved { name, macros_count }) => Ok((name, macros_count)),
-            Ok(_) => Err("Unexpected response".to_string()),
+            Ok(other) => Err(unexpected(other)),
             Err(e) => Err(format!("Failed to save profile: {}", e)),
         }
     }
 
-    /// Load macros from a profile
-    pub async fn load_profile(&self, name: &str) -> Result<(String, usize), String> {
+    /// List the names of saved profiles
+    pub async fn list_profiles(&self) -> Result<Vec<String>, String> {
+        let request = Request::ListProfiles;
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::Profiles(profiles)) => Ok(profiles),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to list profiles: {}", e)),
+        }
+    }
+
+    /// Fetch a saved profile with all of its macros
+    pub async fn export_profile(&self, name: &str) -> Result<Profile, String> {
+        let request = Request::ExportProfile {
+            name: name.to_string(),
+        };
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::ProfileData(profile)) => Ok(profile),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to export profile: {}", e)),
+        }
+    }
+
+    /// Save a profile on the daemon, replacing one of the same name only if `overwrite` is set
+    pub async fn import_profile(&self, profile: Profile, overwrite: bool) -> Result<(String, usize), String> {
+        let request = Request::ImportProfile { profile, overwrite };
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::ProfileSaved { name, macros_count }) => Ok((name, macros_count)),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to import profile: {}", e)),
+        }
+    }
+
+    /// Delete a saved profile
+    pub async fn delete_profile(&self, name: &str) -> Result<(), String> {
+        let request = Request::DeleteProfile {
+            name: name.to_string(),
+        };
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::Ack) => Ok(()),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to delete profile: {}", e)),
+        }
+    }
+
+    /// Load macros from a profile, returning its name, macro count and description
+    pub async fn load_profile(&self, name: &str) -> Result<(String, usize, Option<String>), String> {
         let request = Request::LoadProfile {
             name: name.to_string(),
         };
         match ipc_client::send_to_path(&request, &self.socket_path).await {
-            Ok(Response::ProfileLoaded { name, macros_count }) => Ok((name, macros_count)),
-            Ok(_) => Err("Unexpected response".to_string()),
+            Ok(Response::ProfileLoaded { name, macros_count, description }) => Ok((name, macros_count, description)),
+            Ok(other) => Err(unexpected(other)),
             Err(e) => Err(format!("Failed to load profile: {}", e)),
         }
     }
 
+    /// Read the charge of a wireless device in percent and whether it is charging
+    pub async fn get_battery_level(&self, device_path: &str) -> Result<(u8, bool), String> {
+        let request = Request::GetBatteryLevel {
+            device_path: device_path.to_string(),
+        };
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::Battery { percent, charging }) => Ok((percent, charging)),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to read battery: {}", e)),
+        }
+    }
+
+    /// Read a mouse's X and Y sensor resolution
+    pub async fn get_dpi(&self, device_path: &str) -> Result<(u16, u16), String> {
+        let request = Request::GetDpi {
+            device_path: device_path.to_string(),
+        };
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::Dpi { x, y }) => Ok((x, y)),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to read DPI: {}", e)),
+        }
+    }
+
+    /// Set a mouse's X and Y sensor resolution
+    pub async fn set_dpi(&self, device_path: &str, dpi: (u16, u16)) -> Result<(), String> {
+        let request = Request::SetDpi {
+            device_path: device_path.to_string(),
+            dpi,
+        };
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::Ack) => Ok(()),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to set DPI: {}", e)),
+        }
+    }
+
+    /// Start a lighting effect on a device
+    pub async fn set_led_effect(&self, device_path: &str, effect: LedEffect) -> Result<(), String> {
+        let request = Request::LedEffect {
+            device_path: device_path.to_string(),
+            effect,
+        };
+        match ipc_client::send_to_path(&request, &self.socket_path).await {
+            Ok(Response::Ack) => Ok(()),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to set LED effect: {}", e)),
+        }
+    }
+
     /// Grab a device exclusively for input interception
     pub async fn grab_device(&self, device_path: &str) -> Result<(), String> {
         let request = Request::GrabDevice {
@@ -124,11 +352,26 @@ impl GuiIpcClient {
         };
         match ipc_client::send_to_path(&request, &self.socket_path).await {
             Ok(Response::Ack) => Ok(()),
-            Ok(_) => Err("Unexpected response".to_string()),
+            Ok(Response::ErrorCode { code: ErrorKind::DeviceBusy, .. }) => {
+                Err(format!("{} is already grabbed by another program", device_path))
+            }
+            Ok(other) => Err(unexpected(other)),
             Err(e) => Err(format!("Failed to grab device: {}", e)),
         }
     }
 
+    /// Open a stream of the daemon's device notifications
+    ///
+    /// The daemon sends `DevicesChanged` and `DeviceUngrabbed` to every
+    /// subscriber, so the input event filter is set to a path no device has
+    /// to keep key presses off the stream.
+    pub async fn subscribe_device_events(&self) -> Result<ipc_client::EventSubscription, String> {
+        let client = ipc_client::IpcClient::with_socket_path(&self.socket_path);
+        client.subscribe_events(Some(String::new()))
+            .await
+            .map_err(|e| format!("Failed to subscribe to device events: {}", e))
+    }
+
     /// Release exclusive access to a device
     pub async fn ungrab_device(&self, device_path: &str) -> Result<(), String> {
         let request = Request::UngrabDevice {
@@ -136,11 +379,49 @@ impl GuiIpcClient {
         };
         match ipc_client::send_to_path(&request, &self.socket_path).await {
             Ok(Response::Ack) => Ok(()),
-            Ok(_) => Err("Unexpected response".to_string()),
+            Ok(other) => Err(unexpected(other)),
             Err(e) => Err(format!("Failed to ungrab device: {}", e)),
         }
     }
+
+    /// Grab every device the daemon knows, returning which grabs succeeded
+    pub async fn grab_all_devices(&self) -> Result<Vec<(String, bool)>, String> {
+        match ipc_client::send_to_path(&Request::GrabAllDevices, &self.socket_path).await {
+            Ok(Response::GrabResults(results)) => Ok(results),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to grab devices: {}", e)),
+        }
+    }
+
+    /// Release every grabbed device, returning which releases succeeded
+    pub async fn ungrab_all_devices(&self) -> Result<Vec<(String, bool)>, String> {
+        match ipc_client::send_to_path(&Request::UngrabAllDevices, &self.socket_path).await {
+            Ok(Response::GrabResults(results)) => Ok(results),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(format!("Failed to release devices: {}", e)),
+        }
+    }
 }
 
 /// Type alias for the IPC client used in the GUI
 pub type IpcClient = GuiIpcClient;
+
+/// Message for a reply the request didn't expect, using the daemon's error if it sent one
+fn unexpected(response: Response) -> String {
+    match response.as_error() {
+        Some((code, message)) => error_message(code, message),
+        None => "Unexpected response".to_string(),
+    }
+}
+
+/// The daemon's error message, with a hint for errors the user can do something about
+fn error_message(code: ErrorKind, message: &str) -> String {
+    match code {
+        ErrorKind::AuthRequired => format!("{} - the daemon requires an authentication token", message),
+        ErrorKind::RateLimited => "The daemon is busy with too many requests, try again shortly".to_string(),
+        ErrorKind::Unavailable => format!("{} - the daemon may still be starting up", message),
+        ErrorKind::VersionMismatch => format!("{} - install matching GUI and daemon versions", message),
+        ErrorKind::MessageTooLarge => format!("{} - raise security.max_message_bytes in the daemon configuration", message),
+        _ => message.to_string(),
+    }
+}
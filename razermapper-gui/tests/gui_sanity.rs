@@ -4,7 +4,7 @@
 //! basic message flows without panicking. Tests focus on structural integrity
 //! rather than visual rendering since Iced applications are UI-heavy.
 
-use razermapper_common::{DeviceInfo, MacroEntry, KeyCombo, Action};
+use razermapper_common::{DeviceCapabilities, DeviceInfo, MacroEntry, KeyCombo, Action, TriggerEdge};
 use razermapper_gui::{State, Message};
 use iced::application::Application;
 use std::path::PathBuf;
@@ -19,6 +19,8 @@ fn create_test_device(name: &str, path: &str) -> DeviceInfo {
         vendor_id: 0x1532,
         product_id: 0x0203,
         phys: "usb-0000:00:14.0-1/input/input0".to_string(),
+        capabilities: DeviceCapabilities::default(),
+        stable_id: String::new(),
     }
 }
 
@@ -37,6 +39,10 @@ fn create_test_macro(name: &str, enabled: bool) -> MacroEntry {
         ],
         device_id: Some("test_device".to_string()),
         enabled,
+        cooldown_ms: 0,
+        trigger_on: TriggerEdge::Press,
+        repeat_while_held: false,
+        tags: vec![],
     }
 }
 
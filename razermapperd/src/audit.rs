@@ -0,0 +1,169 @@
+//! Audit log of actions the daemon takes on behalf of clients and devices
+//!
+//! Every executed macro, external command and device grab is appended to the
+//! audit log as one JSON object per line, so the log can be followed with
+//! `tail -f` and parsed line by line.
+
+use razermapper_common::tracing;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+/// Something worth recording in the audit log
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    MacroExecuted {
+        name: String,
+        device_path: Option<String>,
+        success: bool,
+        error: Option<String>,
+    },
+    CommandExecuted {
+        program: String,
+        args: Vec<String>,
+        success: bool,
+        error: Option<String>,
+    },
+    DeviceGrabbed {
+        device_path: String,
+        success: bool,
+        error: Option<String>,
+    },
+    DeviceUngrabbed {
+        device_path: String,
+        success: bool,
+        error: Option<String>,
+    },
+}
+
+/// One line of the audit log
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp_ms: u64,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+/// Append-only JSON lines log shared between the daemon's components
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl AuditLog {
+    /// Create an audit log writing to the given path
+    ///
+    /// The file is opened on the first record, creating it and its parent
+    /// directory if needed.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Append an event to the log
+    ///
+    /// Failing to write the audit log must never stop the action itself, so
+    /// errors are only logged.
+    pub async fn record(&self, event: AuditEvent) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        let mut line = match serde_json::to_string(&AuditRecord { timestamp_ms, event: &event }) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to serialize audit event {:?}: {}", event, e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        let mut file = self.file.lock().await;
+        if file.is_none() {
+            match self.open().await {
+                Ok(opened) => *file = Some(opened),
+                Err(e) => {
+                    warn!("Failed to open audit log {}: {}", self.path.display(), e);
+                    return;
+                }
+            }
+        }
+
+        if let Some(f) = file.as_mut() {
+            // tokio buffers file writes, so flush to get the line on disk now
+            let written = match f.write_all(line.as_bytes()).await {
+                Ok(()) => f.flush().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                warn!("Failed to write audit log {}: {}", self.path.display(), e);
+                // Reopen on the next record in case the file was rotated away
+                *file = None;
+            }
+        }
+    }
+
+    async fn open(&self) -> std::io::Result<File> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&self.path)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_record_writes_json_lines() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("logs").join("audit.log");
+        let audit_log = AuditLog::new(&path);
+
+        audit_log.record(AuditEvent::MacroExecuted {
+            name: "Test".to_string(),
+            device_path: Some("/dev/input/event3".to_string()),
+            success: true,
+            error: None,
+        }).await;
+        audit_log.record(AuditEvent::CommandExecuted {
+            program: "rm".to_string(),
+            args: vec!["-rf".to_string(), "/".to_string()],
+            success: false,
+            error: Some("Command not allowed: rm".to_string()),
+        }).await;
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+
+        assert_eq!(lines[0]["event"], "macro_executed");
+        assert_eq!(lines[0]["name"], "Test");
+        assert_eq!(lines[0]["device_path"], "/dev/input/event3");
+        assert_eq!(lines[0]["success"], true);
+        assert!(lines[0]["timestamp_ms"].as_u64().unwrap() > 0);
+
+        assert_eq!(lines[1]["event"], "command_executed");
+        assert_eq!(lines[1]["program"], "rm");
+        assert_eq!(lines[1]["args"], serde_json::json!(["-rf", "/"]));
+        assert_eq!(lines[1]["success"], false);
+        assert_eq!(lines[1]["error"], "Command not allowed: rm");
+    }
+}
@@ -2,7 +2,7 @@
 //! Usage: cargo run --bin test_grab -- /dev/input/eventX
 
 use razermapper_common::tracing;
-use razermapperd::device::DeviceManager;
+use razermapperd::device::{DeviceManager, KeyState};
 use tracing::{info, error};
 
 #[tokio::main]
@@ -77,8 +77,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Event loop
     loop {
         tokio::select! {
-            Some((path, key_code, pressed)) = event_receiver.recv() => {
-                let action = if pressed { "PRESSED" } else { "RELEASED" };
+            Some((path, key_code, key_state)) = event_receiver.recv() => {
+                let action = match key_state {
+                    KeyState::Pressed => "PRESSED",
+                    KeyState::Released => "RELEASED",
+                    KeyState::Repeat => "REPEAT",
+                };
                 let key_name = key_code_to_name(key_code);
                 info!("[{}] Key {} ({}) {}", path, key_code, key_name, action);
             }
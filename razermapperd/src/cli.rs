@@ -0,0 +1,353 @@
+//! One-shot client commands for scripting the running daemon
+//!
+//! `razermapperd <command>` connects to the daemon's socket, prints the reply
+//! and exits, so these need neither root nor a second daemon instance.
+
+use razermapper_common::ipc_client::IpcClient;
+use razermapper_common::{DeviceInfo, MacroEntry, Request, Response, DEFAULT_REPLAY_SPEED, SOCKET_ENV_VAR};
+use std::path::{Path, PathBuf};
+
+/// Socket the daemon listens on unless `--socket` or `RAZERMAPPER_SOCKET` say otherwise
+pub const DEFAULT_SOCKET_PATH: &str = "/run/razermapper/razermapper.sock";
+
+/// Usage text listing the client commands
+pub const USAGE: &str = "Usage: razermapperd [--socket <path>] [COMMAND]
+
+Without a command the daemon is started. Commands talk to the running daemon:
+  list-devices          List discovered input devices
+  run-macro <name>      Execute a macro by name
+  status                Show daemon version, uptime and counts
+  shutdown              Stop the daemon, if security.allow_remote_shutdown permits it
+  export-macros <file>  Write every macro to a JSON file
+  import-macros <file>  Add or replace the macros in a JSON file, skipping invalid ones
+
+Options:
+  --socket <path>       Socket to listen on or connect to, overrides RAZERMAPPER_SOCKET";
+
+/// Remove `--socket <path>` or `--socket=<path>` from the arguments and return the path
+pub fn take_socket_flag(args: &mut Vec<String>) -> Result<Option<String>, String> {
+    let Some(idx) = args.iter().position(|arg| arg == "--socket" || arg.starts_with("--socket=")) else {
+        return Ok(None);
+    };
+
+    let flag = args.remove(idx);
+    let path = match flag.strip_prefix("--socket=") {
+        Some(path) => path.to_string(),
+        None if idx < args.len() => args.remove(idx),
+        None => return Err("--socket needs a path".to_string()),
+    };
+    if path.is_empty() {
+        return Err("--socket needs a path".to_string());
+    }
+    Ok(Some(path))
+}
+
+/// Pick the socket path: the command line first, then `RAZERMAPPER_SOCKET`, then the default
+pub fn determine_socket_path(cli_socket: Option<String>) -> String {
+    cli_socket
+        .or_else(|| std::env::var(SOCKET_ENV_VAR).ok().filter(|path| !path.is_empty()))
+        .unwrap_or_else(|| DEFAULT_SOCKET_PATH.to_string())
+}
+
+/// A client operation requested on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CliCommand {
+    ListDevices,
+    RunMacro(String),
+    Status,
+    Shutdown,
+    ExportMacros(PathBuf),
+    ImportMacros(PathBuf),
+}
+
+impl CliCommand {
+    /// Parse the process arguments, returning None if they don't name a client command
+    pub fn parse(args: &[String]) -> Option<Result<Self, String>> {
+        let command = args.get(1)?;
+        if command.starts_with('-') {
+            return None;
+        }
+
+        let rest = &args[2..];
+        Some(match (command.as_str(), rest) {
+            ("list-devices", []) => Ok(Self::ListDevices),
+            ("status", []) => Ok(Self::Status),
+            ("shutdown", []) => Ok(Self::Shutdown),
+            ("run-macro", [name]) => Ok(Self::RunMacro(name.clone())),
+            ("run-macro", _) => Err("run-macro takes exactly one macro name".to_string()),
+            ("export-macros", [file]) => Ok(Self::ExportMacros(PathBuf::from(file))),
+            ("import-macros", [file]) => Ok(Self::ImportMacros(PathBuf::from(file))),
+            ("export-macros" | "import-macros", _) => Err(format!("{} takes exactly one file", command)),
+            ("list-devices" | "status" | "shutdown", _) => Err(format!("{} takes no arguments", command)),
+            _ => Err(format!("Unknown command: {}", command)),
+        })
+    }
+
+    fn request(&self) -> Request {
+        match self {
+            Self::ListDevices => Request::GetDevices,
+            Self::RunMacro(name) => Request::ExecuteMacro { name: name.clone(), speed: DEFAULT_REPLAY_SPEED },
+            Self::Status => Request::GetStatus,
+            Self::Shutdown => Request::Shutdown,
+            Self::ExportMacros(_) => Request::ListMacros,
+            Self::ImportMacros(_) => Request::GetDevices,
+        }
+    }
+}
+
+/// Send a command to the daemon listening on `socket_path` and print the reply
+pub async fn run(command: CliCommand, socket_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let client = IpcClient::with_socket_path(socket_path);
+    let response = client.send_with_retries(&command.request(), 0).await
+        .map_err(|e| format!("Cannot reach daemon at {}: {}", socket_path, e))?;
+
+    let output = match &command {
+        CliCommand::ImportMacros(file) => import_macros(&client, file, response).await?,
+        _ => format_response(&command, response)?,
+    };
+    println!("{}", output);
+    Ok(())
+}
+
+/// Send each valid macro in `file` to the daemon, reporting the ones that failed
+///
+/// `devices` is the reply to GetDevices. SetMacro needs a present device, so
+/// macros without a device restriction are set through the first one.
+async fn import_macros(client: &IpcClient, file: &Path, devices: Response) -> Result<String, String> {
+    let devices = match devices {
+        Response::Devices(devices) => devices,
+        other => {
+            return Err(other.as_error()
+                .map(|(_, message)| message.to_string())
+                .unwrap_or_else(|| format!("Unexpected response: {:?}", other)));
+        }
+    };
+    let json = std::fs::read_to_string(file).map_err(|e| format!("Cannot read {}: {}", file.display(), e))?;
+    let entries = parse_macro_file(&json)?;
+
+    let mut imported = 0;
+    let mut failures = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let macro_entry = match entry {
+            Ok(macro_entry) => macro_entry,
+            Err(e) => {
+                failures.push(format!("entry {}: {}", index + 1, e));
+                continue;
+            }
+        };
+        let device_path = match (&macro_entry.device_id, devices.first()) {
+            (Some(device_id), _) => device_id.clone(),
+            (None, Some(device)) => device.path.display().to_string(),
+            (None, None) => {
+                failures.push(format!("{}: no input device to attach it to", macro_entry.name));
+                continue;
+            }
+        };
+
+        let name = macro_entry.name.clone();
+        match client.send(&Request::SetMacro { device_path, macro_entry }).await {
+            Ok(Response::MacroSet { .. }) => imported += 1,
+            Ok(response) => match response.as_error() {
+                Some((_, message)) => failures.push(format!("{}: {}", name, message)),
+                None => failures.push(format!("{}: unexpected response: {:?}", name, response)),
+            },
+            Err(e) => failures.push(format!("{}: {}", name, e)),
+        }
+    }
+
+    Ok(format_import_summary(imported, &failures))
+}
+
+/// Parse a file of macros written by export-macros, validating each one on its own
+///
+/// Only a file that isn't a JSON array fails as a whole; a bad entry becomes
+/// an error in its place so the rest can still be imported.
+fn parse_macro_file(json: &str) -> Result<Vec<Result<MacroEntry, String>>, String> {
+    let values: Vec<serde_json::Value> = serde_json::from_str(json)
+        .map_err(|e| format!("Expected a JSON array of macros: {}", e))?;
+
+    Ok(values.into_iter().map(|value| {
+        let macro_entry: MacroEntry = serde_json::from_value(value).map_err(|e| e.to_string())?;
+        macro_entry.validate().map_err(|e| format!("{}: {}", macro_entry.name, e))?;
+        Ok(macro_entry)
+    }).collect())
+}
+
+/// Count of imported macros, followed by one line per failure
+fn format_import_summary(imported: usize, failures: &[String]) -> String {
+    let mut summary = format!("Imported {} macros", imported);
+    if !failures.is_empty() {
+        summary.push_str(&format!(", {} failed:", failures.len()));
+        for failure in failures {
+            summary.push_str("\n  ");
+            summary.push_str(failure);
+        }
+    }
+    summary
+}
+
+/// Macros sorted by name as an indented JSON array, so exports diff cleanly
+fn format_macros_json(mut macros: Vec<MacroEntry>) -> Result<String, String> {
+    macros.sort_by(|a, b| a.name.cmp(&b.name));
+    serde_json::to_string_pretty(&macros).map_err(|e| format!("Cannot encode macros: {}", e))
+}
+
+/// Render the daemon's reply for the terminal
+fn format_response(command: &CliCommand, response: Response) -> Result<String, String> {
+    if let Some((_, message)) = response.as_error() {
+        return Err(message.to_string());
+    }
+    match (command, response) {
+        (CliCommand::ListDevices, Response::Devices(devices)) => Ok(format_devices(&devices)),
+        (CliCommand::RunMacro(name), Response::Ack) => Ok(format!("Started macro {}", name)),
+        (CliCommand::Shutdown, Response::Ack) => Ok("Daemon is shutting down".to_string()),
+        (CliCommand::ExportMacros(file), Response::Macros(macros)) => {
+            let count = macros.len();
+            std::fs::write(file, format_macros_json(macros)? + "\n")
+                .map_err(|e| format!("Cannot write {}: {}", file.display(), e))?;
+            Ok(format!("Exported {} macros to {}", count, file.display()))
+        }
+        (CliCommand::Status, Response::Status { version, uptime_seconds, devices_count, macros_count }) => Ok(format!(
+            "version: {}\nuptime: {}s\ndevices: {}\nmacros: {}",
+            version, uptime_seconds, devices_count, macros_count
+        )),
+        (_, other) => Err(format!("Unexpected response: {:?}", other)),
+    }
+}
+
+/// One tab separated line per device: path, vendor:product and name
+fn format_devices(devices: &[DeviceInfo]) -> String {
+    if devices.is_empty() {
+        return "No devices found".to_string();
+    }
+    devices.iter()
+        .map(|d| format!("{}\t{:04x}:{:04x}\t{}", d.path.display(), d.vendor_id, d.product_id, d.name))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use razermapper_common::{DeviceCapabilities, ErrorKind, TriggerEdge};
+    use std::path::PathBuf;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        std::iter::once("razermapperd").chain(list.iter().copied()).map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(CliCommand::parse(&args(&[])), None);
+        assert_eq!(CliCommand::parse(&args(&["--test-security"])), None);
+        assert_eq!(CliCommand::parse(&args(&["list-devices"])), Some(Ok(CliCommand::ListDevices)));
+        assert_eq!(CliCommand::parse(&args(&["status"])), Some(Ok(CliCommand::Status)));
+        assert_eq!(CliCommand::parse(&args(&["shutdown"])), Some(Ok(CliCommand::Shutdown)));
+        assert_eq!(
+            CliCommand::parse(&args(&["run-macro", "Quick Reload"])),
+            Some(Ok(CliCommand::RunMacro("Quick Reload".to_string())))
+        );
+        assert!(matches!(CliCommand::parse(&args(&["run-macro"])), Some(Err(_))));
+        assert_eq!(
+            CliCommand::parse(&args(&["export-macros", "macros.json"])),
+            Some(Ok(CliCommand::ExportMacros(PathBuf::from("macros.json"))))
+        );
+        assert!(matches!(CliCommand::parse(&args(&["import-macros"])), Some(Err(_))));
+        assert!(matches!(CliCommand::parse(&args(&["status", "now"])), Some(Err(_))));
+        assert!(matches!(CliCommand::parse(&args(&["frobnicate"])), Some(Err(msg)) if msg.contains("Unknown command")));
+    }
+
+    #[test]
+    fn test_macro_file_round_trip() {
+        let macro_named = |name: &str, key: u16| MacroEntry {
+            name: name.to_string(),
+            trigger: razermapper_common::KeyCombo { keys: vec![key], modifiers: vec![] },
+            actions: vec![razermapper_common::Action::KeyTap(key)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        let json = format_macros_json(vec![macro_named("b", 31), macro_named("a", 30)]).unwrap();
+        assert!(json.find("\"a\"").unwrap() < json.find("\"b\"").unwrap());
+
+        let parsed = parse_macro_file(&json).unwrap();
+        assert_eq!(parsed, vec![Ok(macro_named("a", 30)), Ok(macro_named("b", 31))]);
+
+        // One bad entry doesn't sink the others
+        let mut values: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        values[0]["actions"] = serde_json::json!([{ "KeyTap": 0 }]);
+        values.push(serde_json::json!({ "name": "broken" }));
+        let parsed = parse_macro_file(&serde_json::to_string(&values).unwrap()).unwrap();
+        assert!(matches!(&parsed[0], Err(e) if e.starts_with("a: ")));
+        assert_eq!(parsed[1], Ok(macro_named("b", 31)));
+        assert!(parsed[2].is_err());
+
+        assert!(parse_macro_file("{}").is_err());
+        assert_eq!(
+            format_import_summary(1, &["a: bad key".to_string()]),
+            "Imported 1 macros, 1 failed:\n  a: bad key"
+        );
+    }
+
+    #[test]
+    fn test_take_socket_flag() {
+        let mut parsed = args(&["--socket", "/tmp/a.sock", "status"]);
+        assert_eq!(take_socket_flag(&mut parsed), Ok(Some("/tmp/a.sock".to_string())));
+        assert_eq!(parsed, args(&["status"]));
+
+        let mut parsed = args(&["status", "--socket=/tmp/b.sock"]);
+        assert_eq!(take_socket_flag(&mut parsed), Ok(Some("/tmp/b.sock".to_string())));
+        assert_eq!(parsed, args(&["status"]));
+
+        assert_eq!(take_socket_flag(&mut args(&["status"])), Ok(None));
+        assert!(take_socket_flag(&mut args(&["--socket"])).is_err());
+    }
+
+    #[test]
+    fn test_determine_socket_path_honors_env() {
+        std::env::set_var(SOCKET_ENV_VAR, "/tmp/from-env.sock");
+        assert_eq!(determine_socket_path(None), "/tmp/from-env.sock");
+        // The command line wins over the environment
+        assert_eq!(determine_socket_path(Some("/tmp/from-cli.sock".to_string())), "/tmp/from-cli.sock");
+
+        std::env::remove_var(SOCKET_ENV_VAR);
+        assert_eq!(determine_socket_path(None), DEFAULT_SOCKET_PATH);
+    }
+
+    #[test]
+    fn test_format_response() {
+        let devices = vec![DeviceInfo {
+            name: "Razer Naga".to_string(),
+            path: PathBuf::from("/dev/input/event5"),
+            vendor_id: 0x1532,
+            product_id: 0x0067,
+            phys: String::new(),
+            capabilities: DeviceCapabilities::default(),
+            stable_id: String::new(),
+        }];
+        assert_eq!(
+            format_response(&CliCommand::ListDevices, Response::Devices(devices)).unwrap(),
+            "/dev/input/event5\t1532:0067\tRazer Naga"
+        );
+        assert_eq!(
+            format_response(&CliCommand::RunMacro("Reload".to_string()), Response::Ack).unwrap(),
+            "Started macro Reload"
+        );
+        assert_eq!(
+            format_response(&CliCommand::Status, Response::error(ErrorKind::NotFound, "Macro not found: x")),
+            Err("Macro not found: x".to_string())
+        );
+        assert_eq!(
+            format_response(&CliCommand::Status, Response::Error("old daemon".to_string())),
+            Err("old daemon".to_string())
+        );
+        assert_eq!(
+            format_response(&CliCommand::Shutdown, Response::Ack).unwrap(),
+            "Daemon is shutting down"
+        );
+        assert!(format_response(&CliCommand::Status, Response::Ack).is_err());
+    }
+}
@@ -1,10 +1,13 @@
-use razermapper_common::{tracing, MacroEntry, Profile};
-use serde::{Deserialize, Serialize};
+use razermapper_common::{tracing, DeviceInfo, MacroEntry, Profile};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Mutex, RwLock};
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
 use tracing::{debug, info, warn};
 
 /// Configuration manager for razermapper daemon
@@ -13,14 +16,239 @@ pub struct ConfigManager {
     pub macros_path: PathBuf,
     pub cache_path: PathBuf,
     pub profiles_dir: PathBuf,
-    pub config: DaemonConfig,
+    pub config: Arc<RwLock<DaemonConfig>>,
     pub macros: Arc<RwLock<HashMap<String, MacroEntry>>>,
     pub profiles: Arc<RwLock<HashMap<String, Profile>>>,
+    /// Set while a debounced macro save is waiting to run
+    pub save_pending: Arc<AtomicBool>,
+    /// Modification times of the config and macros files as the daemon last
+    /// wrote or read them, locked for the duration of each write
+    pub known_mtimes: Arc<Mutex<[Option<SystemTime>; 2]>>,
+}
+
+/// How long to wait after a macro change before writing macros to disk, so a
+/// burst of edits results in a single save
+pub const MACRO_SAVE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// How long to let a burst of file events settle before reloading, since
+/// editors often write a file in several steps
+pub const WATCH_SETTLE: Duration = Duration::from_millis(100);
+
+/// Profile loaded at startup when no other one is configured or available
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// `startup_profile` value that reloads the profile most recently loaded over IPC
+pub const LAST_PROFILE: &str = "last";
+
+/// Marks the start of a macros cache file
+const CACHE_MAGIC: u32 = 0xDEADBEEF;
+
+/// Bytes before the cached macros: the magic number, then the length and
+/// CRC32 of the serialized macros, all little endian
+const CACHE_HEADER_LEN: usize = 12;
+
+/// Layout version of the config file written by this daemon
+///
+/// Files without a `version` key predate versioning and are treated as version 1.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Keys renamed between config versions, as the version that renamed them,
+/// the section holding them, the old name and the new name
+const RENAMED_KEYS: &[(u32, &str, &str, &str)] = &[
+    (2, "macro_engine", "default_delay", "default_delay_ms"),
+    (2, "config", "reload_interval", "reload_interval_secs"),
+];
+
+/// Errors for config files that can't be loaded as they are
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Config file has version {found}, but this daemon only understands versions up to {supported}")]
+    UnsupportedVersion { found: u64, supported: u32 },
+    #[error("Config file must be a mapping of sections")]
+    NotAMapping,
+    #[error("Macro cache is corrupt: {0}")]
+    CorruptCache(&'static str),
+    #[error("{}{}: {message}", .path.display(), describe_location(*.location))]
+    InvalidYaml {
+        path: PathBuf,
+        /// One-based line and column of the problem, when serde_yaml knows it
+        location: Option<(usize, usize)>,
+        message: String,
+    },
+}
+
+impl ConfigError {
+    /// Wrap a YAML error for the file at `path`, keeping its position
+    pub fn yaml(path: &Path, error: serde_yaml::Error) -> Self {
+        let location = error.location().map(|l| (l.line(), l.column()));
+        let mut message = error.to_string();
+        // serde_yaml appends the position to its message; it is reported up front instead
+        if let Some(index) = location.and_then(|_| message.rfind(" at line ")) {
+            message.truncate(index);
+        }
+        ConfigError::InvalidYaml {
+            path: path.to_path_buf(),
+            location,
+            message,
+        }
+    }
+}
+
+fn describe_location(location: Option<(usize, usize)>) -> String {
+    location
+        .map(|(line, column)| format!(":{}:{}", line, column))
+        .unwrap_or_default()
+}
+
+/// Deserialize the YAML contents of the file at `path`, reporting errors with
+/// the file's path and the line and column they were found at
+pub fn from_yaml<T: DeserializeOwned>(path: &Path, content: &str) -> Result<T, ConfigError> {
+    serde_yaml::from_str(content).map_err(|e| ConfigError::yaml(path, e))
+}
+
+/// A config file parsed and upgraded to the current layout
+#[derive(Debug, Clone)]
+pub struct ParsedConfig {
+    pub config: DaemonConfig,
+    /// Version the file was written with
+    pub from_version: u32,
+    /// What the upgrade changed, in order, empty when the file was current
+    pub changes: Vec<String>,
+}
+
+impl ParsedConfig {
+    /// Whether the file was written with an older layout and should be rewritten
+    pub fn upgraded(&self) -> bool {
+        self.from_version < CONFIG_VERSION
+    }
+}
+
+/// Parse the config file at `path`, upgrading older layouts to [`CONFIG_VERSION`]
+///
+/// Files from a newer daemon are refused rather than loaded with their
+/// unknown keys silently dropped.
+pub fn parse_config(path: &Path, content: &str) -> Result<ParsedConfig, ConfigError> {
+    let mut value: serde_yaml::Value = from_yaml(path, content)?;
+    let (from_version, changes) = migrate_config(&mut value)?;
+    let config = if changes.is_empty() {
+        // Deserialize the text rather than the value so errors keep their position
+        from_yaml(path, content)?
+    } else {
+        serde_yaml::from_value(value).map_err(|e| ConfigError::yaml(path, e))?
+    };
+    Ok(ParsedConfig {
+        config,
+        from_version,
+        changes,
+    })
+}
+
+/// Upgrade a config document in place, returning the version it had and the changes made
+fn migrate_config(value: &mut serde_yaml::Value) -> Result<(u32, Vec<String>), ConfigError> {
+    let root = value.as_mapping_mut().ok_or(ConfigError::NotAMapping)?;
+    let found = root.get("version").and_then(serde_yaml::Value::as_u64).unwrap_or(1);
+    let from_version = match u32::try_from(found) {
+        Ok(version) if version <= CONFIG_VERSION => version,
+        _ => return Err(ConfigError::UnsupportedVersion { found, supported: CONFIG_VERSION }),
+    };
+
+    let mut changes = Vec::new();
+    for &(since, section, old, new) in RENAMED_KEYS {
+        if from_version >= since {
+            continue;
+        }
+        let Some(section_map) = root.get_mut(section).and_then(serde_yaml::Value::as_mapping_mut) else {
+            continue;
+        };
+        if let Some(old_value) = section_map.remove(old) {
+            // A file that already has the new key keeps it
+            if !section_map.contains_key(new) {
+                section_map.insert(new.into(), old_value);
+            }
+            changes.push(format!("renamed {}.{} to {}.{}", section, old, section, new));
+        }
+    }
+    if from_version < CONFIG_VERSION {
+        root.insert("version".into(), CONFIG_VERSION.into());
+        changes.push(format!("set version to {}", CONFIG_VERSION));
+    }
+    Ok((from_version, changes))
+}
+
+/// Replace the file at `path` with `contents` without ever leaving it half written
+///
+/// The contents go to a temporary file in the same directory, which is flushed
+/// to disk and then renamed over `path`, so a crash or power loss leaves
+/// either the old file or the new one.
+pub async fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let temp_path = path.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id(),
+        NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let result = async {
+        let mut file = fs::File::create(&temp_path).await?;
+        file.write_all(contents.as_ref()).await?;
+        file.sync_all().await?;
+        fs::rename(&temp_path, path).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path).await;
+        return result;
+    }
+
+    // Make the rename itself durable
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        if let Err(e) = fs::File::open(dir).await?.sync_all().await {
+            debug!("Failed to sync {}: {}", dir.display(), e);
+        }
+    }
+    Ok(())
+}
+
+/// Check a macros cache file's header and return the serialized macros after it
+fn cache_payload(content: &[u8]) -> Result<&[u8], ConfigError> {
+    let word = |at: usize| u32::from_le_bytes([content[at], content[at + 1], content[at + 2], content[at + 3]]);
+    if content.len() < CACHE_HEADER_LEN {
+        return Err(ConfigError::CorruptCache("file too short"));
+    }
+    if word(0) != CACHE_MAGIC {
+        return Err(ConfigError::CorruptCache("invalid magic number"));
+    }
+    let payload = &content[CACHE_HEADER_LEN..];
+    if payload.len() != word(4) as usize {
+        return Err(ConfigError::CorruptCache("length does not match header"));
+    }
+    if crc32fast::hash(payload) != word(8) {
+        return Err(ConfigError::CorruptCache("checksum mismatch"));
+    }
+    Ok(payload)
+}
+
+fn default_config_version() -> u32 {
+    1
+}
+
+/// Notification that the config and macros files were reloaded after changing on disk
+#[derive(Debug, Clone)]
+pub struct ConfigReload {
+    pub macros_count: usize,
 }
 
 /// Daemon configuration structure
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DaemonConfig {
+    /// Layout version of the file, see [`CONFIG_VERSION`]
+    #[serde(default = "default_config_version")]
+    pub version: u32,
     pub daemon: DaemonSettings,
     pub device_discovery: DeviceDiscoverySettings,
     pub macro_engine: MacroEngineSettings,
@@ -28,6 +256,8 @@ pub struct DaemonConfig {
     pub security: SecuritySettings,
     pub led_control: LedControlSettings,
     pub performance: PerformanceSettings,
+    #[serde(default)]
+    pub profile_rules: ProfileRulesSettings,
 }
 
 /// Daemon-specific settings
@@ -35,7 +265,24 @@ pub struct DaemonConfig {
 pub struct DaemonSettings {
     pub socket_path: String,
     pub log_level: String,
+    /// File the daemon logs to, rotated daily; empty to log to stderr
+    #[serde(default)]
+    pub log_file: String,
+    /// Rotated log files kept next to `log_file`
+    #[serde(default = "default_log_max_files")]
+    pub log_max_files: usize,
     pub drop_privileges: bool,
+    /// Profile loaded at startup, or "last" for the profile most recently loaded over IPC
+    #[serde(default = "default_startup_profile")]
+    pub startup_profile: String,
+}
+
+fn default_log_max_files() -> usize {
+    7
+}
+
+fn default_startup_profile() -> String {
+    DEFAULT_PROFILE.to_string()
 }
 
 /// Device discovery settings
@@ -44,14 +291,88 @@ pub struct DeviceDiscoverySettings {
     pub input_devices_path: String,
     pub use_openrazer_db: bool,
     pub fallback_name_pattern: String,
+    /// Re-grab a device that was lost to a read error when it reappears
+    #[serde(default)]
+    pub auto_regrab: bool,
 }
 
 /// Macro engine settings
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MacroEngineSettings {
     pub max_concurrent_macros: usize,
-    pub default_delay: u32,
+    /// Whether macros triggered while `max_concurrent_macros` run are dropped or queued
+    #[serde(default)]
+    pub overflow_policy: crate::macro_engine::OverflowPolicy,
+    /// Triggered macros that may wait for a free slot in queue mode
+    #[serde(default = "default_macro_queue_size")]
+    pub macro_queue_size: usize,
+    /// How deeply macros may call other macros with CallMacro
+    #[serde(default = "default_max_call_depth")]
+    pub max_call_depth: usize,
+    /// Most actions a macro may perform with its Repeat blocks expanded
+    #[serde(default = "default_max_macro_actions")]
+    pub max_macro_actions: u64,
+    /// Most milliseconds a macro may spend in Delay and RandomDelay actions
+    #[serde(default = "default_max_total_delay_ms")]
+    pub max_total_delay_ms: u64,
+    pub default_delay_ms: u32,
     pub enable_recording: bool,
+    /// Delay between characters typed by Type actions, 0 for no delay
+    #[serde(default = "default_type_delay_ms")]
+    pub type_delay_ms: u64,
+    /// How long KeyTap and MouseClick actions hold the key down
+    #[serde(default = "default_tap_delay_ms")]
+    pub tap_delay_ms: u64,
+    /// Turn each quickly released key of a recording into a single KeyTap
+    #[serde(default = "default_collapse_taps")]
+    pub collapse_taps: bool,
+    /// Most passes per second a hold-to-repeat macro makes
+    #[serde(default = "default_max_repeat_rate_hz")]
+    pub max_repeat_rate_hz: u32,
+    /// Keyboard layout of the desktop session, used to type text
+    #[serde(default)]
+    pub keyboard_layout: crate::layout::KeyboardLayout,
+    /// How to type characters the keyboard layout has no key for
+    #[serde(default)]
+    pub unicode_strategy: crate::injector::UnicodeStrategy,
+    /// Screen width MouseMoveAbsolute coordinates map to, 0 to detect it
+    #[serde(default)]
+    pub screen_width: i32,
+    /// Screen height MouseMoveAbsolute coordinates map to, 0 to detect it
+    #[serde(default)]
+    pub screen_height: i32,
+}
+
+fn default_macro_queue_size() -> usize {
+    crate::macro_engine::DEFAULT_MACRO_QUEUE_SIZE
+}
+
+fn default_max_call_depth() -> usize {
+    crate::macro_engine::DEFAULT_MAX_CALL_DEPTH
+}
+
+fn default_max_macro_actions() -> u64 {
+    razermapper_common::DEFAULT_MAX_MACRO_ACTIONS
+}
+
+fn default_max_total_delay_ms() -> u64 {
+    razermapper_common::DEFAULT_MAX_TOTAL_DELAY_MS
+}
+
+fn default_type_delay_ms() -> u64 {
+    crate::injector::DEFAULT_TYPE_DELAY_MS
+}
+
+fn default_tap_delay_ms() -> u64 {
+    crate::macro_engine::DEFAULT_TAP_DELAY_MS
+}
+
+fn default_collapse_taps() -> bool {
+    false
+}
+
+fn default_max_repeat_rate_hz() -> u32 {
+    crate::macro_engine::DEFAULT_MAX_REPEAT_RATE_HZ
 }
 
 /// Configuration persistence settings
@@ -61,7 +382,7 @@ pub struct ConfigSettings {
     pub macros_file: String,
     pub cache_file: String,
     pub auto_save: bool,
-    pub reload_interval: u64,
+    pub reload_interval_secs: u64,
 }
 
 /// Security settings
@@ -71,6 +392,49 @@ pub struct SecuritySettings {
     pub socket_permissions: String,
     pub require_auth_token: bool,
     pub retain_capabilities: Vec<String>,
+    /// Requests each client connection may send per second (0 = unlimited)
+    #[serde(default = "default_max_requests_per_second")]
+    pub max_requests_per_second: u32,
+    /// Largest request frame a client may send, in bytes
+    #[serde(default = "default_max_message_bytes")]
+    pub max_message_bytes: usize,
+    /// How long a client may stay silent before its connection is closed (0 = forever)
+    #[serde(default = "default_client_read_timeout_ms")]
+    pub client_read_timeout_ms: u64,
+    /// Record executed macros, commands and device grabs in the audit log
+    #[serde(default = "default_audit_log")]
+    pub audit_log: bool,
+    /// File the audit log is appended to as JSON lines
+    #[serde(default = "default_audit_log_path")]
+    pub audit_log_path: String,
+    /// Accept Shutdown requests from clients not running as root, which are
+    /// otherwise refused whether or not they authenticated with a token
+    #[serde(default = "default_allow_remote_shutdown")]
+    pub allow_remote_shutdown: bool,
+}
+
+fn default_max_requests_per_second() -> u32 {
+    crate::security::DEFAULT_MAX_REQUESTS_PER_SECOND
+}
+
+fn default_max_message_bytes() -> usize {
+    razermapper_common::ipc_client::MAX_MESSAGE_SIZE
+}
+
+fn default_client_read_timeout_ms() -> u64 {
+    60_000
+}
+
+fn default_audit_log() -> bool {
+    true
+}
+
+fn default_audit_log_path() -> String {
+    "/var/log/razermapperd/audit.log".to_string()
+}
+
+fn default_allow_remote_shutdown() -> bool {
+    false
 }
 
 /// LED control settings
@@ -89,36 +453,90 @@ pub struct PerformanceSettings {
     pub thread_pool: bool,
 }
 
+/// Automatic profile switching based on the focused window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileRulesSettings {
+    pub enabled: bool,
+    /// Command printing the class or title of the focused window
+    pub focus_command: String,
+    /// How often to check the focused window in milliseconds
+    pub poll_interval_ms: u64,
+    /// Rules checked in order, the first whose pattern matches picks the profile
+    pub rules: Vec<ProfileRule>,
+}
+
+impl Default for ProfileRulesSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            focus_command: "xdotool getactivewindow getwindowclassname".to_string(),
+            poll_interval_ms: 500,
+            rules: Vec::new(),
+        }
+    }
+}
+
+/// Regular expression on the focused window and the profile it activates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileRule {
+    pub window: String,
+    pub profile: String,
+}
+
 impl Default for DaemonConfig {
     fn default() -> Self {
         Self {
+            version: CONFIG_VERSION,
             daemon: DaemonSettings {
                 socket_path: "/run/razermapper.sock".to_string(),
                 log_level: "info".to_string(),
+                log_file: String::new(),
+                log_max_files: default_log_max_files(),
                 drop_privileges: true,
+                startup_profile: default_startup_profile(),
             },
             device_discovery: DeviceDiscoverySettings {
                 input_devices_path: "/dev/input/by-id".to_string(),
                 use_openrazer_db: true,
                 fallback_name_pattern: "Razer".to_string(),
+                auto_regrab: false,
             },
             macro_engine: MacroEngineSettings {
                 max_concurrent_macros: 10,
-                default_delay: 10,
+                overflow_policy: crate::macro_engine::OverflowPolicy::default(),
+                macro_queue_size: default_macro_queue_size(),
+                max_call_depth: default_max_call_depth(),
+                max_macro_actions: default_max_macro_actions(),
+                max_total_delay_ms: default_max_total_delay_ms(),
+                default_delay_ms: 10,
                 enable_recording: true,
+                type_delay_ms: default_type_delay_ms(),
+                tap_delay_ms: default_tap_delay_ms(),
+                collapse_taps: default_collapse_taps(),
+                max_repeat_rate_hz: default_max_repeat_rate_hz(),
+                keyboard_layout: crate::layout::KeyboardLayout::default(),
+                unicode_strategy: crate::injector::UnicodeStrategy::default(),
+                screen_width: 0,
+                screen_height: 0,
             },
             config: ConfigSettings {
                 config_file: "/etc/razermapperd/config.yaml".to_string(),
                 macros_file: "/etc/razermapperd/macros.yaml".to_string(),
                 cache_file: "/var/cache/razermapperd/macros.bin".to_string(),
                 auto_save: true,
-                reload_interval: 30,
+                reload_interval_secs: 30,
             },
             security: SecuritySettings {
                 socket_group: "input".to_string(),
                 socket_permissions: "0660".to_string(),
                 require_auth_token: false,
                 retain_capabilities: vec!["CAP_SYS_RAWIO".to_string()],
+                max_requests_per_second: default_max_requests_per_second(),
+                max_message_bytes: default_max_message_bytes(),
+                client_read_timeout_ms: default_client_read_timeout_ms(),
+                audit_log: default_audit_log(),
+                audit_log_path: default_audit_log_path(),
+                allow_remote_shutdown: default_allow_remote_shutdown(),
             },
             led_control: LedControlSettings {
                 enabled: true,
@@ -130,86 +548,149 @@ impl Default for DaemonConfig {
                 event_queue_size: 1000,
                 thread_pool: true,
             },
+            profile_rules: ProfileRulesSettings::default(),
         }
     }
 }
 
-impl ConfigManager {
-    /// Create a new configuration manager with default paths
-    pub async fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let config_path = PathBuf::from("/etc/razermapperd/config.yaml");
-        let macros_path = PathBuf::from("/etc/razermapperd/macros.yaml");
-        let cache_path = PathBuf::from("/var/cache/razermapperd/macros.bin");
-        let profiles_dir = PathBuf::from("/etc/razermapperd/profiles");
-
-        let manager = Self {
-            config_path,
-            macros_path,
-            cache_path,
-            profiles_dir,
-            config: DaemonConfig::default(),
-            macros: Arc::new(RwLock::new(HashMap::new())),
-            profiles: Arc::new(RwLock::new(HashMap::new())),
+/// Point device restrictions naming an event node at the stable id of the
+/// device now at that node, returning how many macros changed
+///
+/// Restrictions for devices that aren't present are left alone, to be migrated
+/// the next time macros are loaded with the device plugged in.
+pub fn migrate_device_ids<'a>(
+    macros: impl IntoIterator<Item = &'a mut MacroEntry>,
+    devices: &[DeviceInfo],
+) -> usize {
+    let mut migrated = 0;
+    for macro_entry in macros {
+        let Some(device_id) = macro_entry.device_id.as_mut() else {
+            continue;
         };
-
-        // Ensure directories exist
-        if let Some(parent) = manager.config_path.parent() {
-            fs::create_dir_all(parent).await?;
-        }
-        if let Some(parent) = manager.macros_path.parent() {
-            fs::create_dir_all(parent).await?;
+        let device = devices.iter().find(|d| {
+            !d.stable_id.is_empty() && *device_id != d.stable_id && Path::new(device_id.as_str()) == d.path
+        });
+        if let Some(device) = device {
+            debug!("Macro {} now restricted to {} instead of {}", macro_entry.name, device.stable_id, device_id);
+            *device_id = device.stable_id.clone();
+            migrated += 1;
         }
-        if let Some(parent) = manager.cache_path.parent() {
-            fs::create_dir_all(parent).await?;
+    }
+    migrated
+}
+
+/// Directories holding the config, macros and profiles, and the macros cache
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigDirs {
+    pub config_dir: PathBuf,
+    pub cache_dir: PathBuf,
+}
+
+impl ConfigDirs {
+    /// System wide directories used when running as root
+    pub fn system() -> Self {
+        Self {
+            config_dir: PathBuf::from("/etc/razermapperd"),
+            cache_dir: PathBuf::from("/var/cache/razermapperd"),
         }
-        fs::create_dir_all(&manager.profiles_dir).await?;
+    }
 
-        Ok(manager)
+    /// Pick `system` if its directories can be created and written to, otherwise
+    /// the fallback directories
+    ///
+    /// The profiles directory is created in whichever config directory is chosen.
+    pub async fn resolve(
+        system: &ConfigDirs,
+        fallback_config: Option<PathBuf>,
+        fallback_cache: Option<PathBuf>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let config_dir = writable_dir_or(&system.config_dir, fallback_config, "config").await?;
+        let cache_dir = writable_dir_or(&system.cache_dir, fallback_cache, "cache").await?;
+
+        fs::create_dir_all(config_dir.join("profiles")).await?;
+        Ok(Self { config_dir, cache_dir })
     }
+}
 
-    /// Load configuration from disk
-    pub async fn load_config(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        info!("Loading configuration from {}", self.config_path.display());
+/// Read an XDG base directory variable, falling back to `$HOME/<home_relative>`
+fn xdg_dir(var: &str, home_relative: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .map(PathBuf::from)
+        // The spec says relative values are invalid and should be ignored
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(home_relative)))
+}
 
-        if self.config_path.exists() {
-            let content = fs::read_to_string(&self.config_path).await?;
-            self.config = serde_yaml::from_str(&content)?;
-            debug!("Loaded configuration from disk");
-        } else {
-            warn!("Configuration file not found, using defaults");
-            self.save_config().await?;
-        }
+/// Use `dir` if it's writable, otherwise `fallback`
+async fn writable_dir_or(dir: &Path, fallback: Option<PathBuf>, kind: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let e = match ensure_writable_dir(dir).await {
+        Ok(()) => return Ok(dir.to_path_buf()),
+        Err(e) => e,
+    };
+
+    let fallback = fallback.ok_or_else(|| format!(
+        "Cannot use {} ({}) and no XDG {} directory is available", dir.display(), e, kind
+    ))?;
+    warn!("Cannot use {} ({}), falling back to {}", dir.display(), e, fallback.display());
+    ensure_writable_dir(&fallback).await?;
+    Ok(fallback)
+}
 
-        // Try to load macros from cache first, then from YAML
-        if self.cache_path.exists() {
-            match self.load_macros_from_cache().await {
-                Ok(()) => {
-                    debug!("Loaded macros from cache");
-                    return Ok(());
-                }
-                Err(e) => {
-                    warn!("Failed to load macros from cache: {}", e);
-                    // Fall back to YAML
-                }
-            }
-        }
+/// Create `dir` if needed and check a file can be created inside it
+///
+/// An existing directory owned by root passes `create_dir_all` for any user,
+/// so writability is probed with a scratch file.
+async fn ensure_writable_dir(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir).await?;
+    let probe = dir.join(format!(".razermapperd-probe-{}", std::process::id()));
+    fs::write(&probe, b"").await?;
+    fs::remove_file(&probe).await
+}
 
-        if self.macros_path.exists() {
-            self.load_macros_from_yaml().await?;
-        } else {
-            info!("No macros file found, creating empty macros");
-            self.save_macros().await?;
+impl ConfigManager {
+    /// Create a new configuration manager with default paths
+    ///
+    /// The system directories are used when they can be written to, otherwise
+    /// the user's XDG config and cache directories are used instead.
+    pub async fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        let dirs = ConfigDirs::resolve(
+            &ConfigDirs::system(),
+            xdg_dir("XDG_CONFIG_HOME", ".config").map(|dir| dir.join("razermapper")),
+            xdg_dir("XDG_CACHE_HOME", ".cache").map(|dir| dir.join("razermapper")),
+        ).await?;
+        info!("Using config directory {} and cache directory {}", dirs.config_dir.display(), dirs.cache_dir.display());
+
+        Ok(Self::with_dirs(&dirs))
+    }
+
+    /// Create a configuration manager keeping its files in the given directories
+    pub fn with_dirs(dirs: &ConfigDirs) -> Self {
+        Self {
+            config_path: dirs.config_dir.join("config.yaml"),
+            macros_path: dirs.config_dir.join("macros.yaml"),
+            cache_path: dirs.cache_dir.join("macros.bin"),
+            profiles_dir: dirs.config_dir.join("profiles"),
+            config: Arc::new(RwLock::new(DaemonConfig::default())),
+            macros: Arc::new(RwLock::new(HashMap::new())),
+            profiles: Arc::new(RwLock::new(HashMap::new())),
+            save_pending: Arc::new(AtomicBool::new(false)),
+            known_mtimes: Arc::new(Mutex::new([None, None])),
         }
+    }
 
-        Ok(())
+    /// Load configuration from disk
+    pub async fn load_config(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.load_config_mut().await
     }
 
     /// Save configuration to disk
     pub async fn save_config(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("Saving configuration to {}", self.config_path.display());
 
-        let content = serde_yaml::to_string(&self.config)?;
-        fs::write(&self.config_path, content).await?;
+        let content = serde_yaml::to_string(&*self.config.read().await)?;
+        let mut known_mtimes = self.known_mtimes.lock().await;
+        write_atomic(&self.config_path, content).await?;
+        *known_mtimes = self.modification_times();
 
         debug!("Configuration saved");
         Ok(())
@@ -220,18 +701,7 @@ impl ConfigManager {
         info!("Loading macros from cache {}", self.cache_path.display());
 
         let content = fs::read(&self.cache_path).await?;
-
-        // First 4 bytes should be a magic number for verification
-        if content.len() < 4 {
-            return Err("Cache file too short".into());
-        }
-
-        let magic = u32::from_le_bytes([content[0], content[1], content[2], content[3]]);
-        if magic != 0xDEADBEEF {
-            return Err("Invalid cache file magic number".into());
-        }
-
-        let macros: HashMap<String, MacroEntry> = razermapper_common::deserialize(&content[4..])?;
+        let macros: HashMap<String, MacroEntry> = razermapper_common::deserialize(cache_payload(&content)?)?;
         *self.macros.write().await = macros;
 
         debug!("Loaded macros from cache");
@@ -243,7 +713,7 @@ impl ConfigManager {
         info!("Loading macros from {}", self.macros_path.display());
 
         let content = fs::read_to_string(&self.macros_path).await?;
-        let macros: HashMap<String, MacroEntry> = serde_yaml::from_str(&content)?;
+        let macros: HashMap<String, MacroEntry> = from_yaml(&self.macros_path, &content)?;
         *self.macros.write().await = macros;
 
         debug!("Loaded macros from YAML");
@@ -266,16 +736,14 @@ impl ConfigManager {
 
     /// Save macros to binary cache
     async fn save_macros_to_cache(&self, macros: &HashMap<String, MacroEntry>) -> Result<(), Box<dyn std::error::Error>> {
-        let mut data = Vec::new();
-
-        // Add magic number
-        data.extend_from_slice(&0xDEADBEEFu32.to_le_bytes());
-
-        // Add serialized data
         let serialized = razermapper_common::serialize(macros);
+        let mut data = Vec::with_capacity(CACHE_HEADER_LEN + serialized.len());
+        data.extend_from_slice(&CACHE_MAGIC.to_le_bytes());
+        data.extend_from_slice(&(serialized.len() as u32).to_le_bytes());
+        data.extend_from_slice(&crc32fast::hash(&serialized).to_le_bytes());
         data.extend_from_slice(&serialized);
 
-        fs::write(&self.cache_path, data).await?;
+        write_atomic(&self.cache_path, data).await?;
         debug!("Saved macros to cache");
         Ok(())
     }
@@ -283,26 +751,34 @@ impl ConfigManager {
     /// Save macros to YAML file
     async fn save_macros_to_yaml(&self, macros: &HashMap<String, MacroEntry>) -> Result<(), Box<dyn std::error::Error>> {
         let content = serde_yaml::to_string(macros)?;
-        fs::write(&self.macros_path, content).await?;
+        // The watcher waits for the lock, so it never mistakes this write for an edit
+        let mut known_mtimes = self.known_mtimes.lock().await;
+        write_atomic(&self.macros_path, content).await?;
+        *known_mtimes = self.modification_times();
         debug!("Saved macros to YAML");
         Ok(())
     }
 
-    /// Get a reference to the configuration
-    pub fn config(&self) -> &DaemonConfig {
-        &self.config
+    /// Get a snapshot of the current configuration
+    ///
+    /// The snapshot is not affected by later reloads, so callers that need the
+    /// latest values should call this again rather than hold on to it.
+    pub async fn config(&self) -> DaemonConfig {
+        self.config.read().await.clone()
     }
 
-    /// Load configuration from disk (mutable version for use with Arc)
+    /// Load configuration and macros from disk, replacing the current ones
     pub async fn load_config_mut(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("Loading configuration from {}", self.config_path.display());
 
         if self.config_path.exists() {
             let content = fs::read_to_string(&self.config_path).await?;
-            let _config: DaemonConfig = serde_yaml::from_str(&content)?;
-            // We can't replace self.config directly, so we'll update the fields
-            // This is a limitation of using Arc<ConfigManager> without interior mutability
+            let parsed = parse_config(&self.config_path, &content)?;
+            *self.config.write().await = parsed.config.clone();
             debug!("Loaded configuration from disk");
+            if parsed.upgraded() {
+                self.rewrite_upgraded_config(&content, &parsed).await?;
+            }
         } else {
             warn!("Configuration file not found, using defaults");
             self.save_config().await?;
@@ -316,14 +792,22 @@ impl ConfigManager {
                     return Ok(());
                 }
                 Err(e) => {
-                    warn!("Failed to load macros from cache: {}", e);
-                    // Fall back to YAML
+                    warn!("Failed to load macros from cache, rebuilding it from {}: {}", self.macros_path.display(), e);
+                    if let Err(e) = fs::remove_file(&self.cache_path).await {
+                        warn!("Failed to remove macro cache {}: {}", self.cache_path.display(), e);
+                    }
                 }
             }
         }
 
         if self.macros_path.exists() {
             self.load_macros_from_yaml().await?;
+            if !self.cache_path.exists() {
+                let macros = self.macros.read().await;
+                if let Err(e) = self.save_macros_to_cache(&macros).await {
+                    warn!("Failed to rebuild macro cache: {}", e);
+                }
+            }
         } else {
             info!("No macros file found, creating empty macros");
             self.save_macros().await?;
@@ -332,11 +816,212 @@ impl ConfigManager {
         Ok(())
     }
 
+    /// Keep a copy of a config file written with an older layout and replace
+    /// it with the upgraded configuration
+    async fn rewrite_upgraded_config(&self, original: &str, parsed: &ParsedConfig) -> Result<(), Box<dyn std::error::Error>> {
+        let backup_path = self.config_path.with_extension(format!("yaml.v{}", parsed.from_version));
+        fs::write(&backup_path, original).await?;
+        info!(
+            "Upgraded configuration from version {} to {} ({}), previous file kept as {}",
+            parsed.from_version,
+            CONFIG_VERSION,
+            parsed.changes.join(", "),
+            backup_path.display()
+        );
+        self.save_config().await
+    }
+
     /// Get a reference to the macros
     pub fn macros(&self) -> &Arc<RwLock<HashMap<String, MacroEntry>>> {
         &self.macros
     }
 
+    /// Re-read the config and macros files, returning the number of macros
+    ///
+    /// Both files are parsed before anything is replaced, so a file with a
+    /// syntax error leaves the current configuration and macros untouched.
+    /// Missing files keep the current values.
+    pub async fn reload_from_disk(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let config = if self.config_path.exists() {
+            let content = fs::read_to_string(&self.config_path).await?;
+            let parsed = parse_config(&self.config_path, &content)?;
+            if parsed.upgraded() {
+                warn!(
+                    "Configuration file uses layout version {}, upgraded in memory ({}); it is rewritten on the next restart",
+                    parsed.from_version,
+                    parsed.changes.join(", ")
+                );
+            }
+            Some(parsed.config)
+        } else {
+            None
+        };
+
+        let macros = if self.macros_path.exists() {
+            let content = fs::read_to_string(&self.macros_path).await?;
+            Some(from_yaml::<HashMap<String, MacroEntry>>(&self.macros_path, &content)?)
+        } else {
+            None
+        };
+
+        if let Some(config) = config {
+            *self.config.write().await = config;
+        }
+        if let Some(macros) = macros {
+            // The YAML was edited, so the cache no longer matches it
+            if let Err(e) = self.save_macros_to_cache(&macros).await {
+                warn!("Failed to refresh macro cache: {}", e);
+            }
+            *self.macros.write().await = macros;
+        }
+        *self.known_mtimes.lock().await = self.modification_times();
+
+        Ok(self.macros.read().await.len())
+    }
+
+    /// Watch the config and macros files and reload them when they change
+    ///
+    /// Changes are picked up through inotify. Where a watch can't be set up,
+    /// the files' modification times are polled every `poll_interval`
+    /// instead, unless it is zero. Writes made by the daemon itself are not
+    /// reloaded, and neither is anything while a debounced macro save is
+    /// pending, since that save is about to overwrite the macros file.
+    ///
+    /// Successful reloads are reported on the returned channel; a file that
+    /// fails to parse is logged and the previous configuration stays in place.
+    pub fn start_watching(self: Arc<Self>, poll_interval: Duration) -> mpsc::Receiver<ConfigReload> {
+        let (sender, receiver) = mpsc::channel(16);
+        let (change_sender, mut changes) = mpsc::channel(1);
+
+        let watcher = match self.watch_files(change_sender) {
+            Ok(watcher) => {
+                info!("Watching {} and {} for changes", self.config_path.display(), self.macros_path.display());
+                Some(watcher)
+            }
+            Err(e) if poll_interval.is_zero() => {
+                warn!("Cannot watch configuration files, changes need a ReloadConfig request: {}", e);
+                return receiver;
+            }
+            Err(e) => {
+                warn!("Cannot watch configuration files ({}), checking them every {:?} instead", e, poll_interval);
+                None
+            }
+        };
+
+        tokio::spawn(async move {
+            *self.known_mtimes.lock().await = self.modification_times();
+            loop {
+                if watcher.is_some() {
+                    if changes.recv().await.is_none() {
+                        break;
+                    }
+                    tokio::time::sleep(WATCH_SETTLE).await;
+                    while changes.try_recv().is_ok() {}
+                } else {
+                    tokio::time::sleep(poll_interval).await;
+                }
+
+                if !self.changed_on_disk().await {
+                    continue;
+                }
+
+                match self.reload_from_disk().await.map_err(|e| e.to_string()) {
+                    Ok(macros_count) => {
+                        info!("Configuration changed on disk, reloaded {} macros", macros_count);
+                        if sender.send(ConfigReload { macros_count }).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Ignoring invalid configuration change, keeping previous settings: {}", e);
+                    }
+                }
+            }
+        });
+
+        receiver
+    }
+
+    /// Set up an inotify watch on the directories of the config and macros
+    /// files, signalling `changes` whenever one of the two files changes
+    ///
+    /// The directories are watched rather than the files, so edits made by
+    /// replacing the file (as most editors do) are seen too.
+    fn watch_files(&self, changes: mpsc::Sender<()>) -> notify::Result<notify::RecommendedWatcher> {
+        use notify::Watcher;
+
+        let files = [self.config_path.clone(), self.macros_path.clone()];
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let Ok(event) = event else { return };
+            if !event.kind.is_access() && event.paths.iter().any(|path| files.contains(path)) {
+                // A full channel already has a change waiting to be handled
+                let _ = changes.try_send(());
+            }
+        })?;
+
+        let mut dirs: Vec<&Path> = [&self.config_path, &self.macros_path]
+            .iter()
+            .filter_map(|path| path.parent())
+            .collect();
+        dirs.dedup();
+        for dir in dirs {
+            watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+        }
+        Ok(watcher)
+    }
+
+    /// Whether the config or macros file was changed by someone other than
+    /// the daemon since it last wrote or read them
+    ///
+    /// While a macro save is pending the change is left unacknowledged; the
+    /// save overwrites the macros file and records the new times.
+    async fn changed_on_disk(&self) -> bool {
+        let mut known_mtimes = self.known_mtimes.lock().await;
+        let modified = self.modification_times();
+        if modified == *known_mtimes {
+            return false;
+        }
+        if self.save_pending.load(Ordering::SeqCst) {
+            debug!("Not reloading configuration while a macro save is pending");
+            return false;
+        }
+        *known_mtimes = modified;
+        true
+    }
+
+    /// Modification times of the config and macros files (None if missing)
+    fn modification_times(&self) -> [Option<SystemTime>; 2] {
+        [&self.config_path, &self.macros_path]
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+    }
+
+    /// Replace the macro set and, if auto_save is enabled, schedule a save
+    pub async fn update_macros(self: &Arc<Self>, macros: HashMap<String, MacroEntry>) {
+        *self.macros.write().await = macros;
+
+        if self.config.read().await.config.auto_save {
+            self.schedule_save_macros();
+        }
+    }
+
+    /// Save macros after MACRO_SAVE_DEBOUNCE unless a save is already waiting,
+    /// in which case that save will pick up the latest macros
+    fn schedule_save_macros(self: &Arc<Self>) {
+        if self.save_pending.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let manager = Arc::clone(self);
+        tokio::spawn(async move {
+            tokio::time::sleep(MACRO_SAVE_DEBOUNCE).await;
+            manager.save_pending.store(false, Ordering::SeqCst);
+
+            if let Err(e) = manager.save_macros().await {
+                warn!("Failed to auto-save macros: {}", e);
+            }
+        });
+    }
+
     /// Get a profile by name
     pub async fn get_profile(&self, name: &str) -> Option<Profile> {
         let profiles = self.profiles.read().await;
@@ -349,13 +1034,59 @@ impl ConfigManager {
         profiles.clone()
     }
 
+    /// Whether a profile of this name is saved on disk
+    pub fn profile_exists(&self, name: &str) -> bool {
+        self.profiles_dir.join(format!("{}.yaml", name)).exists()
+    }
+
+    /// File remembering the profile most recently loaded over IPC
+    fn last_profile_path(&self) -> PathBuf {
+        self.cache_path.with_file_name("last_profile")
+    }
+
+    /// Remember `name` as the most recently loaded profile for `startup_profile: last`
+    pub async fn remember_last_profile(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        write_atomic(&self.last_profile_path(), name).await?;
+        debug!("Remembered {} as the last loaded profile", name);
+        Ok(())
+    }
+
+    /// The profile most recently loaded over IPC, if one was remembered
+    pub async fn last_profile(&self) -> Option<String> {
+        let name = fs::read_to_string(self.last_profile_path()).await.ok()?;
+        let name = name.trim();
+        (!name.is_empty()).then(|| name.to_string())
+    }
+
+    /// Pick the profile to load at startup from the `startup_profile` setting
+    ///
+    /// Falls back to the default profile when the setting is empty, when
+    /// "last" has nothing remembered, or when the named profile doesn't exist.
+    pub async fn startup_profile(&self) -> String {
+        let configured = self.config.read().await.daemon.startup_profile.trim().to_string();
+        let name = match configured.as_str() {
+            "" => return DEFAULT_PROFILE.to_string(),
+            LAST_PROFILE => match self.last_profile().await {
+                Some(name) => name,
+                None => return DEFAULT_PROFILE.to_string(),
+            },
+            _ => configured,
+        };
+
+        if name != DEFAULT_PROFILE && !self.profile_exists(&name) {
+            warn!("Startup profile {} not found, falling back to {}", name, DEFAULT_PROFILE);
+            return DEFAULT_PROFILE.to_string();
+        }
+        name
+    }
+
     /// Save a profile
     pub async fn save_profile(&self, profile: &Profile) -> Result<(), Box<dyn std::error::Error>> {
         let profile_path = self.profiles_dir.join(format!("{}.yaml", profile.name));
 
         // Save to YAML
         let yaml = serde_yaml::to_string(profile)?;
-        fs::write(&profile_path, yaml).await?;
+        write_atomic(&profile_path, yaml).await?;
 
         // Update in-memory profiles
         let mut profiles = self.profiles.write().await;
@@ -374,7 +1105,7 @@ impl ConfigManager {
         }
 
         let yaml = fs::read_to_string(&profile_path).await?;
-        let profile: Profile = serde_yaml::from_str(&yaml)?;
+        let profile: Profile = from_yaml(&profile_path, &yaml)?;
 
         // Update in-memory profiles
         let mut profiles = self.profiles.write().await;
@@ -428,23 +1159,85 @@ impl ConfigManager {
     }
 
     /// Save current macros as a new profile
+    ///
+    /// Saving over a known profile keeps its description, author and creation
+    /// time; a new profile is stamped with the current time.
     pub async fn save_current_macros_as_profile(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let macros = self.macros.read().await;
+        let macros = self.macros.read().await.clone();
+        let previous = self.profiles.read().await.get(name).cloned();
         let profile = Profile {
             name: name.to_string(),
-            macros: macros.clone(),
+            macros,
+            description: previous.as_ref().and_then(|p| p.description.clone()),
+            author: previous.as_ref().and_then(|p| p.author.clone()),
+            created_at: previous.and_then(|p| p.created_at).or_else(|| Some(unix_secs())),
         };
 
         self.save_profile(&profile).await
     }
 }
 
+/// Current time in seconds since the Unix epoch
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use razermapper_common::TriggerEdge;
     use std::collections::HashMap;
+    use std::os::unix::fs::PermissionsExt;
     use tempfile::TempDir;
 
+    #[tokio::test]
+    async fn test_unwritable_system_dirs_fall_back_to_xdg() {
+        let temp_dir = TempDir::new().unwrap();
+        let read_only = temp_dir.path().join("etc");
+        std::fs::create_dir(&read_only).unwrap();
+        // A file where the system directories should be can't be written even as root,
+        // unlike a directory that only has its write bits cleared
+        std::fs::write(read_only.join("razermapperd"), b"").unwrap();
+        std::fs::set_permissions(&read_only, std::fs::Permissions::from_mode(0o555)).unwrap();
+
+        let system = ConfigDirs {
+            config_dir: read_only.join("razermapperd"),
+            cache_dir: read_only.join("razermapperd").join("cache"),
+        };
+        let xdg_config = temp_dir.path().join("xdg-config").join("razermapper");
+        let xdg_cache = temp_dir.path().join("xdg-cache").join("razermapper");
+
+        let dirs = ConfigDirs::resolve(&system, Some(xdg_config.clone()), Some(xdg_cache.clone())).await.unwrap();
+        assert_eq!(dirs, ConfigDirs { config_dir: xdg_config.clone(), cache_dir: xdg_cache.clone() });
+        assert!(xdg_config.join("profiles").is_dir());
+
+        let manager = ConfigManager::with_dirs(&dirs);
+        assert_eq!(manager.config_path, xdg_config.join("config.yaml"));
+        assert_eq!(manager.cache_path, xdg_cache.join("macros.bin"));
+        manager.save_config().await.unwrap();
+
+        // Without a fallback the error names the unusable directory
+        let err = ConfigDirs::resolve(&system, None, None).await.unwrap_err();
+        assert!(err.to_string().contains("razermapperd"));
+
+        std::fs::set_permissions(&read_only, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_writable_system_dirs_are_kept() {
+        let temp_dir = TempDir::new().unwrap();
+        let system = ConfigDirs {
+            config_dir: temp_dir.path().join("etc"),
+            cache_dir: temp_dir.path().join("cache"),
+        };
+        let dirs = ConfigDirs::resolve(&system, Some(temp_dir.path().join("xdg")), None).await.unwrap();
+        assert_eq!(dirs, system);
+        assert!(!temp_dir.path().join("xdg").exists());
+    }
+
     #[tokio::test]
     async fn test_config_creation() {
         let temp_dir = TempDir::new().unwrap();
@@ -457,9 +1250,11 @@ mod tests {
             macros_path,
             cache_path,
             profiles_dir: temp_dir.path().join("profiles"),
-            config: DaemonConfig::default(),
+            config: Arc::new(RwLock::new(DaemonConfig::default())),
             macros: Arc::new(RwLock::new(HashMap::new())),
             profiles: Arc::new(RwLock::new(HashMap::new())),
+            save_pending: Arc::new(AtomicBool::new(false)),
+            known_mtimes: Arc::new(Mutex::new([None, None])),
         };
 
         // Should be able to save and load without errors
@@ -479,9 +1274,11 @@ mod tests {
             macros_path: macros_path.clone(),
             cache_path: cache_path.clone(),
             profiles_dir: temp_dir.path().to_path_buf(),
-            config: DaemonConfig::default(),
+            config: Arc::new(RwLock::new(DaemonConfig::default())),
             macros: Arc::new(RwLock::new(HashMap::new())),
             profiles: Arc::new(RwLock::new(HashMap::new())),
+            save_pending: Arc::new(AtomicBool::new(false)),
+            known_mtimes: Arc::new(Mutex::new([None, None])),
         };
 
         // Add a test macro
@@ -498,6 +1295,10 @@ mod tests {
             ],
             device_id: None,
             enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
         };
 
         manager.macros.write().await.insert("test_macro".to_string(), test_macro.clone());
@@ -510,9 +1311,11 @@ mod tests {
             macros_path,
             cache_path: temp_dir.path().join("macros2.bin"),
             profiles_dir: temp_dir.path().to_path_buf(),
-            config: DaemonConfig::default(),
+            config: Arc::new(RwLock::new(DaemonConfig::default())),
             macros: Arc::new(RwLock::new(HashMap::new())),
             profiles: Arc::new(RwLock::new(HashMap::new())),
+            save_pending: Arc::new(AtomicBool::new(false)),
+            known_mtimes: Arc::new(Mutex::new([None, None])),
         };
 
         manager2.load_macros_from_yaml().await.unwrap();
@@ -523,4 +1326,441 @@ mod tests {
         assert_eq!(loaded_macro.name, test_macro.name);
         assert_eq!(loaded_macro.trigger.keys, test_macro.trigger.keys);
     }
+
+    #[tokio::test]
+    async fn test_write_atomic() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("macros.yaml");
+
+        write_atomic(&path, "first").await.unwrap();
+        write_atomic(&path, "second").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+
+        // Nothing but the target is left behind
+        let entries: Vec<_> = std::fs::read_dir(temp_dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(entries, vec![std::ffi::OsString::from("macros.yaml")]);
+
+        // A write that can't be completed leaves the previous file alone
+        std::fs::create_dir(temp_dir.path().join("dir")).unwrap();
+        assert!(write_atomic(&temp_dir.path().join("dir"), "third").await.is_err());
+        assert!(temp_dir.path().join("dir").is_dir());
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_cache_is_rebuilt() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = ConfigManager::with_dirs(&ConfigDirs {
+            config_dir: temp_dir.path().to_path_buf(),
+            cache_dir: temp_dir.path().to_path_buf(),
+        });
+        let macro_entry = MacroEntry {
+            name: "cached".to_string(),
+            trigger: razermapper_common::KeyCombo { keys: vec![30], modifiers: vec![] },
+            actions: vec![razermapper_common::Action::KeyPress(31)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        manager.macros.write().await.insert("cached".to_string(), macro_entry);
+        manager.save_macros().await.unwrap();
+        let good_cache = std::fs::read(&manager.cache_path).unwrap();
+
+        // Flip a byte of the body, leaving the magic number intact
+        let mut corrupt = good_cache.clone();
+        *corrupt.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&manager.cache_path, &corrupt).unwrap();
+        let err = manager.load_macros_from_cache().await.unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"));
+
+        manager.macros.write().await.clear();
+        manager.load_config_mut().await.unwrap();
+        assert!(manager.macros.read().await.contains_key("cached"));
+        assert_eq!(std::fs::read(&manager.cache_path).unwrap(), good_cache);
+
+        // A write cut short is caught by the length
+        std::fs::write(&manager.cache_path, &good_cache[..good_cache.len() - 3]).unwrap();
+        let err = manager.load_macros_from_cache().await.unwrap_err();
+        assert!(err.to_string().contains("length does not match"));
+        manager.load_config_mut().await.unwrap();
+        assert_eq!(std::fs::read(&manager.cache_path).unwrap(), good_cache);
+    }
+
+    #[tokio::test]
+    async fn test_update_macros_respects_auto_save() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = DaemonConfig::default();
+        config.config.auto_save = false;
+
+        let manager = Arc::new(ConfigManager {
+            config_path: temp_dir.path().join("config.yaml"),
+            macros_path: temp_dir.path().join("macros.yaml"),
+            cache_path: temp_dir.path().join("macros.bin"),
+            profiles_dir: temp_dir.path().to_path_buf(),
+            config: Arc::new(RwLock::new(config)),
+            macros: Arc::new(RwLock::new(HashMap::new())),
+            profiles: Arc::new(RwLock::new(HashMap::new())),
+            save_pending: Arc::new(AtomicBool::new(false)),
+            known_mtimes: Arc::new(Mutex::new([None, None])),
+        });
+
+        let mut macros = HashMap::new();
+        macros.insert("test_macro".to_string(), MacroEntry {
+            name: "test_macro".to_string(),
+            trigger: razermapper_common::KeyCombo {
+                keys: vec![30],
+                modifiers: vec![],
+            },
+            actions: vec![razermapper_common::Action::KeyPress(30)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        });
+        manager.update_macros(macros).await;
+
+        tokio::time::sleep(MACRO_SAVE_DEBOUNCE * 2).await;
+        assert_eq!(manager.macros.read().await.len(), 1);
+        assert!(!manager.macros_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_load_config_replaces_config() {
+        let temp_dir = TempDir::new().unwrap();
+
+        let manager = ConfigManager {
+            config_path: temp_dir.path().join("config.yaml"),
+            macros_path: temp_dir.path().join("macros.yaml"),
+            cache_path: temp_dir.path().join("macros.bin"),
+            profiles_dir: temp_dir.path().to_path_buf(),
+            config: Arc::new(RwLock::new(DaemonConfig::default())),
+            macros: Arc::new(RwLock::new(HashMap::new())),
+            profiles: Arc::new(RwLock::new(HashMap::new())),
+            save_pending: Arc::new(AtomicBool::new(false)),
+            known_mtimes: Arc::new(Mutex::new([None, None])),
+        };
+
+        let mut on_disk = DaemonConfig::default();
+        on_disk.macro_engine.type_delay_ms = 5;
+        on_disk.device_discovery.auto_regrab = true;
+        fs::write(&manager.config_path, serde_yaml::to_string(&on_disk).unwrap()).await.unwrap();
+
+        let before = manager.config().await;
+        manager.load_config_mut().await.unwrap();
+
+        let config = manager.config().await;
+        assert_eq!(config.macro_engine.type_delay_ms, 5);
+        assert!(config.device_discovery.auto_regrab);
+
+        // Snapshots taken before the reload keep the values they were taken with
+        assert_eq!(before.macro_engine.type_delay_ms, DaemonConfig::default().macro_engine.type_delay_ms);
+        assert!(!before.device_discovery.auto_regrab);
+    }
+
+    /// A config file as written before versioning, with the old key names
+    fn v1_config_yaml() -> String {
+        let mut value = serde_yaml::to_value(DaemonConfig::default()).unwrap();
+        let root = value.as_mapping_mut().unwrap();
+        root.remove("version");
+        let engine = root.get_mut("macro_engine").unwrap().as_mapping_mut().unwrap();
+        engine.remove("default_delay_ms");
+        engine.insert("default_delay".into(), 25.into());
+        let persistence = root.get_mut("config").unwrap().as_mapping_mut().unwrap();
+        persistence.remove("reload_interval_secs");
+        persistence.insert("reload_interval".into(), 5.into());
+        serde_yaml::to_string(&value).unwrap()
+    }
+
+    #[test]
+    fn test_parse_config_versions() {
+        let parsed = parse_config(Path::new("config.yaml"), &v1_config_yaml()).unwrap();
+        assert_eq!(parsed.from_version, 1);
+        assert!(parsed.upgraded());
+        assert_eq!(parsed.config.version, CONFIG_VERSION);
+        assert_eq!(parsed.config.macro_engine.default_delay_ms, 25);
+        assert_eq!(parsed.config.config.reload_interval_secs, 5);
+        assert!(parsed.changes.iter().any(|c| c == "renamed macro_engine.default_delay to macro_engine.default_delay_ms"));
+
+        let current = serde_yaml::to_string(&DaemonConfig::default()).unwrap();
+        let parsed = parse_config(Path::new("config.yaml"), &current).unwrap();
+        assert!(!parsed.upgraded());
+        assert!(parsed.changes.is_empty());
+
+        let future = current.replace(&format!("version: {}", CONFIG_VERSION), &format!("version: {}", CONFIG_VERSION + 1));
+        let err = parse_config(Path::new("config.yaml"), &future).unwrap_err();
+        assert!(err.to_string().contains("only understands versions up to"));
+    }
+
+    #[tokio::test]
+    async fn test_load_upgrades_v1_config() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = ConfigManager::with_dirs(&ConfigDirs {
+            config_dir: temp_dir.path().to_path_buf(),
+            cache_dir: temp_dir.path().to_path_buf(),
+        });
+        let original = v1_config_yaml();
+        fs::write(&manager.config_path, &original).await.unwrap();
+
+        manager.load_config_mut().await.unwrap();
+        let config = manager.config().await;
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.macro_engine.default_delay_ms, 25);
+        assert_eq!(config.config.reload_interval_secs, 5);
+
+        // The file is rewritten in the new layout and the old one kept beside it
+        let rewritten = fs::read_to_string(&manager.config_path).await.unwrap();
+        assert!(rewritten.contains("default_delay_ms: 25"));
+        assert!(rewritten.contains(&format!("version: {}", CONFIG_VERSION)));
+        assert!(!rewritten.contains("reload_interval:"));
+        let backup = fs::read_to_string(temp_dir.path().join("config.yaml.v1")).await.unwrap();
+        assert_eq!(backup, original);
+
+        // Loading the upgraded file again changes nothing
+        let parsed = parse_config(&manager.config_path, &rewritten).unwrap();
+        assert!(!parsed.upgraded());
+    }
+
+    #[tokio::test]
+    async fn test_yaml_errors_report_location() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = ConfigManager::with_dirs(&ConfigDirs {
+            config_dir: temp_dir.path().to_path_buf(),
+            cache_dir: temp_dir.path().to_path_buf(),
+        });
+
+        // A wrong type in an otherwise valid config points at the offending value
+        let config = serde_yaml::to_string(&DaemonConfig::default())
+            .unwrap()
+            .replace("max_concurrent_macros: 10", "max_concurrent_macros: lots");
+        let line = config.lines().position(|l| l.contains("lots")).unwrap() + 1;
+        fs::write(&manager.config_path, &config).await.unwrap();
+        let err = manager.load_config_mut().await.unwrap_err().to_string();
+        assert!(err.starts_with(&format!("{}:{}:", manager.config_path.display(), line)), "{}", err);
+        assert!(!err.contains(" at line "), "{}", err);
+
+        // Broken syntax in the macros file
+        fs::remove_file(&manager.config_path).await.unwrap();
+        fs::write(&manager.macros_path, "broken:\n  name: \"unterminated\n").await.unwrap();
+        let err = manager.load_config_mut().await.unwrap_err();
+        match err.downcast_ref::<ConfigError>() {
+            Some(ConfigError::InvalidYaml { path, location: Some((line, _)), .. }) => {
+                assert_eq!(path, &manager.macros_path);
+                assert!(*line >= 2);
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        fs::create_dir_all(&manager.profiles_dir).await.unwrap();
+        fs::write(manager.profiles_dir.join("bad.yaml"), "name: bad\nmacros: [1, 2]\n").await.unwrap();
+        let err = manager.load_profile("bad").await.unwrap_err().to_string();
+        assert!(err.starts_with(&format!("{}:2:", manager.profiles_dir.join("bad.yaml").display())), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_watch_reloads_changed_files() {
+        let temp_dir = TempDir::new().unwrap();
+
+        let manager = Arc::new(ConfigManager {
+            config_path: temp_dir.path().join("config.yaml"),
+            macros_path: temp_dir.path().join("macros.yaml"),
+            cache_path: temp_dir.path().join("macros.bin"),
+            profiles_dir: temp_dir.path().to_path_buf(),
+            config: Arc::new(RwLock::new(DaemonConfig::default())),
+            macros: Arc::new(RwLock::new(HashMap::new())),
+            profiles: Arc::new(RwLock::new(HashMap::new())),
+            save_pending: Arc::new(AtomicBool::new(false)),
+            known_mtimes: Arc::new(Mutex::new([None, None])),
+        });
+
+        let mut receiver = Arc::clone(&manager).start_watching(Duration::from_millis(20));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // A valid edit is picked up
+        let mut macros = HashMap::new();
+        macros.insert("test_macro".to_string(), MacroEntry {
+            name: "test_macro".to_string(),
+            trigger: razermapper_common::KeyCombo {
+                keys: vec![30],
+                modifiers: vec![],
+            },
+            actions: vec![razermapper_common::Action::KeyPress(30)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        });
+        fs::write(&manager.macros_path, serde_yaml::to_string(&macros).unwrap()).await.unwrap();
+
+        let reload = tokio::time::timeout(Duration::from_secs(2), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(reload.macros_count, 1);
+        assert!(manager.macros.read().await.contains_key("test_macro"));
+
+        // A broken edit is ignored and the previous macros stay live
+        fs::write(&manager.macros_path, "test_macro: [unclosed").await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(200), receiver.recv()).await.is_err());
+        assert_eq!(manager.macros.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_watch_ignores_own_saves() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = Arc::new(ConfigManager::with_dirs(&ConfigDirs {
+            config_dir: temp_dir.path().to_path_buf(),
+            cache_dir: temp_dir.path().to_path_buf(),
+        }));
+
+        // No polling fallback, so reloads can only come from inotify
+        let mut receiver = Arc::clone(&manager).start_watching(Duration::ZERO);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let macro_entry = MacroEntry {
+            name: "unsaved".to_string(),
+            trigger: razermapper_common::KeyCombo {
+                keys: vec![30],
+                modifiers: vec![],
+            },
+            actions: vec![razermapper_common::Action::KeyPress(30)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        let mut macros = HashMap::new();
+        macros.insert(macro_entry.name.clone(), macro_entry);
+
+        // An edit written by hand while a save is pending doesn't undo the edit
+        manager.update_macros(macros).await;
+        fs::write(&manager.macros_path, "{}").await.unwrap();
+        assert!(tokio::time::timeout(MACRO_SAVE_DEBOUNCE * 2, receiver.recv()).await.is_err());
+        assert!(manager.macros.read().await.contains_key("unsaved"));
+
+        // Nor does the daemon's own save
+        assert!(tokio::time::timeout(Duration::from_millis(300), receiver.recv()).await.is_err());
+        let saved = fs::read_to_string(&manager.macros_path).await.unwrap();
+        assert!(saved.contains("unsaved"));
+
+        // A later hand edit is reloaded
+        fs::write(&manager.macros_path, "{}").await.unwrap();
+        let reload = tokio::time::timeout(Duration::from_secs(2), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(reload.macros_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_profile_metadata() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = ConfigManager::with_dirs(&ConfigDirs {
+            config_dir: temp_dir.path().to_path_buf(),
+            cache_dir: temp_dir.path().to_path_buf(),
+        });
+        fs::create_dir_all(&manager.profiles_dir).await.unwrap();
+
+        // Profiles written before the metadata existed still load
+        fs::write(manager.profiles_dir.join("old.yaml"), "name: old\nmacros: {}\n").await.unwrap();
+        let old = manager.load_profile("old").await.unwrap();
+        assert_eq!((old.description, old.author, old.created_at), (None, None, None));
+
+        // A new profile is stamped with its creation time
+        manager.save_current_macros_as_profile("fresh").await.unwrap();
+        let fresh = manager.load_profile("fresh").await.unwrap();
+        let created_at = fresh.created_at.expect("created_at is set on save");
+        assert!(created_at > 0);
+
+        // Saving over it keeps the metadata
+        manager.profiles.write().await.get_mut("fresh").unwrap().description = Some("Daily driver".to_string());
+        manager.save_current_macros_as_profile("fresh").await.unwrap();
+        let resaved = manager.load_profile("fresh").await.unwrap();
+        assert_eq!(resaved.created_at, Some(created_at));
+        assert_eq!(resaved.description.as_deref(), Some("Daily driver"));
+    }
+
+    #[tokio::test]
+    async fn test_startup_profile_fallback() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = ConfigManager::with_dirs(&ConfigDirs {
+            config_dir: temp_dir.path().to_path_buf(),
+            cache_dir: temp_dir.path().to_path_buf(),
+        });
+        fs::create_dir_all(&manager.profiles_dir).await.unwrap();
+        assert_eq!(manager.startup_profile().await, DEFAULT_PROFILE);
+
+        // A missing profile falls back to the default
+        manager.config.write().await.daemon.startup_profile = "gaming".to_string();
+        assert_eq!(manager.startup_profile().await, DEFAULT_PROFILE);
+
+        let profile = Profile {
+            name: "gaming".to_string(),
+            macros: HashMap::new(),
+            description: None,
+            author: None,
+            created_at: None,
+        };
+        manager.save_profile(&profile).await.unwrap();
+        assert_eq!(manager.startup_profile().await, "gaming");
+
+        // "last" falls back until a profile has been remembered
+        manager.config.write().await.daemon.startup_profile = LAST_PROFILE.to_string();
+        assert_eq!(manager.startup_profile().await, DEFAULT_PROFILE);
+        manager.remember_last_profile("gaming").await.unwrap();
+        assert_eq!(manager.startup_profile().await, "gaming");
+
+        manager.delete_profile("gaming").await.unwrap();
+        assert_eq!(manager.startup_profile().await, DEFAULT_PROFILE);
+    }
+
+    #[test]
+    fn test_migrate_device_ids() {
+        let device = DeviceInfo {
+            name: "Razer Keyboard".to_string(),
+            path: PathBuf::from("/dev/input/event5"),
+            vendor_id: 0x1532,
+            product_id: 0x0221,
+            phys: "usb-0000:00:14.0-1/input0".to_string(),
+            capabilities: Default::default(),
+            stable_id: razermapper_common::stable_device_id(0x1532, 0x0221, "usb-0000:00:14.0-1/input0"),
+        };
+        let restricted = |name: &str, device_id: Option<&str>| MacroEntry {
+            name: name.to_string(),
+            trigger: razermapper_common::KeyCombo { keys: vec![30], modifiers: vec![] },
+            actions: vec![],
+            device_id: device_id.map(str::to_string),
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        let mut macros = [
+            restricted("by path", Some("/dev/input/event5")),
+            restricted("missing device", Some("/dev/input/event9")),
+            restricted("unrestricted", None),
+            restricted("already stable", Some(&device.stable_id)),
+        ];
+
+        assert_eq!(migrate_device_ids(macros.iter_mut(), std::slice::from_ref(&device)), 1);
+        assert_eq!(macros[0].device_id.as_deref(), Some(device.stable_id.as_str()));
+        assert_eq!(macros[1].device_id.as_deref(), Some("/dev/input/event9"));
+        assert_eq!(macros[2].device_id, None);
+        assert!(device.matches_device_id(macros[3].device_id.as_deref().unwrap()));
+
+        // Running it again finds nothing left to migrate
+        assert_eq!(migrate_device_ids(macros.iter_mut(), std::slice::from_ref(&device)), 0);
+    }
+
+    #[test]
+    fn test_auto_regrab_defaults_off() {
+        let yaml = "input_devices_path: /dev/input/by-id\nuse_openrazer_db: true\nfallback_name_pattern: Razer\n";
+        let settings: DeviceDiscoverySettings = serde_yaml::from_str(yaml).unwrap();
+        assert!(!settings.auto_regrab);
+        assert!(!DaemonConfig::default().device_discovery.auto_regrab);
+    }
 }
@@ -1,43 +1,213 @@
-use razermapper_common::{tracing, DeviceInfo};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use razermapper_common::{stable_device_id, tracing, DeviceCapabilities, DeviceInfo, DeviceKind, ErrorKind, LedEffect, WaveDirection, MAX_DPI, MIN_DPI};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::fs;
 use std::os::unix::io::{AsRawFd, RawFd};
-use tokio::sync::mpsc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, RwLock};
 use tracing::{info, warn, error, debug};
 use evdev::{Device as EvdevDevice, InputEventKind};
+use crate::audit::{AuditEvent, AuditLog};
 
 // EVIOCGRAB ioctl number for exclusive device access
 const EVIOCGRAB: u64 = 0x40044590;
 
+// Prefixes of the OpenRazer sysfs effect nodes for each lighting zone,
+// e.g. matrix_effect_static and logo_matrix_effect_static
+const LED_ZONE_PREFIXES: &[&str] = &["", "logo_", "scroll_", "backlight_"];
+
+// Speed of the reactive effect: 1 short, 2 medium, 3 long afterglow
+const LED_REACTIVE_SPEED: u8 = 2;
+
+/// Why a lighting effect couldn't be applied to a device
+#[derive(Debug, thiserror::Error)]
+pub enum LedError {
+    #[error("Device not found: {0}")]
+    DeviceNotFound(String),
+    #[error("Device {0} has no LED interface")]
+    NoLedInterface(String),
+    #[error("Device {device} does not support the {effect} effect")]
+    Unsupported { device: String, effect: &'static str },
+    #[error("failed to write LED effect: {0}")]
+    Write(#[from] std::io::Error),
+}
+
+impl LedError {
+    /// Error kind reported to IPC clients for this failure
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::DeviceNotFound(_) => ErrorKind::NotFound,
+            Self::NoLedInterface(_) | Self::Unsupported { .. } => ErrorKind::InvalidRequest,
+            Self::Write(_) => ErrorKind::Internal,
+        }
+    }
+}
+
+/// Why a device's battery couldn't be read
+#[derive(Debug, thiserror::Error)]
+pub enum BatteryError {
+    #[error("Device not found: {0}")]
+    DeviceNotFound(String),
+    #[error("Device {0} has no battery")]
+    NoBattery(String),
+    #[error("failed to read battery: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("unexpected battery reading '{0}'")]
+    Invalid(String),
+}
+
+impl BatteryError {
+    /// Error kind reported to IPC clients for this failure
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::DeviceNotFound(_) => ErrorKind::NotFound,
+            Self::NoBattery(_) => ErrorKind::InvalidRequest,
+            Self::Read(_) | Self::Invalid(_) => ErrorKind::Internal,
+        }
+    }
+}
+
+/// Why a mouse's DPI couldn't be read or set
+#[derive(Debug, thiserror::Error)]
+pub enum DpiError {
+    #[error("Device not found: {0}")]
+    DeviceNotFound(String),
+    #[error("Device {0} is not a mouse with adjustable DPI")]
+    NotSupported(String),
+    #[error("DPI {0} is outside the supported range of {MIN_DPI} to {MAX_DPI}")]
+    OutOfRange(u16),
+    #[error("failed to access DPI: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("unexpected DPI reading '{0}'")]
+    Invalid(String),
+}
+
+impl DpiError {
+    /// Error kind reported to IPC clients for this failure
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::DeviceNotFound(_) => ErrorKind::NotFound,
+            Self::NotSupported(_) | Self::OutOfRange(_) => ErrorKind::InvalidRequest,
+            Self::Io(_) | Self::Invalid(_) => ErrorKind::Internal,
+        }
+    }
+}
+
 /// Information about a grabbed device
 pub struct GrabbedDevice {
     pub info: DeviceInfo,
     pub evdev: EvdevDevice,
     pub fd: RawFd,
     pub grabbed: bool,
+    /// Key codes the event reader drops instead of forwarding
+    pub ignored_keys: Arc<std::sync::RwLock<HashSet<u16>>>,
+}
+
+/// State of a key reported by the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Released,
+    Pressed,
+    /// Autorepeat while the key is held down
+    Repeat,
+}
+
+impl KeyState {
+    /// Map an evdev key event value to a key state
+    pub fn from_value(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(KeyState::Released),
+            1 => Some(KeyState::Pressed),
+            2 => Some(KeyState::Repeat),
+            _ => None,
+        }
+    }
+}
+
+/// Counters for the input events read from a grabbed device
+///
+/// Shared between the device manager and the device's event reader thread.
+#[derive(Debug, Default)]
+pub struct EventStats {
+    events_seen: AtomicU64,
+    /// Milliseconds since the Unix epoch, zero until the first event
+    last_event_ms: AtomicU64,
+}
+
+impl EventStats {
+    /// Count one event read at the current time
+    pub fn record(&self) {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        self.events_seen.fetch_add(1, Ordering::Relaxed);
+        self.last_event_ms.store(now_ms, Ordering::Relaxed);
+    }
+
+    /// Number of events read so far
+    pub fn events_seen(&self) -> u64 {
+        self.events_seen.load(Ordering::Relaxed)
+    }
+
+    /// When the latest event was read, in milliseconds since the Unix epoch
+    pub fn last_event_ms(&self) -> Option<u64> {
+        Some(self.last_event_ms.load(Ordering::Relaxed)).filter(|&ms| ms > 0)
+    }
+}
+
+/// A change in the set of available input devices
+#[derive(Debug, Clone)]
+pub enum DeviceChange {
+    Added(DeviceInfo),
+    Removed(String),
+    /// A grabbed device stopped delivering events (usually unplugged)
+    GrabLost(String),
 }
 
 /// Manages the discovery and monitoring of input devices
 pub struct DeviceManager {
     devices: HashMap<String, DeviceInfo>,
     grabbed_devices: HashMap<String, GrabbedDevice>,
-    event_sender: mpsc::Sender<(String, u16, bool)>,
-    event_receiver: Option<mpsc::Receiver<(String, u16, bool)>>,
+    event_sender: mpsc::Sender<(String, u16, KeyState)>,
+    event_receiver: Option<mpsc::Receiver<(String, u16, KeyState)>>,
+    change_sender: mpsc::Sender<DeviceChange>,
+    change_receiver: Option<mpsc::Receiver<DeviceChange>>,
+    auto_regrab: bool,
+    lost_grabs: Vec<DeviceInfo>,
+    audit_log: Option<Arc<AuditLog>>,
+    event_stats: HashMap<String, Arc<EventStats>>,
+    /// Ignored key codes by device path, kept for devices that aren't grabbed
+    ignored_keys: HashMap<String, HashSet<u16>>,
 }
 
 impl DeviceManager {
     /// Create a new device manager
     pub fn new() -> Self {
         let (event_sender, event_receiver) = mpsc::channel(1000);
+        let (change_sender, change_receiver) = mpsc::channel(100);
         Self {
             devices: HashMap::new(),
             grabbed_devices: HashMap::new(),
             event_sender,
             event_receiver: Some(event_receiver),
+            change_sender,
+            change_receiver: Some(change_receiver),
+            auto_regrab: false,
+            lost_grabs: Vec::new(),
+            audit_log: None,
+            event_stats: HashMap::new(),
+            ignored_keys: HashMap::new(),
         }
     }
 
+    /// Re-grab devices lost to an error when they reappear
+    pub fn set_auto_regrab(&mut self, enabled: bool) {
+        self.auto_regrab = enabled;
+    }
+
     /// Start device discovery and monitoring
     pub async fn start_discovery(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         info!("Starting device discovery");
@@ -65,13 +235,170 @@ impl DeviceManager {
         self.devices.get(path).cloned()
     }
 
+    /// Whether the device is currently grabbed by the daemon
+    pub fn is_grabbed(&self, path: &str) -> bool {
+        self.grabbed_devices.contains_key(path)
+    }
+
+    /// Event counters for a device, kept across grabs until the device is removed
+    pub fn event_stats(&self, path: &str) -> Option<Arc<EventStats>> {
+        self.event_stats.get(path).cloned()
+    }
+
     /// Get event receiver for new device events
-    pub fn get_event_receiver(&mut self) -> mpsc::Receiver<(String, u16, bool)> {
+    pub fn get_event_receiver(&mut self) -> mpsc::Receiver<(String, u16, KeyState)> {
         self.event_receiver.take().expect("Event receiver already taken")
     }
 
+    /// Get receiver for device add/remove notifications
+    pub fn get_change_receiver(&mut self) -> mpsc::Receiver<DeviceChange> {
+        self.change_receiver.take().expect("Change receiver already taken")
+    }
+
+    /// Start watching /dev/input for devices being plugged in or removed
+    ///
+    /// The device list is rescanned every `interval`; changes update the
+    /// devices map and are reported on the change channel.
+    pub fn start_hotplug_monitor(manager: Arc<RwLock<Self>>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            info!("Starting hotplug monitor (interval: {:?})", interval);
+            loop {
+                tokio::time::sleep(interval).await;
+
+                let present = match list_event_nodes(Path::new("/dev/input")) {
+                    Ok(present) => present,
+                    Err(e) => {
+                        warn!("Failed to scan /dev/input: {}", e);
+                        continue;
+                    }
+                };
+
+                manager.write().await.reconcile_devices(&present).await;
+            }
+        })
+    }
+
+    /// Bring the devices map in line with the event nodes currently present
+    async fn reconcile_devices(&mut self, present: &HashSet<PathBuf>) {
+        let removed: Vec<String> = self.devices.iter()
+            .filter(|(_, device)| !present.contains(&device.path))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in removed {
+            self.remove_device(&path);
+        }
+
+        let known: HashSet<PathBuf> = self.devices.values().map(|d| d.path.clone()).collect();
+        for path in present.difference(&known) {
+            let device = match self.get_device_info(path).await {
+                Ok(device) => device,
+                Err(e) => {
+                    debug!("Ignoring new event node {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            let regrab = self.take_lost_grab(&device);
+            self.add_device(device);
+
+            if regrab {
+                let device_path = path.to_string_lossy().to_string();
+                info!("Lost device reappeared at {}, re-grabbing", device_path);
+                if let Err(e) = self.grab_device(&device_path).await {
+                    error!("Failed to re-grab {}: {}", device_path, e);
+                }
+            }
+        }
+    }
+
+    /// Forget a grabbed device whose event reader failed
+    ///
+    /// The fd is stale at this point, so the grab is dropped without
+    /// EVIOCGRAB. Returns false if the device was not grabbed.
+    pub fn release_lost_grab(&mut self, device_path: &str) -> bool {
+        let Some(grabbed) = self.grabbed_devices.remove(device_path) else {
+            return false;
+        };
+
+        warn!("Removed {} from grabbed devices after read error", device_path);
+        if self.auto_regrab {
+            self.lost_grabs.push(grabbed.info);
+        }
+        true
+    }
+
+    /// Check whether a new device is one we lost a grab on, forgetting it if so
+    fn take_lost_grab(&mut self, device: &DeviceInfo) -> bool {
+        let position = self.lost_grabs.iter().position(|lost| {
+            lost.vendor_id == device.vendor_id
+                && lost.product_id == device.product_id
+                && lost.phys == device.phys
+        });
+
+        match position {
+            Some(index) => {
+                self.lost_grabs.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Add a newly appeared device
+    pub fn add_device(&mut self, device: DeviceInfo) {
+        let path = device.path.to_string_lossy().to_string();
+        if self.devices.contains_key(&path) {
+            return;
+        }
+
+        info!("Device added: {} at {}", device.name, path);
+        self.devices.insert(path, device.clone());
+        self.notify_change(DeviceChange::Added(device));
+    }
+
+    /// Remove a device that has disappeared, dropping any grab held on it
+    pub fn remove_device(&mut self, device_path: &str) -> Option<DeviceInfo> {
+        let device = self.devices.remove(device_path)?;
+        info!("Device removed: {} at {}", device.name, device_path);
+        self.event_stats.remove(device_path);
+
+        if self.release_lost_grab(device_path) {
+            self.notify_change(DeviceChange::GrabLost(device_path.to_string()));
+        }
+
+        self.notify_change(DeviceChange::Removed(device_path.to_string()));
+        Some(device)
+    }
+
+    /// Report a device change without blocking if nobody is listening
+    fn notify_change(&self, change: DeviceChange) {
+        if let Err(e) = self.change_sender.try_send(change) {
+            debug!("Device change not delivered: {}", e);
+        }
+    }
+
+    /// Record every grab and ungrab in the given audit log
+    pub fn set_audit_log(&mut self, audit_log: Arc<AuditLog>) {
+        self.audit_log = Some(audit_log);
+    }
+
     /// Grab a device exclusively (EVIOCGRAB) for input interception
     pub async fn grab_device(&mut self, device_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // Stringify the error so the result can be held across the audit write
+        let result = self.try_grab_device(device_path).await.map_err(|e| e.to_string());
+
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(AuditEvent::DeviceGrabbed {
+                device_path: device_path.to_string(),
+                success: result.is_ok(),
+                error: result.as_ref().err().cloned(),
+            }).await;
+        }
+
+        result.map_err(Into::into)
+    }
+
+    async fn try_grab_device(&mut self, device_path: &str) -> Result<(), Box<dyn std::error::Error>> {
         if self.grabbed_devices.contains_key(device_path) {
             info!("Device {} already grabbed", device_path);
             return Ok(());
@@ -96,8 +423,10 @@ impl DeviceManager {
 
         if result < 0 {
             let err = std::io::Error::last_os_error();
-            error!("Failed to grab device {}: {}", device_path, err);
-            return Err(format!("EVIOCGRAB failed: {}", err).into());
+            debug!("EVIOCGRAB on {} failed: {:?}", device_path, err);
+            let message = grab_error_message(device_path, &err);
+            error!("{}", message);
+            return Err(message.into());
         }
 
         info!("Successfully grabbed device {} (fd={})", device_path, fd);
@@ -108,6 +437,9 @@ impl DeviceManager {
             evdev,
             fd,
             grabbed: true,
+            ignored_keys: Arc::new(std::sync::RwLock::new(
+                self.ignored_keys.get(device_path).cloned().unwrap_or_default(),
+            )),
         });
 
         // Start event reading loop for this device
@@ -119,26 +451,220 @@ impl DeviceManager {
     /// Ungrab a device (release exclusive access)
     pub async fn ungrab_device(&mut self, device_path: &str) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(grabbed) = self.grabbed_devices.remove(device_path) {
-            info!("Ungrabbing device: {}", device_path);
+            info!("Ungrabbing device on request: {}", device_path);
 
             // Release the grab
             let result = unsafe {
                 libc::ioctl(grabbed.fd, EVIOCGRAB, 0 as libc::c_int)
             };
 
-            if result < 0 {
-                warn!("Failed to ungrab device {}: {}", device_path, std::io::Error::last_os_error());
+            let error = if result < 0 {
+                let error = std::io::Error::last_os_error();
+                warn!("Failed to ungrab device {}: {}", device_path, error);
+                Some(error.to_string())
             } else {
                 info!("Successfully ungrabbed device {}", device_path);
+                None
+            };
+
+            if let Some(audit_log) = &self.audit_log {
+                audit_log.record(AuditEvent::DeviceUngrabbed {
+                    device_path: device_path.to_string(),
+                    success: error.is_none(),
+                    error,
+                }).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace the key codes dropped from a device's input
+    ///
+    /// Takes effect at once on a grabbed device and is kept for later grabs.
+    pub fn set_ignored_keys(&mut self, device_path: &str, keys: Vec<u16>) -> Result<(), String> {
+        if !self.devices.contains_key(device_path) {
+            return Err(format!("Device not found: {}", device_path));
+        }
+        let keys: HashSet<u16> = keys.into_iter().collect();
+        info!("Ignoring {} key codes from {}", keys.len(), device_path);
+
+        if let Some(grabbed) = self.grabbed_devices.get(device_path) {
+            *grabbed.ignored_keys.write().unwrap() = keys.clone();
+        }
+        if keys.is_empty() {
+            self.ignored_keys.remove(device_path);
+        } else {
+            self.ignored_keys.insert(device_path.to_string(), keys);
+        }
+        Ok(())
+    }
+
+    /// Key codes currently dropped from a device's input
+    pub fn ignored_keys(&self, device_path: &str) -> HashSet<u16> {
+        self.ignored_keys.get(device_path).cloned().unwrap_or_default()
+    }
+
+    /// Push a synthetic key event onto the channel the device readers feed
+    ///
+    /// The event is dispatched like one read from a grabbed device, so tests
+    /// can drive macro triggers without /dev/input access. Keys the device
+    /// ignores are dropped, as the reader would.
+    #[cfg(any(test, feature = "testing"))]
+    pub async fn inject_test_event(&self, device_path: &str, key_code: u16, pressed: bool) -> Result<(), String> {
+        let ignored = std::sync::RwLock::new(self.ignored_keys(device_path));
+        let key_state = if pressed { KeyState::Pressed } else { KeyState::Released };
+        forward_key_event(&self.event_sender, &ignored, device_path, key_code, key_state).await
+            .map_err(|_| "Device event receiver is gone".to_string())
+    }
+
+    /// Grab every discovered device that isn't grabbed yet
+    ///
+    /// Returns each device path, sorted, with whether it is now grabbed. A
+    /// failed grab is logged and doesn't stop the rest.
+    pub async fn grab_all(&mut self) -> Vec<(String, bool)> {
+        let mut device_paths: Vec<String> = self.devices.keys()
+            .filter(|path| !self.grabbed_devices.contains_key(*path))
+            .cloned()
+            .collect();
+        device_paths.sort();
+
+        let mut results = Vec::with_capacity(device_paths.len());
+        for path in device_paths {
+            let grabbed = match self.grab_device(&path).await {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Skipping device {}: {}", path, e);
+                    false
+                }
+            };
+            results.push((path, grabbed));
+        }
+        results
+    }
+
+    /// Release every grabbed device, returning each path with whether it was released
+    pub async fn ungrab_all(&mut self) -> Vec<(String, bool)> {
+        let mut device_paths: Vec<String> = self.grabbed_devices.keys().cloned().collect();
+        device_paths.sort();
+
+        let mut results = Vec::with_capacity(device_paths.len());
+        for path in device_paths {
+            let released = match self.ungrab_device(&path).await {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Error ungrabbing device {}: {}", path, e);
+                    false
+                }
+            };
+            results.push((path, released));
+        }
+        results
+    }
+
+    /// Set a static LED color on a device through its OpenRazer sysfs interface
+    pub fn set_led_color(&self, device_path: &str, color: (u8, u8, u8)) -> Result<(), LedError> {
+        self.set_led_effect(device_path, LedEffect::Static(color.0, color.1, color.2))
+    }
+
+    /// Start a lighting effect on every zone of a device that supports it
+    pub fn set_led_effect(&self, device_path: &str, effect: LedEffect) -> Result<(), LedError> {
+        let device_info = self.devices.get(device_path)
+            .ok_or_else(|| LedError::DeviceNotFound(device_path.to_string()))?;
+
+        let sysfs_path = Self::resolve_sysfs_path(device_info)
+            .filter(|path| has_led_interface(path))
+            .ok_or_else(|| LedError::NoLedInterface(device_path.to_string()))?;
+
+        let written = write_led_effect(&sysfs_path, effect)?;
+        if written == 0 {
+            return Err(LedError::Unsupported { device: device_path.to_string(), effect: effect.name() });
+        }
+
+        info!("Set LED effect {:?} on {} ({} zones)", effect, device_path, written);
+        Ok(())
+    }
+
+    /// Charge of a wireless device in percent and whether it is charging
+    ///
+    /// Read from the OpenRazer `charge_level` and `charge_status` nodes;
+    /// wired devices have neither.
+    pub fn battery_level(&self, device_path: &str) -> Result<(u8, bool), BatteryError> {
+        let device_info = self.devices.get(device_path)
+            .ok_or_else(|| BatteryError::DeviceNotFound(device_path.to_string()))?;
+
+        let sysfs_path = Self::resolve_sysfs_path(device_info)
+            .filter(|path| path.join("charge_level").exists())
+            .ok_or_else(|| BatteryError::NoBattery(device_path.to_string()))?;
+
+        read_battery(&sysfs_path)
+    }
+
+    /// Sensor resolution of a mouse along X and Y
+    pub fn dpi(&self, device_path: &str) -> Result<(u16, u16), DpiError> {
+        let dpi_path = self.dpi_node(device_path)?;
+        let contents = fs::read_to_string(&dpi_path)?;
+        parse_dpi(&contents).ok_or_else(|| DpiError::Invalid(contents.trim().to_string()))
+    }
+
+    /// Set a mouse's sensor resolution along X and Y
+    pub fn set_dpi(&self, device_path: &str, (x, y): (u16, u16)) -> Result<(), DpiError> {
+        for value in [x, y] {
+            if !(MIN_DPI..=MAX_DPI).contains(&value) {
+                return Err(DpiError::OutOfRange(value));
             }
         }
+        let dpi_path = self.dpi_node(device_path)?;
 
+        // The driver takes both axes as big-endian 16-bit values
+        let [x_hi, x_lo] = x.to_be_bytes();
+        let [y_hi, y_lo] = y.to_be_bytes();
+        fs::write(&dpi_path, [x_hi, x_lo, y_hi, y_lo])?;
+
+        info!("Set DPI {}x{} on {}", x, y, device_path);
         Ok(())
     }
 
+    /// The OpenRazer `dpi` node of a device, if it is a mouse that has one
+    fn dpi_node(&self, device_path: &str) -> Result<PathBuf, DpiError> {
+        let device_info = self.devices.get(device_path)
+            .ok_or_else(|| DpiError::DeviceNotFound(device_path.to_string()))?;
+
+        // Devices found through the OpenRazer scan report no capabilities
+        // and are let through on the strength of their dpi node alone
+        if device_info.capabilities.kind() == DeviceKind::Keyboard {
+            return Err(DpiError::NotSupported(device_path.to_string()));
+        }
+
+        Self::resolve_sysfs_path(device_info)
+            .map(|path| path.join("dpi"))
+            .filter(|path| path.exists())
+            .ok_or_else(|| DpiError::NotSupported(device_path.to_string()))
+    }
+
+    /// Resolve the sysfs directory of a device's HID interface
+    ///
+    /// Devices found through the OpenRazer scan store their sysfs directory in
+    /// `phys`; for others fall back to the parent HID device of the event node.
+    fn resolve_sysfs_path(device_info: &DeviceInfo) -> Option<PathBuf> {
+        let phys = Path::new(&device_info.phys);
+        if phys.is_absolute() && phys.is_dir() {
+            return Some(phys.to_path_buf());
+        }
+
+        let event_name = device_info.path.file_name()?;
+        let hid_path = Path::new("/sys/class/input").join(event_name).join("device/device");
+        fs::canonicalize(hid_path).ok()
+    }
+
     /// Start reading events from a grabbed device
-    async fn start_event_reader(&self, device_path: String) -> Result<(), Box<dyn std::error::Error>> {
+    async fn start_event_reader(&mut self, device_path: String) -> Result<(), Box<dyn std::error::Error>> {
         let sender = self.event_sender.clone();
+        let change_sender = self.change_sender.clone();
+        let stats = Arc::clone(self.event_stats.entry(device_path.clone()).or_default());
+        let ignored_keys = self.grabbed_devices.get(&device_path)
+            .map(|grabbed| Arc::clone(&grabbed.ignored_keys))
+            .unwrap_or_default();
 
         // Clone the path for the async task
         let path = device_path.clone();
@@ -164,17 +690,22 @@ impl DeviceManager {
                 match device.fetch_events() {
                     Ok(events) => {
                         for event in events {
+                            // Count everything but the SYN markers between event batches
+                            if !matches!(event.kind(), InputEventKind::Synchronization(_)) {
+                                stats.record();
+                            }
+
                             // Only process key events
                             if let InputEventKind::Key(key) = event.kind() {
                                 let key_code = key.0;
-                                let pressed = event.value() == 1; // 1 = pressed, 0 = released
+                                let Some(key_state) = KeyState::from_value(event.value()) else {
+                                    continue;
+                                };
 
-                                debug!("Event from {}: key={}, pressed={}", path, key_code, pressed);
+                                debug!("Event from {}: key={}, state={:?}", path, key_code, key_state);
 
                                 // Send event to macro engine using blocking send
-                                let sender_clone = sender.clone();
-                                let path_clone = path.clone();
-                                if let Err(e) = rt.block_on(sender_clone.send((path_clone, key_code, pressed))) {
+                                if let Err(e) = rt.block_on(forward_key_event(&sender, &ignored_keys, &path, key_code, key_state)) {
                                     error!("Failed to send event: {}", e);
                                     return;
                                 }
@@ -183,6 +714,11 @@ impl DeviceManager {
                     }
                     Err(e) => {
                         error!("Error reading event from {}: {}", path, e);
+
+                        // Let the daemon drop the stale grab
+                        if let Err(e) = rt.block_on(change_sender.send(DeviceChange::GrabLost(path.clone()))) {
+                            error!("Failed to report lost grab for {}: {}", path, e);
+                        }
                         break;
                     }
                 }
@@ -248,7 +784,9 @@ impl DeviceManager {
             path: path.clone(),
             vendor_id: input_id.vendor(),
             product_id: input_id.product(),
+            stable_id: stable_device_id(input_id.vendor(), input_id.product(), &phys),
             phys,
+            capabilities: device_capabilities(&device),
         })
     }
 
@@ -318,12 +856,15 @@ impl DeviceManager {
         let event_path = self.find_event_device_for_sysfs(sysfs_path).await
             .unwrap_or_else(|| PathBuf::from("/dev/input/event0"));
 
+        let phys = sysfs_path.to_string_lossy().to_string();
         Ok(DeviceInfo {
             name: device_type,
             path: event_path,
             vendor_id,
             product_id,
-            phys: sysfs_path.to_string_lossy().to_string(),
+            stable_id: stable_device_id(vendor_id, product_id, &phys),
+            phys,
+            capabilities: DeviceCapabilities::default(),
         })
     }
 
@@ -370,6 +911,8 @@ impl DeviceManager {
             vendor_id: 0x1532,
             product_id: 0x0220,
             phys: "fallback-device".to_string(),
+            capabilities: DeviceCapabilities::default(),
+            stable_id: stable_device_id(0x1532, 0x0220, "fallback-device"),
         }
     }
 
@@ -377,19 +920,144 @@ impl DeviceManager {
     pub async fn shutdown(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         info!("Shutting down device manager");
 
-        // Ungrab all devices
-        let device_paths: Vec<String> = self.grabbed_devices.keys().cloned().collect();
-        for path in device_paths {
-            if let Err(e) = self.ungrab_device(&path).await {
-                warn!("Error ungrabbing device {}: {}", path, e);
-            }
-        }
+        // Whether grabbed one at a time or all at once, every grab is released
+        self.ungrab_all().await;
 
         info!("Device manager shutdown complete");
         Ok(())
     }
 }
 
+/// Read which key, relative axis and LED events a device reports
+fn device_capabilities(device: &EvdevDevice) -> DeviceCapabilities {
+    let key_count = device.supported_keys().map_or(0, |keys| keys.iter().count());
+    DeviceCapabilities {
+        has_keys: key_count > 0,
+        has_rel: device.supported_relative_axes().is_some_and(|axes| axes.iter().next().is_some()),
+        has_leds: device.supported_leds().is_some_and(|leds| leds.iter().next().is_some()),
+        key_count,
+    }
+}
+
+/// Pass a key event on to the macro engine unless the device ignores its key code
+async fn forward_key_event(
+    sender: &mpsc::Sender<(String, u16, KeyState)>,
+    ignored_keys: &std::sync::RwLock<HashSet<u16>>,
+    device_path: &str,
+    key_code: u16,
+    key_state: KeyState,
+) -> Result<(), mpsc::error::SendError<(String, u16, KeyState)>> {
+    if ignored_keys.read().unwrap().contains(&key_code) {
+        debug!("Dropping ignored key {} from {}", key_code, device_path);
+        return Ok(());
+    }
+    sender.send((device_path.to_string(), key_code, key_state)).await
+}
+
+/// Explain why EVIOCGRAB failed, naming the likely culprit for the common errors
+fn grab_error_message(device_path: &str, err: &std::io::Error) -> String {
+    match err.raw_os_error() {
+        Some(libc::EBUSY) => format!(
+            "Device {} is already grabbed by another application; \
+             check for other input remapping tools or the OpenRazer daemon",
+            device_path
+        ),
+        Some(libc::EACCES) | Some(libc::EPERM) => format!(
+            "Permission denied grabbing device {}; the daemon needs access to the input devices",
+            device_path
+        ),
+        _ => format!("Failed to grab device {}: {}", device_path, err),
+    }
+}
+
+/// List the event* nodes in an input device directory
+fn list_event_nodes(dir: &Path) -> Result<HashSet<PathBuf>, std::io::Error> {
+    let mut nodes = HashSet::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.file_name().and_then(|s| s.to_str()).is_some_and(|name| name.starts_with("event")) {
+            nodes.insert(path);
+        }
+    }
+    Ok(nodes)
+}
+
+/// Write an RGB color to every static LED effect node under a sysfs directory
+///
+/// Returns the number of nodes written, which is zero when the device exposes
+/// no LED interface.
+fn write_led_effect(sysfs_path: &Path, effect: LedEffect) -> Result<usize, std::io::Error> {
+    let (node, payload) = led_effect_payload(effect);
+    let mut written = 0;
+    for prefix in LED_ZONE_PREFIXES {
+        let node_path = sysfs_path.join(format!("{}{}", prefix, node));
+        if node_path.exists() {
+            fs::write(&node_path, &payload)?;
+            debug!("Wrote LED effect to {}", node_path.display());
+            written += 1;
+        }
+    }
+    Ok(written)
+}
+
+/// The OpenRazer effect node, without its zone prefix, and the bytes it takes
+fn led_effect_payload(effect: LedEffect) -> (&'static str, Vec<u8>) {
+    match effect {
+        LedEffect::Static(r, g, b) => ("matrix_effect_static", vec![r, g, b]),
+        LedEffect::Breathing { color: (r, g, b) } => ("matrix_effect_breath", vec![r, g, b]),
+        // Any write starts the spectrum cycle
+        LedEffect::Spectrum => ("matrix_effect_spectrum", vec![1]),
+        // The wave direction is parsed as an ASCII number
+        LedEffect::Wave { direction: WaveDirection::Left } => ("matrix_effect_wave", b"1".to_vec()),
+        LedEffect::Wave { direction: WaveDirection::Right } => ("matrix_effect_wave", b"2".to_vec()),
+        LedEffect::Reactive { color: (r, g, b) } => ("matrix_effect_reactive", vec![LED_REACTIVE_SPEED, r, g, b]),
+    }
+}
+
+/// Read OpenRazer's battery nodes as a percentage and a charging flag
+///
+/// `charge_level` runs from 0 to 255, not 0 to 100. A missing
+/// `charge_status` counts as not charging.
+fn read_battery(sysfs_path: &Path) -> Result<(u8, bool), BatteryError> {
+    let level = read_sysfs_number(&sysfs_path.join("charge_level"))?;
+    if level > 255 {
+        return Err(BatteryError::Invalid(level.to_string()));
+    }
+    let percent = ((level * 100 + 127) / 255) as u8;
+
+    let charging = match read_sysfs_number(&sysfs_path.join("charge_status")) {
+        Ok(status) => status != 0,
+        Err(BatteryError::Read(e)) if e.kind() == std::io::ErrorKind::NotFound => false,
+        Err(e) => return Err(e),
+    };
+    Ok((percent, charging))
+}
+
+/// Parse OpenRazer's "x:y" DPI reading; older mice report a single value for both axes
+fn parse_dpi(contents: &str) -> Option<(u16, u16)> {
+    let contents = contents.trim();
+    match contents.split_once(':') {
+        Some((x, y)) => Some((x.trim().parse().ok()?, y.trim().parse().ok()?)),
+        None => {
+            let dpi = contents.parse().ok()?;
+            Some((dpi, dpi))
+        }
+    }
+}
+
+/// Read a sysfs node holding a single decimal number
+fn read_sysfs_number(path: &Path) -> Result<u32, BatteryError> {
+    let contents = fs::read_to_string(path)?;
+    contents.trim().parse().map_err(|_| BatteryError::Invalid(contents.trim().to_string()))
+}
+
+/// Whether the sysfs directory has any OpenRazer lighting effect node
+fn has_led_interface(sysfs_path: &Path) -> bool {
+    fs::read_dir(sysfs_path).map(|entries| {
+        entries.flatten().any(|entry| entry.file_name().to_string_lossy().contains("matrix_effect_"))
+    }).unwrap_or(false)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -401,6 +1069,38 @@ mod tests {
         assert!(manager.grabbed_devices.is_empty());
     }
 
+    #[tokio::test]
+    async fn test_injected_event_triggers_macro() {
+        use crate::macro_engine::MacroEngine;
+        use crate::testing::{InjectedAction, RecordingInjector};
+        use razermapper_common::{Action, KeyCombo, MacroEntry, TriggerEdge};
+
+        let recorder = RecordingInjector::new();
+        let engine = MacroEngine::with_injector(recorder.shared());
+        engine.add_macro(MacroEntry {
+            name: "Injected".to_string(),
+            trigger: KeyCombo { keys: vec![30], modifiers: vec![] },
+            actions: vec![Action::KeyPress(48)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        }).await.unwrap();
+
+        let mut manager = DeviceManager::new();
+        let mut state = crate::DaemonState::new();
+        state.macro_engine = Some(Arc::new(engine));
+        crate::start_event_dispatch(Arc::new(RwLock::new(state)), manager.get_event_receiver());
+
+        manager.inject_test_event("/dev/input/event0", 30, true).await.unwrap();
+        manager.inject_test_event("/dev/input/event0", 30, false).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(recorder.recorded(), vec![InjectedAction::KeyPress(48)]);
+    }
+
     #[tokio::test]
     async fn test_device_discovery() {
         let mut manager = DeviceManager::new();
@@ -417,4 +1117,293 @@ mod tests {
             println!("Found {} devices", manager.get_devices().len());
         }
     }
+
+    #[test]
+    fn test_set_led_color() {
+        let sysfs = tempfile::TempDir::new().unwrap();
+        fs::write(sysfs.path().join("matrix_effect_static"), []).unwrap();
+        fs::write(sysfs.path().join("logo_matrix_effect_static"), []).unwrap();
+
+        let mut manager = DeviceManager::new();
+        manager.devices.insert("/dev/input/event5".to_string(), DeviceInfo {
+            name: "Razer Keyboard".to_string(),
+            path: PathBuf::from("/dev/input/event5"),
+            vendor_id: 0x1532,
+            product_id: 0x0221,
+            phys: sysfs.path().to_string_lossy().to_string(),
+            capabilities: DeviceCapabilities::default(),
+            stable_id: String::new(),
+        });
+
+        manager.set_led_color("/dev/input/event5", (255, 128, 0)).unwrap();
+        assert_eq!(fs::read(sysfs.path().join("matrix_effect_static")).unwrap(), vec![255, 128, 0]);
+        assert_eq!(fs::read(sysfs.path().join("logo_matrix_effect_static")).unwrap(), vec![255, 128, 0]);
+
+        // Unknown devices and devices without LED nodes are errors
+        assert!(manager.set_led_color("/dev/input/event9", (0, 0, 0)).is_err());
+        fs::remove_file(sysfs.path().join("matrix_effect_static")).unwrap();
+        fs::remove_file(sysfs.path().join("logo_matrix_effect_static")).unwrap();
+        assert!(manager.set_led_color("/dev/input/event5", (0, 0, 0)).is_err());
+    }
+
+    #[test]
+    fn test_set_led_effect() {
+        let sysfs = tempfile::TempDir::new().unwrap();
+        for node in ["matrix_effect_breath", "logo_matrix_effect_breath", "matrix_effect_wave", "matrix_effect_spectrum"] {
+            fs::write(sysfs.path().join(node), []).unwrap();
+        }
+
+        let mut manager = DeviceManager::new();
+        manager.devices.insert("/dev/input/event5".to_string(), DeviceInfo {
+            name: "Razer Keyboard".to_string(),
+            path: PathBuf::from("/dev/input/event5"),
+            vendor_id: 0x1532,
+            product_id: 0x0221,
+            phys: sysfs.path().to_string_lossy().to_string(),
+            capabilities: DeviceCapabilities::default(),
+            stable_id: String::new(),
+        });
+
+        manager.set_led_effect("/dev/input/event5", LedEffect::Breathing { color: (0, 255, 0) }).unwrap();
+        assert_eq!(fs::read(sysfs.path().join("matrix_effect_breath")).unwrap(), vec![0, 255, 0]);
+        assert_eq!(fs::read(sysfs.path().join("logo_matrix_effect_breath")).unwrap(), vec![0, 255, 0]);
+
+        manager.set_led_effect("/dev/input/event5", LedEffect::Wave { direction: WaveDirection::Right }).unwrap();
+        assert_eq!(fs::read(sysfs.path().join("matrix_effect_wave")).unwrap(), b"2");
+
+        // Effects the device has no node for are refused by name
+        let err = manager.set_led_effect("/dev/input/event5", LedEffect::Reactive { color: (255, 0, 0) }).unwrap_err();
+        assert!(matches!(err, LedError::Unsupported { effect: "reactive", .. }));
+        assert_eq!(err.kind(), ErrorKind::InvalidRequest);
+
+        // Without any effect node the device has no LEDs at all
+        for node in ["matrix_effect_breath", "logo_matrix_effect_breath", "matrix_effect_wave", "matrix_effect_spectrum"] {
+            fs::remove_file(sysfs.path().join(node)).unwrap();
+        }
+        let err = manager.set_led_effect("/dev/input/event5", LedEffect::Spectrum).unwrap_err();
+        assert!(matches!(err, LedError::NoLedInterface(_)));
+        assert!(matches!(manager.set_led_effect("/dev/input/event9", LedEffect::Spectrum), Err(LedError::DeviceNotFound(_))));
+    }
+
+    #[test]
+    fn test_battery_level() {
+        let sysfs = tempfile::TempDir::new().unwrap();
+        let mut manager = DeviceManager::new();
+        manager.devices.insert("/dev/input/event6".to_string(), DeviceInfo {
+            name: "Razer Viper Ultimate".to_string(),
+            path: PathBuf::from("/dev/input/event6"),
+            vendor_id: 0x1532,
+            product_id: 0x007a,
+            phys: sysfs.path().to_string_lossy().to_string(),
+            capabilities: DeviceCapabilities::default(),
+            stable_id: String::new(),
+        });
+
+        // Wired devices have no charge nodes
+        let err = manager.battery_level("/dev/input/event6").unwrap_err();
+        assert!(matches!(err, BatteryError::NoBattery(_)));
+        assert_eq!(err.kind(), ErrorKind::InvalidRequest);
+
+        // charge_level is scaled from 0-255, and a missing status is not charging
+        fs::write(sysfs.path().join("charge_level"), "255\n").unwrap();
+        assert_eq!(manager.battery_level("/dev/input/event6").unwrap(), (100, false));
+        fs::write(sysfs.path().join("charge_level"), "128\n").unwrap();
+        fs::write(sysfs.path().join("charge_status"), "1\n").unwrap();
+        assert_eq!(manager.battery_level("/dev/input/event6").unwrap(), (50, true));
+        fs::write(sysfs.path().join("charge_level"), "0\n").unwrap();
+        fs::write(sysfs.path().join("charge_status"), "0\n").unwrap();
+        assert_eq!(manager.battery_level("/dev/input/event6").unwrap(), (0, false));
+
+        fs::write(sysfs.path().join("charge_level"), "300\n").unwrap();
+        assert!(matches!(manager.battery_level("/dev/input/event6"), Err(BatteryError::Invalid(_))));
+        assert!(matches!(manager.battery_level("/dev/input/event9"), Err(BatteryError::DeviceNotFound(_))));
+    }
+
+    #[test]
+    fn test_dpi() {
+        let sysfs = tempfile::TempDir::new().unwrap();
+        let mut manager = DeviceManager::new();
+        let device = |path: &str, mouse: bool| DeviceInfo {
+            name: "Razer Device".to_string(),
+            path: PathBuf::from(path),
+            vendor_id: 0x1532,
+            product_id: 0x0084,
+            phys: sysfs.path().to_string_lossy().to_string(),
+            capabilities: DeviceCapabilities { has_keys: true, has_rel: mouse, has_leds: false, key_count: if mouse { 5 } else { 100 } },
+            stable_id: String::new(),
+        };
+        manager.devices.insert("/dev/input/event7".to_string(), device("/dev/input/event7", true));
+        manager.devices.insert("/dev/input/event8".to_string(), device("/dev/input/event8", false));
+
+        // No dpi node means no adjustable DPI
+        assert!(matches!(manager.dpi("/dev/input/event7"), Err(DpiError::NotSupported(_))));
+
+        fs::write(sysfs.path().join("dpi"), "1800:1600\n").unwrap();
+        assert_eq!(manager.dpi("/dev/input/event7").unwrap(), (1800, 1600));
+        fs::write(sysfs.path().join("dpi"), "800\n").unwrap();
+        assert_eq!(manager.dpi("/dev/input/event7").unwrap(), (800, 800));
+
+        manager.set_dpi("/dev/input/event7", (3200, 400)).unwrap();
+        assert_eq!(fs::read(sysfs.path().join("dpi")).unwrap(), vec![0x0C, 0x80, 0x01, 0x90]);
+
+        let err = manager.set_dpi("/dev/input/event7", (50, 400)).unwrap_err();
+        assert!(matches!(err, DpiError::OutOfRange(50)));
+        assert_eq!(err.kind(), ErrorKind::InvalidRequest);
+
+        // Keyboards share the sysfs directory here but have no sensor
+        let err = manager.set_dpi("/dev/input/event8", (800, 800)).unwrap_err();
+        assert!(err.to_string().contains("not a mouse"));
+        assert!(matches!(manager.dpi("/dev/input/event9"), Err(DpiError::DeviceNotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_hotplug_add_remove() {
+        let mut manager = DeviceManager::new();
+        let mut changes = manager.get_change_receiver();
+
+        let device = DeviceInfo {
+            name: "Hotplugged Keyboard".to_string(),
+            path: PathBuf::from("/dev/input/event42"),
+            vendor_id: 0x1532,
+            product_id: 0x0221,
+            phys: "usb-0000:00:14.0-2/input0".to_string(),
+            capabilities: DeviceCapabilities::default(),
+            stable_id: String::new(),
+        };
+
+        manager.add_device(device.clone());
+        manager.add_device(device);
+        assert_eq!(manager.get_devices().len(), 1);
+        assert!(matches!(changes.try_recv(), Ok(DeviceChange::Added(d)) if d.name == "Hotplugged Keyboard"));
+        assert!(changes.try_recv().is_err());
+
+        // A rescan that no longer sees the node removes the device
+        manager.reconcile_devices(&HashSet::new()).await;
+        assert!(manager.get_device("/dev/input/event42").is_none());
+        assert!(matches!(changes.try_recv(), Ok(DeviceChange::Removed(p)) if p == "/dev/input/event42"));
+
+        assert!(manager.remove_device("/dev/input/event42").is_none());
+    }
+
+    #[test]
+    fn test_event_stats() {
+        let mut manager = DeviceManager::new();
+        manager.add_device(DeviceInfo {
+            name: "Razer Naga".to_string(),
+            path: PathBuf::from("/dev/input/event5"),
+            vendor_id: 0x1532,
+            product_id: 0x0067,
+            phys: String::new(),
+            capabilities: DeviceCapabilities::default(),
+            stable_id: String::new(),
+        });
+        assert!(manager.event_stats("/dev/input/event5").is_none());
+        assert!(!manager.is_grabbed("/dev/input/event5"));
+
+        // The event reader shares the counters with the manager
+        let stats = Arc::clone(manager.event_stats.entry("/dev/input/event5".to_string()).or_default());
+        assert_eq!(stats.events_seen(), 0);
+        assert_eq!(stats.last_event_ms(), None);
+        stats.record();
+        stats.record();
+
+        let seen = manager.event_stats("/dev/input/event5").unwrap();
+        assert_eq!(seen.events_seen(), 2);
+        assert!(seen.last_event_ms().unwrap() > 0);
+
+        manager.remove_device("/dev/input/event5");
+        assert!(manager.event_stats("/dev/input/event5").is_none());
+    }
+
+    #[test]
+    fn test_list_event_nodes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::write(dir.path().join("event0"), []).unwrap();
+        fs::write(dir.path().join("event7"), []).unwrap();
+        fs::write(dir.path().join("mouse0"), []).unwrap();
+
+        let nodes = list_event_nodes(dir.path()).unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert!(nodes.contains(&dir.path().join("event7")));
+    }
+
+    #[test]
+    fn test_lost_grab_matching() {
+        let mut manager = DeviceManager::new();
+        manager.set_auto_regrab(true);
+
+        let lost = DeviceInfo {
+            name: "Razer Keyboard".to_string(),
+            path: PathBuf::from("/dev/input/event5"),
+            vendor_id: 0x1532,
+            product_id: 0x0221,
+            phys: "usb-0000:00:14.0-1/input0".to_string(),
+            capabilities: DeviceCapabilities::default(),
+            stable_id: String::new(),
+        };
+        manager.lost_grabs.push(lost.clone());
+
+        // Nothing was grabbed, so there is nothing to release
+        assert!(!manager.release_lost_grab("/dev/input/event5"));
+
+        // A different device does not match
+        let other = DeviceInfo { phys: "usb-0000:00:14.0-2/input0".to_string(), ..lost.clone() };
+        assert!(!manager.take_lost_grab(&other));
+
+        // The same device at a new event node matches once
+        let returned = DeviceInfo { path: PathBuf::from("/dev/input/event9"), ..lost };
+        assert!(manager.take_lost_grab(&returned));
+        assert!(!manager.take_lost_grab(&returned));
+    }
+
+    #[test]
+    fn test_key_state_from_value() {
+        assert_eq!(KeyState::from_value(0), Some(KeyState::Released));
+        assert_eq!(KeyState::from_value(1), Some(KeyState::Pressed));
+        assert_eq!(KeyState::from_value(2), Some(KeyState::Repeat));
+        assert_eq!(KeyState::from_value(3), None);
+    }
+
+    #[tokio::test]
+    async fn test_ignored_keys_are_not_forwarded() {
+        let (sender, mut receiver) = mpsc::channel(10);
+        let ignored_keys = std::sync::RwLock::new(HashSet::from([464]));
+
+        forward_key_event(&sender, &ignored_keys, "/dev/input/event3", 464, KeyState::Pressed).await.unwrap();
+        forward_key_event(&sender, &ignored_keys, "/dev/input/event3", 30, KeyState::Pressed).await.unwrap();
+        forward_key_event(&sender, &ignored_keys, "/dev/input/event3", 464, KeyState::Released).await.unwrap();
+
+        assert_eq!(receiver.try_recv().unwrap(), ("/dev/input/event3".to_string(), 30, KeyState::Pressed));
+        assert!(receiver.try_recv().is_err());
+
+        // The list is kept per device, and only for devices that exist
+        let mut manager = DeviceManager::new();
+        assert!(manager.set_ignored_keys("/dev/input/event3", vec![464]).is_err());
+        manager.add_device(DeviceInfo {
+            name: "Razer Keyboard".to_string(),
+            path: PathBuf::from("/dev/input/event3"),
+            vendor_id: 0x1532,
+            product_id: 0x0221,
+            phys: String::new(),
+            capabilities: DeviceCapabilities::default(),
+            stable_id: String::new(),
+        });
+        manager.set_ignored_keys("/dev/input/event3", vec![464, 464]).unwrap();
+        assert_eq!(manager.ignored_keys("/dev/input/event3"), HashSet::from([464]));
+        manager.set_ignored_keys("/dev/input/event3", vec![]).unwrap();
+        assert!(manager.ignored_keys("/dev/input/event3").is_empty());
+    }
+
+    #[test]
+    fn test_grab_error_message() {
+        let busy = grab_error_message("/dev/input/event4", &std::io::Error::from_raw_os_error(libc::EBUSY));
+        assert!(busy.contains("already grabbed by another application"), "{}", busy);
+        assert!(busy.contains("/dev/input/event4"));
+
+        let denied = grab_error_message("/dev/input/event4", &std::io::Error::from_raw_os_error(libc::EACCES));
+        assert!(denied.starts_with("Permission denied"), "{}", denied);
+
+        let other = grab_error_message("/dev/input/event4", &std::io::Error::from_raw_os_error(libc::ENODEV));
+        assert!(other.starts_with("Failed to grab device /dev/input/event4: "), "{}", other);
+    }
 }
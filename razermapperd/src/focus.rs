@@ -0,0 +1,183 @@
+//! Automatic profile switching based on the focused window
+//!
+//! A background task runs the configured focus command every poll interval and
+//! matches its output against the `profile_rules` patterns. When the focused
+//! window starts matching a rule, that rule's profile is loaded into the macro
+//! engine; when it stops matching any rule, the daemon's own macros come back.
+
+use razermapper_common::tracing;
+use regex::Regex;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+use crate::config::{ConfigManager, ProfileRule, ProfileRulesSettings};
+use crate::macro_engine::MacroEngine;
+use crate::DaemonState;
+
+/// Longest the focus command may take before it's abandoned
+const FOCUS_COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What to do with the macro engine after the focused window changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileSwitch {
+    /// Load the named profile's macros
+    Activate(String),
+    /// Go back to the daemon's own macros
+    Restore,
+}
+
+/// Picks the profile for the focused window and remembers which one is active
+pub struct ProfileSwitcher {
+    rules: Vec<(Regex, String)>,
+    active: Option<String>,
+}
+
+impl ProfileSwitcher {
+    /// Compile the rules' window patterns
+    pub fn new(rules: &[ProfileRule]) -> Result<Self, regex::Error> {
+        let rules = rules.iter()
+            .map(|rule| Ok((Regex::new(&rule.window)?, rule.profile.clone())))
+            .collect::<Result<_, regex::Error>>()?;
+        Ok(Self { rules, active: None })
+    }
+
+    /// Profile of the first rule matching the window
+    pub fn profile_for(&self, window: &str) -> Option<&str> {
+        self.rules.iter()
+            .find(|(pattern, _)| pattern.is_match(window))
+            .map(|(_, profile)| profile.as_str())
+    }
+
+    /// Decide whether focusing `window` changes the active profile
+    pub fn switch_for(&mut self, window: &str) -> Option<ProfileSwitch> {
+        let wanted = self.profile_for(window).map(str::to_string);
+        if wanted == self.active {
+            return None;
+        }
+
+        self.active = wanted.clone();
+        Some(match wanted {
+            Some(profile) => ProfileSwitch::Activate(profile),
+            None => ProfileSwitch::Restore,
+        })
+    }
+}
+
+/// Start watching the focused window and switching profiles to match
+pub fn start_focus_watcher(
+    settings: ProfileRulesSettings,
+    state: Arc<RwLock<DaemonState>>,
+    macro_engine: Arc<MacroEngine>,
+    config_manager: Arc<ConfigManager>,
+) -> Result<tokio::task::JoinHandle<()>, regex::Error> {
+    let mut switcher = ProfileSwitcher::new(&settings.rules)?;
+    let command: Vec<String> = settings.focus_command.split_whitespace().map(str::to_string).collect();
+    let interval = Duration::from_millis(settings.poll_interval_ms.max(50));
+    info!("Watching the focused window with {} profile rules", settings.rules.len());
+
+    Ok(tokio::spawn(async move {
+        let mut last_window: Option<String> = None;
+        let mut last_error: Option<String> = None;
+
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let window = match focused_window(&command).await {
+                Ok(window) => {
+                    last_error = None;
+                    window
+                }
+                Err(e) => {
+                    // Only report a failure once until the command works again
+                    if last_error.as_ref() != Some(&e) {
+                        warn!("Failed to read the focused window: {}", e);
+                        last_error = Some(e);
+                    }
+                    continue;
+                }
+            };
+
+            if last_window.as_ref() == Some(&window) {
+                continue;
+            }
+            debug!("Focused window changed to {}", window);
+            last_window = Some(window.clone());
+
+            match switcher.switch_for(&window) {
+                Some(ProfileSwitch::Activate(profile)) => {
+                    match config_manager.load_profile(&profile).await.map_err(|e| e.to_string()) {
+                        Ok(loaded) => {
+                            info!("Focused {}, activating profile {}", window, profile);
+                            macro_engine.replace_macros(loaded.macros).await;
+                        }
+                        Err(e) => warn!("Failed to load profile {} for {}: {}", profile, window, e),
+                    }
+                }
+                Some(ProfileSwitch::Restore) => {
+                    info!("Focused {}, restoring default macros", window);
+                    let macros = state.read().await.macros.read().await.clone();
+                    macro_engine.replace_macros(macros).await;
+                }
+                None => {}
+            }
+        }
+    }))
+}
+
+/// Run the focus command and return its trimmed output
+async fn focused_window(command: &[String]) -> Result<String, String> {
+    let (program, args) = command.split_first().ok_or("focus command is empty")?;
+
+    let output = tokio::time::timeout(FOCUS_COMMAND_TIMEOUT, Command::new(program).args(args).output())
+        .await
+        .map_err(|_| "focus command timed out".to_string())?
+        .map_err(|e| format!("{}: {}", program, e))?;
+
+    if !output.status.success() {
+        return Err(format!("{} exited with {}", program, output.status));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(window: &str, profile: &str) -> ProfileRule {
+        ProfileRule {
+            window: window.to_string(),
+            profile: profile.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_switch_for_focus_changes() {
+        let mut switcher = ProfileSwitcher::new(&[
+            rule("(?i)photoshop", "photoshop"),
+            rule("^steam_app_", "gaming"),
+        ]).unwrap();
+
+        assert_eq!(switcher.switch_for("Photoshop"), Some(ProfileSwitch::Activate("photoshop".to_string())));
+        // Another window of the same app keeps the profile
+        assert_eq!(switcher.switch_for("photoshop.exe"), None);
+        assert_eq!(switcher.switch_for("steam_app_730"), Some(ProfileSwitch::Activate("gaming".to_string())));
+        assert_eq!(switcher.switch_for("firefox"), Some(ProfileSwitch::Restore));
+        assert_eq!(switcher.switch_for("kitty"), None);
+    }
+
+    #[test]
+    fn test_invalid_pattern_is_rejected() {
+        assert!(ProfileSwitcher::new(&[rule("(unclosed", "broken")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_focused_window_runs_command() {
+        let command = vec!["echo".to_string(), " Photoshop ".to_string()];
+        assert_eq!(focused_window(&command).await.unwrap(), "Photoshop");
+        assert!(focused_window(&["false".to_string()]).await.is_err());
+        assert!(focused_window(&[]).await.is_err());
+    }
+}
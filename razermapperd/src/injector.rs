@@ -1,30 +1,52 @@
 use razermapper_common::tracing;
 use std::sync::{Arc, RwLock};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::OpenOptions;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{IntoRawFd, RawFd};
+use std::path::{Path, PathBuf};
 // use std::io::Write;
 use std::mem;
+use serde::{Deserialize, Serialize};
 use tracing::{info, warn, error, debug};
 use tokio::time::{sleep, Duration};
 
+use crate::audit::{AuditEvent, AuditLog};
+use razermapper_common::ErrorKind;
+use crate::layout::{KeyStroke, KeyboardLayout};
+
 // Linux input event constants
 const EV_SYN: u16 = 0x00;
 const EV_KEY: u16 = 0x01;
 const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
 const SYN_REPORT: u16 = 0x00;
 const REL_X: u16 = 0x00;
 const REL_Y: u16 = 0x01;
 const REL_WHEEL: u16 = 0x08;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
 
 // Key codes
 const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTALT: u16 = 100; // AltGr
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_SPACE: u16 = 57;
+
+/// Default screen size used for the absolute pointer axes
+pub const DEFAULT_SCREEN_SIZE: (i32, i32) = (1920, 1080);
+
+/// Device node the kernel's uinput module provides
+pub const UINPUT_PATH: &str = "/dev/uinput";
+
+/// Default delay between typed characters in milliseconds
+pub const DEFAULT_TYPE_DELAY_MS: u64 = 30;
 
 // uinput ioctl constants
 const UINPUT_IOCTL_BASE: u8 = b'U';
 const UI_SET_EVBIT: u64 = 0x40045564;   // _IOW('U', 100, int)
 const UI_SET_KEYBIT: u64 = 0x40045565;  // _IOW('U', 101, int)
 const UI_SET_RELBIT: u64 = 0x40045566;  // _IOW('U', 102, int)
+const UI_SET_ABSBIT: u64 = 0x40045567;  // _IOW('U', 103, int)
 const UI_DEV_CREATE: u64 = 0x5501;      // _IO('U', 1)
 const UI_DEV_DESTROY: u64 = 0x5502;     // _IO('U', 2)
 
@@ -38,6 +60,40 @@ struct InputEvent {
     value: i32,
 }
 
+/// Input events collected to be written to the device in one go
+#[derive(Default)]
+struct EventBatch {
+    events: Vec<InputEvent>,
+}
+
+impl EventBatch {
+    /// Append an event stamped with the current time
+    fn push(&mut self, type_: u16, code: u16, value: i32) {
+        let mut event: InputEvent = unsafe { mem::zeroed() };
+        unsafe {
+            libc::gettimeofday(&mut event.time, std::ptr::null_mut());
+        }
+        event.type_ = type_;
+        event.code = code;
+        event.value = value;
+        self.events.push(event);
+    }
+
+    /// Close the current input frame with a synchronization event
+    fn sync(&mut self) {
+        self.push(EV_SYN, SYN_REPORT, 0);
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self.events.as_ptr() as *const u8,
+                self.events.len() * mem::size_of::<InputEvent>(),
+            )
+        }
+    }
+}
+
 /// uinput_user_dev structure for device setup
 #[repr(C)]
 struct UinputUserDev {
@@ -61,127 +117,280 @@ struct InputId {
 /// Trait for input injection functionality
 #[async_trait::async_trait]
 pub trait Injector: Send + Sync {
-    async fn initialize(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
-    async fn key_press(&self, key_code: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
-    async fn key_release(&self, key_code: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
-    async fn mouse_press(&self, button: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
-    async fn mouse_release(&self, button: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
-    async fn mouse_move(&self, x: i32, y: i32) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
-    async fn mouse_scroll(&self, amount: i32) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
-    async fn type_string(&self, text: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
-    async fn execute_command(&self, command: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+    async fn initialize(&self) -> InjectorResult<()>;
+    async fn key_press(&self, key_code: u16) -> InjectorResult<()>;
+    async fn key_release(&self, key_code: u16) -> InjectorResult<()>;
+    async fn mouse_press(&self, button: u16) -> InjectorResult<()>;
+    async fn mouse_release(&self, button: u16) -> InjectorResult<()>;
+    async fn mouse_move(&self, x: i32, y: i32) -> InjectorResult<()>;
+    /// Move the pointer to an absolute screen position in pixels
+    ///
+    /// Requires the virtual device to register EV_ABS with ABS_X/ABS_Y. The
+    /// axis range has to match the screen resolution for the position to map
+    /// 1:1 to pixels, and on multi-monitor setups it spans the whole desktop.
+    async fn mouse_move_absolute(&self, x: i32, y: i32) -> InjectorResult<()>;
+    async fn mouse_scroll(&self, amount: i32) -> InjectorResult<()>;
+    async fn type_string(&self, text: &str) -> InjectorResult<()>;
+    /// Run a whitelisted command, returning what it printed to stdout
+    async fn execute_command(&self, command: &str) -> InjectorResult<String>;
+    /// Press all keys, hold them for `hold`, then release them all
+    ///
+    /// Injectors that can batch events should deliver each half as a single
+    /// input frame; by default the keys are pressed and released one by one.
+    async fn press_keys_then_release(&self, keys: &[u16], hold: Duration) -> InjectorResult<()> {
+        for &key in keys {
+            self.key_press(key).await?;
+        }
+        sleep(hold).await;
+        for &key in keys.iter().rev() {
+            self.key_release(key).await?;
+        }
+        Ok(())
+    }
+    /// Release every key the injector is holding down, such as after a macro was cancelled
+    async fn release_all(&self) -> InjectorResult<()> {
+        Ok(())
+    }
+    /// Keys the injector is currently holding down, for diagnostics
+    fn held_keys(&self) -> Vec<u16> {
+        Vec::new()
+    }
+    /// Release any virtual devices; injectors without resources have nothing to do
+    async fn shutdown(&self) -> InjectorResult<()> {
+        Ok(())
+    }
+}
+
+/// How to type characters that have no key on the keyboard layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnicodeStrategy {
+    /// Skip the character
+    #[default]
+    Drop,
+    /// Enter the codepoint with Ctrl+Shift+U (IBus and GTK input methods)
+    CtrlShiftU,
+    /// Put the text on the clipboard and paste it with Ctrl+V
+    Clipboard,
+}
+
+/// Why injecting input or running a command failed
+#[derive(Debug, thiserror::Error)]
+pub enum InjectorError {
+    #[error("uinput device not initialized")]
+    NotInitialized,
+    #[error("uinput injector has been shut down")]
+    ShutDown,
+    #[error("failed to set up uinput device: {0}")]
+    Setup(String),
+    #[error("failed to write event: {0}")]
+    WriteFailed(#[source] std::io::Error),
+    #[error("command '{0}' is not allowed")]
+    CommandNotAllowed(String),
+    #[error("command timed out")]
+    CommandTimeout,
+    #[error("command failed: {0}")]
+    CommandFailed(String),
+    #[error("no key mapping for '{0}' on this layout")]
+    UnmappedChar(char),
+    #[error("clipboard unavailable: {0}")]
+    Clipboard(String),
+}
+
+impl InjectorError {
+    /// Error kind reported to IPC clients for this failure
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::NotInitialized | Self::ShutDown | Self::Clipboard(_) => ErrorKind::Unavailable,
+            Self::CommandNotAllowed(_) => ErrorKind::PermissionDenied,
+            Self::CommandTimeout => ErrorKind::Timeout,
+            Self::UnmappedChar(_) => ErrorKind::InvalidRequest,
+            Self::Setup(_) | Self::WriteFailed(_) | Self::CommandFailed(_) => ErrorKind::Internal,
+        }
+    }
+}
+
+/// Result of an injector operation
+pub type InjectorResult<T> = Result<T, InjectorError>;
+
+/// Why the uinput device node can't be used to create virtual devices
+#[derive(Debug, thiserror::Error)]
+pub enum UinputPreflightError {
+    #[error("{0} does not exist, the uinput kernel module is probably not loaded: run `sudo modprobe uinput`")]
+    ModuleNotLoaded(PathBuf),
+    #[error("no write access to {0}: run the daemon as root or grant its user write access, e.g. with a udev rule")]
+    PermissionDenied(PathBuf),
+    #[error("cannot open {path}: {source}")]
+    Open {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+/// Check that the uinput device node at `path` exists and can be opened for writing
+pub fn preflight_uinput(path: &Path) -> Result<(), UinputPreflightError> {
+    if !path.exists() {
+        return Err(UinputPreflightError::ModuleNotLoaded(path.to_path_buf()));
+    }
+    match OpenOptions::new().write(true).open(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(UinputPreflightError::PermissionDenied(path.to_path_buf()))
+        }
+        Err(source) => Err(UinputPreflightError::Open { path: path.to_path_buf(), source }),
+    }
+}
+
+/// Owned uinput file descriptor that destroys its virtual device when dropped
+///
+/// Keeping the fd in its own type means cleanup never depends on taking a
+/// lock in `Drop`: the device goes away as soon as the last owner lets go.
+struct UinputDevice {
+    fd: RawFd,
+    created: bool,
+}
+
+impl Drop for UinputDevice {
+    fn drop(&mut self) {
+        unsafe {
+            if self.created {
+                info!("Destroying uinput virtual device");
+                libc::ioctl(self.fd, UI_DEV_DESTROY);
+            }
+            libc::close(self.fd);
+        }
+    }
 }
 
 /// Real uinput-based injector that creates virtual input devices
 #[derive(Clone)]
 pub struct UinputInjector {
     initialized: Arc<RwLock<bool>>,
-    uinput_fd: Arc<RwLock<Option<RawFd>>>,
-    key_map: Arc<RwLock<HashMap<char, u16>>>,
+    /// Set by `shutdown`; the device is never created again afterwards
+    shut_down: Arc<RwLock<bool>>,
+    uinput_device: Arc<RwLock<Option<UinputDevice>>>,
+    key_map: Arc<RwLock<HashMap<char, KeyStroke>>>,
+    type_delay_ms: Arc<RwLock<u64>>,
+    unmapped_chars: Arc<RwLock<HashSet<char>>>,
+    unicode_strategy: UnicodeStrategy,
+    screen_size: Arc<RwLock<(i32, i32)>>,
+    /// Keys and buttons the virtual device currently reports as pressed
+    held_keys: Arc<RwLock<HashSet<u16>>>,
+    audit_log: Option<Arc<AuditLog>>,
 }
 
 impl UinputInjector {
-    /// Create a new injector instance
+    /// Create a new injector instance using the US keyboard layout
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        info!("Creating new UinputInjector instance");
-
-        // Initialize US QWERTY keyboard mapping
-        let mut key_map = HashMap::new();
-
-        // Numbers 0-9 (KEY_1=2, KEY_2=3, ..., KEY_0=11)
-        key_map.insert('1', 2);
-        key_map.insert('2', 3);
-        key_map.insert('3', 4);
-        key_map.insert('4', 5);
-        key_map.insert('5', 6);
-        key_map.insert('6', 7);
-        key_map.insert('7', 8);
-        key_map.insert('8', 9);
-        key_map.insert('9', 10);
-        key_map.insert('0', 11);
-
-        // Letters (KEY_Q=16, KEY_W=17, etc.)
-        let qwerty_row1 = "qwertyuiop";
-        for (i, c) in qwerty_row1.chars().enumerate() {
-            key_map.insert(c, 16 + i as u16);
-            key_map.insert(c.to_ascii_uppercase(), 16 + i as u16);
-        }
-
-        let qwerty_row2 = "asdfghjkl";
-        for (i, c) in qwerty_row2.chars().enumerate() {
-            key_map.insert(c, 30 + i as u16);
-            key_map.insert(c.to_ascii_uppercase(), 30 + i as u16);
-        }
-
-        let qwerty_row3 = "zxcvbnm";
-        for (i, c) in qwerty_row3.chars().enumerate() {
-            key_map.insert(c, 44 + i as u16);
-            key_map.insert(c.to_ascii_uppercase(), 44 + i as u16);
-        }
-
-        // Special characters
-        key_map.insert(' ', 57);  // KEY_SPACE
-        key_map.insert('-', 12);  // KEY_MINUS
-        key_map.insert('=', 13);  // KEY_EQUAL
-        key_map.insert('[', 26);  // KEY_LEFTBRACE
-        key_map.insert(']', 27);  // KEY_RIGHTBRACE
-        key_map.insert('\\', 43); // KEY_BACKSLASH
-        key_map.insert(';', 39);  // KEY_SEMICOLON
-        key_map.insert('\'', 40); // KEY_APOSTROPHE
-        key_map.insert('`', 41);  // KEY_GRAVE
-        key_map.insert(',', 51);  // KEY_COMMA
-        key_map.insert('.', 52);  // KEY_DOT
-        key_map.insert('/', 53);  // KEY_SLASH
-        key_map.insert('\n', 28); // KEY_ENTER
-        key_map.insert('\t', 15); // KEY_TAB
+        Self::with_layout(KeyboardLayout::Us)
+    }
+
+    /// Create a new injector instance that types text for the given keyboard layout
+    pub fn with_layout(layout: KeyboardLayout) -> Result<Self, Box<dyn std::error::Error>> {
+        info!("Creating new UinputInjector instance ({:?} layout)", layout);
 
         Ok(Self {
             initialized: Arc::new(RwLock::new(false)),
-            uinput_fd: Arc::new(RwLock::new(None)),
-            key_map: Arc::new(RwLock::new(key_map)),
+            shut_down: Arc::new(RwLock::new(false)),
+            uinput_device: Arc::new(RwLock::new(None)),
+            key_map: Arc::new(RwLock::new(layout.key_map())),
+            type_delay_ms: Arc::new(RwLock::new(DEFAULT_TYPE_DELAY_MS)),
+            unmapped_chars: Arc::new(RwLock::new(HashSet::new())),
+            unicode_strategy: UnicodeStrategy::Drop,
+            screen_size: Arc::new(RwLock::new(DEFAULT_SCREEN_SIZE)),
+            held_keys: Arc::new(RwLock::new(HashSet::new())),
+            audit_log: None,
         })
     }
 
+    /// Set the screen size the absolute pointer axes are scaled to
+    ///
+    /// Must be called before `initialize`, since the axis range is fixed
+    /// when the virtual device is created.
+    pub fn set_screen_size(&self, width: i32, height: i32) {
+        *self.screen_size.write().unwrap() = (width.max(1), height.max(1));
+    }
+
+    /// Set how characters missing from the layout are typed
+    pub fn with_unicode_strategy(mut self, strategy: UnicodeStrategy) -> Self {
+        self.unicode_strategy = strategy;
+        self
+    }
+
+    /// Record every executed command in the given audit log
+    pub fn with_audit_log(mut self, audit_log: Arc<AuditLog>) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Set the delay between typed characters in milliseconds
+    ///
+    /// Keys are held for two thirds and Shift is settled for one third of
+    /// this delay. A delay of 0 types as fast as possible without sleeping.
+    pub fn set_type_delay(&self, delay_ms: u64) {
+        *self.type_delay_ms.write().unwrap() = delay_ms;
+    }
+
+    /// Get the delay between typed characters in milliseconds
+    pub fn type_delay(&self) -> u64 {
+        *self.type_delay_ms.read().unwrap()
+    }
+
+    /// Check that /dev/uinput is usable before initializing
+    ///
+    /// Tells a missing uinput module apart from missing permissions so the
+    /// user can be told how to fix it.
+    pub fn preflight() -> Result<(), UinputPreflightError> {
+        preflight_uinput(Path::new(UINPUT_PATH))
+    }
+
     /// Initialize the uinput device - creates a virtual keyboard and mouse
-    pub async fn initialize(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    pub async fn initialize(&self) -> InjectorResult<()> {
         {
             let initialized = self.initialized.read().unwrap();
             if *initialized {
                 return Ok(());
             }
         }
+        if *self.shut_down.read().unwrap() {
+            return Err(InjectorError::ShutDown);
+        }
 
         info!("Initializing uinput virtual device");
 
         // Open /dev/uinput
         let uinput_file = OpenOptions::new()
             .write(true)
-            .open("/dev/uinput")
+            .open(UINPUT_PATH)
             .map_err(|e| {
-                error!("Failed to open /dev/uinput: {}. Ensure you have root privileges and uinput module is loaded.", e);
-                format!("Failed to open /dev/uinput: {}", e)
+                error!("Failed to open {}: {}. Ensure you have root privileges and uinput module is loaded.", UINPUT_PATH, e);
+                InjectorError::Setup(format!("cannot open {}: {}", UINPUT_PATH, e))
             })?;
 
-        let fd = uinput_file.as_raw_fd();
-
-        // Leak the file to keep fd valid (we'll clean up in Drop)
-        mem::forget(uinput_file);
+        // The device owns the fd from here on, so early returns close it
+        let mut device = UinputDevice { fd: uinput_file.into_raw_fd(), created: false };
+        let fd = device.fd;
 
         // Set up event types
         unsafe {
             // Enable EV_KEY events (keyboard/mouse buttons)
             if libc::ioctl(fd, UI_SET_EVBIT, EV_KEY as libc::c_int) < 0 {
-                return Err("Failed to set EV_KEY bit".into());
+                return Err(InjectorError::Setup("failed to set EV_KEY bit".to_string()));
             }
 
             // Enable EV_REL events (relative mouse movement)
             if libc::ioctl(fd, UI_SET_EVBIT, EV_REL as libc::c_int) < 0 {
-                return Err("Failed to set EV_REL bit".into());
+                return Err(InjectorError::Setup("failed to set EV_REL bit".to_string()));
+            }
+
+            // Enable EV_ABS events (absolute pointer positioning)
+            if libc::ioctl(fd, UI_SET_EVBIT, EV_ABS as libc::c_int) < 0 {
+                return Err(InjectorError::Setup("failed to set EV_ABS bit".to_string()));
             }
 
             // Enable EV_SYN events (synchronization)
             if libc::ioctl(fd, UI_SET_EVBIT, EV_SYN as libc::c_int) < 0 {
-                return Err("Failed to set EV_SYN bit".into());
+                return Err(InjectorError::Setup("failed to set EV_SYN bit".to_string()));
             }
 
             // Enable all key codes (0-255)
@@ -208,6 +417,14 @@ impl UinputInjector {
             if libc::ioctl(fd, UI_SET_RELBIT, REL_WHEEL as libc::c_int) < 0 {
                 warn!("Failed to set REL_WHEEL bit");
             }
+
+            // Enable absolute axes for pointer positioning
+            if libc::ioctl(fd, UI_SET_ABSBIT, ABS_X as libc::c_int) < 0 {
+                warn!("Failed to set ABS_X bit");
+            }
+            if libc::ioctl(fd, UI_SET_ABSBIT, ABS_Y as libc::c_int) < 0 {
+                warn!("Failed to set ABS_Y bit");
+            }
         }
 
         // Create device structure
@@ -219,27 +436,35 @@ impl UinputInjector {
         dev.id.product = 0xFFFF; // Virtual device
         dev.id.version = 1;
 
+        // Absolute axes span the screen so coordinates map to pixels
+        let (width, height) = *self.screen_size.read().unwrap();
+        dev.absmin[ABS_X as usize] = 0;
+        dev.absmax[ABS_X as usize] = width - 1;
+        dev.absmin[ABS_Y as usize] = 0;
+        dev.absmax[ABS_Y as usize] = height - 1;
+
         // Write device structure
         unsafe {
             let dev_ptr = &dev as *const UinputUserDev as *const u8;
             let dev_slice = std::slice::from_raw_parts(dev_ptr, mem::size_of::<UinputUserDev>());
 
             if libc::write(fd, dev_slice.as_ptr() as *const libc::c_void, dev_slice.len()) < 0 {
-                return Err("Failed to write uinput device structure".into());
+                return Err(InjectorError::Setup("failed to write uinput device structure".to_string()));
             }
 
             // Create the device
             if libc::ioctl(fd, UI_DEV_CREATE) < 0 {
-                return Err("Failed to create uinput device".into());
+                return Err(InjectorError::Setup("failed to create uinput device".to_string()));
             }
         }
+        device.created = true;
 
         info!("Successfully created uinput virtual device: {}", String::from_utf8_lossy(name));
 
-        // Store the file descriptor
+        // Store the device
         {
-            let mut uinput_fd = self.uinput_fd.write().unwrap();
-            *uinput_fd = Some(fd);
+            let mut uinput_device = self.uinput_device.write().unwrap();
+            *uinput_device = Some(device);
         }
 
         {
@@ -253,170 +478,344 @@ impl UinputInjector {
         Ok(())
     }
 
-    /// Write an input event to the uinput device
-    fn write_event(&self, type_: u16, code: u16, value: i32) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let fd = {
-            let uinput_fd = self.uinput_fd.read().unwrap();
-            uinput_fd.ok_or("Uinput device not initialized")?
-        };
+    /// Destroy the virtual device now instead of waiting for the last clone to drop
+    ///
+    /// Keys still held are released first, so nothing stays stuck down once
+    /// the device is gone.
+    pub async fn shutdown(&self) -> InjectorResult<()> {
+        if let Err(e) = self.release_all() {
+            warn!("Failed to release held keys on shutdown: {}", e);
+        }
 
-        let mut event: InputEvent = unsafe { mem::zeroed() };
+        *self.shut_down.write().unwrap() = true;
+        // Waits for any write in progress, which holds the read lock
+        let device = self.uinput_device.write().unwrap().take();
+        *self.initialized.write().unwrap() = false;
 
-        // Get current time
-        unsafe {
-            libc::gettimeofday(&mut event.time, std::ptr::null_mut());
+        if device.is_some() {
+            info!("Shutting down uinput injector");
         }
+        drop(device);
+        Ok(())
+    }
 
-        event.type_ = type_;
-        event.code = code;
-        event.value = value;
-
-        unsafe {
-            let event_ptr = &event as *const InputEvent as *const u8;
-            let event_slice = std::slice::from_raw_parts(event_ptr, mem::size_of::<InputEvent>());
+    /// Create the virtual device on first use, unless the injector was shut down
+    async fn ensure_initialized(&self) -> InjectorResult<()> {
+        if !*self.initialized.read().unwrap() {
+            self.initialize().await?;
+        }
+        Ok(())
+    }
 
-            let written = libc::write(fd, event_slice.as_ptr() as *const libc::c_void, event_slice.len());
+    /// Write all events of a batch to the uinput device with a single write()
+    fn write_batch(&self, batch: &EventBatch) -> InjectorResult<()> {
+        // Held for the whole write so shutdown can't close the fd, and the
+        // number can't be reused, while it is being written to
+        let uinput_device = self.uinput_device.read().unwrap();
+        let fd = uinput_device.as_ref().map(|device| device.fd).ok_or(InjectorError::NotInitialized)?;
+
+        let bytes = batch.as_bytes();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let remaining = &bytes[offset..];
+            let written = unsafe { libc::write(fd, remaining.as_ptr() as *const libc::c_void, remaining.len()) };
             if written < 0 {
-                return Err(format!("Failed to write event: {}", std::io::Error::last_os_error()).into());
+                return Err(InjectorError::WriteFailed(std::io::Error::last_os_error()));
+            }
+            offset += written as usize;
+        }
+
+        let mut held_keys = self.held_keys.write().unwrap();
+        for event in batch.events.iter().filter(|event| event.type_ == EV_KEY) {
+            if event.value == 0 {
+                held_keys.remove(&event.code);
+            } else {
+                held_keys.insert(event.code);
             }
         }
 
         Ok(())
     }
 
-    /// Send a synchronization event
-    fn sync(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        self.write_event(EV_SYN, SYN_REPORT, 0)
+    /// Keys and buttons the device currently reports as pressed, sorted
+    pub fn held_keys(&self) -> Vec<u16> {
+        let mut held: Vec<u16> = self.held_keys.read().unwrap().iter().copied().collect();
+        held.sort_unstable();
+        held
     }
 
-    /// Press a key (sends key down event + sync)
-    pub async fn key_press(&self, key_code: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        if !*self.initialized.read().unwrap() {
-            self.initialize().await?;
+    /// Release every key and button the device currently holds, in one frame
+    pub fn release_all(&self) -> InjectorResult<()> {
+        let held: Vec<u16> = self.held_keys.read().unwrap().iter().copied().collect();
+        if held.is_empty() {
+            return Ok(());
         }
 
+        info!("Releasing {} held keys", held.len());
+        let releases: Vec<_> = held.into_iter().map(|key| (EV_KEY, key, 0)).collect();
+        self.write_frame(&releases)
+    }
+
+    /// Write the events as one input frame, followed by a synchronization event
+    fn write_frame(&self, events: &[(u16, u16, i32)]) -> InjectorResult<()> {
+        let mut batch = EventBatch::default();
+        for &(type_, code, value) in events {
+            batch.push(type_, code, value);
+        }
+        batch.sync();
+        self.write_batch(&batch)
+    }
+
+    /// Press a key (sends key down event + sync)
+    pub async fn key_press(&self, key_code: u16) -> InjectorResult<()> {
+        self.ensure_initialized().await?;
+
         debug!("Key press: {}", key_code);
-        self.write_event(EV_KEY, key_code, 1)?; // 1 = key down
-        self.sync()?;
-        Ok(())
+        self.write_frame(&[(EV_KEY, key_code, 1)]) // 1 = key down
     }
 
     /// Release a key (sends key up event + sync)
-    pub async fn key_release(&self, key_code: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        if !*self.initialized.read().unwrap() {
-            self.initialize().await?;
-        }
+    pub async fn key_release(&self, key_code: u16) -> InjectorResult<()> {
+        self.ensure_initialized().await?;
 
         debug!("Key release: {}", key_code);
-        self.write_event(EV_KEY, key_code, 0)?; // 0 = key up
-        self.sync()?;
-        Ok(())
+        self.write_frame(&[(EV_KEY, key_code, 0)]) // 0 = key up
+    }
+
+    /// Press all keys with a single sync, hold them, then release them with a single sync
+    pub async fn press_keys_then_release(&self, keys: &[u16], hold: Duration) -> InjectorResult<()> {
+        self.ensure_initialized().await?;
+
+        debug!("Chord: {:?}", keys);
+        let presses: Vec<_> = keys.iter().map(|&key| (EV_KEY, key, 1)).collect();
+        self.write_frame(&presses)?;
+
+        sleep(hold).await;
+
+        let releases: Vec<_> = keys.iter().rev().map(|&key| (EV_KEY, key, 0)).collect();
+        self.write_frame(&releases)
     }
 
     /// Press a mouse button
-    pub async fn mouse_press(&self, button: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        if !*self.initialized.read().unwrap() {
-            self.initialize().await?;
-        }
+    pub async fn mouse_press(&self, button: u16) -> InjectorResult<()> {
+        self.ensure_initialized().await?;
 
         // Convert button number to Linux button code
         // 1=left (272), 2=right (273), 3=middle (274)
         let btn_code = 271 + button;
         debug!("Mouse button {} press (code {})", button, btn_code);
-        self.write_event(EV_KEY, btn_code, 1)?;
-        self.sync()?;
-        Ok(())
+        self.write_frame(&[(EV_KEY, btn_code, 1)])
     }
 
     /// Release a mouse button
-    pub async fn mouse_release(&self, button: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        if !*self.initialized.read().unwrap() {
-            self.initialize().await?;
-        }
+    pub async fn mouse_release(&self, button: u16) -> InjectorResult<()> {
+        self.ensure_initialized().await?;
 
         let btn_code = 271 + button;
         debug!("Mouse button {} release (code {})", button, btn_code);
-        self.write_event(EV_KEY, btn_code, 0)?;
-        self.sync()?;
-        Ok(())
+        self.write_frame(&[(EV_KEY, btn_code, 0)])
     }
 
     /// Move the mouse cursor (relative movement)
-    pub async fn mouse_move(&self, x: i32, y: i32) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        if !*self.initialized.read().unwrap() {
-            self.initialize().await?;
-        }
+    pub async fn mouse_move(&self, x: i32, y: i32) -> InjectorResult<()> {
+        self.ensure_initialized().await?;
 
         debug!("Mouse move: dx={}, dy={}", x, y);
+        let mut batch = EventBatch::default();
         if x != 0 {
-            self.write_event(EV_REL, REL_X, x)?;
+            batch.push(EV_REL, REL_X, x);
         }
         if y != 0 {
-            self.write_event(EV_REL, REL_Y, y)?;
+            batch.push(EV_REL, REL_Y, y);
         }
-        self.sync()?;
-        Ok(())
+        batch.sync();
+        self.write_batch(&batch)
+    }
+
+    /// Move the mouse cursor to an absolute screen position
+    pub async fn mouse_move_absolute(&self, x: i32, y: i32) -> InjectorResult<()> {
+        self.ensure_initialized().await?;
+
+        let (x, y) = clamp_to_screen(x, y, *self.screen_size.read().unwrap());
+        debug!("Mouse move absolute: x={}, y={}", x, y);
+        self.write_frame(&[(EV_ABS, ABS_X, x), (EV_ABS, ABS_Y, y)])
     }
 
     /// Scroll the mouse wheel
-    pub async fn mouse_scroll(&self, amount: i32) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        if !*self.initialized.read().unwrap() {
-            self.initialize().await?;
-        }
+    pub async fn mouse_scroll(&self, amount: i32) -> InjectorResult<()> {
+        self.ensure_initialized().await?;
 
         debug!("Mouse scroll: {}", amount);
-        self.write_event(EV_REL, REL_WHEEL, amount)?;
-        self.sync()?;
-        Ok(())
+        self.write_frame(&[(EV_REL, REL_WHEEL, amount)])
     }
 
     /// Type a string by simulating key presses and releases
-    pub async fn type_string(&self, text: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        if !*self.initialized.read().unwrap() {
-            self.initialize().await?;
-        }
+    pub async fn type_string(&self, text: &str) -> InjectorResult<()> {
+        self.ensure_initialized().await?;
 
         info!("Typing string: {}", text);
 
+        // A delay of 0 makes every pause a no-op for bulk text
+        let delays = split_type_delay(self.type_delay());
+
+        // Characters without a key are collected into runs so the clipboard
+        // strategy can paste them in one go
+        let mut unmapped = String::new();
         for c in text.chars() {
-            let key_code = {
+            let stroke = {
                 let key_map = self.key_map.read().unwrap();
                 key_map.get(&c).copied()
             };
 
-            if let Some(key_code) = key_code {
-                let needs_shift = c.is_ascii_uppercase() || "!@#$%^&*()_+{}|:\"<>?~".contains(c);
-
-                if needs_shift {
-                    self.key_press(KEY_LEFTSHIFT).await?;
-                    sleep(Duration::from_millis(10)).await;
+            match stroke {
+                Some(stroke) => {
+                    self.type_unmapped(&unmapped, delays).await?;
+                    unmapped.clear();
+                    self.type_stroke(stroke, delays).await?;
                 }
+                None => unmapped.push(c),
+            }
+        }
+        self.type_unmapped(&unmapped, delays).await?;
 
-                self.key_press(key_code).await?;
-                sleep(Duration::from_millis(20)).await;
-                self.key_release(key_code).await?;
+        Ok(())
+    }
 
-                if needs_shift {
-                    sleep(Duration::from_millis(10)).await;
-                    self.key_release(KEY_LEFTSHIFT).await?;
-                }
+    /// Type one mapped character, holding its modifiers around the key
+    async fn type_stroke(&self, stroke: KeyStroke, delays: (Duration, Duration, Duration)) -> InjectorResult<()> {
+        let mut modifiers = Vec::new();
+        if stroke.shift {
+            modifiers.push(KEY_LEFTSHIFT);
+        }
+        if stroke.altgr {
+            modifiers.push(KEY_RIGHTALT);
+        }
 
-                sleep(Duration::from_millis(30)).await;
-            } else {
-                warn!("No key mapping for character: '{}' (U+{:04X})", c, c as u32);
+        self.type_chord(&modifiers, stroke.code, delays).await
+    }
+
+    /// Press a key while holding the given modifiers
+    ///
+    /// Without delays the whole stroke goes out in one write, each step still
+    /// its own input frame so consumers see the same sequence of key states.
+    async fn type_chord(&self, modifiers: &[u16], key_code: u16, delays: (Duration, Duration, Duration)) -> InjectorResult<()> {
+        let (modifier_delay, hold_delay, char_delay) = delays;
+
+        if modifier_delay.is_zero() && hold_delay.is_zero() && char_delay.is_zero() {
+            let mut batch = EventBatch::default();
+            for &modifier in modifiers {
+                batch.push(EV_KEY, modifier, 1);
+                batch.sync();
+            }
+            batch.push(EV_KEY, key_code, 1);
+            batch.sync();
+            batch.push(EV_KEY, key_code, 0);
+            batch.sync();
+            for &modifier in modifiers.iter().rev() {
+                batch.push(EV_KEY, modifier, 0);
+                batch.sync();
             }
+            return self.write_batch(&batch);
         }
 
+        for modifier in modifiers {
+            self.key_press(*modifier).await?;
+        }
+        if !modifiers.is_empty() {
+            pause(modifier_delay).await;
+        }
+
+        self.key_press(key_code).await?;
+        pause(hold_delay).await;
+        self.key_release(key_code).await?;
+
+        if !modifiers.is_empty() {
+            pause(modifier_delay).await;
+        }
+        for modifier in modifiers.iter().rev() {
+            self.key_release(*modifier).await?;
+        }
+
+        pause(char_delay).await;
         Ok(())
     }
 
+    /// Type characters that have no key on the current layout
+    async fn type_unmapped(&self, text: &str, delays: (Duration, Duration, Duration)) -> InjectorResult<()> {
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        match self.unicode_strategy {
+            UnicodeStrategy::Drop => {
+                for c in text.chars() {
+                    if self.unmapped_chars.write().unwrap().insert(c) {
+                        // Only warn the first time so long texts don't flood the log
+                        warn!("No key mapping for character: '{}' (U+{:04X})", c, c as u32);
+                    }
+                }
+            }
+            UnicodeStrategy::CtrlShiftU => {
+                for c in text.chars() {
+                    self.type_codepoint(c, delays).await?;
+                }
+            }
+            UnicodeStrategy::Clipboard => {
+                set_clipboard(text).await?;
+                let v = self.mapped_key('v')?;
+                self.type_chord(&[KEY_LEFTCTRL], v.code, delays).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Type a character by its codepoint with the Ctrl+Shift+U input method sequence
+    async fn type_codepoint(&self, c: char, delays: (Duration, Duration, Duration)) -> InjectorResult<()> {
+        debug!("Typing U+{:04X} via Ctrl+Shift+U", c as u32);
+
+        let u = self.mapped_key('u')?;
+        self.type_chord(&[KEY_LEFTCTRL, KEY_LEFTSHIFT], u.code, delays).await?;
+
+        for digit in unicode_hex(c).chars() {
+            let stroke = self.mapped_key(digit)?;
+            self.type_stroke(stroke, delays).await?;
+        }
+
+        // Space commits the sequence
+        self.type_chord(&[], KEY_SPACE, delays).await
+    }
+
+    /// Look up a character the fallback strategies rely on
+    fn mapped_key(&self, c: char) -> InjectorResult<KeyStroke> {
+        self.key_map.read().unwrap().get(&c).copied()
+            .ok_or(InjectorError::UnmappedChar(c))
+    }
+
     /// Execute a system command with security restrictions
-    pub async fn execute_command(&self, command: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// Run a whitelisted command with a timeout, returning its stdout
+    pub async fn execute_command(&self, command: &str) -> InjectorResult<String> {
+        let result = self.run_command(command).await;
+
+        if let Some(audit_log) = &self.audit_log {
+            let mut parts = command.split_whitespace();
+            audit_log.record(AuditEvent::CommandExecuted {
+                program: parts.next().unwrap_or_default().to_string(),
+                args: parts.map(str::to_string).collect(),
+                success: result.is_ok(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            }).await;
+        }
+
+        result
+    }
+
+    async fn run_command(&self, command: &str) -> InjectorResult<String> {
         info!("Executing command: {}", command);
 
         let parts: Vec<&str> = command.split_whitespace().collect();
         if parts.is_empty() {
-            return Err("Empty command".into());
+            return Err(InjectorError::CommandFailed("empty command".to_string()));
         }
 
         let program = parts[0];
@@ -430,7 +829,7 @@ impl UinputInjector {
 
         if !allowed_commands.contains(&program) {
             warn!("Blocked non-whitelisted command: {}", program);
-            return Err(format!("Command '{}' is not allowed", program).into());
+            return Err(InjectorError::CommandNotAllowed(program.to_string()));
         }
 
         info!("Executing allowed command: {} {:?}", program, args);
@@ -455,73 +854,182 @@ impl UinputInjector {
             Ok(Ok(output)) => {
                 if output.status.success() {
                     info!("Command executed successfully");
-                    Ok(())
+                    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
                 } else {
                     let stderr = String::from_utf8_lossy(&output.stderr);
                     error!("Command failed: {}", stderr);
-                    Err(format!("Command failed: {}", stderr).into())
+                    Err(InjectorError::CommandFailed(stderr.into_owned()))
                 }
             }
-            Ok(Err(e)) => Err(format!("Failed to execute: {}", e).into()),
-            Err(_) => Err("Command timed out".into()),
+            Ok(Err(e)) => Err(InjectorError::CommandFailed(format!("failed to execute: {}", e))),
+            Err(_) => Err(InjectorError::CommandTimeout),
         }
     }
 }
 
+/// Size of the first connected display, read from the kernel's DRM connectors
+///
+/// Each connector lists its modes with the preferred one first, so this is
+/// the native resolution of the display rather than whatever the desktop
+/// currently scales to. Returns None without a connected display.
+pub fn detect_screen_size() -> Option<(i32, i32)> {
+    let mut connectors: Vec<_> = std::fs::read_dir("/sys/class/drm").ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.join("modes").exists())
+        .collect();
+    connectors.sort();
+
+    connectors.into_iter().find_map(|connector| {
+        let status = std::fs::read_to_string(connector.join("status")).ok()?;
+        if status.trim() != "connected" {
+            return None;
+        }
+        let modes = std::fs::read_to_string(connector.join("modes")).ok()?;
+        parse_drm_mode(modes.lines().next()?)
+    })
+}
+
+/// Parse a DRM mode line such as "2560x1440" or "1920x1080i"
+fn parse_drm_mode(mode: &str) -> Option<(i32, i32)> {
+    let (width, height) = mode.trim().split_once('x')?;
+    let height = height.trim_end_matches(|c: char| !c.is_ascii_digit());
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+/// Clamp an absolute position to the axis range of the given screen size
+fn clamp_to_screen(x: i32, y: i32, screen_size: (i32, i32)) -> (i32, i32) {
+    (x.clamp(0, screen_size.0 - 1), y.clamp(0, screen_size.1 - 1))
+}
+
+/// Lowercase hex digits of a character's codepoint, as entered after Ctrl+Shift+U
+fn unicode_hex(c: char) -> String {
+    format!("{:x}", c as u32)
+}
+
+/// Put text on the desktop clipboard using wl-copy (Wayland) or xclip (X11)
+async fn set_clipboard(text: &str) -> InjectorResult<()> {
+    use tokio::io::AsyncWriteExt;
+    use tokio::process::Command;
+    use std::process::Stdio;
+
+    let tools: [(&str, &[&str]); 2] = [
+        ("wl-copy", &[]),
+        ("xclip", &["-selection", "clipboard"]),
+    ];
+
+    for (program, args) in tools {
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .env_clear()
+            .env("PATH", "/usr/bin:/bin")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        for var in ["DISPLAY", "WAYLAND_DISPLAY", "XDG_RUNTIME_DIR"] {
+            if let Ok(value) = std::env::var(var) {
+                command.env(var, value);
+            }
+        }
+
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                debug!("Clipboard tool {} unavailable: {}", program, e);
+                continue;
+            }
+        };
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes()).await
+                .map_err(|e| InjectorError::Clipboard(format!("writing to {}: {}", program, e)))?;
+        }
+
+        let status = tokio::time::timeout(Duration::from_secs(5), child.wait()).await
+            .map_err(|_| InjectorError::Clipboard(format!("{} timed out", program)))?
+            .map_err(|e| InjectorError::Clipboard(format!("waiting for {}: {}", program, e)))?;
+        if status.success() {
+            return Ok(());
+        }
+        debug!("Clipboard tool {} failed with {}", program, status);
+    }
+
+    Err(InjectorError::Clipboard("no clipboard tool available (install wl-clipboard or xclip)".to_string()))
+}
+
+/// Sleep for a typing pause, skipping the sleep entirely when it is zero
+async fn pause(duration: Duration) {
+    if !duration.is_zero() {
+        sleep(duration).await;
+    }
+}
+
+/// Split the per-character typing delay into (shift settle, key hold, between characters)
+fn split_type_delay(delay_ms: u64) -> (Duration, Duration, Duration) {
+    (
+        Duration::from_millis(delay_ms / 3),
+        Duration::from_millis(delay_ms * 2 / 3),
+        Duration::from_millis(delay_ms),
+    )
+}
+
 #[async_trait::async_trait]
 impl Injector for UinputInjector {
-    async fn initialize(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    async fn initialize(&self) -> InjectorResult<()> {
         UinputInjector::initialize(self).await
     }
 
-    async fn key_press(&self, key_code: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    async fn key_press(&self, key_code: u16) -> InjectorResult<()> {
         UinputInjector::key_press(self, key_code).await
     }
 
-    async fn key_release(&self, key_code: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    async fn key_release(&self, key_code: u16) -> InjectorResult<()> {
         UinputInjector::key_release(self, key_code).await
     }
 
-    async fn mouse_press(&self, button: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    async fn mouse_press(&self, button: u16) -> InjectorResult<()> {
         UinputInjector::mouse_press(self, button).await
     }
 
-    async fn mouse_release(&self, button: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    async fn mouse_release(&self, button: u16) -> InjectorResult<()> {
         UinputInjector::mouse_release(self, button).await
     }
 
-    async fn mouse_move(&self, x: i32, y: i32) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    async fn mouse_move(&self, x: i32, y: i32) -> InjectorResult<()> {
         UinputInjector::mouse_move(self, x, y).await
     }
 
-    async fn mouse_scroll(&self, amount: i32) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    async fn mouse_move_absolute(&self, x: i32, y: i32) -> InjectorResult<()> {
+        UinputInjector::mouse_move_absolute(self, x, y).await
+    }
+
+    async fn mouse_scroll(&self, amount: i32) -> InjectorResult<()> {
         UinputInjector::mouse_scroll(self, amount).await
     }
 
-    async fn type_string(&self, text: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    async fn type_string(&self, text: &str) -> InjectorResult<()> {
         UinputInjector::type_string(self, text).await
     }
 
-    async fn execute_command(&self, command: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    async fn execute_command(&self, command: &str) -> InjectorResult<String> {
         UinputInjector::execute_command(self, command).await
     }
-}
 
-impl Drop for UinputInjector {
-    fn drop(&mut self) {
-        if let Ok(initialized) = self.initialized.try_read() {
-            if *initialized {
-                if let Ok(uinput_fd) = self.uinput_fd.try_read() {
-                    if let Some(fd) = *uinput_fd {
-                        info!("Destroying uinput virtual device");
-                        unsafe {
-                            libc::ioctl(fd, UI_DEV_DESTROY);
-                            libc::close(fd);
-                        }
-                    }
-                }
-            }
-        }
+    async fn press_keys_then_release(&self, keys: &[u16], hold: Duration) -> InjectorResult<()> {
+        UinputInjector::press_keys_then_release(self, keys, hold).await
+    }
+
+    async fn release_all(&self) -> InjectorResult<()> {
+        UinputInjector::release_all(self)
+    }
+
+    fn held_keys(&self) -> Vec<u16> {
+        UinputInjector::held_keys(self)
+    }
+
+    async fn shutdown(&self) -> InjectorResult<()> {
+        UinputInjector::shutdown(self).await
     }
 }
 
@@ -535,16 +1043,191 @@ mod tests {
         assert!(!*injector.initialized.read().unwrap());
     }
 
+    #[tokio::test]
+    async fn test_device_fd_closed_on_drop() {
+        let (mut reader, writer) = std::os::unix::net::UnixStream::pair().unwrap();
+
+        let injector = UinputInjector::new().unwrap();
+        *injector.uinput_device.write().unwrap() = Some(UinputDevice { fd: writer.into_raw_fd(), created: false });
+
+        // Dropping a clone keeps the device alive for the others
+        drop(injector.clone());
+        assert!(injector.write_frame(&[]).is_ok());
+
+        // Shutdown closes the fd deterministically, so the reader sees EOF
+        injector.shutdown().await.unwrap();
+        assert!(matches!(injector.write_frame(&[]), Err(InjectorError::NotInitialized)));
+
+        // Injecting after shutdown fails rather than creating a new device
+        let err = injector.key_press(30).await.unwrap_err();
+        assert!(matches!(err, InjectorError::ShutDown));
+        assert_eq!(err.kind(), ErrorKind::Unavailable);
+        assert!(injector.uinput_device.read().unwrap().is_none());
+
+        let mut written = Vec::new();
+        std::io::Read::read_to_end(&mut reader, &mut written).unwrap();
+        assert_eq!(written.len(), mem::size_of::<InputEvent>());
+    }
+
+    #[tokio::test]
+    async fn test_strokes_are_written_in_one_batch() {
+        let (mut reader, writer) = std::os::unix::net::UnixStream::pair().unwrap();
+
+        let injector = UinputInjector::new().unwrap();
+        injector.set_type_delay(0);
+        *injector.uinput_device.write().unwrap() = Some(UinputDevice { fd: writer.into_raw_fd(), created: false });
+        *injector.initialized.write().unwrap() = true;
+
+        // Shift down, A down, A up, Shift up, each its own frame
+        injector.type_string("A").await.unwrap();
+        injector.shutdown().await.unwrap();
+
+        let mut written = Vec::new();
+        std::io::Read::read_to_end(&mut reader, &mut written).unwrap();
+        let events: Vec<(u16, u16, i32)> = written.chunks(mem::size_of::<InputEvent>())
+            .map(|chunk| {
+                let event = unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const InputEvent) };
+                (event.type_, event.code, event.value)
+            })
+            .collect();
+        assert_eq!(events, vec![
+            (EV_KEY, KEY_LEFTSHIFT, 1), (EV_SYN, SYN_REPORT, 0),
+            (EV_KEY, 30, 1), (EV_SYN, SYN_REPORT, 0),
+            (EV_KEY, 30, 0), (EV_SYN, SYN_REPORT, 0),
+            (EV_KEY, KEY_LEFTSHIFT, 0), (EV_SYN, SYN_REPORT, 0),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_release_all_clears_held_keys() {
+        let (mut reader, writer) = std::os::unix::net::UnixStream::pair().unwrap();
+
+        let injector = UinputInjector::new().unwrap();
+        *injector.uinput_device.write().unwrap() = Some(UinputDevice { fd: writer.into_raw_fd(), created: false });
+        *injector.initialized.write().unwrap() = true;
+
+        injector.key_press(30).await.unwrap();
+        injector.key_press(42).await.unwrap();
+        injector.mouse_press(1).await.unwrap();
+        injector.key_release(30).await.unwrap();
+        assert_eq!(injector.held_keys(), vec![42, 272]);
+
+        injector.release_all().unwrap();
+        assert!(injector.held_keys().is_empty());
+
+        // Nothing left to release, so nothing more is written
+        injector.release_all().unwrap();
+        drop(injector);
+
+        let mut written = Vec::new();
+        std::io::Read::read_to_end(&mut reader, &mut written).unwrap();
+        let frame_sizes = [2, 2, 2, 2, 3];
+        assert_eq!(written.len(), frame_sizes.iter().sum::<usize>() * mem::size_of::<InputEvent>());
+    }
+
     #[tokio::test]
     async fn test_key_map_setup() {
         let injector = UinputInjector::new().unwrap();
         let key_map = injector.key_map.read().unwrap();
 
         // Check that basic keys are mapped
-        assert_eq!(key_map.get(&'a'), Some(&30));
-        assert_eq!(key_map.get(&'A'), Some(&30));
-        assert_eq!(key_map.get(&' '), Some(&57));
-        assert_eq!(key_map.get(&'1'), Some(&2));
+        assert_eq!(key_map.get(&'a').map(|k| k.code), Some(30));
+        assert_eq!(key_map.get(&'A').map(|k| k.code), Some(30));
+        assert_eq!(key_map.get(&' ').map(|k| k.code), Some(57));
+        assert_eq!(key_map.get(&'1').map(|k| k.code), Some(2));
+        assert!(key_map[&'A'].shift);
+    }
+
+    #[tokio::test]
+    async fn test_layout_selection() {
+        let injector = UinputInjector::with_layout(KeyboardLayout::De).unwrap();
+        let key_map = injector.key_map.read().unwrap();
+
+        // QWERTZ swaps Y and Z
+        assert_eq!(key_map.get(&'z').map(|k| k.code), Some(21));
+        assert_eq!(key_map.get(&'y').map(|k| k.code), Some(44));
+    }
+
+    #[test]
+    fn test_type_delay() {
+        let injector = UinputInjector::new().unwrap();
+        assert_eq!(injector.type_delay(), DEFAULT_TYPE_DELAY_MS);
+
+        // The default keeps the original 10/20/30ms timings
+        assert_eq!(
+            split_type_delay(DEFAULT_TYPE_DELAY_MS),
+            (Duration::from_millis(10), Duration::from_millis(20), Duration::from_millis(30))
+        );
+
+        // Clones share the setting
+        let clone = injector.clone();
+        clone.set_type_delay(0);
+        assert_eq!(injector.type_delay(), 0);
+    }
+
+    #[test]
+    fn test_unicode_strategy() {
+        let injector = UinputInjector::new().unwrap();
+        assert_eq!(injector.unicode_strategy, UnicodeStrategy::Drop);
+
+        let injector = injector.with_unicode_strategy(UnicodeStrategy::CtrlShiftU);
+        assert_eq!(injector.unicode_strategy, UnicodeStrategy::CtrlShiftU);
+
+        let strategy: UnicodeStrategy = serde_yaml::from_str("ctrl_shift_u").unwrap();
+        assert_eq!(strategy, UnicodeStrategy::CtrlShiftU);
+
+        // Codepoints are entered as lowercase hex and every digit is typeable
+        assert_eq!(unicode_hex('€'), "20ac");
+        assert_eq!(unicode_hex('😀'), "1f600");
+        for digit in unicode_hex('😀').chars() {
+            assert!(injector.mapped_key(digit).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_typed_errors() {
+        let injector = UinputInjector::new().unwrap();
+
+        assert!(matches!(injector.mapped_key('€'), Err(InjectorError::UnmappedChar('€'))));
+
+        let err = injector.execute_command("rm -rf /").await.unwrap_err();
+        assert!(matches!(&err, InjectorError::CommandNotAllowed(program) if program == "rm"));
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(matches!(injector.execute_command("  ").await, Err(InjectorError::CommandFailed(_))));
+    }
+
+    #[test]
+    fn test_absolute_position_clamping() {
+        let injector = UinputInjector::new().unwrap();
+        assert_eq!(*injector.screen_size.read().unwrap(), DEFAULT_SCREEN_SIZE);
+
+        injector.set_screen_size(2560, 1440);
+        let screen = *injector.screen_size.read().unwrap();
+        assert_eq!(clamp_to_screen(100, 200, screen), (100, 200));
+        assert_eq!(clamp_to_screen(-5, 5000, screen), (0, 1439));
+
+        assert_eq!(parse_drm_mode("2560x1440\n"), Some((2560, 1440)));
+        assert_eq!(parse_drm_mode("1920x1080i"), Some((1920, 1080)));
+        assert_eq!(parse_drm_mode("garbage"), None);
+    }
+
+    #[test]
+    fn test_preflight_uinput() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let missing = dir.path().join("uinput");
+        assert!(matches!(preflight_uinput(&missing), Err(UinputPreflightError::ModuleNotLoaded(_))));
+        assert!(preflight_uinput(&missing).unwrap_err().to_string().contains("modprobe uinput"));
+
+        std::fs::write(&missing, b"").unwrap();
+        assert!(preflight_uinput(&missing).is_ok());
+
+        // Root can write to read-only files, so only check this as a user
+        std::fs::set_permissions(&missing, std::fs::Permissions::from_mode(0o444)).unwrap();
+        if !nix::unistd::geteuid().is_root() {
+            assert!(matches!(preflight_uinput(&missing), Err(UinputPreflightError::PermissionDenied(_))));
+        }
     }
 
     // Note: Actual injection tests require root privileges and /dev/uinput access
@@ -1,11 +1,13 @@
-use razermapper_common::{tracing, serialize, deserialize, Request, Response};
+use razermapper_common::{tracing, decode_frame_with_header, encode_frame_with_header, validate_key_code, validate_profile_name, ErrorKind, FrameHeader, KeyCombo, RemapScope, Request, Response, WireFormat, PROTOCOL_VERSION};
+use std::future::Future;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{UnixListener, UnixStream};
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 use tokio::task;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, warn, Instrument};
 
 use crate::macro_engine;
 use crate::config;
@@ -13,6 +15,9 @@ use crate::injector;
 use crate::security;
 // crate::device used via DaemonState.device_manager
 
+/// Longest a client may wait for a trigger to be captured
+pub const MAX_CAPTURE_TIMEOUT: Duration = Duration::from_secs(60);
+
 /// IPC server for handling communication with GUI clients
 pub struct IpcServer {
     socket_path: String,
@@ -156,93 +161,298 @@ pub async fn handle_client(
     // Note: set_keepalive is not available on UnixStream in this version of tokio
     // stream.set_keepalive(Some(std::time::Duration::from_secs(30)))?;
 
-    // Read message length first
-    let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf).await?;
-    let msg_len = u32::from_le_bytes(len_buf) as usize;
-
-    // Validate message length to prevent excessive memory usage
-    if msg_len > 1024 * 1024 { // 1MB max message size
-        warn!("Received oversized message: {} bytes", msg_len);
-        return Err("Message too large".into());
-    }
-
-    // Read the actual message
-    let mut msg_buf = vec![0u8; msg_len];
-    stream.read_exact(&mut msg_buf).await?;
-
-    // Deserialize the request
-    let request: Request = deserialize(&msg_buf)?;
-    debug!("Received request: {:?}", request);
-
     // Check authentication if token auth is enabled
     let auth_required = cfg!(feature = "token-auth");
+    let mut authenticated = false;
+
+    // Limit how fast, how much and how slowly this connection may send
+    let (max_requests_per_second, max_message_bytes, read_timeout, allow_remote_shutdown) = {
+        let config = config_manager.config().await;
+        let read_timeout = Some(config.security.client_read_timeout_ms)
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis);
+        (
+            config.security.max_requests_per_second,
+            config.security.max_message_bytes,
+            read_timeout,
+            config.security.allow_remote_shutdown,
+        )
+    };
+    let mut rate_limiter = security::RateLimiter::new(max_requests_per_second);
+
+    // Serve requests until the client disconnects
+    loop {
+        // Read message length first; EOF here is a clean disconnect
+        let mut len_buf = [0u8; 4];
+        match with_read_timeout(read_timeout, stream.read_exact(&mut len_buf)).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                debug!("Client disconnected");
+                return Ok(());
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                info!("Closing idle client connection");
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let msg_len = u32::from_le_bytes(len_buf) as usize;
+
+        // No frame is empty, and an empty one has no header to echo
+        if msg_len == 0 {
+            warn!("Received an empty message");
+            let response = Response::error(ErrorKind::InvalidRequest, "Empty message");
+            send_response(&mut stream, &response, FrameHeader::from(WireFormat::Bincode)).await?;
+            continue;
+        }
 
-    if auth_required {
-        // Handle authentication request
-        if let Request::Authenticate { token } = &request {
-            let security = security_manager.read().await;
-            if security.validate_auth_token(token).await {
-                debug!("Authentication successful");
-                let response = Response::Authenticated;
-                let response_bytes = serialize(&response);
+        // Validate message length to prevent excessive memory usage. The frame
+        // is skipped rather than buffered so the client still gets a reply.
+        if msg_len > max_message_bytes {
+            warn!("Received oversized message: {} bytes", msg_len);
+            let header = match with_read_timeout(read_timeout, skip_frame(&mut stream, msg_len)).await {
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    warn!("Client stalled in the middle of a request, closing connection");
+                    return Ok(());
+                }
+                result => result?,
+            };
+            let response = Response::error(ErrorKind::MessageTooLarge, format!(
+                "Message of {} bytes exceeds the daemon's limit of {} bytes", msg_len, max_message_bytes
+            ));
+            send_response(&mut stream, &response, header).await?;
+            continue;
+        }
 
-                // Send the response length first
-                let len = response_bytes.len() as u32;
-                stream.write_all(&len.to_le_bytes()).await?;
+        // Read the actual message
+        let mut msg_buf = vec![0u8; msg_len];
+        match with_read_timeout(read_timeout, stream.read_exact(&mut msg_buf)).await {
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                warn!("Client stalled in the middle of a request, closing connection");
+                return Ok(());
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                warn!("Client disconnected in the middle of a {} byte request", msg_len);
+                return Ok(());
+            }
+            result => result?,
+        };
+
+        // Deserialize the request; a malformed frame closes this connection only.
+        // Responses go back in whichever encoding the request used, with its id.
+        let (request, header): (Request, FrameHeader) = match decode_frame_with_header(&msg_buf) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                warn!("Malformed request, closing connection: {}", e);
+                return Err(e.into());
+            }
+        };
+        debug!("Received request {}: {:?}", request_label(header.request_id), request);
 
-                // Send the response
-                stream.write_all(&response_bytes).await?;
-                stream.flush().await?;
+        if !rate_limiter.try_acquire() {
+            debug!("Client exceeded {} requests per second", max_requests_per_second);
+            send_response(&mut stream, &Response::error(ErrorKind::RateLimited, "rate limited"), header).await?;
+            continue;
+        }
 
+        // Version handshake, answered before authentication. A client speaking
+        // another protocol can't be understood, so its connection is closed.
+        if let Request::Hello { client_version } = request {
+            let response = hello_response(client_version);
+            send_response(&mut stream, &response, header).await?;
+            if matches!(response, Response::ErrorCode { .. }) {
+                warn!("Closing connection from client with protocol version {}", client_version);
                 return Ok(());
-            } else {
-                debug!("Authentication failed");
-                let response = Response::Error("Invalid authentication token".to_string());
-                let response_bytes = serialize(&response);
+            }
+            continue;
+        }
 
-                // Send the response length first
-                let len = response_bytes.len() as u32;
-                stream.write_all(&len.to_le_bytes()).await?;
+        // Token ids and expiries are only shown to root, and only root may revoke
+        if matches!(request, Request::ListTokens | Request::RevokeToken { .. }) && !peer_is_root(&stream) {
+            let response = Response::error(ErrorKind::PermissionDenied, "Managing tokens requires root privileges");
+            send_response(&mut stream, &response, header).await?;
+            continue;
+        }
 
-                // Send the response
-                stream.write_all(&response_bytes).await?;
-                stream.flush().await?;
+        if auth_required && !authenticated {
+            // Handle authentication request
+            if let Request::Authenticate { token } = &request {
+                let security = security_manager.read().await;
+                let response = if security.validate_auth_token(token).await {
+                    debug!("Authentication successful");
+                    authenticated = true;
+                    Response::Authenticated
+                } else {
+                    debug!("Authentication failed");
+                    Response::error(ErrorKind::AuthRequired, "Invalid authentication token")
+                };
+                send_response(&mut stream, &response, header).await?;
+                continue;
+            }
+            // Allow GenerateToken without authentication
+            else if !matches!(request, Request::GenerateToken { .. }) {
+                debug!("Authentication required but not provided");
+                let response = Response::error(ErrorKind::AuthRequired, "Authentication required");
+                send_response(&mut stream, &response, header).await?;
+                continue;
+            }
+        }
 
-                return Ok(());
+        let is_shutdown = matches!(request, Request::Shutdown);
+        if is_shutdown {
+            if let Some(refusal) = shutdown_refusal(peer_is_root(&stream), allow_remote_shutdown) {
+                send_response(&mut stream, &refusal, header).await?;
+                continue;
             }
         }
-        // Allow GenerateToken without authentication
-        else if !matches!(request, Request::GenerateToken { .. }) {
-            debug!("Authentication required but not provided");
-            let response = Response::Error("Authentication required".to_string());
-            let response_bytes = serialize(&response);
 
-            // Send the response length first
-            let len = response_bytes.len() as u32;
-            stream.write_all(&len.to_le_bytes()).await?;
+        // Event subscriptions keep the connection open and stream responses
+        if let Request::SubscribeEvents { device_path } = request {
+            return stream_input_events(stream, state, device_path, header).await;
+        }
 
-            // Send the response
-            stream.write_all(&response_bytes).await?;
-            stream.flush().await?;
+        // Process the request and generate a response; everything it logs
+        // carries the request id
+        let response = handle_request(
+            request,
+            Arc::clone(&state),
+            Arc::clone(&macro_engine),
+            Arc::clone(&injector),
+            Arc::clone(&config_manager),
+            Arc::clone(&security_manager)
+        )
+        .instrument(tracing::info_span!("request", id = header.request_id))
+        .await;
+        debug!("Sending response {}: {:?}", request_label(header.request_id), response);
 
+        send_response(&mut stream, &response, header).await?;
+        if is_shutdown && matches!(response, Response::Ack) {
             return Ok(());
         }
     }
+}
+
+/// Refusal for a client that may not shut the daemon down, if it may not
+///
+/// Root may always do so. Anyone else needs `allow_remote_shutdown`, even
+/// with token auth, since any client on the socket can generate a token.
+fn shutdown_refusal(peer_is_root: bool, allow_remote_shutdown: bool) -> Option<Response> {
+    (!peer_is_root && !allow_remote_shutdown).then(|| Response::error(
+        ErrorKind::PermissionDenied,
+        "Shutting the daemon down requires root privileges, or security.allow_remote_shutdown to let other clients do it",
+    ))
+}
+
+/// Await a read, failing with `TimedOut` if it takes longer than `read_timeout`
+async fn with_read_timeout<T>(
+    read_timeout: Option<Duration>,
+    read: impl Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    match read_timeout {
+        Some(limit) => tokio::time::timeout(limit, read)
+            .await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into())),
+        None => read.await,
+    }
+}
+
+/// Discard a frame payload of `len` bytes, returning the format and request id it starts with
+async fn skip_frame(stream: &mut UnixStream, len: usize) -> std::io::Result<FrameHeader> {
+    // Enough for the format byte and a request id
+    let mut header_bytes = [0u8; 9];
+    let header_len = header_bytes.len().min(len);
+    stream.read_exact(&mut header_bytes[..header_len]).await?;
+    let remaining = (len - header_len) as u64;
+    let skipped = tokio::io::copy(&mut (&mut *stream).take(remaining), &mut tokio::io::sink()).await?;
+    if skipped < remaining {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(FrameHeader::parse(&header_bytes[..header_len]).map(|(header, _)| header).unwrap_or_default())
+}
+
+/// How a request id reads in the log, e.g. "#42"
+fn request_label(request_id: Option<u64>) -> String {
+    request_id.map_or_else(|| "without id".to_string(), |id| format!("#{}", id))
+}
+
+/// Whether the process on the other end of the socket runs as root
+fn peer_is_root(stream: &UnixStream) -> bool {
+    stream.peer_cred().map(|cred| cred.uid() == 0).unwrap_or(false)
+}
+
+/// Error kind for a failed profile operation: missing profiles are NotFound
+fn profile_error_kind(config_manager: &config::ConfigManager, name: &str) -> ErrorKind {
+    if config_manager.profile_exists(name) {
+        ErrorKind::Internal
+    } else {
+        ErrorKind::NotFound
+    }
+}
+
+/// Answer a version handshake, refusing clients that speak another protocol
+fn hello_response(client_version: u32) -> Response {
+    if client_version != PROTOCOL_VERSION {
+        return Response::error(ErrorKind::VersionMismatch, format!(
+            "Protocol version mismatch: client speaks version {}, daemon speaks version {}",
+            client_version, PROTOCOL_VERSION
+        ));
+    }
+
+    Response::Hello {
+        daemon_version: daemon_version(),
+        protocol: PROTOCOL_VERSION,
+    }
+}
+
+/// The daemon's package version packed as major * 10000 + minor * 100 + patch
+fn daemon_version() -> u32 {
+    let mut parts = env!("CARGO_PKG_VERSION").split('.').map(|part| part.parse::<u32>().unwrap_or(0));
+    let (major, minor, patch) = (parts.next().unwrap_or(0), parts.next().unwrap_or(0), parts.next().unwrap_or(0));
+    major * 10000 + minor * 100 + patch
+}
+
+/// Wait for the next key combination on the input event stream
+///
+/// Keys are collected while they're held and the combination is complete as
+/// soon as one of them is released. Modifiers go into `modifiers`, everything
+/// else into `keys`, including mouse buttons. Returns None if nothing was
+/// pressed before the timeout.
+async fn capture_key_combo(
+    mut events: broadcast::Receiver<(String, u16, bool)>,
+    timeout: Duration,
+) -> Option<KeyCombo> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut pressed: Vec<u16> = Vec::new();
+
+    loop {
+        match tokio::time::timeout_at(deadline, events.recv()).await {
+            Ok(Ok((_, key_code, true))) => {
+                if !pressed.contains(&key_code) {
+                    pressed.push(key_code);
+                }
+            }
+            // Releases of keys held before the capture started don't end it
+            Ok(Ok((_, _, false))) if pressed.is_empty() => {}
+            Ok(Ok((_, _, false))) => break,
+            Ok(Err(broadcast::error::RecvError::Lagged(skipped))) => {
+                warn!("Trigger capture lagged, dropped {} events", skipped);
+            }
+            // Keys still held when time runs out are taken as they are
+            Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => break,
+        }
+    }
+
+    if pressed.is_empty() {
+        return None;
+    }
 
-    // Process the request and generate a response
-    let response = handle_request(
-        request,
-        Arc::clone(&state),
-        Arc::clone(&macro_engine),
-        Arc::clone(&injector),
-        Arc::clone(&config_manager),
-        Arc::clone(&security_manager)
-    ).await;
-    debug!("Sending response: {:?}", response);
+    Some(KeyCombo::from_pressed(pressed))
+}
 
-    // Serialize the response
-    let response_bytes = serialize(&response);
+/// Write a single length-prefixed response frame to a client
+async fn send_response(stream: &mut UnixStream, response: &Response, header: FrameHeader) -> Result<(), std::io::Error> {
+    let response_bytes = encode_frame_with_header(response, header)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("failed to encode response: {}", e)))?;
 
     // Send the response length first
     let len = response_bytes.len() as u32;
@@ -250,11 +460,129 @@ pub async fn handle_client(
 
     // Send the response
     stream.write_all(&response_bytes).await?;
-    stream.flush().await?;
+    stream.flush().await
+}
+
+/// Stream live input events to a subscribed client
+///
+/// Uses the same framing and encoding as the subscribe request: every message
+/// is a 4-byte little-endian length followed by an encoded `Response`. The first
+/// frame is `Response::Ack`, confirming the subscription; every following
+/// frame is a `Response::InputEvent`. The stream ends when the client closes
+/// its end of the socket.
+async fn stream_input_events(
+    mut stream: UnixStream,
+    state: Arc<RwLock<crate::DaemonState>>,
+    device_filter: Option<String>,
+    header: FrameHeader,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut events, mut device_events) = {
+        let state = state.read().await;
+        (state.input_events.subscribe(), state.device_events.subscribe())
+    };
+    info!("Client subscribed to input events (device filter: {:?})", device_filter);
+
+    send_response(&mut stream, &Response::Ack, header).await?;
+
+    // Pushed events answer no request, so they carry no id
+    let format = FrameHeader::from(header.format);
+    let mut read_buf = [0u8; 64];
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                match event {
+                    Ok((device_path, key_code, pressed)) => {
+                        if device_filter.as_ref().is_some_and(|filter| *filter != device_path) {
+                            continue;
+                        }
+
+                        let response = Response::InputEvent { device_path, key_code, pressed };
+                        if let Err(e) = send_response(&mut stream, &response, format).await {
+                            debug!("Event subscriber went away: {}", e);
+                            break;
+                        }
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Event subscriber lagged, dropped {} events", skipped);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            notification = device_events.recv() => {
+                match notification {
+                    Ok(response) => {
+                        if let Err(e) = send_response(&mut stream, &response, format).await {
+                            debug!("Event subscriber went away: {}", e);
+                            break;
+                        }
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Event subscriber lagged, dropped {} device updates", skipped);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            // Subscribers don't send anything else, so any read result means they are done
+            _ = stream.read(&mut read_buf) => {
+                break;
+            }
+        }
+    }
 
+    info!("Client unsubscribed from input events");
     Ok(())
 }
 
+/// Make the config manager's macros the daemon's macros, replacing the ones
+/// in the daemon state and the engine
+///
+/// Called after the macros were loaded or reloaded from disk, so that a later
+/// `persist_macros` saves every macro rather than only those edited since.
+///
+/// Device restrictions still naming an event node are migrated to the stable
+/// id of the device now at that node, and the migrated macros saved.
+pub async fn apply_loaded_macros(
+    state: &Arc<RwLock<crate::DaemonState>>,
+    macro_engine: &macro_engine::MacroEngine,
+    config_manager: &Arc<config::ConfigManager>,
+) {
+    let mut macros = config_manager.macros.read().await.clone();
+    let migrated = {
+        let state = state.read().await;
+        let devices = state.devices.read().await;
+        config::migrate_device_ids(macros.values_mut(), &devices)
+    };
+    if migrated > 0 {
+        info!("Migrated {} macros from event node paths to stable device ids", migrated);
+        config_manager.update_macros(macros.clone()).await;
+    }
+
+    *state.read().await.macros.write().await = macros.clone();
+    macro_engine.replace_macros(macros).await;
+}
+
+/// Stable id of the device at an event node, or the path itself if the device is unknown
+pub async fn stable_id_for_path(state: &crate::DaemonState, device_path: &str) -> String {
+    state.devices.read().await.iter()
+        .find(|d| d.path.to_string_lossy() == device_path && !d.stable_id.is_empty())
+        .map(|d| d.stable_id.clone())
+        .unwrap_or_else(|| device_path.to_string())
+}
+
+/// The macro engine settings that can be changed at runtime
+fn engine_config(macro_engine: &macro_engine::MacroEngine) -> Response {
+    Response::EngineConfig {
+        max_concurrent_macros: macro_engine.max_concurrent_macros(),
+        default_delay: macro_engine.default_delay(),
+    }
+}
+
+/// Hand the daemon's current macros to the config manager so they are saved
+async fn persist_macros(state: &Arc<RwLock<crate::DaemonState>>, config_manager: &Arc<config::ConfigManager>) {
+    let macros = state.read().await.macros.read().await.clone();
+    config_manager.update_macros(macros).await;
+}
+
 /// Process a request and generate a response
 async fn handle_request(
     request: Request,
@@ -265,12 +593,13 @@ async fn handle_request(
     security_manager: Arc<RwLock<security::SecurityManager>>,
 ) -> Response {
     match request {
+        Request::Hello { client_version } => hello_response(client_version),
         Request::GenerateToken { client_id } => {
             debug!("Generating token for client: {}", client_id);
             let security = security_manager.read().await;
             match security.generate_auth_token().await {
                 Ok(token) => Response::Token(token),
-                Err(e) => Response::Error(format!("Failed to generate token: {}", e)),
+                Err(e) => Response::error(ErrorKind::Internal, format!("Failed to generate token: {}", e)),
             }
         }
         Request::Authenticate { token } => {
@@ -278,62 +607,333 @@ async fn handle_request(
             if security.validate_auth_token(&token).await {
                 Response::Authenticated
             } else {
-                Response::Error("Invalid authentication token".to_string())
+                Response::error(ErrorKind::AuthRequired, "Invalid authentication token")
+            }
+        }
+        Request::RevokeToken { id } => {
+            let security = security_manager.read().await;
+            if security.revoke_auth_token(&id).await {
+                Response::Ack
+            } else {
+                Response::error(ErrorKind::NotFound, "Token not found")
             }
         }
+        Request::ListTokens => {
+            let security = security_manager.read().await;
+            Response::Tokens(security.list_auth_tokens().await)
+        }
         Request::GetDevices => {
             let state = state.read().await;
-            let devices = state.devices.lock().unwrap().clone();
+            let devices = state.devices.read().await.clone();
+            Response::Devices(devices)
+        }
+        Request::GetRazerDevices => {
+            let state = state.read().await;
+            let devices = state.devices.read().await.iter()
+                .filter(|device| device.is_razer())
+                .cloned()
+                .collect();
             Response::Devices(devices)
         }
+        Request::GetDeviceDetails { device_path } => {
+            let state = state.read().await;
+            let Some(device_manager) = &state.device_manager else {
+                return Response::error(ErrorKind::Unavailable, "Device manager not initialized");
+            };
+            let dm = device_manager.read().await;
+            let Some(info) = dm.get_device(&device_path) else {
+                return Response::error(ErrorKind::NotFound, format!("Device not found: {}", device_path));
+            };
+            let stats = dm.event_stats(&device_path);
+            Response::DeviceDetails {
+                info,
+                grabbed: dm.is_grabbed(&device_path),
+                events_seen: stats.as_ref().map_or(0, |stats| stats.events_seen()),
+                last_event_ts: stats.and_then(|stats| stats.last_event_ms()),
+            }
+        }
         Request::ListMacros => {
             let state = state.read().await;
-            let macros = state.macros.lock().unwrap().values().cloned().collect();
+            let macros = state.macros.read().await.values().cloned().collect();
             return Response::Macros(macros);
         }
-        Request::SetMacro { device_path, macro_entry } => {
-            let state = state.write().await;
-
-            // Check if the device exists
-            let devices = state.devices.lock().unwrap();
-            let device_exists = devices.iter().any(|d| d.path.to_string_lossy() == device_path);
-            if !device_exists {
-                return Response::Error(format!("Device not found: {}", device_path));
+        Request::SetMaxConcurrent { n } => {
+            match macro_engine.set_max_concurrent_macros(n) {
+                Ok(()) => engine_config(&macro_engine),
+                Err(e) => Response::error(ErrorKind::InvalidRequest, e),
+            }
+        }
+        Request::GetEngineConfig => engine_config(&macro_engine),
+        Request::ListMacrosByTag { tag } => {
+            let state = state.read().await;
+            let macros = state.macros.read().await.values()
+                .filter(|m| m.has_tag(&tag))
+                .cloned()
+                .collect();
+            Response::Macros(macros)
+        }
+        Request::GetMacro { name } => {
+            let state = state.read().await;
+            let macro_entry = state.macros.read().await.get(&name).cloned();
+            match macro_entry {
+                Some(macro_entry) => Response::Macro(macro_entry),
+                None => Response::error(ErrorKind::NotFound, format!("Macro not found: {}", name)),
+            }
+        }
+        Request::SetMacro { device_path, mut macro_entry } => {
+            if let Err(e) = macro_engine.validate_macro(&macro_entry) {
+                return Response::error(ErrorKind::InvalidMacro, format!("Invalid macro '{}': {}", macro_entry.name, e));
             }
 
-            // Add or update the macro
-            let mut macros = state.macros.lock().unwrap();
-            macros.insert(macro_entry.name.clone(), macro_entry);
+            let created = {
+                let state = state.write().await;
 
-            return Response::Ack;
+                // Only new macros need their device present; an existing one
+                // may be edited while its device is unplugged
+                let devices = state.devices.read().await;
+                let created = !state.macros.read().await.contains_key(&macro_entry.name)
+                    && macro_engine.get_macro(&macro_entry.name).await.is_none();
+                if created && !devices.iter().any(|d| d.matches_device_id(&device_path)) {
+                    return Response::error(ErrorKind::NotFound, format!("Device not found: {}", device_path));
+                }
+
+                // Older clients restrict macros by event node
+                config::migrate_device_ids(std::iter::once(&mut macro_entry), &devices);
+
+                // Add or update the macro
+                let mut macros = state.macros.write().await;
+                macros.insert(macro_entry.name.clone(), macro_entry.clone());
+                created
+            };
+
+            // Keep the engine in step so triggers and tests run the edited actions
+            if let Err(e) = macro_engine.remove_macro(&macro_entry.name).await {
+                warn!("Failed to remove old version of macro {}: {}", macro_entry.name, e);
+            }
+            if let Err(e) = macro_engine.add_macro(macro_entry).await {
+                return Response::error(ErrorKind::Internal, format!("Failed to update macro engine: {}", e));
+            }
+
+            persist_macros(&state, &config_manager).await;
+            Response::MacroSet { created }
         }
         Request::DeleteMacro { name } => {
-            let state = state.write().await;
-
             // Find and remove the macro
-            let mut macros = state.macros.lock().unwrap();
-            let original_len = macros.len();
-            macros.remove(&name);
+            let removed = {
+                let state = state.write().await;
+                let mut macros = state.macros.write().await;
+                macros.remove(&name).is_some()
+            };
+
+            // A macro left in the engine would keep firing on its trigger
+            let removed_from_engine = match macro_engine.remove_macro(&name).await {
+                Ok(removed) => removed,
+                Err(e) => return Response::error(ErrorKind::Internal, format!("Failed to update macro engine: {}", e)),
+            };
 
-            if macros.len() == original_len {
-                return Response::Error(format!("Macro not found: {}", name));
+            if removed || removed_from_engine {
+                persist_macros(&state, &config_manager).await;
+                Response::Ack
             } else {
-                return Response::Ack;
+                Response::error(ErrorKind::NotFound, format!("Macro not found: {}", name))
+            }
+        }
+        Request::DuplicateMacro { source, new_name } => {
+            if new_name.trim().is_empty() {
+                return Response::error(ErrorKind::InvalidRequest, "Macro name must not be empty");
+            }
+
+            let copy = {
+                let state = state.read().await;
+                let mut macros = state.macros.write().await;
+                if macros.contains_key(&new_name) || macro_engine.get_macro(&new_name).await.is_some() {
+                    return Response::error(ErrorKind::AlreadyExists, format!("Macro '{}' already exists", new_name));
+                }
+                let Some(original) = macros.get(&source) else {
+                    return Response::error(ErrorKind::NotFound, format!("Macro not found: {}", source));
+                };
+
+                let mut copy = original.clone();
+                copy.name = new_name;
+                macros.insert(copy.name.clone(), copy.clone());
+                copy
+            };
+
+            if let Err(e) = macro_engine.add_macro(copy.clone()).await {
+                return Response::error(ErrorKind::Internal, format!("Failed to update macro engine: {}", e));
+            }
+
+            persist_macros(&state, &config_manager).await;
+            info!("Duplicated macro {} as {}", source, copy.name);
+            Response::Macro(copy)
+        }
+        Request::SetMacroEnabled { name, enabled } => {
+            let found_in_state = {
+                let state = state.read().await;
+                let mut macros = state.macros.write().await;
+                match macros.get_mut(&name) {
+                    Some(macro_entry) => {
+                        macro_entry.enabled = enabled;
+                        true
+                    }
+                    None => false,
+                }
+            };
+
+            if found_in_state {
+                persist_macros(&state, &config_manager).await;
+            }
+
+            match macro_engine.set_macro_enabled(&name, enabled).await {
+                Ok(found_in_engine) if found_in_state || found_in_engine => Response::Ack,
+                Ok(_) => Response::error(ErrorKind::NotFound, format!("Macro not found: {}", name)),
+                Err(e) => Response::error(ErrorKind::Internal, format!("Failed to update macro: {}", e)),
             }
         }
         Request::ReloadConfig => {
-            // This would trigger a config reload in a real implementation
             info!("Config reload requested");
-            return Response::Ack;
+            match config_manager.reload_from_disk().await.map_err(|e| e.to_string()) {
+                Ok(macros_count) => {
+                    apply_loaded_macros(&state, &macro_engine, &config_manager).await;
+
+                    info!("Reloaded configuration with {} macros", macros_count);
+                    Response::ConfigReloaded { macros_count }
+                }
+                Err(e) => {
+                    error!("Failed to reload configuration: {}", e);
+                    Response::error(ErrorKind::Internal, format!("Failed to reload configuration: {}", e))
+                }
+            }
         }
         Request::LedSet { device_path, color } => {
-            // This would set LED colors in a real implementation
-            info!("LED set request for {}: {:?}", device_path, color);
-            return Response::Ack;
+            let state = state.read().await;
+            if let Some(device_manager) = &state.device_manager {
+                let dm = device_manager.read().await;
+                match dm.set_led_color(&device_path, color) {
+                    Ok(_) => Response::Ack,
+                    Err(e) => {
+                        error!("Failed to set LED color on {}: {}", device_path, e);
+                        Response::error(e.kind(), format!("Failed to set LED color: {}", e))
+                    }
+                }
+            } else {
+                Response::error(ErrorKind::Unavailable, "Device manager not initialized")
+            }
+        }
+        Request::LedEffect { device_path, effect } => {
+            let state = state.read().await;
+            let Some(device_manager) = &state.device_manager else {
+                return Response::error(ErrorKind::Unavailable, "Device manager not initialized");
+            };
+            let result = device_manager.read().await.set_led_effect(&device_path, effect);
+            match result {
+                Ok(()) => Response::Ack,
+                Err(e) => {
+                    error!("Failed to set LED effect on {}: {}", device_path, e);
+                    Response::error(e.kind(), format!("Failed to set LED effect: {}", e))
+                }
+            }
+        }
+        Request::GetBatteryLevel { device_path } => {
+            let state = state.read().await;
+            let Some(device_manager) = &state.device_manager else {
+                return Response::error(ErrorKind::Unavailable, "Device manager not initialized");
+            };
+            let result = device_manager.read().await.battery_level(&device_path);
+            match result {
+                Ok((percent, charging)) => Response::Battery { percent, charging },
+                Err(e) => {
+                    debug!("Failed to read battery of {}: {}", device_path, e);
+                    Response::error(e.kind(), format!("Failed to read battery: {}", e))
+                }
+            }
+        }
+        Request::GetDpi { device_path } => {
+            let state = state.read().await;
+            let Some(device_manager) = &state.device_manager else {
+                return Response::error(ErrorKind::Unavailable, "Device manager not initialized");
+            };
+            let result = device_manager.read().await.dpi(&device_path);
+            match result {
+                Ok((x, y)) => Response::Dpi { x, y },
+                Err(e) => {
+                    debug!("Failed to read DPI of {}: {}", device_path, e);
+                    Response::error(e.kind(), format!("Failed to read DPI: {}", e))
+                }
+            }
+        }
+        Request::SetDpi { device_path, dpi } => {
+            let state = state.read().await;
+            let Some(device_manager) = &state.device_manager else {
+                return Response::error(ErrorKind::Unavailable, "Device manager not initialized");
+            };
+            let result = device_manager.read().await.set_dpi(&device_path, dpi);
+            match result {
+                Ok(()) => Response::Ack,
+                Err(e) => {
+                    error!("Failed to set DPI on {}: {}", device_path, e);
+                    Response::error(e.kind(), format!("Failed to set DPI: {}", e))
+                }
+            }
+        }
+        Request::RemapKeyCode { from, to, scope } => {
+            if let Err(e) = validate_key_code(from).and_then(|_| validate_key_code(to)) {
+                return Response::error(ErrorKind::InvalidRequest, format!("Invalid key code: {}", e));
+            }
+            if from == to {
+                return Response::error(ErrorKind::InvalidRequest, "Key codes to swap must differ");
+            }
+
+            let remapped = {
+                let state = state.read().await;
+                let mut macros = state.macros.write().await;
+                if let RemapScope::Macro(name) = &scope {
+                    if !macros.contains_key(name) {
+                        return Response::error(ErrorKind::NotFound, format!("Macro not found: {}", name));
+                    }
+                }
+                let (mut macros_changed, mut actions_changed) = (0, 0);
+                for macro_entry in macros.values_mut() {
+                    if matches!(&scope, RemapScope::Macro(name) if *name != macro_entry.name) {
+                        continue;
+                    }
+                    let (trigger_changed, actions) = macro_entry.remap_key_code(from, to);
+                    if trigger_changed || actions > 0 {
+                        macros_changed += 1;
+                        actions_changed += actions;
+                    }
+                }
+                (macros_changed > 0).then(|| macros.clone()).map(|all| (all, macros_changed, actions_changed))
+            };
+
+            match remapped {
+                Some((macros, macros_changed, actions_changed)) => {
+                    macro_engine.replace_macros(macros).await;
+                    persist_macros(&state, &config_manager).await;
+                    info!("Remapped key code {} to {} in {} macros ({} actions)", from, to, macros_changed, actions_changed);
+                    Response::KeyCodeRemapped { macros: macros_changed, actions: actions_changed }
+                }
+                None => Response::KeyCodeRemapped { macros: 0, actions: 0 },
+            }
         }
-        Request::RecordMacro { device_path, name } => {
+        Request::RecordMacro { device_path, name, capture_trigger } => {
+            // Only grabbed devices deliver events, so recording anything else captures nothing
+            {
+                let state = state.read().await;
+                let Some(device_manager) = &state.device_manager else {
+                    return Response::error(ErrorKind::Unavailable, "Device manager not initialized");
+                };
+                if !device_manager.read().await.is_grabbed(&device_path) {
+                    return Response::error(
+                        ErrorKind::InvalidRequest,
+                        format!("Device {} is not grabbed, grab it before recording", device_path),
+                    );
+                }
+            }
+
             // Start macro recording
-            match macro_engine.start_recording(name.clone(), device_path.clone()).await.map_err(|e| format!("Failed to start recording: {}", e)) {
+            let device_id = stable_id_for_path(&*state.read().await, &device_path).await;
+            match macro_engine.start_recording(name.clone(), device_id, capture_trigger).await.map_err(|e| format!("Failed to start recording: {}", e)) {
                 Ok(_) => {
                     info!("Macro recording started for {} on {}", name, device_path);
 
@@ -345,7 +945,7 @@ async fn handle_request(
                 }
                 Err(e) => {
                     error!("Failed to start recording: {}", e);
-                    return Response::Error(format!("Failed to start recording: {}", e));
+                    return Response::error(ErrorKind::DeviceBusy, format!("Failed to start recording: {}", e));
                 }
             }
         }
@@ -356,14 +956,17 @@ async fn handle_request(
                     let macro_name = macro_entry.name.clone();
                     info!("Macro recording stopped: {}", macro_name);
 
-                    // Update the daemon state to remove the active recording
-                    let mut state = state.write().await;
-                    state.active_recording = None;
+                    {
+                        // Update the daemon state to remove the active recording
+                        let mut state = state.write().await;
+                        state.active_recording = None;
 
-                    // Add the macro to the daemon state
-                    let mut macros = state.macros.lock().unwrap();
-                    macros.insert(macro_entry.name.clone(), macro_entry.clone());
-                    drop(macros);
+                        // Add the macro to the daemon state
+                        let mut macros = state.macros.write().await;
+                        macros.insert(macro_entry.name.clone(), macro_entry.clone());
+                    }
+
+                    persist_macros(&state, &config_manager).await;
 
                     return Response::RecordingStopped { macro_entry };
                 }
@@ -372,17 +975,29 @@ async fn handle_request(
                     let mut state = state.write().await;
                     state.active_recording = None;
 
-                    return Response::Error("Recording stopped but no macro was created".to_string());
+                    return Response::error(ErrorKind::NotFound, "Recording stopped but no macro was created");
                 }
                 Err(e) => {
                     error!("Failed to stop recording: {}", e);
-                    return Response::Error(format!("Failed to stop recording: {}", e));
+                    return Response::error(ErrorKind::Internal, format!("Failed to stop recording: {}", e));
                 }
             }
         }
-        Request::TestMacro { name } => {
+        Request::CaptureTrigger { timeout_ms } => {
+            let timeout = Duration::from_millis(timeout_ms).min(MAX_CAPTURE_TIMEOUT);
+            let events = state.read().await.input_events.subscribe();
+            info!("Capturing a trigger for up to {} ms", timeout.as_millis());
+            match capture_key_combo(events, timeout).await {
+                Some(combo) => Response::TriggerCaptured(combo),
+                None => Response::error(ErrorKind::Timeout, format!("No key combination pressed within {} ms", timeout.as_millis())),
+            }
+        }
+        Request::TestMacro { name, simulate, speed } => {
             // Test macro execution
             info!("Test macro execution requested: {}", name);
+            if let Err(e) = macro_engine::check_replay_speed(speed) {
+                return Response::error(ErrorKind::InvalidRequest, e.to_string());
+            }
             // Get the macro to execute
             let macro_to_execute = {
                 let macros = macro_engine.list_macros().await;
@@ -390,29 +1005,35 @@ async fn handle_request(
             };
 
             match macro_to_execute {
+                Some(macro_entry) if simulate => {
+                    Response::MacroTrace(macro_engine.simulate_macro(&macro_entry))
+                }
                 Some(macro_entry) => {
                     // Execute macro using macro engine
                     debug!("Macro execution requested: {}", macro_entry.name);
-                    match macro_engine.execute_macro(macro_entry.clone()).await {
+                    match macro_engine.execute_macro(macro_entry.clone(), speed).await {
                         Ok(_) => {
                             info!("Successfully executed macro: {}", macro_entry.name);
                             Response::Ack
                         }
                         Err(e) => {
                             error!("Failed to execute macro '{}': {}", macro_entry.name, e);
-                            Response::Error(format!("Failed to execute macro '{}': {}", macro_entry.name, e))
+                            Response::error(macro_engine::error_kind(e.as_ref()), format!("Failed to execute macro '{}': {}", macro_entry.name, e))
                         }
                     }
                 }
                 None => {
                     error!("Macro not found: {}", name);
-                    Response::Error(format!("Macro not found: {}", name))
+                    Response::error(ErrorKind::NotFound, format!("Macro not found: {}", name))
                 }
             }
         }
-        Request::ExecuteMacro { name } => {
+        Request::ExecuteMacro { name, speed } => {
             // Execute macro by name
             info!("Execute macro requested: {}", name);
+            if let Err(e) = macro_engine::check_replay_speed(speed) {
+                return Response::error(ErrorKind::InvalidRequest, e.to_string());
+            }
             // Get the macro to execute
             let macro_to_execute = {
                 let macros = macro_engine.list_macros().await;
@@ -422,30 +1043,76 @@ async fn handle_request(
             match macro_to_execute {
                 Some(macro_entry) => {
                     // Use execute_macro method instead of manually executing actions
-                    match macro_engine.execute_macro(macro_entry.clone()).await {
+                    match macro_engine.execute_macro(macro_entry.clone(), speed).await {
                         Ok(_) => {
                             info!("Successfully executed macro: {}", macro_entry.name);
                             Response::Ack
                         }
                         Err(e) => {
                             error!("Failed to execute macro '{}': {}", macro_entry.name, e);
-                            Response::Error(format!("Failed to execute macro '{}': {}", macro_entry.name, e))
+                            Response::error(macro_engine::error_kind(e.as_ref()), format!("Failed to execute macro '{}': {}", macro_entry.name, e))
                         }
                     }
                 }
                 None => {
                     error!("Macro not found: {}", name);
-                    Response::Error(format!("Macro not found: {}", name))
+                    Response::error(ErrorKind::NotFound, format!("Macro not found: {}", name))
+                }
+            }
+        }
+        Request::StopMacro { name } => {
+            info!("Stop macro requested: {}", name);
+            match macro_engine.stop_macro(&name).await {
+                Ok(true) => Response::Ack,
+                Ok(false) => Response::error(ErrorKind::NotFound, format!("Macro not running: {}", name)),
+                Err(e) => {
+                    error!("Failed to stop macro '{}': {}", name, e);
+                    Response::error(ErrorKind::Internal, format!("Failed to stop macro '{}': {}", name, e))
+                }
+            }
+        }
+        Request::StopAllMacros => {
+            info!("Stop all macros requested");
+            let mut stopped = 0;
+            let mut failed = Vec::new();
+            for name in macro_engine.get_executing_macros().await {
+                match macro_engine.stop_macro(&name).await {
+                    Ok(true) => stopped += 1,
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!("Failed to stop macro '{}': {}", name, e);
+                        failed.push(name);
+                    }
                 }
             }
+            if failed.is_empty() {
+                Response::Stopped(stopped)
+            } else {
+                Response::error(
+                    ErrorKind::Internal,
+                    format!("Stopped {} macros but failed to stop: {}", stopped, failed.join(", ")),
+                )
+            }
+        }
+        Request::SubscribeEvents { .. } => {
+            // Subscriptions are streamed directly by handle_client
+            Response::error(ErrorKind::InvalidRequest, "Event subscriptions require a streaming connection")
+        }
+        Request::Shutdown => {
+            // handle_client has checked the client may do this and closes
+            // the connection after the reply
+            info!("Shutdown requested by client");
+            state.read().await.shutdown_requested.notify_one();
+            Response::Ack
         }
+        Request::GetMetrics => Response::Metrics(macro_engine.get_metrics().await),
         Request::GetStatus => {
             let state = state.read().await;
-            let devices_count = state.devices.lock().unwrap().len();
-            let macros_count = state.macros.lock().unwrap().len();
+            let devices_count = state.devices.read().await.len();
+            let macros_count = state.macros.read().await.len();
             return Response::Status {
-                version: "0.1.0".to_string(),
-                uptime_seconds: 0, // Would be calculated in real implementation
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                uptime_seconds: state.start_time.elapsed().as_secs(),
                 devices_count,
                 macros_count,
             };
@@ -454,7 +1121,7 @@ async fn handle_request(
             // Save current macros as a profile
             let macros_count = {
                 let state_guard = state.read().await;
-                let macros = state_guard.macros.lock().unwrap();
+                let macros = state_guard.macros.read().await;
                 let count = macros.len();
                 drop(macros);
                 drop(state_guard);
@@ -471,23 +1138,27 @@ async fn handle_request(
                 }
                 Err(e) => {
                     error!("Failed to save profile: {}", e);
-                    return Response::Error(format!("Failed to save profile: {}", e));
+                    return Response::error(ErrorKind::Internal, format!("Failed to save profile: {}", e));
                 }
             }
         }
         Request::LoadProfile { name } => {
             // Load a profile
-            match config_manager.load_profile(&name).await {
+            match config_manager.load_profile(&name).await.map_err(|e| e.to_string()) {
                 Ok(profile) => {
                     info!("Profile {} loaded", name);
+                    if let Err(e) = config_manager.remember_last_profile(&name).await {
+                        warn!("Failed to remember last loaded profile: {}", e);
+                    }
                     return Response::ProfileLoaded {
                         name,
-                        macros_count: profile.macros.len()
+                        macros_count: profile.macros.len(),
+                        description: profile.description,
                     };
                 }
                 Err(e) => {
                     error!("Failed to load profile: {}", e);
-                    return Response::Error(format!("Failed to load profile: {}", e));
+                    return Response::error(profile_error_kind(&config_manager, &name), format!("Failed to load profile: {}", e));
                 }
             }
         }
@@ -497,8 +1168,41 @@ async fn handle_request(
                 Ok(profiles) => return Response::Profiles(profiles),
                 Err(e) => {
                     error!("Failed to list profiles: {}", e);
-                    return Response::Error(format!("Failed to list profiles: {}", e));
+                    return Response::error(ErrorKind::Internal, format!("Failed to list profiles: {}", e));
+                }
+            }
+        }
+        Request::ExportProfile { name } => {
+            if let Err(e) = validate_profile_name(&name) {
+                return Response::error(ErrorKind::InvalidRequest, format!("Invalid profile: {}", e));
+            }
+            match config_manager.load_profile(&name).await {
+                Ok(profile) => Response::ProfileData(profile),
+                Err(e) => Response::error(profile_error_kind(&config_manager, &name), format!("Failed to export profile: {}", e)),
+            }
+        }
+        Request::ImportProfile { profile, overwrite } => {
+            if let Err(e) = profile.validate() {
+                return Response::error(ErrorKind::InvalidRequest, format!("Invalid profile: {}", e));
+            }
+            // Hold imported macros to the same configured limits as SetMacro
+            for macro_entry in profile.macros.values() {
+                if let Err(e) = macro_engine.validate_macro(macro_entry) {
+                    return Response::error(ErrorKind::InvalidRequest, format!("Invalid profile: macro '{}': {}", macro_entry.name, e));
+                }
+            }
+            if !overwrite && config_manager.profile_exists(&profile.name) {
+                return Response::error(ErrorKind::AlreadyExists, format!("Profile '{}' already exists", profile.name));
+            }
+            match config_manager.save_profile(&profile).await {
+                Ok(()) => {
+                    info!("Profile {} imported with {} macros", profile.name, profile.macros.len());
+                    Response::ProfileSaved {
+                        macros_count: profile.macros.len(),
+                        name: profile.name,
+                    }
                 }
+                Err(e) => Response::error(ErrorKind::Internal, format!("Failed to import profile: {}", e)),
             }
         }
         Request::DeleteProfile { name } => {
@@ -510,7 +1214,7 @@ async fn handle_request(
                 }
                 Err(e) => {
                     error!("Failed to delete profile: {}", e);
-                    return Response::Error(format!("Failed to delete profile: {}", e));
+                    return Response::error(profile_error_kind(&config_manager, &name), format!("Failed to delete profile: {}", e));
                 }
             }
         }
@@ -526,30 +1230,63 @@ async fn handle_request(
                     }
                     Err(e) => {
                         error!("Failed to grab device {}: {}", device_path, e);
-                        return Response::Error(format!("Failed to grab device: {}", e));
+                        return Response::error(ErrorKind::DeviceBusy, format!("Failed to grab device: {}", e));
                     }
                 }
             } else {
-                return Response::Error("Device manager not initialized".to_string());
+                return Response::error(ErrorKind::Unavailable, "Device manager not initialized");
             }
         }
-        Request::UngrabDevice { device_path } => {
-            // Release exclusive access to a device
+        Request::GrabAllDevices => {
             let state = state.read().await;
-            if let Some(device_manager) = &state.device_manager {
-                let mut dm = device_manager.write().await;
-                match dm.ungrab_device(&device_path).await {
-                    Ok(_) => {
-                        info!("Device {} ungrabbed successfully", device_path);
-                        return Response::Ack;
-                    }
-                    Err(e) => {
+            let Some(device_manager) = &state.device_manager else {
+                return Response::error(ErrorKind::Unavailable, "Device manager not initialized");
+            };
+            let results = device_manager.write().await.grab_all().await;
+            let grabbed = results.iter().filter(|(_, grabbed)| *grabbed).count();
+            info!("Grabbed {} of {} devices", grabbed, results.len());
+            Response::GrabResults(results)
+        }
+        Request::UngrabAllDevices => {
+            let state = state.read().await;
+            let Some(device_manager) = &state.device_manager else {
+                return Response::error(ErrorKind::Unavailable, "Device manager not initialized");
+            };
+            let results = device_manager.write().await.ungrab_all().await;
+            info!("Released {} devices", results.len());
+            Response::GrabResults(results)
+        }
+        Request::SetDeviceIgnoreKeys { device_path, keys } => {
+            if let Some(e) = keys.iter().find_map(|&code| validate_key_code(code).err()) {
+                return Response::error(ErrorKind::InvalidRequest, format!("Invalid ignore list: {}", e));
+            }
+            let state = state.read().await;
+            let Some(device_manager) = &state.device_manager else {
+                return Response::error(ErrorKind::Unavailable, "Device manager not initialized");
+            };
+            let result = device_manager.write().await.set_ignored_keys(&device_path, keys);
+            match result {
+                Ok(()) => Response::Ack,
+                Err(e) => Response::error(ErrorKind::NotFound, e),
+            }
+        }
+        Request::UngrabDevice { device_path } => {
+            // Release exclusive access to a device
+            let state = state.read().await;
+            if let Some(device_manager) = &state.device_manager {
+                let mut dm = device_manager.write().await;
+                match dm.ungrab_device(&device_path).await {
+                    Ok(_) => {
+                        info!("Device {} ungrabbed successfully", device_path);
+                        return Response::Ack;
+                    }
+                    Err(e) => {
                         error!("Failed to ungrab device {}: {}", device_path, e);
-                        return Response::Error(format!("Failed to ungrab device: {}", e));
+                        return Response::error(ErrorKind::Internal, format!("Failed to ungrab device: {}", e));
                     }
                 }
             } else {
-                return Response::Error("Device manager not initialized".to_string());
+                return Response::error(ErrorKind::Unavailable, "Device manager not initialized");
             }
         }
     }
@@ -572,20 +1309,15 @@ fn get_group_gid(group_name: &str) -> Option<u32> {
 mod tests {
     use super::*;
     use crate::DaemonState;
-    use razermapper_common::{DeviceInfo, MacroEntry, KeyCombo, Action};
+    use crate::testing::{InjectedAction, RecordingInjector};
+    use razermapper_common::{decode_frame, encode_frame, DeviceCapabilities, DeviceInfo, MacroEntry, KeyCombo, Action, Profile, TriggerEdge, WireFormat, DEFAULT_REPLAY_SPEED};
     use std::path::PathBuf;
     use std::sync::Arc;
     use tempfile::TempDir;
 
     // Helper function to create a test injector or skip the test if permissions are insufficient
-    fn create_test_injector() -> Arc<injector::UinputInjector> {
-        match injector::UinputInjector::new() {
-            Ok(injector) => Arc::new(injector),
-            Err(_) => {
-                // Skip test if we don't have permission to create injector
-                panic!("Test requires root access to create UinputInjector. Run with sudo or set CAP_SYS_ADMIN capability.");
-            }
-        }
+    fn create_test_injector() -> Arc<RwLock<dyn injector::Injector + Send + Sync>> {
+        RecordingInjector::new().shared()
     }
 
     // Helper function to create a test ConfigManager with temporary paths
@@ -601,14 +1333,31 @@ mod tests {
             macros_path,
             cache_path,
             profiles_dir,
-            config: config::DaemonConfig::default(),
+            config: Arc::new(tokio::sync::RwLock::new(config::DaemonConfig::default())),
             macros: Arc::new(tokio::sync::RwLock::new(std::collections::HashMap::new())),
             profiles: Arc::new(tokio::sync::RwLock::new(std::collections::HashMap::new())),
+            save_pending: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            known_mtimes: Arc::new(tokio::sync::Mutex::new([None, None])),
         };
 
         Arc::new(manager)
     }
 
+    // Helper function to create a ConfigManager whose files live in the given directory
+    fn config_manager_in(dir: &TempDir) -> Arc<config::ConfigManager> {
+        Arc::new(config::ConfigManager {
+            config_path: dir.path().join("config.yaml"),
+            macros_path: dir.path().join("macros.yaml"),
+            cache_path: dir.path().join("macros.bin"),
+            profiles_dir: dir.path().join("profiles"),
+            config: Arc::new(tokio::sync::RwLock::new(config::DaemonConfig::default())),
+            macros: Arc::new(tokio::sync::RwLock::new(std::collections::HashMap::new())),
+            profiles: Arc::new(tokio::sync::RwLock::new(std::collections::HashMap::new())),
+            save_pending: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            known_mtimes: Arc::new(tokio::sync::Mutex::new([None, None])),
+        })
+    }
+
     #[tokio::test]
     async fn test_ipc_server_creation() {
         let temp_dir = TempDir::new().unwrap();
@@ -633,7 +1382,7 @@ mod tests {
         // Test GetStatus request
         let response = handle_request(Request::GetStatus, Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
         match response {
-            Response::Status { version, .. } => assert_eq!(version, "0.1.0"),
+            Response::Status { version, .. } => assert_eq!(version, env!("CARGO_PKG_VERSION")),
             _ => panic!("Expected Status response"),
         }
 
@@ -647,6 +1396,10 @@ mod tests {
             actions: vec![Action::KeyPress(30)],
             device_id: None,
             enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
         };
 
         let response = handle_request(
@@ -662,7 +1415,7 @@ mod tests {
         ).await;
 
         match response {
-            Response::Error(msg) => assert!(msg.contains("not found")),
+            Response::ErrorCode { code: ErrorKind::NotFound, message: msg } => assert!(msg.contains("not found")),
             _ => panic!("Expected Error response"),
         }
     }
@@ -679,12 +1432,14 @@ mod tests {
         // Add a device first
         {
             let state = state.write().await;
-            state.devices.lock().unwrap().push(DeviceInfo {
+            state.devices.write().await.push(DeviceInfo {
                 name: "Test Device".to_string(),
                 path: PathBuf::from("/dev/input/test"),
                 vendor_id: 0x1234,
                 product_id: 0x5678,
                 phys: "test-phys".to_string(),
+                capabilities: DeviceCapabilities::default(),
+                stable_id: String::new(),
             });
         }
 
@@ -698,6 +1453,10 @@ mod tests {
             actions: vec![Action::KeyPress(30)],
             device_id: None,
             enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
         };
 
         let response = handle_request(
@@ -712,13 +1471,1428 @@ mod tests {
             Arc::clone(&security_manager)
         ).await;
 
-        assert!(matches!(response, Response::Ack));
+        assert!(matches!(response, Response::MacroSet { created: true }));
 
         // Verify the macro was added
         let state = state.read().await;
-        assert_eq!(state.macros.lock().unwrap().len(), 1);
-        let macros = state.macros.lock().unwrap();
+        assert_eq!(state.macros.read().await.len(), 1);
+        let macros = state.macros.read().await;
         let first_macro = macros.values().next().unwrap();
         assert_eq!(first_macro.name, test_macro.name);
     }
+
+    #[tokio::test]
+    async fn test_get_macro_request() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let test_macro = MacroEntry {
+            name: "Lookup".to_string(),
+            trigger: KeyCombo {
+                keys: vec![30],
+                modifiers: vec![],
+            },
+            actions: vec![Action::KeyPress(30), Action::KeyRelease(30)],
+            device_id: None,
+            enabled: false,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        state.read().await.macros.write().await.insert(test_macro.name.clone(), test_macro.clone());
+
+        let response = handle_request(Request::GetMacro { name: "Lookup".to_string() }, Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        match response {
+            Response::Macro(macro_entry) => assert_eq!(macro_entry, test_macro),
+            other => panic!("Expected Macro response, got {:?}", other),
+        }
+
+        let response = handle_request(Request::GetMacro { name: "Missing".to_string() }, state, macro_engine, injector, config_manager, security_manager).await;
+        match response {
+            Response::ErrorCode { code: ErrorKind::NotFound, message: msg } => assert!(msg.contains("Macro not found: Missing")),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_engine_config_requests() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+        let send = |request: Request| handle_request(request, Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager));
+
+        let response = send(Request::GetEngineConfig).await;
+        assert!(matches!(response, Response::EngineConfig { max_concurrent_macros: 10, default_delay: 10 }));
+
+        let response = send(Request::SetMaxConcurrent { n: 0 }).await;
+        assert!(matches!(response, Response::ErrorCode { code: ErrorKind::InvalidRequest, .. }));
+
+        let response = send(Request::SetMaxConcurrent { n: 3 }).await;
+        assert!(matches!(response, Response::EngineConfig { max_concurrent_macros: 3, .. }));
+        assert_eq!(macro_engine.max_concurrent_macros(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_set_macro_for_unplugged_device() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let mut recorded = MacroEntry {
+            name: "recorded".to_string(),
+            trigger: KeyCombo { keys: vec![30], modifiers: vec![] },
+            actions: vec![Action::KeyTap(31)],
+            device_id: Some("usb-Razer_Naga-event-mouse".to_string()),
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        macro_engine.add_macro(recorded.clone()).await.unwrap();
+        state.read().await.macros.write().await.insert(recorded.name.clone(), recorded.clone());
+        let set_macro = |macro_entry: MacroEntry| Request::SetMacro {
+            device_path: "usb-Razer_Naga-event-mouse".to_string(),
+            macro_entry,
+        };
+
+        // No devices are present, yet the existing macro can be edited
+        recorded.actions.push(Action::KeyTap(32));
+        let response = handle_request(set_macro(recorded.clone()), Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        assert!(matches!(response, Response::MacroSet { created: false }), "{:?}", response);
+        assert_eq!(macro_engine.get_macro("recorded").await, Some(recorded.clone()));
+
+        // Creating a new macro for the missing device still fails
+        let mut fresh = recorded.clone();
+        fresh.name = "fresh".to_string();
+        let response = handle_request(set_macro(fresh), Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        assert!(matches!(response, Response::ErrorCode { code: ErrorKind::NotFound, .. }));
+        assert!(!state.read().await.macros.read().await.contains_key("fresh"));
+    }
+
+    #[tokio::test]
+    async fn test_remap_key_code() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let caps_lock = |name: &str, trigger: Vec<u16>, actions: Vec<Action>| MacroEntry {
+            name: name.to_string(),
+            trigger: KeyCombo { keys: trigger, modifiers: vec![29] },
+            actions,
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        let entries = [
+            caps_lock("Trigger", vec![58], vec![Action::KeyTap(30)]),
+            caps_lock("Actions", vec![31], vec![
+                Action::KeyPress(58),
+                Action::Repeat { count: 2, actions: vec![Action::KeyTap(58), Action::Delay(5)] },
+                Action::IfKeyHeld { key_code: 58, then: vec![Action::Chord(vec![58, 30])], else_: vec![] },
+                Action::KeyRelease(58),
+            ]),
+            caps_lock("Untouched", vec![32], vec![Action::KeyTap(30)]),
+        ];
+        {
+            let state = state.read().await;
+            let mut macros = state.macros.write().await;
+            for entry in entries {
+                macro_engine.add_macro(entry.clone()).await.unwrap();
+                macros.insert(entry.name.clone(), entry);
+            }
+        }
+        let remap = |scope: RemapScope| Request::RemapKeyCode { from: 58, to: 1, scope };
+
+        // A named macro is remapped alone
+        let response = handle_request(remap(RemapScope::Macro("Trigger".to_string())), Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        assert!(matches!(response, Response::KeyCodeRemapped { macros: 1, actions: 0 }), "{:?}", response);
+        assert_eq!(macro_engine.get_macro("Trigger").await.unwrap().trigger.keys, vec![1]);
+        assert_eq!(state.read().await.macros.read().await["Actions"].actions[0], Action::KeyPress(58));
+
+        let response = handle_request(remap(RemapScope::All), Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        assert!(matches!(response, Response::KeyCodeRemapped { macros: 1, actions: 5 }), "{:?}", response);
+        let remapped = state.read().await.macros.read().await["Actions"].clone();
+        assert_eq!(remapped.actions, vec![
+            Action::KeyPress(1),
+            Action::Repeat { count: 2, actions: vec![Action::KeyTap(1), Action::Delay(5)] },
+            Action::IfKeyHeld { key_code: 1, then: vec![Action::Chord(vec![1, 30])], else_: vec![] },
+            Action::KeyRelease(1),
+        ]);
+        assert_eq!(macro_engine.get_macro("Actions").await.unwrap().actions, remapped.actions);
+        assert!(config_manager.macros.read().await["Actions"].actions.contains(&Action::KeyRelease(1)));
+
+        // Nothing left to change
+        let response = handle_request(remap(RemapScope::All), Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        assert!(matches!(response, Response::KeyCodeRemapped { macros: 0, actions: 0 }));
+
+        let response = handle_request(remap(RemapScope::Macro("Missing".to_string())), state, macro_engine, injector, config_manager, security_manager).await;
+        assert!(matches!(response, Response::ErrorCode { code: ErrorKind::NotFound, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_list_macros_by_tag() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let tagged = |name: &str, tags: &[&str]| MacroEntry {
+            name: name.to_string(),
+            trigger: KeyCombo { keys: vec![30], modifiers: vec![] },
+            actions: vec![Action::KeyPress(30)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+        };
+        {
+            let state = state.read().await;
+            let mut macros = state.macros.write().await;
+            for entry in [tagged("Deploy", &["Work"]), tagged("Reload", &["games", "work"]), tagged("Loose", &[])] {
+                macros.insert(entry.name.clone(), entry);
+            }
+        }
+
+        let response = handle_request(Request::ListMacrosByTag { tag: "work".to_string() }, Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        match response {
+            Response::Macros(macros) => {
+                let mut names: Vec<String> = macros.into_iter().map(|m| m.name).collect();
+                names.sort();
+                assert_eq!(names, vec!["Deploy", "Reload"]);
+            }
+            other => panic!("Expected Macros response, got {:?}", other),
+        }
+
+        let response = handle_request(Request::ListMacrosByTag { tag: "music".to_string() }, state, macro_engine, injector, config_manager, security_manager).await;
+        assert!(matches!(response, Response::Macros(macros) if macros.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_get_razer_devices_request() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let razer = DeviceInfo {
+            name: "Razer Keyboard".to_string(),
+            path: PathBuf::from("/dev/input/event3"),
+            vendor_id: 0x1532,
+            product_id: 0x0221,
+            phys: "usb-0000:00:14.0-1/input0".to_string(),
+            capabilities: DeviceCapabilities::default(),
+            stable_id: String::new(),
+        };
+        let other = DeviceInfo {
+            name: "Other Keyboard".to_string(),
+            path: PathBuf::from("/dev/input/event4"),
+            vendor_id: 0x046d,
+            ..razer.clone()
+        };
+        *state.read().await.devices.write().await = vec![razer, other];
+
+        let response = handle_request(Request::GetRazerDevices, Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        match response {
+            Response::Devices(devices) => {
+                assert_eq!(devices.len(), 1);
+                assert_eq!(devices[0].name, "Razer Keyboard");
+            }
+            other => panic!("Expected Devices response, got {:?}", other),
+        }
+
+        // The full list stays available
+        let response = handle_request(Request::GetDevices, state, macro_engine, injector, config_manager, security_manager).await;
+        assert!(matches!(response, Response::Devices(devices) if devices.len() == 2));
+    }
+
+    #[tokio::test]
+    async fn test_record_macro_requires_grabbed_device() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+        state.write().await.device_manager = Some(Arc::new(RwLock::new(crate::device::DeviceManager::new())));
+
+        let request = Request::RecordMacro {
+            device_path: "/dev/input/event5".to_string(),
+            name: "Unheard".to_string(),
+            capture_trigger: false,
+        };
+        let response = handle_request(request, Arc::clone(&state), Arc::clone(&macro_engine), injector, config_manager, security_manager).await;
+        match response {
+            Response::ErrorCode { code: ErrorKind::InvalidRequest, message } => assert!(message.contains("not grabbed")),
+            other => panic!("Expected InvalidRequest, got {:?}", other),
+        }
+
+        // Nothing was left recording
+        assert!(!macro_engine.is_recording().await);
+        assert!(state.read().await.active_recording.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_grab_all_devices_reports_each_device() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let response = handle_request(Request::GrabAllDevices, Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        assert!(matches!(response, Response::ErrorCode { code: ErrorKind::Unavailable, .. }));
+
+        // Neither node exists, so both grabs fail without stopping each other
+        let mut device_manager = crate::device::DeviceManager::new();
+        for path in ["/nonexistent/event91", "/nonexistent/event90"] {
+            device_manager.add_device(DeviceInfo {
+                name: "Razer Naga".to_string(),
+                path: PathBuf::from(path),
+                vendor_id: 0x1532,
+                product_id: 0x0067,
+                phys: String::new(),
+                capabilities: DeviceCapabilities::default(),
+                stable_id: String::new(),
+            });
+        }
+        state.write().await.device_manager = Some(Arc::new(RwLock::new(device_manager)));
+
+        let response = handle_request(Request::GrabAllDevices, Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        match response {
+            Response::GrabResults(results) => assert_eq!(results, vec![
+                ("/nonexistent/event90".to_string(), false),
+                ("/nonexistent/event91".to_string(), false),
+            ]),
+            other => panic!("Expected GrabResults, got {:?}", other),
+        }
+
+        let response = handle_request(Request::UngrabAllDevices, state, macro_engine, injector, config_manager, security_manager).await;
+        assert!(matches!(response, Response::GrabResults(results) if results.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_get_device_details_request() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let request = || Request::GetDeviceDetails { device_path: "/dev/input/event5".to_string() };
+        let response = handle_request(request(), Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        assert!(matches!(response, Response::ErrorCode { code: ErrorKind::Unavailable, .. }));
+
+        let mut device_manager = crate::device::DeviceManager::new();
+        device_manager.add_device(DeviceInfo {
+            name: "Razer Naga".to_string(),
+            path: PathBuf::from("/dev/input/event5"),
+            vendor_id: 0x1532,
+            product_id: 0x0067,
+            phys: String::new(),
+            capabilities: DeviceCapabilities::default(),
+            stable_id: String::new(),
+        });
+        state.write().await.device_manager = Some(Arc::new(RwLock::new(device_manager)));
+
+        let response = handle_request(request(), Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        match response {
+            Response::DeviceDetails { info, grabbed, events_seen, last_event_ts } => {
+                assert_eq!(info.name, "Razer Naga");
+                assert!(!grabbed);
+                assert_eq!(events_seen, 0);
+                assert_eq!(last_event_ts, None);
+            }
+            other => panic!("Expected DeviceDetails response, got {:?}", other),
+        }
+
+        let response = handle_request(Request::GetDeviceDetails { device_path: "/dev/input/event9".to_string() }, state, macro_engine, injector, config_manager, security_manager).await;
+        assert!(matches!(response, Response::ErrorCode { code: ErrorKind::NotFound, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_execute_macro_request_injects_actions() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let recorder = RecordingInjector::new();
+        let macro_engine = Arc::new(macro_engine::MacroEngine::with_injector(recorder.shared()));
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        macro_engine.add_macro(MacroEntry {
+            name: "Greet".to_string(),
+            trigger: KeyCombo {
+                keys: vec![30],
+                modifiers: vec![],
+            },
+            actions: vec![Action::KeyPress(35), Action::KeyRelease(35), Action::Type("hi".to_string())],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        }).await.unwrap();
+
+        let response = handle_request(Request::ExecuteMacro { name: "Greet".to_string(), speed: DEFAULT_REPLAY_SPEED }, state, macro_engine, injector, config_manager, security_manager).await;
+        assert!(matches!(response, Response::Ack));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(recorder.recorded(), vec![
+            InjectedAction::KeyPress(35),
+            InjectedAction::KeyRelease(35),
+            InjectedAction::TypeString("hi".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_set_macro_rejects_invalid_macro() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let response = handle_request(
+            Request::SetMacro {
+                device_path: "/dev/input/test".to_string(),
+                macro_entry: MacroEntry {
+                    name: "bad".to_string(),
+                    trigger: KeyCombo {
+                        keys: vec![30],
+                        modifiers: vec![],
+                    },
+                    actions: vec![Action::MousePress(999)],
+                    device_id: None,
+                    enabled: true,
+                    cooldown_ms: 0,
+                    trigger_on: TriggerEdge::Press,
+                    repeat_while_held: false,
+                    tags: vec![],
+                },
+            },
+            Arc::clone(&state),
+            Arc::clone(&macro_engine),
+            Arc::clone(&injector),
+            Arc::clone(&config_manager),
+            Arc::clone(&security_manager)
+        ).await;
+
+        match response {
+            Response::ErrorCode { code: ErrorKind::InvalidMacro, message: msg } => assert!(msg.contains("mouse button 999")),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+        assert!(state.read().await.macros.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_set_macro_auto_saves() {
+        let temp_dir = TempDir::new().unwrap();
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = config_manager_in(&temp_dir);
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        state.read().await.devices.write().await.push(DeviceInfo {
+            name: "Test Device".to_string(),
+            path: PathBuf::from("/dev/input/test"),
+            vendor_id: 0x1234,
+            product_id: 0x5678,
+            phys: "test-phys".to_string(),
+            capabilities: DeviceCapabilities::default(),
+            stable_id: String::new(),
+        });
+
+        for name in ["first", "second"] {
+            let response = handle_request(
+                Request::SetMacro {
+                    device_path: "/dev/input/test".to_string(),
+                    macro_entry: MacroEntry {
+                        name: name.to_string(),
+                        trigger: KeyCombo {
+                            keys: vec![30],
+                            modifiers: vec![],
+                        },
+                        actions: vec![Action::KeyPress(30)],
+                        device_id: None,
+                        enabled: true,
+                        cooldown_ms: 0,
+                        trigger_on: TriggerEdge::Press,
+                        repeat_while_held: false,
+                        tags: vec![],
+                    },
+                },
+                Arc::clone(&state),
+                Arc::clone(&macro_engine),
+                Arc::clone(&injector),
+                Arc::clone(&config_manager),
+                Arc::clone(&security_manager)
+            ).await;
+            assert!(matches!(response, Response::MacroSet { created: true }));
+        }
+
+        // Both changes are written by a single debounced save
+        assert!(!temp_dir.path().join("macros.yaml").exists());
+        tokio::time::sleep(config::MACRO_SAVE_DEBOUNCE * 2).await;
+
+        let reloaded = config_manager_in(&temp_dir);
+        reloaded.load_config_mut().await.unwrap();
+        let macros = reloaded.macros.read().await;
+        assert_eq!(macros.len(), 2);
+        assert!(macros.contains_key("first") && macros.contains_key("second"));
+    }
+
+    #[tokio::test]
+    async fn test_set_macro_replaces_edited_macro() {
+        let temp_dir = TempDir::new().unwrap();
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = config_manager_in(&temp_dir);
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        state.read().await.devices.write().await.push(DeviceInfo {
+            name: "Test Device".to_string(),
+            path: PathBuf::from("/dev/input/test"),
+            vendor_id: 0x1234,
+            product_id: 0x5678,
+            phys: "test-phys".to_string(),
+            capabilities: DeviceCapabilities::default(),
+            stable_id: String::new(),
+        });
+
+        let original = MacroEntry {
+            name: "edited".to_string(),
+            trigger: KeyCombo {
+                keys: vec![30],
+                modifiers: vec![29],
+            },
+            actions: vec![Action::KeyPress(30), Action::Delay(500), Action::KeyRelease(30)],
+            device_id: Some("/dev/input/test".to_string()),
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        macro_engine.add_macro(original.clone()).await.unwrap();
+        state.read().await.macros.write().await.insert(original.name.clone(), original.clone());
+
+        // What the editor sends after fixing a delay, retyping a string and reordering
+        let mut edited = original.clone();
+        edited.actions = vec![
+            Action::Type("héllo wörld".to_string()),
+            Action::KeyPress(30),
+            Action::Delay(25),
+            Action::KeyRelease(30),
+            Action::Repeat { count: 2, actions: vec![Action::MouseScroll(-1)] },
+        ];
+
+        let response = handle_request(
+            Request::SetMacro {
+                device_path: "/dev/input/test".to_string(),
+                macro_entry: edited.clone(),
+            },
+            Arc::clone(&state),
+            Arc::clone(&macro_engine),
+            Arc::clone(&injector),
+            Arc::clone(&config_manager),
+            Arc::clone(&security_manager)
+        ).await;
+        assert!(matches!(response, Response::MacroSet { created: false }));
+
+        assert_eq!(macro_engine.get_macro("edited").await, Some(edited.clone()));
+        assert_eq!(state.read().await.macros.read().await.get("edited"), Some(&edited));
+
+        // The saved file reloads to exactly the edited macro
+        tokio::time::sleep(config::MACRO_SAVE_DEBOUNCE * 2).await;
+        let reloaded = config_manager_in(&temp_dir);
+        reloaded.load_config_mut().await.unwrap();
+        assert_eq!(reloaded.macros.read().await.get("edited"), Some(&edited));
+    }
+
+    #[tokio::test]
+    async fn test_set_macro_after_restart_keeps_saved_macros() {
+        let temp_dir = TempDir::new().unwrap();
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        state.read().await.devices.write().await.push(DeviceInfo {
+            name: "Test Device".to_string(),
+            path: PathBuf::from("/dev/input/test"),
+            vendor_id: 0x1234,
+            product_id: 0x5678,
+            phys: "test-phys".to_string(),
+            capabilities: DeviceCapabilities::default(),
+            stable_id: String::new(),
+        });
+
+        let macro_named = |name: &str| MacroEntry {
+            name: name.to_string(),
+            trigger: KeyCombo {
+                keys: vec![30],
+                modifiers: vec![],
+            },
+            actions: vec![Action::KeyPress(30)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+
+        // Macros saved by a previous run of the daemon
+        let saved: std::collections::HashMap<_, _> = ["one", "two"]
+            .into_iter()
+            .map(|name| (name.to_string(), macro_named(name)))
+            .collect();
+        std::fs::write(temp_dir.path().join("macros.yaml"), serde_yaml::to_string(&saved).unwrap()).unwrap();
+
+        // Startup: load from disk, then hand the macros to the state and engine
+        let config_manager = config_manager_in(&temp_dir);
+        config_manager.load_config_mut().await.unwrap();
+        apply_loaded_macros(&state, &macro_engine, &config_manager).await;
+        assert!(macro_engine.get_macro("one").await.is_some());
+
+        let response = handle_request(
+            Request::SetMacro {
+                device_path: "/dev/input/test".to_string(),
+                macro_entry: macro_named("three"),
+            },
+            Arc::clone(&state),
+            Arc::clone(&macro_engine),
+            Arc::clone(&injector),
+            Arc::clone(&config_manager),
+            Arc::clone(&security_manager)
+        ).await;
+        assert!(matches!(response, Response::MacroSet { created: true }));
+
+        tokio::time::sleep(config::MACRO_SAVE_DEBOUNCE * 2).await;
+        let reloaded = config_manager_in(&temp_dir);
+        reloaded.load_config_mut().await.unwrap();
+        let mut names: Vec<_> = reloaded.macros.read().await.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, ["one", "three", "two"]);
+    }
+
+    #[tokio::test]
+    async fn test_deleted_macro_no_longer_triggers() {
+        let recorder = RecordingInjector::new();
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::with_injector(recorder.shared()));
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let doomed = MacroEntry {
+            name: "doomed".to_string(),
+            trigger: KeyCombo {
+                keys: vec![30],
+                modifiers: vec![],
+            },
+            actions: vec![Action::KeyPress(48)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        state.read().await.macros.write().await.insert(doomed.name.clone(), doomed.clone());
+        macro_engine.add_macro(doomed).await.unwrap();
+
+        let response = handle_request(
+            Request::DeleteMacro { name: "doomed".to_string() },
+            Arc::clone(&state),
+            Arc::clone(&macro_engine),
+            Arc::clone(&injector),
+            Arc::clone(&config_manager),
+            Arc::clone(&security_manager)
+        ).await;
+        assert!(matches!(response, Response::Ack));
+
+        macro_engine.process_input_event(30, true, "/dev/input/test").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(recorder.recorded().is_empty());
+        assert!(macro_engine.get_macro("doomed").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reload_config_request() {
+        let temp_dir = TempDir::new().unwrap();
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = config_manager_in(&temp_dir);
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        // Stale macro that should disappear on reload
+        let stale = MacroEntry {
+            name: "stale".to_string(),
+            trigger: KeyCombo {
+                keys: vec![31],
+                modifiers: vec![],
+            },
+            actions: vec![Action::KeyPress(31)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        state.read().await.macros.write().await.insert(stale.name.clone(), stale.clone());
+        macro_engine.add_macro(stale).await.unwrap();
+
+        // Write a config and macros file as if edited by hand
+        let mut on_disk = config::DaemonConfig::default();
+        on_disk.macro_engine.type_delay_ms = 7;
+        std::fs::write(temp_dir.path().join("config.yaml"), serde_yaml::to_string(&on_disk).unwrap()).unwrap();
+        let mut macros = std::collections::HashMap::new();
+        for name in ["one", "two"] {
+            macros.insert(name.to_string(), MacroEntry {
+                name: name.to_string(),
+                trigger: KeyCombo {
+                    keys: vec![30],
+                    modifiers: vec![],
+                },
+                actions: vec![Action::KeyPress(30)],
+                device_id: None,
+                enabled: true,
+                cooldown_ms: 0,
+                trigger_on: TriggerEdge::Press,
+                repeat_while_held: false,
+                tags: vec![],
+            });
+        }
+        std::fs::write(temp_dir.path().join("macros.yaml"), serde_yaml::to_string(&macros).unwrap()).unwrap();
+
+        let response = handle_request(
+            Request::ReloadConfig,
+            Arc::clone(&state),
+            Arc::clone(&macro_engine),
+            Arc::clone(&injector),
+            Arc::clone(&config_manager),
+            Arc::clone(&security_manager)
+        ).await;
+
+        assert!(matches!(response, Response::ConfigReloaded { macros_count: 2 }));
+        assert_eq!(config_manager.config().await.macro_engine.type_delay_ms, 7);
+        assert_eq!(state.read().await.macros.read().await.len(), 2);
+        assert!(macro_engine.get_macro("stale").await.is_none());
+        assert!(macro_engine.get_macro("one").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_macro() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let original = MacroEntry {
+            name: "Original".to_string(),
+            trigger: KeyCombo {
+                keys: vec![30],
+                modifiers: vec![29],
+            },
+            actions: vec![Action::KeyPress(31), Action::Delay(10), Action::KeyRelease(31)],
+            device_id: Some("/dev/input/test".to_string()),
+            enabled: false,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        state.read().await.macros.write().await.insert(original.name.clone(), original.clone());
+        macro_engine.add_macro(original.clone()).await.unwrap();
+
+        let duplicate = |source: &str, new_name: &str| Request::DuplicateMacro {
+            source: source.to_string(),
+            new_name: new_name.to_string(),
+        };
+
+        let response = handle_request(duplicate("Original", "Copy"), Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        let copy = match response {
+            Response::Macro(copy) => copy,
+            other => panic!("Expected Macro response, got {:?}", other),
+        };
+        assert_eq!(copy.name, "Copy");
+        assert_eq!(copy.trigger, original.trigger);
+        assert_eq!(copy.actions, original.actions);
+        assert_eq!(copy.device_id, original.device_id);
+        assert!(!copy.enabled);
+        assert_eq!(state.read().await.macros.read().await["Copy"], copy);
+        assert_eq!(macro_engine.get_macro("Copy").await, Some(copy));
+        assert!(state.read().await.macros.read().await.contains_key("Original"));
+
+        let response = handle_request(duplicate("Original", "Copy"), Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        assert!(matches!(response, Response::ErrorCode { code: ErrorKind::AlreadyExists, .. }));
+
+        let response = handle_request(duplicate("Missing", "Other"), state, macro_engine, injector, config_manager, security_manager).await;
+        assert!(matches!(response, Response::ErrorCode { code: ErrorKind::NotFound, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_set_macro_enabled_request() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let test_macro = MacroEntry {
+            name: "Toggle Macro".to_string(),
+            trigger: KeyCombo {
+                keys: vec![30],
+                modifiers: vec![],
+            },
+            actions: vec![Action::KeyPress(31)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        state.read().await.macros.write().await.insert(test_macro.name.clone(), test_macro.clone());
+        macro_engine.add_macro(test_macro).await.unwrap();
+
+        let response = handle_request(
+            Request::SetMacroEnabled { name: "Toggle Macro".to_string(), enabled: false },
+            Arc::clone(&state),
+            Arc::clone(&macro_engine),
+            Arc::clone(&injector),
+            Arc::clone(&config_manager),
+            Arc::clone(&security_manager)
+        ).await;
+        assert!(matches!(response, Response::Ack));
+        assert!(!state.read().await.macros.read().await["Toggle Macro"].enabled);
+        assert!(!macro_engine.get_macro("Toggle Macro").await.unwrap().enabled);
+
+        let response = handle_request(
+            Request::SetMacroEnabled { name: "Missing".to_string(), enabled: true },
+            Arc::clone(&state),
+            Arc::clone(&macro_engine),
+            Arc::clone(&injector),
+            Arc::clone(&config_manager),
+            Arc::clone(&security_manager)
+        ).await;
+        match response {
+            Response::ErrorCode { code: ErrorKind::NotFound, message: msg } => assert!(msg.contains("not found")),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stop_macro_requests() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        // Stopping a macro that isn't running is an error
+        let response = handle_request(
+            Request::StopMacro { name: "missing".to_string() },
+            Arc::clone(&state),
+            Arc::clone(&macro_engine),
+            Arc::clone(&injector),
+            Arc::clone(&config_manager),
+            Arc::clone(&security_manager)
+        ).await;
+        match response {
+            Response::ErrorCode { code: ErrorKind::NotFound, message: msg } => assert!(msg.contains("not running")),
+            _ => panic!("Expected Error response"),
+        }
+
+        // Stopping everything with nothing running stops nothing
+        let response = handle_request(
+            Request::StopAllMacros,
+            Arc::clone(&state),
+            Arc::clone(&macro_engine),
+            Arc::clone(&injector),
+            Arc::clone(&config_manager),
+            Arc::clone(&security_manager)
+        ).await;
+        assert!(matches!(response, Response::Stopped(0)));
+    }
+
+    #[tokio::test]
+    async fn test_status_uptime() {
+        let mut daemon_state = DaemonState::new();
+        daemon_state.start_time -= std::time::Duration::from_secs(2);
+        let state = Arc::new(RwLock::new(daemon_state));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let response = handle_request(Request::GetStatus, state, macro_engine, injector, config_manager, security_manager).await;
+        match response {
+            Response::Status { uptime_seconds, .. } => assert!(uptime_seconds >= 2),
+            _ => panic!("Expected Status response"),
+        }
+    }
+
+    #[test]
+    fn test_shutdown_refusal() {
+        // Root may always shut the daemon down
+        assert!(shutdown_refusal(true, false).is_none());
+        assert!(shutdown_refusal(true, true).is_none());
+        assert!(shutdown_refusal(false, true).is_none());
+
+        // Anyone else is refused by default, authenticated with a token or not
+        match shutdown_refusal(false, false) {
+            Some(Response::ErrorCode { code: ErrorKind::PermissionDenied, message }) => {
+                assert!(message.contains("allow_remote_shutdown"))
+            }
+            other => panic!("Expected PermissionDenied, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remote_shutdown() {
+        let state = Arc::new(RwLock::new(crate::DaemonState::new()));
+        let config_manager = create_test_config_manager().await;
+
+        async fn exchange(stream: &mut UnixStream, request: &Request) -> Response {
+            let request = encode_frame(request, WireFormat::Bincode).unwrap();
+            stream.write_all(&(request.len() as u32).to_le_bytes()).await.unwrap();
+            stream.write_all(&request).await.unwrap();
+            let mut len_buf = [0u8; 4];
+            stream.read_exact(&mut len_buf).await.unwrap();
+            let mut msg_buf = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+            stream.read_exact(&mut msg_buf).await.unwrap();
+            decode_frame(&msg_buf).unwrap().0
+        }
+
+        async fn send_shutdown(
+            state: &Arc<RwLock<crate::DaemonState>>,
+            config_manager: &Arc<config::ConfigManager>,
+        ) -> Response {
+            let (mut client, server) = UnixStream::pair().unwrap();
+            let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(cfg!(feature = "token-auth"))));
+            let (state, config_manager) = (Arc::clone(state), Arc::clone(config_manager));
+            let server_task = tokio::spawn(async move {
+                let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+                handle_client(server, state, macro_engine, create_test_injector(), config_manager, security_manager)
+                    .await
+                    .map_err(|e| e.to_string())
+            });
+
+            // With token auth, only an authenticated client gets as far as the shutdown check
+            if cfg!(feature = "token-auth") {
+                let response = exchange(&mut client, &Request::Shutdown).await;
+                assert!(matches!(response, Response::ErrorCode { code: ErrorKind::AuthRequired, .. }), "{:?}", response);
+                let token = match exchange(&mut client, &Request::GenerateToken { client_id: "test".to_string() }).await {
+                    Response::Token(token) => token,
+                    other => panic!("Expected Token, got {:?}", other),
+                };
+                let response = exchange(&mut client, &Request::Authenticate { token }).await;
+                assert!(matches!(response, Response::Authenticated), "{:?}", response);
+            }
+
+            let response = exchange(&mut client, &Request::Shutdown).await;
+            drop(client);
+            server_task.await.unwrap().unwrap();
+            response
+        }
+
+        // The test talks to itself, so whether it runs as root decides the default
+        let response = send_shutdown(&state, &config_manager).await;
+        if nix::unistd::getuid().is_root() {
+            assert!(matches!(response, Response::Ack), "{:?}", response);
+            let shutdown_requested = Arc::clone(&state.read().await.shutdown_requested);
+            tokio::time::timeout(Duration::from_secs(1), shutdown_requested.notified()).await.unwrap();
+        } else {
+            assert!(matches!(response, Response::ErrorCode { code: ErrorKind::PermissionDenied, .. }), "{:?}", response);
+        }
+
+        config_manager.config.write().await.security.allow_remote_shutdown = true;
+        assert!(matches!(send_shutdown(&state, &config_manager).await, Response::Ack));
+
+        // The main loop is woken to run its cleanup
+        let shutdown_requested = Arc::clone(&state.read().await.shutdown_requested);
+        tokio::time::timeout(Duration::from_secs(1), shutdown_requested.notified()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_events() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let server_state = Arc::clone(&state);
+        let server_task = tokio::spawn(async move {
+            handle_client(server, server_state, macro_engine, injector, config_manager, security_manager)
+                .await
+                .map_err(|e| e.to_string())
+        });
+
+        async fn read_response(stream: &mut UnixStream) -> Response {
+            let mut len_buf = [0u8; 4];
+            stream.read_exact(&mut len_buf).await.unwrap();
+            let mut msg_buf = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+            stream.read_exact(&mut msg_buf).await.unwrap();
+            decode_frame(&msg_buf).unwrap().0
+        }
+
+        // Subscribe to a single device
+        let request = encode_frame(&Request::SubscribeEvents {
+            device_path: Some("/dev/input/event3".to_string()),
+        }, WireFormat::Bincode).unwrap();
+        client.write_all(&(request.len() as u32).to_le_bytes()).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        assert!(matches!(read_response(&mut client).await, Response::Ack));
+
+        // Events from other devices are filtered out
+        {
+            let state = state.read().await;
+            state.input_events.send(("/dev/input/event1".to_string(), 31, true)).unwrap();
+            state.input_events.send(("/dev/input/event3".to_string(), 30, true)).unwrap();
+        }
+
+        match read_response(&mut client).await {
+            Response::InputEvent { device_path, key_code, pressed } => {
+                assert_eq!(device_path, "/dev/input/event3");
+                assert_eq!(key_code, 30);
+                assert!(pressed);
+            }
+            other => panic!("Expected InputEvent response, got {:?}", other),
+        }
+
+        // Device list changes are delivered regardless of the filter
+        {
+            let state = state.read().await;
+            state.device_events.send(Response::DevicesChanged(Vec::new())).unwrap();
+        }
+        assert!(matches!(read_response(&mut client).await, Response::DevicesChanged(devices) if devices.is_empty()));
+
+        // Disconnecting ends the stream cleanly
+        drop(client);
+        assert!(server_task.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_token_request() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(true)));
+
+        let token = security_manager.read().await.generate_auth_token().await.unwrap();
+
+        let response = handle_request(Request::ListTokens, Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        let id = match response {
+            Response::Tokens(tokens) => {
+                assert_eq!(tokens.len(), 1);
+                assert!(!tokens[0].id.contains(&token));
+                tokens[0].id.clone()
+            }
+            other => panic!("Expected Tokens response, got {:?}", other),
+        };
+
+        let response = handle_request(Request::RevokeToken { id: id.clone() }, Arc::clone(&state), Arc::clone(&macro_engine), Arc::clone(&injector), Arc::clone(&config_manager), Arc::clone(&security_manager)).await;
+        assert!(matches!(response, Response::Ack));
+        assert!(!security_manager.read().await.validate_auth_token(&token).await);
+
+        let response = handle_request(Request::RevokeToken { id }, state, macro_engine, injector, config_manager, Arc::clone(&security_manager)).await;
+        assert!(matches!(response, Response::ErrorCode { code: ErrorKind::NotFound, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiting() {
+        let temp_dir = TempDir::new().unwrap();
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = config_manager_in(&temp_dir);
+        config_manager.config.write().await.security.max_requests_per_second = 5;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        tokio::spawn(async move {
+            let _ = handle_client(server, state, macro_engine, injector, config_manager, security_manager).await;
+        });
+
+        let mut responses = Vec::new();
+        for _ in 0..10 {
+            let request = encode_frame(&Request::GetStatus, WireFormat::Bincode).unwrap();
+            client.write_all(&(request.len() as u32).to_le_bytes()).await.unwrap();
+            client.write_all(&request).await.unwrap();
+
+            let mut len_buf = [0u8; 4];
+            client.read_exact(&mut len_buf).await.unwrap();
+            let mut msg_buf = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+            client.read_exact(&mut msg_buf).await.unwrap();
+            responses.push(decode_frame::<Response>(&msg_buf).unwrap().0);
+        }
+
+        // The first five fit the budget, the burst after that is rejected
+        assert!(responses[..5].iter().all(|r| matches!(r, Response::Status { .. })));
+        assert!(responses[5..].iter().all(|r| matches!(r, Response::ErrorCode { code: ErrorKind::RateLimited, message } if message == "rate limited")));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_is_rejected() {
+        let temp_dir = TempDir::new().unwrap();
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = config_manager_in(&temp_dir);
+        config_manager.config.write().await.security.max_message_bytes = 256;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        tokio::spawn(async move {
+            let _ = handle_client(server, state, macro_engine, injector, config_manager, security_manager).await;
+        });
+
+        async fn exchange(stream: &mut UnixStream, request: &Request) -> Response {
+            let request = encode_frame(request, WireFormat::Json).unwrap();
+            stream.write_all(&(request.len() as u32).to_le_bytes()).await.unwrap();
+            stream.write_all(&request).await.unwrap();
+
+            let mut len_buf = [0u8; 4];
+            stream.read_exact(&mut len_buf).await.unwrap();
+            let mut msg_buf = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+            stream.read_exact(&mut msg_buf).await.unwrap();
+            let (response, format) = decode_frame(&msg_buf).unwrap();
+            assert_eq!(format, WireFormat::Json);
+            response
+        }
+
+        let oversized = Request::ExecuteMacro { name: "x".repeat(1000), speed: DEFAULT_REPLAY_SPEED };
+        match exchange(&mut client, &oversized).await {
+            Response::ErrorCode { code: ErrorKind::MessageTooLarge, message } => assert!(message.contains("limit of 256 bytes")),
+            other => panic!("Expected MessageTooLarge error, got {:?}", other),
+        }
+
+        // The oversized frame was skipped, so the connection is still usable
+        assert!(matches!(exchange(&mut client, &Request::GetStatus).await, Response::Status { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_is_disconnected() {
+        let temp_dir = TempDir::new().unwrap();
+        let config_manager = config_manager_in(&temp_dir);
+        config_manager.config.write().await.security.client_read_timeout_ms = 100;
+
+        let serve = |server: UnixStream| {
+            let state = Arc::new(RwLock::new(DaemonState::new()));
+            let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+            let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+            let config_manager = Arc::clone(&config_manager);
+            tokio::spawn(async move {
+                handle_client(server, state, macro_engine, create_test_injector(), config_manager, security_manager)
+                    .await
+                    .map_err(|e| e.to_string())
+            })
+        };
+
+        // A client that connects and never sends anything
+        let (mut silent, server) = UnixStream::pair().unwrap();
+        let task = serve(server);
+        let result = tokio::time::timeout(Duration::from_secs(2), task).await
+            .expect("server task should end once the client times out")
+            .unwrap();
+        assert_eq!(result, Ok(()));
+        let mut buf = [0u8; 1];
+        assert_eq!(silent.read(&mut buf).await.unwrap(), 0);
+
+        // A client that stops halfway through a frame
+        let (mut stalled, server) = UnixStream::pair().unwrap();
+        let task = serve(server);
+        stalled.write_all(&64u32.to_le_bytes()).await.unwrap();
+        stalled.write_all(&[0u8; 8]).await.unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(2), task).await
+            .expect("server task should end once the client stalls")
+            .unwrap();
+        assert_eq!(result, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_hello_handshake() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let server_task = tokio::spawn(async move {
+            handle_client(server, state, macro_engine, injector, config_manager, security_manager)
+                .await
+                .map_err(|e| e.to_string())
+        });
+
+        async fn exchange(stream: &mut UnixStream, request: &Request) -> Response {
+            let request = encode_frame(request, WireFormat::Bincode).unwrap();
+            stream.write_all(&(request.len() as u32).to_le_bytes()).await.unwrap();
+            stream.write_all(&request).await.unwrap();
+
+            let mut len_buf = [0u8; 4];
+            stream.read_exact(&mut len_buf).await.unwrap();
+            let mut msg_buf = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+            stream.read_exact(&mut msg_buf).await.unwrap();
+            decode_frame(&msg_buf).unwrap().0
+        }
+
+        // A matching client is greeted and can carry on
+        match exchange(&mut client, &Request::Hello { client_version: PROTOCOL_VERSION }).await {
+            Response::Hello { daemon_version, protocol } => {
+                assert_eq!(protocol, PROTOCOL_VERSION);
+                assert!(daemon_version >= 200); // 0.2.0 or later
+            }
+            other => panic!("Expected Hello response, got {:?}", other),
+        }
+        assert!(matches!(exchange(&mut client, &Request::GetStatus).await, Response::Status { .. }));
+
+        // A client from another protocol version gets a clear error and is disconnected
+        match exchange(&mut client, &Request::Hello { client_version: PROTOCOL_VERSION + 1 }).await {
+            Response::ErrorCode { code: ErrorKind::VersionMismatch, message: msg } => assert!(msg.contains("Protocol version mismatch")),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+        assert!(server_task.await.unwrap().is_ok());
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_connection() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let server_task = tokio::spawn(async move {
+            handle_client(server, state, macro_engine, injector, config_manager, security_manager)
+                .await
+                .map_err(|e| e.to_string())
+        });
+
+        // Several requests are answered over the same connection, each in
+        // the encoding it was sent in
+        for format in [WireFormat::Bincode, WireFormat::Json, WireFormat::Bincode] {
+            let request = encode_frame(&Request::GetStatus, format).unwrap();
+            client.write_all(&(request.len() as u32).to_le_bytes()).await.unwrap();
+            client.write_all(&request).await.unwrap();
+
+            let mut len_buf = [0u8; 4];
+            client.read_exact(&mut len_buf).await.unwrap();
+            let mut msg_buf = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+            client.read_exact(&mut msg_buf).await.unwrap();
+            let (response, response_format): (Response, _) = decode_frame(&msg_buf).unwrap();
+            assert!(matches!(response, Response::Status { .. }));
+            assert_eq!(response_format, format);
+        }
+
+        // Hand-written JSON, as a third-party client would send it
+        let request = b"\x01\"GetStatus\"";
+        client.write_all(&(request.len() as u32).to_le_bytes()).await.unwrap();
+        client.write_all(request).await.unwrap();
+        let mut len_buf = [0u8; 4];
+        client.read_exact(&mut len_buf).await.unwrap();
+        let mut msg_buf = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+        client.read_exact(&mut msg_buf).await.unwrap();
+        assert_eq!(msg_buf[0], 0x01);
+        assert!(msg_buf[1..].starts_with(b"{\"Status\":{\"version\":"));
+
+        // A request id is echoed in the reply
+        let header = FrameHeader { format: WireFormat::Json, request_id: Some(7) };
+        let request = encode_frame_with_header(&Request::GetStatus, header).unwrap();
+        client.write_all(&(request.len() as u32).to_le_bytes()).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut len_buf = [0u8; 4];
+        client.read_exact(&mut len_buf).await.unwrap();
+        let mut msg_buf = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+        client.read_exact(&mut msg_buf).await.unwrap();
+        let (response, response_header): (Response, _) = decode_frame_with_header(&msg_buf).unwrap();
+        assert!(matches!(response, Response::Status { .. }));
+        assert_eq!(response_header, header);
+
+        // A malformed request closes only this connection
+        let garbage = [0xFFu8; 8];
+        client.write_all(&(garbage.len() as u32).to_le_bytes()).await.unwrap();
+        client.write_all(&garbage).await.unwrap();
+        assert!(server_task.await.unwrap().is_err());
+
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_capture_key_combo() {
+        let (sender, receiver) = broadcast::channel(16);
+        let device = "/dev/input/test".to_string();
+        for (key_code, pressed) in [(30, false), (29, true), (30, true), (30, true), (30, false), (29, false)] {
+            sender.send((device.clone(), key_code, pressed)).unwrap();
+        }
+
+        // The stray release of a key held before the capture is ignored
+        let combo = capture_key_combo(receiver, Duration::from_secs(1)).await;
+        assert_eq!(combo, Some(KeyCombo { keys: vec![30], modifiers: vec![29] }));
+
+        // A side mouse button is captured like a key
+        let (sender, receiver) = broadcast::channel(16);
+        for (key_code, pressed) in [(42, true), (275, true), (275, false)] {
+            sender.send((device.clone(), key_code, pressed)).unwrap();
+        }
+        let combo = capture_key_combo(receiver, Duration::from_secs(1)).await;
+        assert_eq!(combo, Some(KeyCombo { keys: vec![275], modifiers: vec![42] }));
+    }
+
+    #[tokio::test]
+    async fn test_capture_trigger_times_out() {
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = create_test_config_manager().await;
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+
+        let response = handle_request(Request::CaptureTrigger { timeout_ms: 50 }, state, macro_engine, injector, config_manager, security_manager).await;
+        match response {
+            Response::ErrorCode { code: ErrorKind::Timeout, message: msg } => assert!(msg.contains("No key combination pressed")),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_export_import_profile() {
+        let temp_dir = TempDir::new().unwrap();
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new());
+        let injector = create_test_injector();
+        let config_manager = config_manager_in(&temp_dir);
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+        tokio::fs::create_dir_all(&config_manager.profiles_dir).await.unwrap();
+
+        let shared_macro = MacroEntry {
+            name: "Reload".to_string(),
+            trigger: KeyCombo {
+                keys: vec![19],
+                modifiers: vec![29],
+            },
+            actions: vec![Action::Type("/reload".to_string())],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        let profile = Profile {
+            name: "shared".to_string(),
+            macros: [(shared_macro.name.clone(), shared_macro)].into_iter().collect(),
+            description: None,
+            author: None,
+            created_at: None,
+        };
+
+        let import = |profile: Profile, overwrite: bool| handle_request(
+            Request::ImportProfile { profile, overwrite },
+            Arc::clone(&state),
+            Arc::clone(&macro_engine),
+            Arc::clone(&injector),
+            Arc::clone(&config_manager),
+            Arc::clone(&security_manager)
+        );
+
+        match import(profile.clone(), false).await {
+            Response::ProfileSaved { name, macros_count } => assert_eq!((name.as_str(), macros_count), ("shared", 1)),
+            other => panic!("Expected ProfileSaved response, got {:?}", other),
+        }
+
+        // A second import of the same name needs overwrite
+        match import(profile.clone(), false).await {
+            Response::ErrorCode { code: ErrorKind::AlreadyExists, message: msg } => assert!(msg.contains("already exists")),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+        assert!(matches!(import(profile.clone(), true).await, Response::ProfileSaved { .. }));
+
+        // Invalid macros and names that escape the profiles directory are refused
+        let mut invalid = profile.clone();
+        invalid.macros.get_mut("Reload").unwrap().actions.push(Action::MousePress(0));
+        assert!(matches!(import(invalid, true).await, Response::ErrorCode { code: ErrorKind::InvalidRequest, message } if message.contains("Invalid profile")));
+        let mut escaping = profile.clone();
+        escaping.name = "../escaped".to_string();
+        assert!(matches!(import(escaping, true).await, Response::ErrorCode { code: ErrorKind::InvalidRequest, message } if message.contains("Invalid profile")));
+        assert!(!temp_dir.path().join("escaped.yaml").exists());
+
+        let response = handle_request(Request::ExportProfile { name: "shared".to_string() }, state, macro_engine, injector, config_manager, security_manager).await;
+        match response {
+            Response::ProfileData(exported) => assert_eq!(exported, profile),
+            other => panic!("Expected ProfileData response, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_profile_over_engine_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let state = Arc::new(RwLock::new(DaemonState::new()));
+        let macro_engine = Arc::new(macro_engine::MacroEngine::new().with_limits(2, 100));
+        let injector = create_test_injector();
+        let config_manager = config_manager_in(&temp_dir);
+        let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(false)));
+        tokio::fs::create_dir_all(&config_manager.profiles_dir).await.unwrap();
+
+        // Within the default limits, but over the ones this engine was configured with
+        let long_macro = MacroEntry {
+            name: "Combo".to_string(),
+            trigger: KeyCombo {
+                keys: vec![33],
+                modifiers: vec![],
+            },
+            actions: vec![Action::KeyPress(2), Action::KeyPress(3), Action::KeyPress(4)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        let profile = Profile {
+            name: "limited".to_string(),
+            macros: [(long_macro.name.clone(), long_macro)].into_iter().collect(),
+            description: None,
+            author: None,
+            created_at: None,
+        };
+        assert!(profile.validate().is_ok());
+
+        let response = handle_request(Request::ImportProfile { profile, overwrite: false }, state, macro_engine, injector, Arc::clone(&config_manager), security_manager).await;
+        match response {
+            Response::ErrorCode { code: ErrorKind::InvalidRequest, message: msg } => assert!(msg.contains("macro 'Combo'")),
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+        assert!(!config_manager.profile_exists("limited"));
+    }
 }
@@ -0,0 +1,152 @@
+//! Keyboard layouts for translating characters into key strokes
+//!
+//! Linux key codes describe physical key positions, so typing a character
+//! depends on the layout the desktop session is using. Each layout maps the
+//! characters it can produce to a key code plus the modifiers needed.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Physical key positions, row by row (Linux key codes)
+const ROW_NUMBERS: [u16; 13] = [41, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13];
+const ROW_TOP: [u16; 12] = [16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27];
+const ROW_HOME: [u16; 12] = [30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 43];
+const ROW_BOTTOM: [u16; 11] = [86, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53];
+
+// Marks a position that types nothing (missing key or dead key)
+const NONE: char = '\0';
+
+/// A key press needed to type one character
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyStroke {
+    pub code: u16,
+    pub shift: bool,
+    pub altgr: bool,
+}
+
+/// Keyboard layout used when typing text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyboardLayout {
+    /// US QWERTY
+    #[default]
+    Us,
+    /// UK QWERTY
+    Uk,
+    /// German QWERTZ
+    De,
+    /// French AZERTY
+    Fr,
+}
+
+impl KeyboardLayout {
+    /// Build the character to key stroke map for this layout
+    pub fn key_map(&self) -> HashMap<char, KeyStroke> {
+        let mut map = HashMap::new();
+
+        // Keys that are the same on every layout
+        add_key(&mut map, ' ', 57, false, false); // KEY_SPACE
+        add_key(&mut map, '\n', 28, false, false); // KEY_ENTER
+        add_key(&mut map, '\t', 15, false, false); // KEY_TAB
+
+        match self {
+            KeyboardLayout::Us => {
+                add_row(&mut map, &ROW_NUMBERS, "`1234567890-=", "~!@#$%^&*()_+");
+                add_row(&mut map, &ROW_TOP, "qwertyuiop[]", "QWERTYUIOP{}");
+                add_row(&mut map, &ROW_HOME, "asdfghjkl;'\\", "ASDFGHJKL:\"|");
+                add_row(&mut map, &ROW_BOTTOM, "\0zxcvbnm,./", "\0ZXCVBNM<>?");
+            }
+            KeyboardLayout::Uk => {
+                add_row(&mut map, &ROW_NUMBERS, "`1234567890-=", "¬!\"£$%^&*()_+");
+                add_row(&mut map, &ROW_TOP, "qwertyuiop[]", "QWERTYUIOP{}");
+                add_row(&mut map, &ROW_HOME, "asdfghjkl;'#", "ASDFGHJKL:@~");
+                add_row(&mut map, &ROW_BOTTOM, "\\zxcvbnm,./", "|ZXCVBNM<>?");
+                add_key(&mut map, '€', 5, false, true);
+                add_key(&mut map, '¦', 41, false, true);
+            }
+            KeyboardLayout::De => {
+                // ^, ´ and ` are dead keys and are left out
+                add_row(&mut map, &ROW_NUMBERS, "\u{0}1234567890ß\0", "°!\"§$%&/()=?\0");
+                add_row(&mut map, &ROW_TOP, "qwertzuiopü+", "QWERTZUIOPÜ*");
+                add_row(&mut map, &ROW_HOME, "asdfghjklöä#", "ASDFGHJKLÖÄ'");
+                add_row(&mut map, &ROW_BOTTOM, "<yxcvbnm,.-", ">YXCVBNM;:_");
+                for (c, code) in [('²', 3), ('³', 4), ('{', 8), ('[', 9), (']', 10), ('}', 11),
+                                  ('\\', 12), ('@', 16), ('€', 18), ('~', 27), ('|', 86), ('µ', 50)] {
+                    add_key(&mut map, c, code, false, true);
+                }
+            }
+            KeyboardLayout::Fr => {
+                // ^ and ¨ are dead keys and are left out
+                add_row(&mut map, &ROW_NUMBERS, "²&é\"'(-è_çà)=", "\u{0}1234567890°+");
+                add_row(&mut map, &ROW_TOP, "azertyuiop\0$", "AZERTYUIOP\0£");
+                add_row(&mut map, &ROW_HOME, "qsdfghjklmù*", "QSDFGHJKLM%µ");
+                add_row(&mut map, &ROW_BOTTOM, "<wxcvbn,;:!", ">WXCVBN?./§");
+                for (c, code) in [('#', 4), ('{', 5), ('[', 6), ('|', 7), ('\\', 9), ('^', 10),
+                                  ('@', 11), (']', 12), ('}', 13), ('€', 18)] {
+                    add_key(&mut map, c, code, false, true);
+                }
+            }
+        }
+
+        map
+    }
+}
+
+/// Add one row of keys with their unshifted and shifted characters
+fn add_row(map: &mut HashMap<char, KeyStroke>, codes: &[u16], normal: &str, shifted: &str) {
+    for (code, c) in codes.iter().zip(normal.chars()) {
+        add_key(map, c, *code, false, false);
+    }
+    for (code, c) in codes.iter().zip(shifted.chars()) {
+        add_key(map, c, *code, true, false);
+    }
+}
+
+/// Add a single character, keeping the first (simplest) way to type it
+fn add_key(map: &mut HashMap<char, KeyStroke>, c: char, code: u16, shift: bool, altgr: bool) {
+    if c != NONE {
+        map.entry(c).or_insert(KeyStroke { code, shift, altgr });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stroke(code: u16, shift: bool, altgr: bool) -> Option<KeyStroke> {
+        Some(KeyStroke { code, shift, altgr })
+    }
+
+    #[test]
+    fn test_layout_key_maps() {
+        let us = KeyboardLayout::Us.key_map();
+        assert_eq!(us.get(&'a').copied(), stroke(30, false, false));
+        assert_eq!(us.get(&'!').copied(), stroke(2, true, false));
+        assert_eq!(us.get(&'"').copied(), stroke(40, true, false));
+
+        let uk = KeyboardLayout::Uk.key_map();
+        assert_eq!(uk.get(&'"').copied(), stroke(3, true, false));
+        assert_eq!(uk.get(&'£').copied(), stroke(4, true, false));
+        assert_eq!(uk.get(&'€').copied(), stroke(5, false, true));
+
+        let de = KeyboardLayout::De.key_map();
+        assert_eq!(de.get(&'z').copied(), stroke(21, false, false));
+        assert_eq!(de.get(&'Y').copied(), stroke(44, true, false));
+        assert_eq!(de.get(&'ö').copied(), stroke(39, false, false));
+        assert_eq!(de.get(&'@').copied(), stroke(16, false, true));
+        assert!(!de.contains_key(&'^'));
+
+        let fr = KeyboardLayout::Fr.key_map();
+        assert_eq!(fr.get(&'a').copied(), stroke(16, false, false));
+        assert_eq!(fr.get(&'1').copied(), stroke(2, true, false));
+        assert_eq!(fr.get(&'é').copied(), stroke(3, false, false));
+        assert_eq!(fr.get(&'m').copied(), stroke(39, false, false));
+    }
+
+    #[test]
+    fn test_layout_config_names() {
+        let layout: KeyboardLayout = serde_yaml::from_str("de").unwrap();
+        assert_eq!(layout, KeyboardLayout::De);
+        assert_eq!(KeyboardLayout::default(), KeyboardLayout::Us);
+    }
+}
@@ -7,17 +7,25 @@
 //! - IPC communication
 //! - Security management
 
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::time::Instant;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, mpsc, Notify, RwLock};
+use tokio::task::JoinHandle;
 use std::collections::HashMap;
 
+pub mod audit;
+pub mod cli;
 pub mod config;
 pub mod device;
+pub mod focus;
 pub mod macro_engine;
 pub mod injector;
 pub mod ipc;
+pub mod layout;
+pub mod logging;
 pub mod security;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 // Re-export common types
 pub use razermapper_common::{DeviceInfo, MacroEntry, Profile};
@@ -25,24 +33,70 @@ pub use razermapper_common::{DeviceInfo, MacroEntry, Profile};
 /// DaemonState holds the shared state of the daemon
 pub struct DaemonState {
     pub start_time: Instant,
-    pub devices: Arc<Mutex<Vec<DeviceInfo>>>,
-    pub macros: Arc<Mutex<HashMap<String, MacroEntry>>>,
-    pub profiles: Arc<Mutex<HashMap<String, Profile>>>,
+    pub devices: Arc<RwLock<Vec<DeviceInfo>>>,
+    pub macros: Arc<RwLock<HashMap<String, MacroEntry>>>,
+    pub profiles: Arc<RwLock<HashMap<String, Profile>>>,
     pub macro_engine: Option<Arc<macro_engine::MacroEngine>>,
     pub device_manager: Option<Arc<RwLock<device::DeviceManager>>>,
     pub active_recording: Option<(String, String)>, // (name, device_path)
+    pub input_events: broadcast::Sender<(String, u16, bool)>, // (device_path, key_code, pressed)
+    pub device_events: broadcast::Sender<razermapper_common::Response>, // device notifications for subscribers
+    pub shutdown_requested: Arc<Notify>, // notified when a client asks the daemon to shut down
 }
 
 impl DaemonState {
     pub fn new() -> Self {
+        let (input_events, _) = broadcast::channel(1000);
+        let (device_events, _) = broadcast::channel(16);
         Self {
             start_time: Instant::now(),
-            devices: Arc::new(Mutex::new(Vec::new())),
-            macros: Arc::new(Mutex::new(HashMap::new())),
-            profiles: Arc::new(Mutex::new(HashMap::new())),
+            devices: Arc::new(RwLock::new(Vec::new())),
+            macros: Arc::new(RwLock::new(HashMap::new())),
+            profiles: Arc::new(RwLock::new(HashMap::new())),
             macro_engine: None,
             device_manager: None,
             active_recording: None,
+            input_events,
+            device_events,
+            shutdown_requested: Arc::new(Notify::new()),
         }
     }
 }
+
+/// Hand key events read from devices to subscribers and the macro engine
+///
+/// Runs until every sender of `event_receiver` is gone.
+pub fn start_event_dispatch(
+    state: Arc<RwLock<DaemonState>>,
+    mut event_receiver: mpsc::Receiver<(String, u16, device::KeyState)>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some((device_path, key_code, key_state)) = event_receiver.recv().await {
+            // Autorepeat doesn't change which keys are held, so macros and
+            // subscribers only see the initial press and the final release
+            let pressed = match key_state {
+                device::KeyState::Pressed => true,
+                device::KeyState::Released => false,
+                device::KeyState::Repeat => continue,
+            };
+
+            let state = state.read().await;
+
+            // Fan the event out to subscribed clients (no subscribers is fine)
+            let _ = state.input_events.send((device_path.clone(), key_code, pressed));
+
+            // Forward event to macro engine for processing, naming the device
+            // by an id that survives event node renumbering
+            if let Some(macro_engine) = &state.macro_engine {
+                let device_id = ipc::stable_id_for_path(&state, &device_path).await;
+                if let Err(e) = macro_engine.process_input_event(
+                    key_code,
+                    pressed,
+                    &device_id
+                ).await {
+                    tracing::error!("Error processing input event: {}", e);
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,242 @@
+//! Daemon log output, to stderr or to a log file started afresh each day
+//!
+//! The current day's log is always at the configured path, so it can be
+//! followed with `tail -F`. At the first line written on a new day (UTC) the
+//! file is renamed to `<path>.YYYY-MM-DD` after the day it covers, and the
+//! oldest of those are removed beyond the configured number of files.
+
+use crate::config::DaemonSettings;
+use razermapper_common::tracing;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Level used when `log_level` isn't one of the recognized names
+pub const DEFAULT_LOG_LEVEL: tracing::Level = tracing::Level::INFO;
+
+/// Parse a `log_level` setting: trace, debug, info, warn or error, ignoring case
+pub fn parse_log_level(level: &str) -> Result<tracing::Level, String> {
+    match level.trim().to_ascii_lowercase().as_str() {
+        "trace" => Ok(tracing::Level::TRACE),
+        "debug" => Ok(tracing::Level::DEBUG),
+        "info" => Ok(tracing::Level::INFO),
+        "warn" => Ok(tracing::Level::WARN),
+        "error" => Ok(tracing::Level::ERROR),
+        _ => Err(format!("unknown log level '{}', expected trace, debug, info, warn or error", level)),
+    }
+}
+
+/// Install the global subscriber, writing to `settings.log_file` or to
+/// stderr when no log file is configured
+pub fn init(settings: &DaemonSettings, level: tracing::Level) -> io::Result<()> {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_target(false);
+    if settings.log_file.is_empty() {
+        builder.init();
+    } else {
+        let file = DailyRotatingFile::new(&settings.log_file, settings.log_max_files)?;
+        builder.with_ansi(false).with_writer(Arc::new(file)).init();
+    }
+    Ok(())
+}
+
+/// Log file that moves aside the previous day's lines when the day changes
+pub struct DailyRotatingFile {
+    path: PathBuf,
+    max_files: usize,
+    state: Mutex<RotationState>,
+}
+
+struct RotationState {
+    file: Option<File>,
+    /// Days since the Unix epoch of the lines in the current file
+    day: u64,
+}
+
+impl DailyRotatingFile {
+    /// Open the log file at `path` for appending, creating it and its parent
+    /// directory if needed, and keep at most `max_files` rotated files
+    ///
+    /// A file left over from an earlier day is rotated on the first write.
+    pub fn new(path: impl Into<PathBuf>, max_files: usize) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = open_append(&path)?;
+        let day = file.metadata()?.modified().map(day_of).unwrap_or_else(|_| day_of(SystemTime::now()));
+        Ok(Self {
+            path,
+            max_files,
+            state: Mutex::new(RotationState { file: Some(file), day }),
+        })
+    }
+
+    /// Write `buf` as of `now`, rotating first if the day has changed
+    ///
+    /// If the file can't be rotated, e.g. because the daemon no longer has
+    /// the privileges to rename it, lines keep being appended to it.
+    fn write_at(&self, buf: &[u8], now: SystemTime) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let today = day_of(now);
+        if today != state.day {
+            // Close the file before moving it
+            state.file = None;
+            if let Err(e) = self.rotate(state.day) {
+                // Logging the failure would come back to this writer
+                eprintln!("Failed to rotate log file {}: {}", self.path.display(), e);
+            }
+            state.day = today;
+        }
+        let file = match &mut state.file {
+            Some(file) => file,
+            file => file.insert(open_append(&self.path)?),
+        };
+        file.write(buf)
+    }
+
+    /// Move the current file aside as the log of `day` and prune old ones
+    fn rotate(&self, day: u64) -> io::Result<()> {
+        let rotated = self.rotated_path(day);
+        match fs::rename(&self.path, &rotated) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        let mut old_files = self.rotated_files()?;
+        old_files.sort();
+        let excess = old_files.len().saturating_sub(self.max_files);
+        for old_file in old_files.into_iter().take(excess) {
+            fs::remove_file(old_file)?;
+        }
+        Ok(())
+    }
+
+    fn rotated_path(&self, day: u64) -> PathBuf {
+        let (year, month, day) = civil_date(day);
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{:04}-{:02}-{:02}", year, month, day));
+        self.path.with_file_name(name)
+    }
+
+    /// Rotated files of this log, whose names sort by date
+    fn rotated_files(&self) -> io::Result<Vec<PathBuf>> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = format!("{}.", self.path.file_name().unwrap_or_default().to_string_lossy());
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let is_rotated = name.strip_prefix(&prefix).is_some_and(|date| {
+                date.len() == 10 && date.bytes().enumerate().all(|(i, b)| if i == 4 || i == 7 { b == b'-' } else { b.is_ascii_digit() })
+            });
+            if is_rotated {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+}
+
+impl Write for &DailyRotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_at(buf, SystemTime::now())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match state.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn day_of(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() / SECS_PER_DAY).unwrap_or(0)
+}
+
+/// Year, month and day of the month of a count of days since 1970-01-01
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    // Howard Hinnant's civil_from_days, for dates after the epoch
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn on_day(day: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(day * SECS_PER_DAY + 3600)
+    }
+
+    #[test]
+    fn test_parse_log_level() {
+        for (name, level) in [
+            ("trace", tracing::Level::TRACE),
+            ("debug", tracing::Level::DEBUG),
+            ("info", tracing::Level::INFO),
+            ("warn", tracing::Level::WARN),
+            ("error", tracing::Level::ERROR),
+        ] {
+            assert_eq!(parse_log_level(name), Ok(level));
+            assert_eq!(parse_log_level(&format!(" {} ", name.to_uppercase())), Ok(level));
+        }
+        for bad in ["", "verbose", "3", "warning"] {
+            assert!(parse_log_level(bad).unwrap_err().contains("unknown log level"));
+        }
+    }
+
+    #[test]
+    fn test_civil_date() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(19_723), (2024, 1, 1));
+        assert_eq!(civil_date(19_782), (2024, 2, 29));
+        assert_eq!(civil_date(20_741), (2026, 10, 15));
+    }
+
+    #[test]
+    fn test_daily_rotation() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("logs").join("razermapperd.log");
+        let log = DailyRotatingFile::new(&path, 2).unwrap();
+        let today = day_of(SystemTime::now());
+
+        log.write_at(b"today\n", on_day(today)).unwrap();
+        for day in 1..=3 {
+            log.write_at(format!("day {}\n", day).as_bytes(), on_day(today + day)).unwrap();
+            log.write_at(b"more\n", on_day(today + day)).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "day 3\nmore\n");
+        let rotated = |day: u64| log.rotated_path(day);
+        assert!(!rotated(today).exists(), "oldest file should have been pruned");
+        assert_eq!(fs::read_to_string(rotated(today + 1)).unwrap(), "day 1\nmore\n");
+        assert_eq!(fs::read_to_string(rotated(today + 2)).unwrap(), "day 2\nmore\n");
+        assert_eq!(log.rotated_files().unwrap().len(), 2);
+    }
+}
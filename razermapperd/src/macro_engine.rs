@@ -1,11 +1,20 @@
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, RwLock};
+use tokio::task::JoinHandle;
 use tracing::{debug, error, info, warn};
 
-use razermapper_common::{Action, KeyCombo, MacroEntry};
-use crate::injector::Injector;
+use razermapper_common::{
+    Action, CaptureTarget, ErrorKind, KeyCombo, MacroEntry, MacroMetric, DEFAULT_MAX_MACRO_ACTIONS, DEFAULT_MAX_TOTAL_DELAY_MS,
+    DEFAULT_REPLAY_SPEED, REPLAY_SPEED_RANGE, TriggerEdge,
+};
+use crate::audit::{AuditEvent, AuditLog};
+use crate::injector::{Injector, InjectorError, InjectorResult};
 
 // Type alias for our error type that implements Send + Sync
 pub type EngineResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
@@ -17,15 +26,125 @@ pub struct ExecutionState {
     pub stop: Arc<tokio::sync::RwLock<bool>>,
 }
 
+/// Maximum delay inserted between two recorded events, in milliseconds
+pub const MAX_RECORDED_DELAY_MS: u32 = 5000;
+
+/// Maximum number of actions in a simulated trace, so large repeat counts stay bounded
+pub const MAX_TRACE_ACTIONS: usize = 10_000;
+
+/// Maximum number of actions and repeat iterations a simulation walks, so
+/// loops that add nothing to the trace stay bounded too
+pub const MAX_TRACE_STEPS: usize = 10 * MAX_TRACE_ACTIONS;
+
+/// Default number of triggered macros that may wait for a free slot
+pub const DEFAULT_MACRO_QUEUE_SIZE: usize = 32;
+
+/// Default limit on nested CallMacro actions
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 8;
+
+/// Default time KeyTap and MouseClick hold the key down, in milliseconds
+pub const DEFAULT_TAP_DELAY_MS: u64 = 20;
+
+/// Most passes per second a hold-to-repeat macro makes by default
+pub const DEFAULT_MAX_REPEAT_RATE_HZ: u32 = 50;
+
+/// How long shutdown waits for running macros to stop before aborting them
+pub const MACRO_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest hold of a recorded key that is still collapsed into a KeyTap
+pub const MAX_TAP_HOLD_MS: u32 = 150;
+
+/// Key presses buffered for each waiting macro before older ones are dropped
+const KEY_PRESS_CHANNEL_SIZE: usize = 64;
+
+/// How often a WaitForKey action checks whether its macro was stopped
+const WAIT_FOR_KEY_POLL: Duration = Duration::from_millis(50);
+
+/// What happens to a triggered macro while `max_concurrent_macros` are running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Ignore the trigger
+    #[default]
+    Drop,
+    /// Run the macro as soon as a running one finishes
+    Queue,
+}
+
+/// State for an in-progress macro recording
+pub struct RecordingState {
+    pub macro_entry: MacroEntry,
+    pub last_event_time: Option<Instant>,
+    /// Keys pressed so far while the trigger is still being captured
+    pub trigger_keys: Option<Vec<u16>>,
+    /// Trigger keys whose releases are left out of the recorded actions
+    pub trigger_releases: HashSet<u16>,
+}
+
+impl RecordingState {
+    /// Take an event as part of the trigger, returning false if it belongs to the actions
+    ///
+    /// The trigger is complete at the first release of one of its keys. That
+    /// release and the releases of the other trigger keys aren't recorded.
+    fn record_trigger_key(&mut self, key_code: u16, is_pressed: bool) -> bool {
+        let Some(trigger_keys) = self.trigger_keys.as_mut() else {
+            return !is_pressed && self.trigger_releases.remove(&key_code);
+        };
+
+        if is_pressed {
+            if !trigger_keys.contains(&key_code) {
+                trigger_keys.push(key_code);
+            }
+        } else if trigger_keys.contains(&key_code) {
+            let pressed = self.trigger_keys.take().unwrap_or_default();
+            self.trigger_releases = pressed.iter().copied().filter(|&key| key != key_code).collect();
+            self.macro_entry.trigger = KeyCombo::from_pressed(pressed);
+            debug!("Captured trigger {:?} for recording", self.macro_entry.trigger);
+        }
+        // Releases of keys held before the recording started are dropped too
+        true
+    }
+}
+
 /// Macro engine that manages and executes macros
 pub struct MacroEngine {
     macros: Arc<RwLock<HashMap<String, MacroEntry>>>,
     active_combos: Arc<RwLock<Vec<KeyCombo>>>,
-    recording: Arc<RwLock<Option<MacroEntry>>>,
+    recording: Arc<RwLock<Option<RecordingState>>>,
     executing: Arc<RwLock<HashMap<String, ExecutionState>>>,
-    max_concurrent_macros: usize,
+    held_keys: Arc<RwLock<HashSet<u16>>>,
+    latched_combos: Arc<RwLock<HashSet<String>>>,
+    /// Fresh key presses from input devices, for WaitForKey actions
+    key_presses: broadcast::Sender<u16>,
+    /// When each macro was last started by its trigger, for cooldowns
+    last_triggered: Arc<RwLock<HashMap<String, Instant>>>,
+    /// Changeable while running, so it is read afresh for every trigger
+    max_concurrent_macros: AtomicUsize,
+    overflow_policy: OverflowPolicy,
+    macro_queue_size: usize,
+    /// How deeply CallMacro actions may nest
+    max_call_depth: usize,
+    /// Most actions a macro may perform, counting repeats and called macros
+    max_macro_actions: u64,
+    /// Most time a macro may spend in Delay and RandomDelay actions
+    max_total_delay_ms: u64,
+    /// How long KeyTap and MouseClick hold the key down
+    tap_delay: Duration,
+    /// Shortest time between the starts of two passes of a hold-to-repeat macro
+    repeat_interval: Duration,
+    /// Whether stop_recording folds quick press/release pairs into KeyTap
+    collapse_taps: bool,
+    /// Triggered macros waiting for a free slot, oldest first
+    pending: Arc<RwLock<VecDeque<MacroEntry>>>,
+    /// Execution counts and durations, updated as each run finishes
+    metrics: Arc<RwLock<HashMap<String, MacroMetric>>>,
     default_delay: u32,
     injector: Option<Arc<RwLock<dyn Injector + Send + Sync>>>,
+    audit_log: Option<Arc<AuditLog>>,
+    /// Tasks running macros, kept so shutdown can wait for them
+    runs: Arc<std::sync::Mutex<Vec<JoinHandle<()>>>>,
+    /// Set by `shutdown`; no macro starts afterwards
+    shutting_down: Arc<AtomicBool>,
 }
 
 impl MacroEngine {
@@ -41,9 +160,26 @@ impl MacroEngine {
             active_combos: Arc::new(RwLock::new(Vec::new())),
             recording: Arc::new(RwLock::new(None)),
             executing: Arc::new(RwLock::new(HashMap::new())),
-            max_concurrent_macros,
+            held_keys: Arc::new(RwLock::new(HashSet::new())),
+            latched_combos: Arc::new(RwLock::new(HashSet::new())),
+            key_presses: broadcast::channel(KEY_PRESS_CHANNEL_SIZE).0,
+            last_triggered: Arc::new(RwLock::new(HashMap::new())),
+            max_concurrent_macros: AtomicUsize::new(max_concurrent_macros),
+            overflow_policy: OverflowPolicy::Drop,
+            macro_queue_size: DEFAULT_MACRO_QUEUE_SIZE,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_macro_actions: DEFAULT_MAX_MACRO_ACTIONS,
+            max_total_delay_ms: DEFAULT_MAX_TOTAL_DELAY_MS,
+            tap_delay: Duration::from_millis(DEFAULT_TAP_DELAY_MS),
+            repeat_interval: repeat_interval(DEFAULT_MAX_REPEAT_RATE_HZ),
+            collapse_taps: false,
+            pending: Arc::new(RwLock::new(VecDeque::new())),
+            metrics: Arc::new(RwLock::new(HashMap::new())),
             default_delay,
             injector: None,
+            audit_log: None,
+            runs: Arc::new(std::sync::Mutex::new(Vec::new())),
+            shutting_down: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -54,10 +190,98 @@ impl MacroEngine {
             active_combos: Arc::new(RwLock::new(Vec::new())),
             recording: Arc::new(RwLock::new(None)),
             executing: Arc::new(RwLock::new(HashMap::new())),
-            max_concurrent_macros: 10,
+            held_keys: Arc::new(RwLock::new(HashSet::new())),
+            latched_combos: Arc::new(RwLock::new(HashSet::new())),
+            key_presses: broadcast::channel(KEY_PRESS_CHANNEL_SIZE).0,
+            last_triggered: Arc::new(RwLock::new(HashMap::new())),
+            max_concurrent_macros: AtomicUsize::new(10),
+            overflow_policy: OverflowPolicy::Drop,
+            macro_queue_size: DEFAULT_MACRO_QUEUE_SIZE,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_macro_actions: DEFAULT_MAX_MACRO_ACTIONS,
+            max_total_delay_ms: DEFAULT_MAX_TOTAL_DELAY_MS,
+            tap_delay: Duration::from_millis(DEFAULT_TAP_DELAY_MS),
+            repeat_interval: repeat_interval(DEFAULT_MAX_REPEAT_RATE_HZ),
+            collapse_taps: false,
+            pending: Arc::new(RwLock::new(VecDeque::new())),
+            metrics: Arc::new(RwLock::new(HashMap::new())),
             default_delay: 10,
             injector: Some(injector),
+            audit_log: None,
+            runs: Arc::new(std::sync::Mutex::new(Vec::new())),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Record every executed macro in the given audit log
+    pub fn with_audit_log(mut self, audit_log: Arc<AuditLog>) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Limit how many triggered macros run at once and what happens to the rest
+    pub fn with_concurrency(mut self, max_concurrent_macros: usize, overflow_policy: OverflowPolicy, macro_queue_size: usize) -> Self {
+        self.max_concurrent_macros = AtomicUsize::new(max_concurrent_macros);
+        self.overflow_policy = overflow_policy;
+        self.macro_queue_size = macro_queue_size;
+        self
+    }
+
+    /// How many triggered macros may currently run at once
+    pub fn max_concurrent_macros(&self) -> usize {
+        self.max_concurrent_macros.load(Ordering::SeqCst)
+    }
+
+    /// Change how many triggered macros may run at once
+    ///
+    /// Macros already running are left alone when the limit drops below
+    /// their number; new triggers wait or are dropped until enough finish.
+    pub fn set_max_concurrent_macros(&self, max_concurrent_macros: usize) -> Result<(), String> {
+        if max_concurrent_macros == 0 {
+            return Err("At least one macro must be allowed to run".to_string());
         }
+        self.max_concurrent_macros.store(max_concurrent_macros, Ordering::SeqCst);
+        info!("Max concurrent macros set to {}", max_concurrent_macros);
+        Ok(())
+    }
+
+    /// Default delay between actions in milliseconds
+    pub fn default_delay(&self) -> u32 {
+        self.default_delay
+    }
+
+    /// Limit how deeply macros may call other macros
+    pub fn with_max_call_depth(mut self, max_call_depth: usize) -> Self {
+        self.max_call_depth = max_call_depth;
+        self
+    }
+
+    /// Cap how many actions a macro may perform and how long it may delay
+    ///
+    /// SetMacro refuses macros over either cap, and runs that reach one
+    /// through CallMacro or random delays are aborted.
+    pub fn with_limits(mut self, max_macro_actions: u64, max_total_delay_ms: u64) -> Self {
+        self.max_macro_actions = max_macro_actions;
+        self.max_total_delay_ms = max_total_delay_ms;
+        self
+    }
+
+    /// Check a macro against the engine's action and delay caps
+    pub fn validate_macro(&self, macro_entry: &MacroEntry) -> Result<(), String> {
+        macro_entry.validate_with_limits(self.max_macro_actions, self.max_total_delay_ms)
+    }
+
+    /// Set how long taps hold their key and whether recordings are folded into taps
+    pub fn with_taps(mut self, tap_delay_ms: u64, collapse_taps: bool) -> Self {
+        self.tap_delay = Duration::from_millis(tap_delay_ms);
+        self.collapse_taps = collapse_taps;
+        self
+    }
+
+    /// Cap how many passes per second a hold-to-repeat macro makes
+    pub fn with_max_repeat_rate(mut self, max_repeat_rate_hz: u32) -> Self {
+        self.repeat_interval = repeat_interval(max_repeat_rate_hz);
+        self
     }
 
     /// Set the injector to use for executing actions
@@ -76,6 +300,7 @@ impl MacroEngine {
 
         // Add the macro
         macros.insert(macro_entry.name.clone(), macro_entry.clone());
+        drop(macros);
 
         // Update active combos
         self.update_active_combos().await;
@@ -95,6 +320,8 @@ impl MacroEngine {
 
         // Remove the macro
         macros.remove(name);
+        drop(macros);
+        self.last_triggered.write().await.remove(name);
 
         // Update active combos
         self.update_active_combos().await;
@@ -103,6 +330,34 @@ impl MacroEngine {
         Ok(true)
     }
 
+    /// Replace the whole macro set, e.g. after reloading it from disk
+    pub async fn replace_macros(&self, new_macros: HashMap<String, MacroEntry>) {
+        let count = new_macros.len();
+        *self.macros.write().await = new_macros;
+
+        // Update active combos
+        self.update_active_combos().await;
+
+        info!("Replaced macro set with {} macros", count);
+    }
+
+    /// Enable or disable a macro, returning false if it doesn't exist
+    pub async fn set_macro_enabled(&self, name: &str, enabled: bool) -> EngineResult<bool> {
+        let mut macros = self.macros.write().await;
+
+        let Some(macro_entry) = macros.get_mut(name) else {
+            return Ok(false);
+        };
+        macro_entry.enabled = enabled;
+        drop(macros);
+
+        // Update active combos
+        self.update_active_combos().await;
+
+        info!("{} macro: {}", if enabled { "Enabled" } else { "Disabled" }, name);
+        Ok(true)
+    }
+
     /// Get a macro by name
     pub async fn get_macro(&self, name: &str) -> Option<MacroEntry> {
         let macros = self.macros.read().await;
@@ -116,7 +371,11 @@ impl MacroEngine {
     }
 
     /// Start recording a new macro
-    pub async fn start_recording(&self, name: String, device_path: String) -> EngineResult<()> {
+    ///
+    /// With `capture_trigger` the keys pressed before the first release become
+    /// the trigger, Ctrl, Shift, Alt and Meta as modifiers, and recording of
+    /// the actions starts after that.
+    pub async fn start_recording(&self, name: String, device_id: String, capture_trigger: bool) -> EngineResult<()> {
         let mut recording = self.recording.write().await;
 
         // Check if already recording
@@ -125,15 +384,24 @@ impl MacroEngine {
         }
 
         // Create a new macro entry for recording
-        *recording = Some(MacroEntry {
-            name,
-            trigger: KeyCombo {
-                keys: vec![],
-                modifiers: vec![],
+        *recording = Some(RecordingState {
+            macro_entry: MacroEntry {
+                name,
+                trigger: KeyCombo {
+                    keys: vec![],
+                    modifiers: vec![],
+                },
+                actions: vec![],
+                device_id: Some(device_id),
+                enabled: true,
+                cooldown_ms: 0,
+                trigger_on: TriggerEdge::Press,
+                repeat_while_held: false,
+                tags: vec![],
             },
-            actions: vec![],
-            device_id: Some(device_path),
-            enabled: true,
+            last_event_time: None,
+            trigger_keys: capture_trigger.then(Vec::new),
+            trigger_releases: HashSet::new(),
         });
 
         info!("Started recording macro");
@@ -150,7 +418,10 @@ impl MacroEngine {
         }
 
         // Get the recorded macro
-        let macro_entry = recording.take().unwrap();
+        let mut macro_entry = recording.take().unwrap().macro_entry;
+        if self.collapse_taps {
+            macro_entry.actions = collapse_taps(macro_entry.actions);
+        }
 
         info!("Stopped recording macro: {}", macro_entry.name);
         Ok(Some(macro_entry))
@@ -163,20 +434,59 @@ impl MacroEngine {
     }
 
     /// Process an input event and add it to the recording if recording
-    pub async fn process_input_event(&self, key_code: u16, is_pressed: bool, device_path: &str) -> EngineResult<()> {
+    ///
+    /// `device_id` is the stable id of the device the event came from.
+    pub async fn process_input_event(&self, key_code: u16, is_pressed: bool, device_id: &str) -> EngineResult<()> {
+        // Track which keys are currently held so combos can be matched as a whole
+        let (newly_pressed, newly_released) = {
+            let mut held_keys = self.held_keys.write().await;
+            if is_pressed {
+                (held_keys.insert(key_code), false)
+            } else {
+                (false, held_keys.remove(&key_code))
+            }
+        };
+
+        if !is_pressed {
+            self.release_latched_combos(key_code).await;
+        }
+
+        // Waiting macros see every fresh press, even one that is recorded
+        // below or that triggers a macro; nobody waiting is not an error
+        if newly_pressed {
+            let _ = self.key_presses.send(key_code);
+        }
+
         // First check if we're recording
         {
             let mut recording = self.recording.write().await;
 
-            if let Some(macro_entry) = recording.as_mut() {
+            if let Some(recording_state) = recording.as_mut() {
                 // Check if the event is from the recording device
-                let should_record = if let Some(ref recording_device) = macro_entry.device_id {
-                    recording_device == device_path
+                let should_record = if let Some(ref recording_device) = recording_state.macro_entry.device_id {
+                    recording_device == device_id
                 } else {
                     true
                 };
 
+                if should_record && recording_state.record_trigger_key(key_code, is_pressed) {
+                    return Ok(());
+                }
+
                 if should_record {
+                    let macro_entry = &mut recording_state.macro_entry;
+
+                    // Insert the gap since the previous event so playback keeps the original cadence
+                    let now = Instant::now();
+                    if let Some(last_event_time) = recording_state.last_event_time {
+                        let elapsed_ms = now.duration_since(last_event_time).as_millis();
+                        let delay_ms = elapsed_ms.min(MAX_RECORDED_DELAY_MS as u128) as u32;
+                        if delay_ms > 0 {
+                            macro_entry.actions.push(Action::Delay(delay_ms));
+                        }
+                    }
+                    recording_state.last_event_time = Some(now);
+
                     // Add the action to recording
                     if is_pressed {
                         macro_entry.actions.push(Action::KeyPress(key_code));
@@ -189,9 +499,11 @@ impl MacroEngine {
             }
         }
 
-        // Not recording, check for macro triggers on key press
-        if is_pressed {
-            self.check_macro_triggers(key_code, device_path).await?;
+        // Not recording, check for macro triggers on a fresh press or release
+        if newly_pressed {
+            self.check_macro_triggers(key_code, device_id).await?;
+        } else if newly_released {
+            self.fire_triggers(key_code, device_id, false).await?;
         }
 
         Ok(())
@@ -213,47 +525,140 @@ impl MacroEngine {
         }
     }
 
+    /// Re-arm combos that were broken by releasing the given key
+    async fn release_latched_combos(&self, key_code: u16) {
+        let macros = self.macros.read().await;
+        let mut latched_combos = self.latched_combos.write().await;
+
+        latched_combos.retain(|name| match macros.get(name) {
+            Some(macro_entry) => {
+                !macro_entry.trigger.keys.contains(&key_code)
+                    && !macro_entry.trigger.modifiers.contains(&key_code)
+            }
+            None => false,
+        });
+    }
+
     /// Check if any macro should be triggered
-    pub async fn check_macro_triggers(&self, key_code: u16, device_path: &str) -> EngineResult<()> {
+    pub async fn check_macro_triggers(&self, key_code: u16, device_id: &str) -> EngineResult<()> {
+        self.fire_triggers(key_code, device_id, true).await
+    }
+
+    /// Start the macros whose trigger combo the key completed, for a press,
+    /// or broke up, for a release, if they fire on that edge
+    async fn fire_triggers(&self, key_code: u16, device_id: &str, pressed: bool) -> EngineResult<()> {
         let macros = self.macros.read().await;
+        let mut held_keys = self.held_keys.read().await.clone();
+        if !pressed {
+            // The combo counts as it was just before the key went up
+            held_keys.insert(key_code);
+        }
         let executing_count = self.executing.read().await.len();
 
-        if executing_count >= self.max_concurrent_macros {
+        if executing_count >= self.max_concurrent_macros() && self.overflow_policy == OverflowPolicy::Drop {
             warn!("Max concurrent macros reached, ignoring trigger");
             return Ok(());
         }
 
         // Check each macro
         for macro_entry in macros.values() {
-            // Skip disabled macros
-            if !macro_entry.enabled {
+            // Skip disabled macros and those that fire on the other edge
+            if !macro_entry.enabled || !macro_entry.trigger_on.fires_on(pressed) {
                 continue;
             }
 
             // Skip macros restricted to other devices
-            if let Some(ref device_id) = macro_entry.device_id {
-                if device_id != device_path {
+            if let Some(ref restriction) = macro_entry.device_id {
+                if restriction != device_id {
                     continue;
                 }
             }
 
             // Check if the trigger matches
-            if self.keys_match(&macro_entry.trigger, key_code) {
+            if self.keys_match(&macro_entry.trigger, key_code, &held_keys) {
+                // Only fire once per press of the combo; a release can only happen once
+                if pressed && !self.latched_combos.write().await.insert(macro_entry.name.clone()) {
+                    continue;
+                }
+
+                if self.in_cooldown(macro_entry).await {
+                    debug!("Macro {} is cooling down, ignoring trigger", macro_entry.name);
+                    continue;
+                }
+
                 debug!("Macro {} triggered", macro_entry.name);
-                self.execute_macro(macro_entry.clone()).await?;
+                let name = macro_entry.name.clone();
+                let result = self.start_macro(macro_entry.clone(), true, DEFAULT_REPLAY_SPEED).await;
+                self.record_execution(name, Some(device_id), &result).await;
+                result?;
             }
         }
 
         Ok(())
     }
 
-    /// Check if a key code matches a key combo
-    fn keys_match(&self, combo: &KeyCombo, key_code: u16) -> bool {
-        combo.keys.contains(&key_code)
+    /// Check whether a macro was triggered within its cooldown, stamping it if not
+    async fn in_cooldown(&self, macro_entry: &MacroEntry) -> bool {
+        if macro_entry.cooldown_ms == 0 {
+            return false;
+        }
+
+        let cooldown = Duration::from_millis(macro_entry.cooldown_ms as u64);
+        let now = Instant::now();
+        let mut last_triggered = self.last_triggered.write().await;
+        if let Some(last) = last_triggered.get(&macro_entry.name) {
+            if now.duration_since(*last) < cooldown {
+                return true;
+            }
+        }
+        last_triggered.insert(macro_entry.name.clone(), now);
+        false
+    }
+
+    /// Check if pressing a key completes a key combo
+    ///
+    /// The pressed key must be part of the combo, and every key and modifier
+    /// of the combo must currently be held down.
+    fn keys_match(&self, combo: &KeyCombo, key_code: u16, held_keys: &HashSet<u16>) -> bool {
+        if !combo.keys.contains(&key_code) && !combo.modifiers.contains(&key_code) {
+            return false;
+        }
+
+        combo_held(combo, held_keys)
+    }
+
+    /// Execute a macro, dividing its delays by `speed`
+    pub async fn execute_macro(&self, macro_entry: MacroEntry, speed: f32) -> EngineResult<()> {
+        self.execute_macro_from(macro_entry, None, speed).await
+    }
+
+    /// Execute a macro, noting the device that triggered it in the audit log
+    pub async fn execute_macro_from(&self, macro_entry: MacroEntry, device_path: Option<&str>, speed: f32) -> EngineResult<()> {
+        let name = macro_entry.name.clone();
+        let result = match check_replay_speed(speed) {
+            Ok(()) => self.start_macro(macro_entry, false, speed).await,
+            Err(e) => Err(e),
+        };
+        self.record_execution(name, device_path, &result).await;
+        result
+    }
+
+    async fn record_execution(&self, name: String, device_path: Option<&str>, result: &EngineResult<()>) {
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(AuditEvent::MacroExecuted {
+                name,
+                device_path: device_path.map(str::to_string),
+                success: result.is_ok(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            }).await;
+        }
     }
 
-    /// Execute a macro
-    pub async fn execute_macro(&self, macro_entry: MacroEntry) -> EngineResult<()> {
+    /// Spawn a task running the macro's actions
+    ///
+    /// With `limited` set the macro counts against `max_concurrent_macros`
+    /// and is queued when no slot is free.
+    async fn start_macro(&self, macro_entry: MacroEntry, limited: bool, speed: f32) -> EngineResult<()> {
         // Get injector reference
         let injector = match self.injector.as_ref() {
             Some(i) => Arc::clone(i),
@@ -263,105 +668,191 @@ impl MacroEngine {
             }
         };
 
-        // Check if already executing
-        {
-            let executing = self.executing.read().await;
+        // Check the slots and register the run under one lock, so a finishing
+        // macro can't miss a macro queued in between
+        let stop_flag = {
+            let mut executing = self.executing.write().await;
+            if self.shutting_down.load(Ordering::SeqCst) {
+                return Err("Macro engine is shutting down".into());
+            }
             if executing.contains_key(&macro_entry.name) {
                 warn!("Macro {} is already executing", macro_entry.name);
                 return Ok(());
             }
-        }
 
-        // Create execution state
-        let stop_flag = Arc::new(tokio::sync::RwLock::new(false));
-        let execution_state = ExecutionState {
-            name: macro_entry.name.clone(),
-            start_time: Instant::now(),
-            stop: stop_flag.clone(),
-        };
+            if limited && executing.len() >= self.max_concurrent_macros() {
+                let mut pending = self.pending.write().await;
+                // A full queue falls back to dropping, like the drop policy
+                if pending.len() >= self.macro_queue_size {
+                    warn!("Macro queue is full, ignoring trigger for {}", macro_entry.name);
+                    return Ok(());
+                }
+                debug!("Max concurrent macros reached, queueing {}", macro_entry.name);
+                pending.push_back(macro_entry);
+                return Ok(());
+            }
 
-        // Add to executing list
-        {
-            let mut executing = self.executing.write().await;
-            executing.insert(macro_entry.name.clone(), execution_state);
-        }
+            register_execution(&mut executing, &macro_entry.name)
+        };
 
-        // Clone actions and injector for spawned task
-        let actions = macro_entry.actions.clone();
-        let injector_clone = Arc::clone(&injector);
-        let _macro_name = macro_entry.name.clone();
+        let executing = Arc::clone(&self.executing);
+        let pending = Arc::clone(&self.pending);
+        let metrics = Arc::clone(&self.metrics);
+        let mut run = MacroRun {
+            injector,
+            held_keys: Arc::clone(&self.held_keys),
+            macros: Arc::clone(&self.macros),
+            max_call_depth: self.max_call_depth,
+            tap_delay: self.tap_delay,
+            repeat_interval: self.repeat_interval,
+            key_presses: self.key_presses.clone(),
+            speed,
+            budget: RunBudget::new(self.max_macro_actions, self.max_total_delay_ms),
+        };
+        let macro_name = macro_entry.name.clone();
 
         // Execute in a separate task
-        tokio::spawn(async move {
-            for action in actions {
-                // Check if we should stop
-                if *stop_flag.read().await {
-                    break;
+        let handle = tokio::spawn(async move {
+            let mut current = Some((macro_entry, stop_flag));
+            while let Some((macro_entry, stop_flag)) = current.take() {
+                let started = Instant::now();
+                let started_ts = unix_millis();
+                let completed = if macro_entry.repeat_while_held {
+                    run_while_held(&macro_entry, &run, &stop_flag).await
+                } else {
+                    run.budget.reset();
+                    run_actions(&macro_entry.actions, &run, &stop_flag, 0).await
+                };
+                if !completed {
+                    // A cancelled macro may have stopped between a press and its release
+                    if let Err(e) = run.injector.read().await.release_all().await {
+                        error!("Failed to release keys held by cancelled macro {}: {}", macro_entry.name, e);
+                    }
                 }
+                let mut metrics = metrics.write().await;
+                let metric = metrics
+                    .entry(macro_entry.name.clone())
+                    .or_insert_with(|| MacroMetric::new(macro_entry.name.clone()));
+                metric.record(started.elapsed(), started_ts);
+                if run.budget.exceeded.load(Ordering::SeqCst) {
+                    metric.record_failure();
+                }
+                drop(metrics);
 
-                // Get a reference to the injector for each action
-                let injector_ref = injector_clone.read().await;
-
-                match action {
-                    Action::KeyPress(code) => {
-                        if let Err(e) = injector_ref.key_press(code).await {
-                            error!("Failed to inject key press: {}", e);
-                        }
-                    }
-                    Action::KeyRelease(code) => {
-                        if let Err(e) = injector_ref.key_release(code).await {
-                            error!("Failed to inject key release: {}", e);
-                        }
-                    }
-                    Action::Delay(ms) => {
-                        tokio::time::sleep(Duration::from_millis(ms as u64)).await;
-                    }
-                    Action::Execute(cmd) => {
-                        if let Err(e) = injector_ref.execute_command(&cmd).await {
-                            error!("Failed to execute command: {}", e);
-                        }
-                    }
-                    Action::Type(text) => {
-                        if let Err(e) = injector_ref.type_string(&text).await {
-                            error!("Failed to type text: {}", e);
-                        }
-                    }
-                    Action::MousePress(button) => {
-                        if let Err(e) = injector_ref.mouse_press(button).await {
-                            error!("Failed to inject mouse press: {}", e);
-                        }
-                    }
-                    Action::MouseRelease(button) => {
-                        if let Err(e) = injector_ref.mouse_release(button).await {
-                            error!("Failed to inject mouse release: {}", e);
-                        }
-                    }
-                    Action::MouseMove(x, y) => {
-                        if let Err(e) = injector_ref.mouse_move(x, y).await {
-                            error!("Failed to inject mouse move: {}", e);
-                        }
-                    }
-                    Action::MouseScroll(amount) => {
-                        if let Err(e) = injector_ref.mouse_scroll(amount).await {
-                            error!("Failed to inject mouse scroll: {}", e);
-                        }
+                // Remove our entry, unless it was already stopped and replaced by a new run
+                let mut executing = executing.write().await;
+                if executing.get(&macro_entry.name).is_some_and(|state| Arc::ptr_eq(&state.stop, &stop_flag)) {
+                    executing.remove(&macro_entry.name);
+                }
+                debug!("Macro {} execution completed", macro_entry.name);
+
+                // Hand the freed slot to the oldest queued macro
+                let mut pending = pending.write().await;
+                while let Some(next) = pending.pop_front() {
+                    if executing.contains_key(&next.name) {
+                        warn!("Macro {} is already executing", next.name);
+                        continue;
                     }
+                    info!("Started executing queued macro: {}", next.name);
+                    // Only triggered macros are queued, and those play as recorded
+                    run.speed = DEFAULT_REPLAY_SPEED;
+                    let stop_flag = register_execution(&mut executing, &next.name);
+                    current = Some((next, stop_flag));
+                    break;
                 }
             }
-
-            // Note: We can't modify self.executing here because we're in a spawned task
-            // In a real implementation, we would use a channel or other communication method
-            debug!("Macro {} execution completed", _macro_name);
         });
 
-        info!("Started executing macro: {}", macro_entry.name);
+        let mut runs = self.runs.lock().unwrap();
+        runs.retain(|run| !run.is_finished());
+        runs.push(handle);
+        drop(runs);
+
+        info!("Started executing macro: {}", macro_name);
         Ok(())
     }
 
+    /// Stop every executing and queued macro and wait up to `timeout` for them to finish
+    ///
+    /// No macro can start once this is called. Runs still going after the
+    /// timeout are aborted. Returns how many runs were in flight.
+    pub async fn shutdown(&self, timeout: Duration) -> usize {
+        {
+            let executing = self.executing.read().await;
+            self.shutting_down.store(true, Ordering::SeqCst);
+            self.pending.write().await.clear();
+            for state in executing.values() {
+                *state.stop.write().await = true;
+            }
+        }
+
+        let runs: Vec<JoinHandle<()>> = self.runs.lock().unwrap().drain(..).filter(|run| !run.is_finished()).collect();
+        if runs.is_empty() {
+            return 0;
+        }
+
+        info!("Waiting for {} running macros to stop", runs.len());
+        let count = runs.len();
+        let aborts: Vec<_> = runs.iter().map(|run| run.abort_handle()).collect();
+        let drain = async {
+            for run in runs {
+                let _ = run.await;
+            }
+        };
+        if tokio::time::timeout(timeout, drain).await.is_err() {
+            warn!("Macros still running after {:?}, aborting them", timeout);
+            for abort in aborts {
+                abort.abort();
+            }
+            if let Some(injector) = &self.injector {
+                if let Err(e) = injector.read().await.release_all().await {
+                    error!("Failed to release keys held by aborted macros: {}", e);
+                }
+            }
+        }
+        count
+    }
+
+    /// Get the execution metrics of every macro that has run, sorted by name
+    pub async fn get_metrics(&self) -> Vec<MacroMetric> {
+        let mut metrics: Vec<MacroMetric> = self.metrics.read().await.values().cloned().collect();
+        metrics.sort_by(|a, b| a.name.cmp(&b.name));
+        metrics
+    }
+
+    /// Get the names of triggered macros waiting for a free slot, oldest first
+    pub async fn get_queued_macros(&self) -> Vec<String> {
+        self.pending.read().await.iter().map(|entry| entry.name.clone()).collect()
+    }
+
+    /// Walk a macro's actions without injecting anything
+    ///
+    /// Returns each action that would be performed together with its offset
+    /// from the start of the macro. Repeat blocks are expanded, IfKeyHeld
+    /// takes its else branch since a simulation has no keyboard to read, and
+    /// CallMacro, KeyTap, MouseClick and Chord are listed as single actions.
+    /// WaitForKey is listed as if its key were pressed straight away.
+    pub fn simulate_macro(&self, macro_entry: &MacroEntry) -> Vec<(Action, Duration)> {
+        let mut trace = Vec::new();
+        let mut elapsed = Duration::ZERO;
+        let mut steps = 0;
+        if !trace_actions(&macro_entry.actions, self.tap_delay, &mut elapsed, &mut steps, &mut trace) {
+            warn!("Trace of macro {} truncated after {} actions and {} steps", macro_entry.name, trace.len(), steps);
+        }
+
+        for (action, offset) in &trace {
+            debug!("[simulate {}] +{}ms {:?}", macro_entry.name, offset.as_millis(), action);
+        }
+        trace
+    }
+
     /// Stop an executing macro
     pub async fn stop_macro(&self, name: &str) -> EngineResult<bool> {
         let mut executing = self.executing.write().await;
 
+        // A stopped macro shouldn't start again from the queue
+        self.pending.write().await.retain(|entry| entry.name != name);
+
         if let Some(state) = executing.get(name) {
             info!("Stopping macro: {}", name);
             *state.stop.write().await = true;
@@ -384,117 +875,681 @@ impl MacroEngine {
     /// This method allows executing individual actions without creating a full macro.
     /// Used by the IPC module when executing macros that have been retrieved.
     pub async fn execute_action(&self, action: &razermapper_common::Action, injector: &(dyn crate::injector::Injector + Send + Sync)) -> EngineResult<()> {
+        self.execute_action_at(action, injector, 0).await
+    }
+
+    /// Execute a single action `depth` macro calls deep
+    async fn execute_action_at(&self, action: &Action, injector: &(dyn Injector + Send + Sync), depth: usize) -> EngineResult<()> {
         // Use the injector directly since we have a reference to it
         match action {
             razermapper_common::Action::KeyPress(code) => {
                 if let Err(e) = injector.key_press(*code).await {
                     error!("Failed to inject key press: {}", e);
-                    return Err(format!("Key press failed: {}", e).into());
+                    return Err(e.into());
                 }
             }
             razermapper_common::Action::KeyRelease(code) => {
                 if let Err(e) = injector.key_release(*code).await {
                     error!("Failed to inject key release: {}", e);
-                    return Err(format!("Key release failed: {}", e).into());
+                    return Err(e.into());
                 }
             }
             razermapper_common::Action::Delay(ms) => {
                 tokio::time::sleep(tokio::time::Duration::from_millis(*ms as u64)).await;
             }
+            razermapper_common::Action::RandomDelay { min_ms, max_ms } => {
+                tokio::time::sleep(random_delay(*min_ms, *max_ms)?).await;
+            }
             razermapper_common::Action::Execute(command) => {
                 if let Err(e) = injector.execute_command(command).await {
                     error!("Failed to execute command: {}", e);
-                    return Err(format!("Command execution failed: {}", e).into());
+                    return Err(e.into());
+                }
+            }
+            razermapper_common::Action::ExecuteCapture { command, into } => {
+                if let Err(e) = execute_capture(injector, command, *into).await {
+                    error!("Failed to capture command output: {}", e);
+                    return Err(e.into());
                 }
             }
             razermapper_common::Action::Type(text) => {
                 if let Err(e) = injector.type_string(text).await {
                     error!("Failed to type text: {}", e);
-                    return Err(format!("Text typing failed: {}", e).into());
+                    return Err(e.into());
                 }
             }
             razermapper_common::Action::MousePress(button) => {
                 if let Err(e) = injector.mouse_press(*button).await {
                     error!("Failed to inject mouse press: {}", e);
-                    return Err(format!("Mouse press failed: {}", e).into());
+                    return Err(e.into());
                 }
             }
             razermapper_common::Action::MouseRelease(button) => {
                 if let Err(e) = injector.mouse_release(*button).await {
                     error!("Failed to inject mouse release: {}", e);
-                    return Err(format!("Mouse release failed: {}", e).into());
+                    return Err(e.into());
                 }
             }
             razermapper_common::Action::MouseMove(x, y) => {
                 if let Err(e) = injector.mouse_move(*x, *y).await {
                     error!("Failed to inject mouse move: {}", e);
-                    return Err(format!("Mouse move failed: {}", e).into());
+                    return Err(e.into());
+                }
+            }
+            razermapper_common::Action::MouseMoveAbsolute(x, y) => {
+                if let Err(e) = injector.mouse_move_absolute(*x, *y).await {
+                    error!("Failed to inject absolute mouse move: {}", e);
+                    return Err(e.into());
                 }
             }
             razermapper_common::Action::MouseScroll(amount) => {
                 if let Err(e) = injector.mouse_scroll(*amount).await {
                     error!("Failed to inject mouse scroll: {}", e);
-                    return Err(format!("Mouse scroll failed: {}", e).into());
+                    return Err(e.into());
+                }
+            }
+            razermapper_common::Action::KeyTap(code) => {
+                if let Err(e) = injector.key_press(*code).await {
+                    error!("Failed to inject key press: {}", e);
+                    return Err(e.into());
+                }
+                tokio::time::sleep(self.tap_delay).await;
+                if let Err(e) = injector.key_release(*code).await {
+                    error!("Failed to inject key release: {}", e);
+                    return Err(e.into());
+                }
+            }
+            razermapper_common::Action::MouseClick(button) => {
+                if let Err(e) = injector.mouse_press(*button).await {
+                    error!("Failed to inject mouse press: {}", e);
+                    return Err(e.into());
+                }
+                tokio::time::sleep(self.tap_delay).await;
+                if let Err(e) = injector.mouse_release(*button).await {
+                    error!("Failed to inject mouse release: {}", e);
+                    return Err(e.into());
+                }
+            }
+            razermapper_common::Action::Chord(keys) => {
+                if let Err(e) = injector.press_keys_then_release(keys, self.tap_delay).await {
+                    error!("Failed to inject chord: {}", e);
+                    return Err(e.into());
+                }
+            }
+            razermapper_common::Action::Repeat { count, actions } => {
+                for _ in 0..*count {
+                    for action in actions {
+                        Box::pin(self.execute_action_at(action, injector, depth)).await?;
+                    }
+                }
+            }
+            razermapper_common::Action::CallMacro(name) => {
+                let actions = called_macro_actions(&self.macros, name, depth, self.max_call_depth).await?;
+                for action in &actions {
+                    Box::pin(self.execute_action_at(action, injector, depth + 1)).await?;
+                }
+            }
+            razermapper_common::Action::IfKeyHeld { key_code, then, else_ } => {
+                let held = self.held_keys.read().await.contains(key_code);
+                for action in if held { then } else { else_ } {
+                    Box::pin(self.execute_action_at(action, injector, depth)).await?;
                 }
             }
+            razermapper_common::Action::WaitForKey { key_code, timeout_ms } => {
+                wait_for_key(&self.key_presses, *key_code, *timeout_ms, None).await?;
+            }
         }
 
         Ok(())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::injector::Injector;
-    use std::sync::Arc;
-
-    // Create a mock injector for testing
-    struct MockInjector;
-
-    impl MockInjector {
-        fn new() -> Arc<Self> {
-            Arc::new(Self)
+/// Append the actions to a simulated trace, advancing `elapsed` over delays
+///
+/// `steps` counts every action and repeat iteration walked. Returns `false`
+/// once the trace reaches MAX_TRACE_ACTIONS or the walk MAX_TRACE_STEPS.
+fn trace_actions(
+    actions: &[Action],
+    tap_delay: Duration,
+    elapsed: &mut Duration,
+    steps: &mut usize,
+    trace: &mut Vec<(Action, Duration)>,
+) -> bool {
+    for action in actions {
+        *steps += 1;
+        if *steps > MAX_TRACE_STEPS {
+            return false;
+        }
+        match action {
+            Action::Repeat { count, actions } => {
+                for _ in 0..*count {
+                    *steps += 1;
+                    if *steps > MAX_TRACE_STEPS || !trace_actions(actions, tap_delay, elapsed, steps, trace) {
+                        return false;
+                    }
+                }
+            }
+            Action::IfKeyHeld { else_, .. } => {
+                if !trace_actions(else_, tap_delay, elapsed, steps, trace) {
+                    return false;
+                }
+            }
+            _ => {
+                if trace.len() >= MAX_TRACE_ACTIONS {
+                    return false;
+                }
+                trace.push((action.clone(), *elapsed));
+                match action {
+                    Action::Delay(ms) => *elapsed += Duration::from_millis(*ms as u64),
+                    // Offsets after a random delay are the earliest they can happen
+                    Action::RandomDelay { min_ms, .. } => *elapsed += Duration::from_millis(*min_ms as u64),
+                    Action::KeyTap(_) | Action::MouseClick(_) | Action::Chord(_) => *elapsed += tap_delay,
+                    _ => {}
+                }
+            }
         }
     }
+    true
+}
 
-    #[async_trait::async_trait]
-    impl Injector for MockInjector {
-        async fn initialize(&self) -> Result<(), Box<dyn std::error::Error>> {
-            Ok(())
+/// Fold each key press released again within MAX_TAP_HOLD_MS into a KeyTap
+///
+/// Only a press directly followed by its release, with at most a short delay
+/// in between, is folded; overlapping keys such as held modifiers stay as
+/// separate presses and releases.
+pub fn collapse_taps(actions: Vec<Action>) -> Vec<Action> {
+    let mut collapsed = Vec::with_capacity(actions.len());
+    let mut i = 0;
+    while i < actions.len() {
+        if let Action::KeyPress(code) = actions[i] {
+            match actions.get(i + 1..i + 3) {
+                Some([Action::Delay(ms), Action::KeyRelease(released)]) if *released == code && *ms <= MAX_TAP_HOLD_MS => {
+                    collapsed.push(Action::KeyTap(code));
+                    i += 3;
+                    continue;
+                }
+                _ => {}
+            }
+            if actions.get(i + 1) == Some(&Action::KeyRelease(code)) {
+                collapsed.push(Action::KeyTap(code));
+                i += 2;
+                continue;
+            }
         }
+        collapsed.push(actions[i].clone());
+        i += 1;
+    }
+    collapsed
+}
 
-        async fn key_press(&self, _key_code: u16) -> Result<(), Box<dyn std::error::Error>> {
-            Ok(())
-        }
+/// Pick a delay uniformly from `min_ms..=max_ms`
+fn random_delay(min_ms: u32, max_ms: u32) -> EngineResult<Duration> {
+    if min_ms > max_ms {
+        return Err(format!("random delay minimum {} ms is above its maximum {} ms", min_ms, max_ms).into());
+    }
+    Ok(Duration::from_millis(fastrand::u32(min_ms..=max_ms) as u64))
+}
 
-        async fn key_release(&self, _key_code: u16) -> Result<(), Box<dyn std::error::Error>> {
-            Ok(())
-        }
+/// Error kind to report to clients for an engine error
+///
+/// Injector failures keep their own kind, so a blocked command is reported as
+/// PermissionDenied; anything else is Internal.
+pub fn error_kind(error: &(dyn std::error::Error + Send + Sync + 'static)) -> ErrorKind {
+    error.downcast_ref::<InjectorError>().map(InjectorError::kind).unwrap_or(ErrorKind::Internal)
+}
+
+/// Reject replay speeds outside `REPLAY_SPEED_RANGE`, including zero, negative and NaN
+pub fn check_replay_speed(speed: f32) -> EngineResult<()> {
+    if !REPLAY_SPEED_RANGE.contains(&speed) {
+        return Err(format!(
+            "Replay speed {} is outside {} to {}",
+            speed, REPLAY_SPEED_RANGE.start(), REPLAY_SPEED_RANGE.end()
+        ).into());
+    }
+    Ok(())
+}
+
+/// Current time in milliseconds since the Unix epoch
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Add a fresh run of a macro to the executing map, returning its stop flag
+fn register_execution(executing: &mut HashMap<String, ExecutionState>, name: &str) -> Arc<RwLock<bool>> {
+    let stop_flag = Arc::new(RwLock::new(false));
+    executing.insert(name.to_string(), ExecutionState {
+        name: name.to_string(),
+        start_time: Instant::now(),
+        stop: Arc::clone(&stop_flag),
+    });
+    stop_flag
+}
+
+/// Engine state shared by a spawned macro run and the macros it calls
+struct MacroRun {
+    injector: Arc<RwLock<dyn Injector + Send + Sync>>,
+    held_keys: Arc<RwLock<HashSet<u16>>>,
+    macros: Arc<RwLock<HashMap<String, MacroEntry>>>,
+    max_call_depth: usize,
+    /// Hold time of KeyTap and MouseClick, not scaled by the replay speed
+    tap_delay: Duration,
+    /// Shortest time between the starts of two passes of a hold-to-repeat macro
+    repeat_interval: Duration,
+    key_presses: broadcast::Sender<u16>,
+    /// Replay speed every delay is divided by
+    speed: f32,
+    /// Actions and delay the current macro has used of its caps
+    budget: RunBudget,
+}
+
+/// What a single run has spent against the engine's action and delay caps
+struct RunBudget {
+    max_actions: u64,
+    max_total_delay_ms: u64,
+    actions: AtomicU64,
+    delay_ms: AtomicU64,
+    /// Set once the run was aborted for going over a cap
+    exceeded: AtomicBool,
+}
+
+impl RunBudget {
+    fn new(max_actions: u64, max_total_delay_ms: u64) -> Self {
+        Self {
+            max_actions,
+            max_total_delay_ms,
+            actions: AtomicU64::new(0),
+            delay_ms: AtomicU64::new(0),
+            exceeded: AtomicBool::new(false),
+        }
+    }
+
+    /// Start counting afresh for the next macro of a run
+    fn reset(&self) {
+        self.actions.store(0, Ordering::SeqCst);
+        self.delay_ms.store(0, Ordering::SeqCst);
+        self.exceeded.store(false, Ordering::SeqCst);
+    }
+
+    /// Count one more action, returning false if that goes over the cap
+    fn spend_action(&self) -> bool {
+        let actions = self.actions.fetch_add(1, Ordering::SeqCst) + 1;
+        if actions > self.max_actions {
+            error!("Macro aborted after performing more than {} actions", self.max_actions);
+            self.exceeded.store(true, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
 
-        async fn mouse_press(&self, _button: u16) -> Result<(), Box<dyn std::error::Error>> {
+    /// Count a delay before sleeping it, returning false if it would go over the cap
+    fn spend_delay(&self, delay: Duration) -> bool {
+        let ms = delay.as_millis().min(u64::MAX as u128) as u64;
+        let total = self.delay_ms.fetch_add(ms, Ordering::SeqCst).saturating_add(ms);
+        if total > self.max_total_delay_ms {
+            error!("Macro aborted, its delays would exceed {} ms", self.max_total_delay_ms);
+            self.exceeded.store(true, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+}
+
+/// Wait for a fresh press of `key_code`, failing once `timeout_ms` has passed
+///
+/// Returns `Ok(false)` if the stop flag is raised while waiting, which is
+/// checked every WAIT_FOR_KEY_POLL.
+async fn wait_for_key(
+    key_presses: &broadcast::Sender<u16>,
+    key_code: u16,
+    timeout_ms: Option<u32>,
+    stop_flag: Option<&RwLock<bool>>,
+) -> EngineResult<bool> {
+    let mut receiver = key_presses.subscribe();
+    let deadline = timeout_ms.map(|ms| Instant::now() + Duration::from_millis(ms as u64));
+    debug!("Waiting for key {}", key_code);
+
+    loop {
+        if let Some(stop_flag) = stop_flag {
+            if *stop_flag.read().await {
+                return Ok(false);
+            }
+        }
+        let poll = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(format!("Timed out after {} ms waiting for key {}", timeout_ms.unwrap_or(0), key_code).into());
+                }
+                left.min(WAIT_FOR_KEY_POLL)
+            }
+            None => WAIT_FOR_KEY_POLL,
+        };
+
+        match tokio::time::timeout(poll, receiver.recv()).await {
+            Ok(Ok(pressed)) if pressed == key_code => return Ok(true),
+            // Presses missed while this run was busy can't be the one awaited
+            Ok(Ok(_)) | Ok(Err(broadcast::error::RecvError::Lagged(_))) | Err(_) => {}
+            Ok(Err(broadcast::error::RecvError::Closed)) => {
+                return Err(format!("Input closed while waiting for key {}", key_code).into());
+            }
+        }
+    }
+}
+
+/// Run a command and type or log what it printed, without the trailing newline
+async fn execute_capture(injector: &(dyn Injector + Send + Sync), command: &str, into: CaptureTarget) -> InjectorResult<()> {
+    let output = injector.execute_command(command).await?;
+    let output = output.trim_end_matches(['\n', '\r']);
+    match into {
+        CaptureTarget::Type if output.is_empty() => Ok(()),
+        CaptureTarget::Type => injector.type_string(output).await,
+        CaptureTarget::Log => {
+            info!("Output of '{}': {}", command, output);
             Ok(())
         }
+    }
+}
 
-        async fn mouse_release(&self, _button: u16) -> Result<(), Box<dyn std::error::Error>> {
+/// Look up the actions of the macro named by a CallMacro action
+///
+/// `depth` is how many calls are already nested; calling again is refused
+/// once it reaches `max_call_depth`.
+async fn called_macro_actions(
+    macros: &RwLock<HashMap<String, MacroEntry>>,
+    name: &str,
+    depth: usize,
+    max_call_depth: usize,
+) -> EngineResult<Vec<Action>> {
+    if depth >= max_call_depth {
+        return Err(format!("Calling macro {} exceeds the maximum call depth of {}", name, max_call_depth).into());
+    }
+    macros.read().await
+        .get(name)
+        .map(|entry| entry.actions.clone())
+        .ok_or_else(|| format!("Called macro not found: {}", name).into())
+}
+
+/// Check whether every key and modifier of a non-empty combo is held down
+fn combo_held(combo: &KeyCombo, held_keys: &HashSet<u16>) -> bool {
+    !(combo.keys.is_empty() && combo.modifiers.is_empty())
+        && combo.keys.iter()
+            .chain(combo.modifiers.iter())
+            .all(|key| held_keys.contains(key))
+}
+
+/// Time between passes for a repeat rate, treating 0 as 1 pass per second
+fn repeat_interval(max_repeat_rate_hz: u32) -> Duration {
+    Duration::from_secs(1) / max_repeat_rate_hz.max(1)
+}
+
+/// Play a hold-to-repeat macro's actions over until its trigger combo is let go
+///
+/// The first pass always runs, so a macro started without its trigger held
+/// plays once. Each pass gets a fresh budget and passes start no closer than
+/// the engine's repeat interval. Returns false if a pass was cut short.
+async fn run_while_held(macro_entry: &MacroEntry, run: &MacroRun, stop_flag: &Arc<RwLock<bool>>) -> bool {
+    loop {
+        let started = Instant::now();
+        run.budget.reset();
+        if !run_actions(&macro_entry.actions, run, stop_flag, 0).await {
+            return false;
+        }
+        if let Some(rest) = run.repeat_interval.checked_sub(started.elapsed()) {
+            tokio::time::sleep(rest).await;
+        }
+        if *stop_flag.read().await {
+            return false;
+        }
+        if !combo_held(&macro_entry.trigger, &*run.held_keys.read().await) {
+            return true;
+        }
+    }
+}
+
+/// Run a sequence of actions, stopping as soon as the stop flag is set
+///
+/// Returns `false` if execution was stopped before all actions completed.
+fn run_actions<'a>(
+    actions: &'a [Action],
+    run: &'a MacroRun,
+    stop_flag: &'a Arc<RwLock<bool>>,
+    depth: usize,
+) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+    Box::pin(async move {
+        for action in actions {
+            // Check if we should stop
+            if *stop_flag.read().await {
+                return false;
+            }
+            // Blocks only count the actions inside them, as in validation
+            let is_block = matches!(action, Action::Repeat { .. } | Action::IfKeyHeld { .. });
+            if !is_block && !run.budget.spend_action() {
+                return false;
+            }
+
+            // Get a reference to the injector for each action
+            let injector_ref = run.injector.read().await;
+
+            match action {
+                Action::KeyPress(code) => {
+                    if let Err(e) = injector_ref.key_press(*code).await {
+                        error!("Failed to inject key press: {}", e);
+                    }
+                }
+                Action::KeyRelease(code) => {
+                    if let Err(e) = injector_ref.key_release(*code).await {
+                        error!("Failed to inject key release: {}", e);
+                    }
+                }
+                Action::Delay(ms) => {
+                    let delay = Duration::from_millis(*ms as u64);
+                    if !run.budget.spend_delay(delay) {
+                        return false;
+                    }
+                    tokio::time::sleep(delay.div_f32(run.speed)).await;
+                }
+                Action::RandomDelay { min_ms, max_ms } => match random_delay(*min_ms, *max_ms) {
+                    Ok(delay) => {
+                        if !run.budget.spend_delay(delay) {
+                            return false;
+                        }
+                        tokio::time::sleep(delay.div_f32(run.speed)).await;
+                    }
+                    Err(e) => error!("Skipping random delay: {}", e),
+                },
+                Action::Execute(cmd) => {
+                    if let Err(e) = injector_ref.execute_command(cmd).await {
+                        error!("Failed to execute command: {}", e);
+                    }
+                }
+                Action::ExecuteCapture { command, into } => {
+                    if let Err(e) = execute_capture(&*injector_ref, command, *into).await {
+                        error!("Failed to capture command output: {}", e);
+                    }
+                }
+                Action::Type(text) => {
+                    if let Err(e) = injector_ref.type_string(text).await {
+                        error!("Failed to type text: {}", e);
+                    }
+                }
+                Action::MousePress(button) => {
+                    if let Err(e) = injector_ref.mouse_press(*button).await {
+                        error!("Failed to inject mouse press: {}", e);
+                    }
+                }
+                Action::MouseRelease(button) => {
+                    if let Err(e) = injector_ref.mouse_release(*button).await {
+                        error!("Failed to inject mouse release: {}", e);
+                    }
+                }
+                Action::MouseMove(x, y) => {
+                    if let Err(e) = injector_ref.mouse_move(*x, *y).await {
+                        error!("Failed to inject mouse move: {}", e);
+                    }
+                }
+                Action::MouseMoveAbsolute(x, y) => {
+                    if let Err(e) = injector_ref.mouse_move_absolute(*x, *y).await {
+                        error!("Failed to inject absolute mouse move: {}", e);
+                    }
+                }
+                Action::MouseScroll(amount) => {
+                    if let Err(e) = injector_ref.mouse_scroll(*amount).await {
+                        error!("Failed to inject mouse scroll: {}", e);
+                    }
+                }
+                Action::KeyTap(code) => {
+                    if let Err(e) = injector_ref.key_press(*code).await {
+                        error!("Failed to inject key press: {}", e);
+                    }
+                    tokio::time::sleep(run.tap_delay).await;
+                    if let Err(e) = injector_ref.key_release(*code).await {
+                        error!("Failed to inject key release: {}", e);
+                    }
+                }
+                Action::MouseClick(button) => {
+                    if let Err(e) = injector_ref.mouse_press(*button).await {
+                        error!("Failed to inject mouse press: {}", e);
+                    }
+                    tokio::time::sleep(run.tap_delay).await;
+                    if let Err(e) = injector_ref.mouse_release(*button).await {
+                        error!("Failed to inject mouse release: {}", e);
+                    }
+                }
+                Action::Chord(keys) => {
+                    if let Err(e) = injector_ref.press_keys_then_release(keys, run.tap_delay).await {
+                        error!("Failed to inject chord: {}", e);
+                    }
+                }
+                Action::Repeat { count, actions } => {
+                    // Nested sequences take their own injector reads
+                    drop(injector_ref);
+                    for _ in 0..*count {
+                        // Check once per iteration too, so a body with nothing
+                        // to wait on can neither outlive a stop nor hog the worker
+                        if *stop_flag.read().await {
+                            return false;
+                        }
+                        tokio::task::yield_now().await;
+                        if !run_actions(actions, run, stop_flag, depth).await {
+                            return false;
+                        }
+                    }
+                }
+                Action::CallMacro(name) => {
+                    drop(injector_ref);
+                    // A failed call aborts the whole run rather than skipping
+                    // ahead, so runaway recursion unwinds immediately
+                    let actions = match called_macro_actions(&run.macros, name, depth, run.max_call_depth).await {
+                        Ok(actions) => actions,
+                        Err(e) => {
+                            error!("{}", e);
+                            return false;
+                        }
+                    };
+                    if !run_actions(&actions, run, stop_flag, depth + 1).await {
+                        return false;
+                    }
+                }
+                Action::IfKeyHeld { key_code, then, else_ } => {
+                    drop(injector_ref);
+                    let held = run.held_keys.read().await.contains(key_code);
+                    if !run_actions(if held { then } else { else_ }, run, stop_flag, depth).await {
+                        return false;
+                    }
+                }
+                Action::WaitForKey { key_code, timeout_ms } => {
+                    drop(injector_ref);
+                    match wait_for_key(&run.key_presses, *key_code, *timeout_ms, Some(stop_flag)).await {
+                        Ok(true) => {}
+                        Ok(false) => return false,
+                        Err(e) => {
+                            error!("{}", e);
+                            return false;
+                        }
+                    }
+                }
+            }
+        }
+
+        true
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::injector::Injector;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Create a mock injector for testing that counts injected key presses
+    struct MockInjector {
+        key_presses: Arc<AtomicUsize>,
+        pressed_keys: Option<Arc<std::sync::Mutex<Vec<u16>>>>,
+    }
+
+    impl MockInjector {
+        fn shared(key_presses: Arc<AtomicUsize>) -> Arc<RwLock<dyn Injector + Send + Sync>> {
+            Arc::new(RwLock::new(Self { key_presses, pressed_keys: None }))
+        }
+
+        /// Mock that also records the order keys were pressed in
+        fn recording(pressed_keys: Arc<std::sync::Mutex<Vec<u16>>>) -> Arc<RwLock<dyn Injector + Send + Sync>> {
+            Arc::new(RwLock::new(Self {
+                key_presses: Arc::new(AtomicUsize::new(0)),
+                pressed_keys: Some(pressed_keys),
+            }))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Injector for MockInjector {
+        async fn initialize(&self) -> crate::injector::InjectorResult<()> {
+            Ok(())
+        }
+
+        async fn key_press(&self, key_code: u16) -> crate::injector::InjectorResult<()> {
+            self.key_presses.fetch_add(1, Ordering::SeqCst);
+            if let Some(pressed_keys) = &self.pressed_keys {
+                pressed_keys.lock().unwrap().push(key_code);
+            }
+            Ok(())
+        }
+
+        async fn key_release(&self, _key_code: u16) -> crate::injector::InjectorResult<()> {
+            Ok(())
+        }
+
+        async fn mouse_press(&self, _button: u16) -> crate::injector::InjectorResult<()> {
             Ok(())
         }
 
-        async fn mouse_move(&self, _x: i32, _y: i32) -> Result<(), Box<dyn std::error::Error>> {
+        async fn mouse_release(&self, _button: u16) -> crate::injector::InjectorResult<()> {
             Ok(())
         }
 
-        async fn mouse_scroll(&self, _amount: i32) -> Result<(), Box<dyn std::error::Error>> {
+        async fn mouse_move(&self, _x: i32, _y: i32) -> crate::injector::InjectorResult<()> {
             Ok(())
         }
 
-        async fn type_string(&self, _text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        async fn mouse_move_absolute(&self, _x: i32, _y: i32) -> crate::injector::InjectorResult<()> {
             Ok(())
         }
 
-        async fn execute_command(&self, _command: &str) -> Result<(), Box<dyn std::error::Error>> {
+        async fn mouse_scroll(&self, _amount: i32) -> crate::injector::InjectorResult<()> {
             Ok(())
         }
+
+        async fn type_string(&self, _text: &str) -> crate::injector::InjectorResult<()> {
+            Ok(())
+        }
+
+        async fn execute_command(&self, _command: &str) -> crate::injector::InjectorResult<String> {
+            Ok(String::new())
+        }
     }
 
     #[tokio::test]
@@ -514,6 +1569,10 @@ mod tests {
             ],
             device_id: None,
             enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
         };
 
         // Add macro
@@ -538,6 +1597,10 @@ mod tests {
             actions: vec![],
             device_id: None,
             enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
         };
 
         // Add macro
@@ -554,12 +1617,43 @@ mod tests {
         assert!(engine.get_macro("Test Macro").await.is_none());
     }
 
+    #[tokio::test]
+    async fn test_set_macro_enabled() {
+        let engine = MacroEngine::new();
+
+        let macro_entry = MacroEntry {
+            name: "Toggle Macro".to_string(),
+            trigger: KeyCombo {
+                keys: vec![30],
+                modifiers: vec![],
+            },
+            actions: vec![Action::KeyPress(31)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        engine.add_macro(macro_entry).await.unwrap();
+        assert_eq!(engine.active_combos.read().await.len(), 1);
+
+        assert!(engine.set_macro_enabled("Toggle Macro", false).await.unwrap());
+        assert!(!engine.get_macro("Toggle Macro").await.unwrap().enabled);
+        assert!(engine.active_combos.read().await.is_empty());
+
+        assert!(engine.set_macro_enabled("Toggle Macro", true).await.unwrap());
+        assert_eq!(engine.active_combos.read().await.len(), 1);
+
+        assert!(!engine.set_macro_enabled("Missing", true).await.unwrap());
+    }
+
     #[tokio::test]
     async fn test_macro_recording() {
         let engine = MacroEngine::new();
 
         // Start recording
-        engine.start_recording("Test Recording".to_string(), "/dev/input/event0".to_string()).await.unwrap();
+        engine.start_recording("Test Recording".to_string(), "/dev/input/event0".to_string(), false).await.unwrap();
         assert!(engine.is_recording().await);
 
         // Process some events
@@ -569,19 +1663,777 @@ mod tests {
         // Stop recording
         let macro_entry = engine.stop_recording().await.unwrap().unwrap();
         assert_eq!(macro_entry.name, "Test Recording");
-        assert_eq!(macro_entry.actions.len(), 2);
+        let key_actions = macro_entry.actions.iter()
+            .filter(|a| !matches!(a, Action::Delay(_)))
+            .count();
+        assert_eq!(key_actions, 2);
 
         // Verify recording stopped
         assert!(!engine.is_recording().await);
     }
 
+    #[tokio::test]
+    async fn test_recording_captures_trigger() {
+        let engine = MacroEngine::new();
+        engine.start_recording("Captured".to_string(), "/dev/input/event0".to_string(), true).await.unwrap();
+
+        // Ctrl+A is the trigger, B is the body
+        engine.process_input_event(29, true, "/dev/input/event0").await.unwrap();
+        engine.process_input_event(30, true, "/dev/input/event0").await.unwrap();
+        engine.process_input_event(30, false, "/dev/input/event0").await.unwrap();
+        engine.process_input_event(29, false, "/dev/input/event0").await.unwrap();
+        engine.process_input_event(48, true, "/dev/input/event0").await.unwrap();
+        engine.process_input_event(48, false, "/dev/input/event0").await.unwrap();
+
+        let macro_entry = engine.stop_recording().await.unwrap().unwrap();
+        assert_eq!(macro_entry.trigger, KeyCombo { keys: vec![30], modifiers: vec![29] });
+        let key_actions: Vec<_> = macro_entry.actions.iter().filter(|a| !matches!(a, Action::Delay(_))).collect();
+        assert_eq!(key_actions, [&Action::KeyPress(48), &Action::KeyRelease(48)]);
+    }
+
+    #[tokio::test]
+    async fn test_macro_recording_timing() {
+        let engine = MacroEngine::new();
+
+        engine.start_recording("Timed Recording".to_string(), "/dev/input/event0".to_string(), false).await.unwrap();
+
+        engine.process_input_event(30, true, "/dev/input/event0").await.unwrap(); // A down
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        engine.process_input_event(30, false, "/dev/input/event0").await.unwrap(); // A up
+
+        let macro_entry = engine.stop_recording().await.unwrap().unwrap();
+        assert_eq!(macro_entry.actions.len(), 3);
+        assert!(matches!(macro_entry.actions[0], Action::KeyPress(30)));
+        match macro_entry.actions[1] {
+            Action::Delay(ms) => assert!((50..=MAX_RECORDED_DELAY_MS).contains(&ms)),
+            ref other => panic!("Expected Delay, got {:?}", other),
+        }
+        assert!(matches!(macro_entry.actions[2], Action::KeyRelease(30)));
+    }
+
     #[tokio::test]
     async fn test_macro_triggering() {
-        let _engine = MacroEngine::new();
+        let key_presses = Arc::new(AtomicUsize::new(0));
+        let engine = MacroEngine::with_injector(MockInjector::shared(Arc::clone(&key_presses)));
+
+        let macro_entry = MacroEntry {
+            name: "Ctrl+A".to_string(),
+            trigger: KeyCombo {
+                keys: vec![30], // A key
+                modifiers: vec![29], // Ctrl key
+            },
+            actions: vec![Action::KeyPress(48)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+        engine.add_macro(macro_entry).await.unwrap();
+
+        // A alone must not trigger the combo
+        engine.process_input_event(30, true, "/dev/input/event0").await.unwrap();
+        engine.process_input_event(30, false, "/dev/input/event0").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(key_presses.load(Ordering::SeqCst), 0);
+
+        // Ctrl then A triggers it once
+        engine.process_input_event(29, true, "/dev/input/event0").await.unwrap();
+        engine.process_input_event(30, true, "/dev/input/event0").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(key_presses.load(Ordering::SeqCst), 1);
+
+        // A repeated press while the combo is still held does not fire again
+        engine.process_input_event(30, true, "/dev/input/event0").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(key_presses.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cooldown_suppresses_rapid_triggers() {
+        let key_presses = Arc::new(AtomicUsize::new(0));
+        let engine = MacroEngine::with_injector(MockInjector::shared(Arc::clone(&key_presses)));
+
+        let mut macro_entry = macro_named("Cooldown", vec![Action::KeyPress(48)]);
+        macro_entry.trigger.keys = vec![30];
+        macro_entry.cooldown_ms = 200;
+        engine.add_macro(macro_entry).await.unwrap();
+
+        engine.process_input_event(30, true, "/dev/input/event0").await.unwrap();
+        engine.process_input_event(30, false, "/dev/input/event0").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(key_presses.load(Ordering::SeqCst), 1);
+
+        // A second press inside the window is ignored
+        engine.process_input_event(30, true, "/dev/input/event0").await.unwrap();
+        engine.process_input_event(30, false, "/dev/input/event0").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(key_presses.load(Ordering::SeqCst), 1);
+
+        // Once the window has passed the trigger works again
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        engine.process_input_event(30, true, "/dev/input/event0").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(key_presses.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_release_triggered_macro() {
+        let key_presses = Arc::new(AtomicUsize::new(0));
+        let engine = MacroEngine::with_injector(MockInjector::shared(Arc::clone(&key_presses)));
+
+        let mut macro_entry = macro_named("OnRelease", vec![Action::KeyPress(48)]);
+        macro_entry.trigger.keys = vec![30];
+        macro_entry.trigger_on = TriggerEdge::Release;
+        engine.add_macro(macro_entry).await.unwrap();
+
+        // Pressing the trigger does nothing
+        engine.process_input_event(30, true, "/dev/input/event0").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(key_presses.load(Ordering::SeqCst), 0);
+
+        // Letting go of it fires the macro
+        engine.process_input_event(30, false, "/dev/input/event0").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(key_presses.load(Ordering::SeqCst), 1);
+
+        // A release without a matching press is ignored
+        engine.process_input_event(30, false, "/dev/input/event0").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(key_presses.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_repeat_while_held() {
+        let key_presses = Arc::new(AtomicUsize::new(0));
+        let engine = MacroEngine::with_injector(MockInjector::shared(Arc::clone(&key_presses)))
+            .with_max_repeat_rate(100);
+
+        let mut macro_entry = macro_named("AutoClick", vec![Action::KeyPress(48)]);
+        macro_entry.trigger.keys = vec![30];
+        macro_entry.repeat_while_held = true;
+        engine.add_macro(macro_entry).await.unwrap();
+
+        // Holding the trigger keeps the macro going
+        engine.process_input_event(30, true, "/dev/input/event0").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        let while_held = key_presses.load(Ordering::SeqCst);
+        assert!(while_held > 2, "only {} passes while held", while_held);
+        // The rate cap keeps it from spinning
+        assert!(while_held <= 10, "{} passes in 80ms at 100Hz", while_held);
+
+        // Letting go ends it after the pass in flight
+        engine.process_input_event(30, false, "/dev/input/event0").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let after_release = key_presses.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(key_presses.load(Ordering::SeqCst), after_release);
+        assert!(engine.get_executing_macros().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_key_pauses_until_pressed() {
+        use crate::testing::InjectedAction;
+
+        let recorder = crate::testing::RecordingInjector::new();
+        let engine = MacroEngine::with_injector(recorder.shared());
+        let guided = macro_named("Guided", vec![
+            Action::KeyPress(30),
+            Action::WaitForKey { key_code: 28, timeout_ms: None },
+            Action::KeyPress(31),
+        ]);
+
+        engine.execute_macro(guided, DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(recorder.recorded(), vec![InjectedAction::KeyPress(30)]);
+
+        // Other keys don't release the wait
+        engine.process_input_event(57, true, "/dev/input/event0").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(recorder.recorded().len(), 1);
+
+        engine.process_input_event(28, true, "/dev/input/event0").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(recorder.recorded(), vec![InjectedAction::KeyPress(30), InjectedAction::KeyPress(31)]);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_key_times_out() {
+        use crate::testing::InjectedAction;
+
+        let recorder = crate::testing::RecordingInjector::new();
+        let engine = MacroEngine::with_injector(recorder.shared());
+        let guided = macro_named("Impatient", vec![
+            Action::WaitForKey { key_code: 28, timeout_ms: Some(60) },
+            Action::KeyPress(31),
+        ]);
+
+        // The run is abandoned, releasing anything it held
+        engine.execute_macro(guided.clone(), DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(recorder.recorded(), vec![InjectedAction::ReleaseAll]);
+
+        // Running it directly reports the timeout
+        let err = engine.execute_action(&guided.actions[0], &recorder).await.unwrap_err();
+        assert!(err.to_string().contains("Timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_execute_capture_uses_command_output() {
+        use crate::testing::{InjectedAction, RecordingInjector};
+
+        let engine = MacroEngine::new();
+        let recorder = RecordingInjector::new().with_command_output("Playing\n");
+        let capture = |into| Action::ExecuteCapture { command: "playerctl status".to_string(), into };
+
+        engine.execute_action(&capture(CaptureTarget::Type), &recorder).await.unwrap();
+        engine.execute_action(&capture(CaptureTarget::Log), &recorder).await.unwrap();
+        assert_eq!(recorder.recorded(), vec![
+            InjectedAction::ExecuteCommand("playerctl status".to_string()),
+            InjectedAction::TypeString("Playing".to_string()),
+            InjectedAction::ExecuteCommand("playerctl status".to_string()),
+        ]);
+
+        // Commands off the whitelist are refused before anything is typed
+        let injector = crate::injector::UinputInjector::new().unwrap();
+        let blocked = Action::ExecuteCapture { command: "cat /etc/shadow".to_string(), into: CaptureTarget::Type };
+        let err = engine.execute_action(&blocked, &injector).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<InjectorError>(), Some(InjectorError::CommandNotAllowed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_injector_errors_keep_their_kind() {
+        let engine = MacroEngine::new();
+        let injector = crate::injector::UinputInjector::new().unwrap();
+
+        let err = engine.execute_action(&Action::Execute("rm -rf /".to_string()), &injector).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<InjectorError>(), Some(InjectorError::CommandNotAllowed(_))));
+        assert_eq!(error_kind(err.as_ref()), ErrorKind::PermissionDenied);
+
+        let other: Box<dyn std::error::Error + Send + Sync> = "queue full".into();
+        assert_eq!(error_kind(other.as_ref()), ErrorKind::Internal);
+    }
+
+    #[tokio::test]
+    async fn test_repeat_action() {
+        let key_presses = Arc::new(AtomicUsize::new(0));
+        let engine = MacroEngine::with_injector(MockInjector::shared(Arc::clone(&key_presses)));
+
+        let macro_entry = MacroEntry {
+            name: "Repeat".to_string(),
+            trigger: KeyCombo {
+                keys: vec![30],
+                modifiers: vec![],
+            },
+            actions: vec![Action::Repeat {
+                count: 3,
+                actions: vec![
+                    Action::KeyPress(48),
+                    Action::KeyRelease(48),
+                    Action::Repeat {
+                        count: 2,
+                        actions: vec![Action::KeyPress(49), Action::KeyRelease(49)],
+                    },
+                ],
+            }],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+
+        engine.execute_macro(macro_entry, DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // 3 outer presses plus 3 * 2 nested presses
+        assert_eq!(key_presses.load(Ordering::SeqCst), 9);
+    }
+
+    #[tokio::test]
+    async fn test_stop_ends_repeat_without_actions() {
+        let engine = MacroEngine::with_injector(crate::testing::RecordingInjector::new().shared());
+        let spinning = macro_named("Spinning", vec![Action::Repeat {
+            count: u32::MAX,
+            actions: vec![Action::Repeat { count: u32::MAX, actions: vec![] }],
+        }]);
+
+        engine.execute_macro(spinning, DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(engine.stop_macro("Spinning").await.unwrap());
+
+        // The run records its metric once run_actions has returned
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(engine.get_metrics().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_macro_releases_held_keys() {
+        use crate::testing::InjectedAction;
+
+        let recorder = crate::testing::RecordingInjector::new();
+        let engine = MacroEngine::with_injector(recorder.shared());
+        let holding = macro_named("Holding", vec![Action::KeyPress(42), Action::Delay(200), Action::KeyRelease(42)]);
+
+        engine.execute_macro(holding.clone(), DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(engine.stop_macro("Holding").await.unwrap());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(recorder.recorded(), vec![InjectedAction::KeyPress(42), InjectedAction::ReleaseAll]);
+
+        // A run that completes releases its own keys
+        engine.execute_macro(macro_named("Quick", vec![Action::KeyTap(30)]), DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(recorder.recorded().last(), Some(&InjectedAction::KeyRelease(30)));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_running_macros() {
+        let engine = MacroEngine::with_injector(crate::testing::RecordingInjector::new().shared());
+        let looping = macro_named("Looping", vec![Action::Repeat {
+            count: 1000,
+            actions: vec![Action::KeyPress(30), Action::Delay(10), Action::KeyRelease(30)],
+        }]);
+        let sleeping = macro_named("Sleeping", vec![Action::Delay(60_000)]);
+
+        engine.execute_macro(looping, DEFAULT_REPLAY_SPEED).await.unwrap();
+        engine.execute_macro(sleeping, DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // The loop stops at its next action, the long delay is aborted at the timeout
+        let started = Instant::now();
+        assert_eq!(engine.shutdown(Duration::from_millis(200)).await, 2);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let late = macro_named("Late", vec![Action::KeyPress(30)]);
+        assert!(engine.execute_macro(late, DEFAULT_REPLAY_SPEED).await.is_err());
+        assert_eq!(engine.shutdown(Duration::from_millis(200)).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_simulate_macro() {
+        let key_presses = Arc::new(AtomicUsize::new(0));
+        let engine = MacroEngine::with_injector(MockInjector::shared(Arc::clone(&key_presses)));
+
+        let macro_entry = MacroEntry {
+            name: "Simulated".to_string(),
+            trigger: KeyCombo {
+                keys: vec![30],
+                modifiers: vec![],
+            },
+            actions: vec![
+                Action::KeyPress(30),
+                Action::Delay(50),
+                Action::Repeat {
+                    count: 2,
+                    actions: vec![Action::KeyPress(31), Action::Delay(10)],
+                },
+                Action::KeyRelease(30),
+            ],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+
+        let trace = engine.simulate_macro(&macro_entry);
+        let offsets: Vec<u128> = trace.iter().map(|(_, offset)| offset.as_millis()).collect();
+        assert_eq!(offsets, vec![0, 0, 50, 50, 60, 60, 70]);
+        assert!(matches!(trace[6].0, Action::KeyRelease(30)));
+        assert_eq!(key_presses.load(Ordering::SeqCst), 0);
+
+        let huge = MacroEntry {
+            actions: vec![Action::Repeat { count: u32::MAX, actions: vec![Action::KeyPress(30)] }],
+            ..macro_entry
+        };
+        assert_eq!(engine.simulate_macro(&huge).len(), MAX_TRACE_ACTIONS);
+
+        // Loops that never add to the trace still end
+        let empty = MacroEntry {
+            actions: vec![Action::Repeat {
+                count: u32::MAX,
+                actions: vec![Action::Repeat { count: u32::MAX, actions: vec![] }],
+            }],
+            ..huge
+        };
+        assert!(engine.simulate_macro(&empty).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_finished_macro_is_removed() {
+        let key_presses = Arc::new(AtomicUsize::new(0));
+        let engine = MacroEngine::with_injector(MockInjector::shared(Arc::clone(&key_presses)));
+
+        let macro_entry = MacroEntry {
+            name: "Short".to_string(),
+            trigger: KeyCombo {
+                keys: vec![30],
+                modifiers: vec![],
+            },
+            actions: vec![Action::KeyPress(48), Action::Delay(10), Action::KeyRelease(48)],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+
+        engine.execute_macro(macro_entry.clone(), DEFAULT_REPLAY_SPEED).await.unwrap();
+        assert_eq!(engine.get_executing_macros().await, vec!["Short".to_string()]);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(engine.get_executing_macros().await.is_empty());
+
+        // The macro can be triggered again once it has finished
+        engine.execute_macro(macro_entry, DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(key_presses.load(Ordering::SeqCst), 2);
+        assert!(engine.get_executing_macros().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queued_macros_run_in_trigger_order() {
+        let pressed_keys = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let engine = MacroEngine::with_injector(MockInjector::recording(Arc::clone(&pressed_keys)))
+            .with_concurrency(1, OverflowPolicy::Queue, 2);
+
+        // Four macros triggered by keys 2-5 that each press their own key and take a while
+        for key in 2..=5u16 {
+            engine.add_macro(MacroEntry {
+                name: format!("Macro {}", key),
+                trigger: KeyCombo {
+                    keys: vec![key],
+                    modifiers: vec![],
+                },
+                actions: vec![Action::KeyPress(key + 100), Action::Delay(30)],
+                device_id: None,
+                enabled: true,
+                cooldown_ms: 0,
+                trigger_on: TriggerEdge::Press,
+                repeat_while_held: false,
+                tags: vec![],
+            }).await.unwrap();
+        }
+
+        for key in 2..=5u16 {
+            engine.process_input_event(key, true, "/dev/input/event0").await.unwrap();
+            engine.process_input_event(key, false, "/dev/input/event0").await.unwrap();
+        }
+
+        // One runs, two wait and the last trigger doesn't fit in the queue
+        assert_eq!(engine.get_executing_macros().await, vec!["Macro 2".to_string()]);
+        assert_eq!(engine.get_queued_macros().await, vec!["Macro 3".to_string(), "Macro 4".to_string()]);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(*pressed_keys.lock().unwrap(), vec![102, 103, 104]);
+        assert!(engine.get_executing_macros().await.is_empty());
+        assert!(engine.get_queued_macros().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_if_key_held_picks_branch_from_held_keys() {
+        let pressed_keys = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let injector = MockInjector::recording(Arc::clone(&pressed_keys));
+        let engine = MacroEngine::with_injector(Arc::clone(&injector));
+
+        let shifted = MacroEntry {
+            name: "Shifted".to_string(),
+            trigger: KeyCombo {
+                keys: vec![30],
+                modifiers: vec![],
+            },
+            actions: vec![
+                Action::KeyPress(2),
+                Action::IfKeyHeld {
+                    key_code: 42,
+                    then: vec![Action::KeyPress(3)],
+                    else_: vec![Action::KeyPress(4), Action::KeyPress(5)],
+                },
+            ],
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        };
+
+        engine.execute_macro(shifted.clone(), DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*pressed_keys.lock().unwrap(), vec![2, 4, 5]);
+
+        // Shift held while the macro runs
+        pressed_keys.lock().unwrap().clear();
+        engine.held_keys.write().await.insert(42);
+        engine.execute_macro(shifted.clone(), DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*pressed_keys.lock().unwrap(), vec![2, 3]);
+
+        // Single actions executed directly read the same state
+        pressed_keys.lock().unwrap().clear();
+        engine.execute_action(&shifted.actions[1], &*injector.read().await).await.unwrap();
+        engine.held_keys.write().await.clear();
+        engine.execute_action(&shifted.actions[1], &*injector.read().await).await.unwrap();
+        assert_eq!(*pressed_keys.lock().unwrap(), vec![3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_random_delay_stays_in_bounds() {
+        for _ in 0..1000 {
+            let delay = random_delay(20, 40).unwrap();
+            assert!(delay >= Duration::from_millis(20) && delay <= Duration::from_millis(40));
+        }
+        assert_eq!(random_delay(15, 15).unwrap(), Duration::from_millis(15));
+        assert!(random_delay(40, 20).is_err());
+
+        let injector = MockInjector::shared(Arc::new(AtomicUsize::new(0)));
+        let engine = MacroEngine::with_injector(Arc::clone(&injector));
+        for _ in 0..5 {
+            let start = Instant::now();
+            engine.execute_action(&Action::RandomDelay { min_ms: 10, max_ms: 30 }, &*injector.read().await).await.unwrap();
+            let elapsed = start.elapsed();
+            // Sleeping may overshoot, so only the lower bound is exact
+            assert!(elapsed >= Duration::from_millis(10) && elapsed < Duration::from_millis(200), "{:?}", elapsed);
+        }
+
+        let inverted = Action::RandomDelay { min_ms: 30, max_ms: 10 };
+        assert!(engine.execute_action(&inverted, &*injector.read().await).await.is_err());
+    }
+
+    fn macro_named(name: &str, actions: Vec<Action>) -> MacroEntry {
+        MacroEntry {
+            name: name.to_string(),
+            trigger: KeyCombo {
+                keys: vec![],
+                modifiers: vec![],
+            },
+            actions,
+            device_id: None,
+            enabled: true,
+            cooldown_ms: 0,
+            trigger_on: TriggerEdge::Press,
+            repeat_while_held: false,
+            tags: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_finished_runs_update_metrics() {
+        let key_presses = Arc::new(AtomicUsize::new(0));
+        let engine = MacroEngine::with_injector(MockInjector::shared(Arc::clone(&key_presses)));
+        assert!(engine.get_metrics().await.is_empty());
+
+        let slow = macro_named("Slow", vec![Action::KeyPress(48), Action::Delay(40)]);
+        engine.execute_macro(slow.clone(), DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        engine.execute_macro(slow, DEFAULT_REPLAY_SPEED).await.unwrap();
+        engine.execute_macro(macro_named("Quick", vec![Action::KeyPress(30)]), DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let metrics = engine.get_metrics().await;
+        let names: Vec<&str> = metrics.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["Quick", "Slow"]);
+        assert_eq!(metrics[0].total_executions, 1);
+        assert_eq!(metrics[1].total_executions, 2);
+        assert!(metrics[1].average_duration >= Duration::from_millis(40));
+        assert!(metrics[1].last_run_ts > 0);
+    }
+
+    #[tokio::test]
+    async fn test_replay_speed_scales_delays() {
+        let recorder = crate::testing::RecordingInjector::new();
+        let engine = MacroEngine::with_injector(recorder.shared());
+        let timed = macro_named("Timed", vec![Action::KeyPress(30), Action::Delay(200), Action::KeyRelease(30)]);
+
+        engine.execute_macro(timed.clone(), 2.0).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let metric = engine.get_metrics().await.remove(0);
+        assert!(metric.average_duration >= Duration::from_millis(100), "{:?}", metric.average_duration);
+        assert!(metric.average_duration < Duration::from_millis(190), "{:?}", metric.average_duration);
+        assert_eq!(recorder.recorded().len(), 2);
 
-        // Note: This test is disabled for now because we can't use MockInjector with set_injector anymore
-        // In a real test, we would need to create a UinputInjector
-        // For now, we'll just test that's macro engine creates without error
-        assert!(true); // Placeholder assertion to indicate test purpose
+        for speed in [0.0, -1.0, f32::NAN, 1000.0] {
+            assert!(engine.execute_macro(timed.clone(), speed).await.is_err());
+        }
+        assert!(engine.get_executing_macros().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_taps_expand_to_press_and_release() {
+        use crate::testing::InjectedAction;
+
+        let recorder = crate::testing::RecordingInjector::new();
+        let engine = MacroEngine::with_injector(recorder.shared()).with_taps(5, true);
+
+        engine.execute_macro(macro_named("Taps", vec![Action::KeyTap(30), Action::MouseClick(1)]), DEFAULT_REPLAY_SPEED)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(recorder.recorded(), vec![
+            InjectedAction::KeyPress(30),
+            InjectedAction::KeyRelease(30),
+            InjectedAction::MousePress(1),
+            InjectedAction::MouseRelease(1),
+        ]);
+
+        let trace = engine.simulate_macro(&macro_named("Taps", vec![Action::KeyTap(30), Action::KeyTap(31)]));
+        assert_eq!(trace[1].1, Duration::from_millis(5));
+    }
+
+    #[tokio::test]
+    async fn test_chord_uses_batched_injection() {
+        use crate::testing::InjectedAction;
+
+        let recorder = crate::testing::RecordingInjector::new();
+        let engine = MacroEngine::with_injector(recorder.shared()).with_taps(5, false);
+
+        engine.execute_macro(macro_named("Chord", vec![Action::Chord(vec![30, 31, 32]), Action::KeyTap(33)]), DEFAULT_REPLAY_SPEED)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(recorder.recorded(), vec![
+            InjectedAction::Chord(vec![30, 31, 32]),
+            InjectedAction::KeyPress(33),
+            InjectedAction::KeyRelease(33),
+        ]);
+
+        let trace = engine.simulate_macro(&macro_named("Chord", vec![Action::Chord(vec![30, 31]), Action::KeyTap(33)]));
+        assert_eq!(trace[1].1, Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_collapse_taps() {
+        let recorded = vec![
+            Action::KeyPress(42),
+            Action::KeyPress(30),
+            Action::Delay(40),
+            Action::KeyRelease(30),
+            Action::KeyRelease(42),
+            Action::KeyPress(31),
+            Action::Delay(MAX_TAP_HOLD_MS + 1),
+            Action::KeyRelease(31),
+        ];
+        assert_eq!(collapse_taps(recorded), vec![
+            Action::KeyPress(42),
+            Action::KeyTap(30),
+            Action::KeyRelease(42),
+            Action::KeyPress(31),
+            Action::Delay(MAX_TAP_HOLD_MS + 1),
+            Action::KeyRelease(31),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_call_macro_runs_called_actions_inline() {
+        let pressed_keys = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let injector = MockInjector::recording(Arc::clone(&pressed_keys));
+        let engine = MacroEngine::with_injector(Arc::clone(&injector));
+
+        engine.add_macro(macro_named("B", vec![Action::KeyPress(3), Action::KeyPress(4)])).await.unwrap();
+        let a = macro_named("A", vec![Action::KeyPress(2), Action::CallMacro("B".to_string()), Action::KeyPress(5)]);
+        engine.add_macro(a.clone()).await.unwrap();
+
+        engine.execute_macro(a, DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*pressed_keys.lock().unwrap(), vec![2, 3, 4, 5]);
+
+        let err = engine.execute_action(&Action::CallMacro("Missing".to_string()), &*injector.read().await)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Called macro not found: Missing");
+    }
+
+    #[tokio::test]
+    async fn test_self_calling_macro_stops_at_depth_limit() {
+        let pressed_keys = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let injector = MockInjector::recording(Arc::clone(&pressed_keys));
+        let engine = MacroEngine::with_injector(Arc::clone(&injector));
+
+        let looping = macro_named("Loop", vec![Action::KeyPress(2), Action::CallMacro("Loop".to_string()), Action::KeyPress(3)]);
+        engine.add_macro(looping.clone()).await.unwrap();
+
+        let err = engine.execute_action(&Action::CallMacro("Loop".to_string()), &*injector.read().await)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("maximum call depth of 8"), "{}", err);
+        assert_eq!(pressed_keys.lock().unwrap().len(), DEFAULT_MAX_CALL_DEPTH);
+
+        // A spawned run unwinds at the limit without running the actions after the call
+        pressed_keys.lock().unwrap().clear();
+        let engine = engine.with_max_call_depth(2);
+        engine.execute_macro(looping, DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*pressed_keys.lock().unwrap(), vec![2, 2, 2]);
+        assert!(engine.get_executing_macros().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_aborts_over_limits() {
+        let pressed_keys = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let engine = MacroEngine::with_injector(MockInjector::recording(Arc::clone(&pressed_keys)))
+            .with_limits(5, 100);
+
+        // The second delay would take the run past 100 ms, so it stops before it
+        let slow = macro_named("Slow", vec![
+            Action::KeyPress(2),
+            Action::Delay(60),
+            Action::KeyPress(3),
+            Action::Delay(60),
+            Action::KeyPress(4),
+        ]);
+        assert!(engine.validate_macro(&slow).unwrap_err().contains("120 ms"));
+        engine.execute_macro(slow, DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(*pressed_keys.lock().unwrap(), vec![2, 3]);
+
+        // Only the actions inside a Repeat count against the action cap
+        pressed_keys.lock().unwrap().clear();
+        let long = macro_named("Long", vec![Action::Repeat { count: 10, actions: vec![Action::KeyPress(5)] }]);
+        engine.execute_macro(long, DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*pressed_keys.lock().unwrap(), vec![5; 5]);
+
+        let metrics = engine.get_metrics().await;
+        assert!(metrics.iter().all(|metric| metric.failed_executions == 1 && metric.total_executions == 1));
+        assert_eq!(metrics.len(), 2);
+
+        // A run within both caps isn't counted as failed
+        let short = macro_named("Long", vec![Action::KeyPress(5), Action::Delay(10)]);
+        engine.execute_macro(short, DEFAULT_REPLAY_SPEED).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let metric = engine.get_metrics().await.into_iter().find(|metric| metric.name == "Long").unwrap();
+        assert_eq!((metric.total_executions, metric.failed_executions), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn test_mouse_button_triggers_macro() {
+        let pressed_keys = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let engine = MacroEngine::with_injector(MockInjector::recording(Arc::clone(&pressed_keys)));
+
+        // BTN_SIDE on a mouse, with and without Shift
+        let mut side = macro_named("Side", vec![Action::KeyPress(2)]);
+        side.trigger.keys = vec![275];
+        let mut shift_side = macro_named("Shift+Side", vec![Action::KeyPress(3)]);
+        shift_side.trigger = KeyCombo { keys: vec![275], modifiers: vec![42] };
+        engine.add_macro(side).await.unwrap();
+        engine.add_macro(shift_side).await.unwrap();
+
+        engine.process_input_event(275, true, "/dev/input/event7").await.unwrap();
+        engine.process_input_event(275, false, "/dev/input/event7").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(*pressed_keys.lock().unwrap(), vec![2]);
+
+        // Shift from the keyboard and the button from the mouse make one combo
+        pressed_keys.lock().unwrap().clear();
+        engine.process_input_event(42, true, "/dev/input/event3").await.unwrap();
+        engine.process_input_event(275, true, "/dev/input/event7").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(pressed_keys.lock().unwrap().contains(&3));
     }
 }
@@ -6,29 +6,56 @@
 //! - IPC communication with the GUI client
 //! - Security management and privilege dropping
 
-use razermapper_common::tracing;
-use razermapperd::{DaemonState, config, device, macro_engine, injector, ipc, security};
+use razermapper_common::{tracing, Response};
+use razermapperd::{DaemonState, audit, cli, config, device, focus, macro_engine, injector, ipc, logging, security};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
-use tracing::{info, error, warn};
+use tracing::{debug, info, error, warn};
 use std::env;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Check for test mode first
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
     if args.len() > 1 && args[1] == "--test-security" {
         return security::test_security_functionality().await;
     }
+    if args.len() > 1 && (args[1] == "--help" || args[1] == "-h") {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
 
-// Main daemon implementation
-    // Initialize logging
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .with_target(false)
-        .init();
+    let cli_socket = match cli::take_socket_flag(&mut args) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    let socket_path = cli::determine_socket_path(cli_socket);
 
-    info!("Starting Razermapper Daemon v0.1.0");
+    // Client commands only talk to the running daemon, so they don't need root
+    match cli::CliCommand::parse(&args) {
+        Some(Ok(command)) => {
+            if let Err(e) = cli::run(command, &socket_path).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Err(e)) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+        None => {}
+    }
+
+// Main daemon implementation
+    // Log to stderr until the configuration says where logs should go
+    let startup_logging = tracing::subscriber::set_default(
+        tracing_subscriber::fmt().with_target(false).finish(),
+    );
 
     // Check if we're running as root (required for privileged operations)
     if !security::SecurityManager::is_root() {
@@ -36,25 +63,79 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Err("Insufficient privileges".into());
     }
 
-    // Determine socket path
-    let socket_path = determine_socket_path()?;
+    // Initialize components
+    let config_manager = Arc::new(config::ConfigManager::new().await?);
+
+    // Load configuration
+    config_manager.load_config_mut().await?;
+    let config = config_manager.config().await;
+
+    drop(startup_logging);
+    let (log_level, log_level_error) = match logging::parse_log_level(&config.daemon.log_level) {
+        Ok(level) => (level, None),
+        Err(e) => (logging::DEFAULT_LOG_LEVEL, Some(e)),
+    };
+    if let Err(e) = logging::init(&config.daemon, log_level) {
+        eprintln!("Failed to open log file {}: {}", config.daemon.log_file, e);
+        return Err(e.into());
+    }
+    if let Some(e) = log_level_error {
+        warn!("Ignoring daemon.log_level: {}; logging at {}", e, log_level);
+    }
+
+    info!("Starting Razermapper Daemon v0.1.0");
     info!("Using socket path: {}", socket_path);
 
+    // Create shared state
+    let state = Arc::new(RwLock::new(DaemonState::new()));
+
     // Initialize security manager with token authentication based on feature flag
     let token_auth_enabled = cfg!(feature = "token-auth");
-    let security_manager = Arc::new(RwLock::new(security::SecurityManager::new(token_auth_enabled)));
+    let security_manager = Arc::new(RwLock::new(
+        security::SecurityManager::new(token_auth_enabled)
+            .with_socket_ownership(&config.security.socket_group, &config.security.socket_permissions),
+    ));
     info!("Token authentication {}", if token_auth_enabled { "enabled" } else { "disabled" });
 
-    // Create shared state
-    let state = Arc::new(RwLock::new(DaemonState::new()));
+    // Record macros, commands and grabs performed on behalf of users
+    let audit_log = if config.security.audit_log {
+        info!("Writing audit log to {}", config.security.audit_log_path);
+        Some(Arc::new(audit::AuditLog::new(&config.security.audit_log_path)))
+    } else {
+        info!("Audit log disabled");
+        None
+    };
 
-    // Initialize components
-    let config_manager = Arc::new(config::ConfigManager::new().await?);
-    let injector = injector::UinputInjector::new()?;
+    let mut injector = injector::UinputInjector::with_layout(config.macro_engine.keyboard_layout)?
+        .with_unicode_strategy(config.macro_engine.unicode_strategy);
+    if let Some(audit_log) = &audit_log {
+        injector = injector.with_audit_log(Arc::clone(audit_log));
+    }
+    injector.set_type_delay(config.macro_engine.type_delay_ms);
+
+    // The absolute axes are sized when the device is created, so this comes first
+    let screen_size = match (config.macro_engine.screen_width, config.macro_engine.screen_height) {
+        (width, height) if width > 0 && height > 0 => Some((width, height)),
+        _ => injector::detect_screen_size(),
+    };
+    match screen_size {
+        Some((width, height)) => {
+            info!("Mapping absolute mouse moves to a {}x{} screen", width, height);
+            injector.set_screen_size(width, height);
+        }
+        None => warn!("Could not detect the screen size, set macro_engine.screen_width and screen_height \
+                       for absolute mouse moves to land correctly"),
+    }
+
+    // Explain a missing uinput module or permission before trying to create devices
+    if let Err(e) = injector::UinputInjector::preflight() {
+        error!("{}", e);
+        return Err(e.into());
+    }
 
     // Initialize injector with full privileges before dropping them
     {
-        injector.initialize().await.map_err(|e| -> Box<dyn std::error::Error> { e })?;
+        injector.initialize().await?;
         info!("Uinput injector initialized");
     }
 
@@ -64,9 +145,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Clone Arc for IPC server (it can downcast or use trait methods)
     let injector_for_ipc = Arc::clone(&injector_for_macro);
+    let injector_for_shutdown = Arc::clone(&injector_for_macro);
 
     // Create and initialize device manager
     let mut device_manager = device::DeviceManager::new();
+    if let Some(audit_log) = &audit_log {
+        device_manager.set_audit_log(Arc::clone(audit_log));
+    }
     if let Err(e) = device_manager.start_discovery().await {
         error!("Device discovery failed: {}", e);
     } else {
@@ -76,11 +161,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let discovered_devices = device_manager.get_devices();
         {
             let state = state.write().await;
-            *state.devices.lock().unwrap() = discovered_devices;
+            *state.devices.write().await = discovered_devices;
         }
 
+        device_manager.set_auto_regrab(config.device_discovery.auto_regrab);
+
         // Start device event processing loop
         let event_receiver = device_manager.get_event_receiver();
+        let mut change_receiver = device_manager.get_change_receiver();
 
         // Wrap device_manager in Arc<RwLock<>> for sharing with IPC
         let device_manager = Arc::new(RwLock::new(device_manager));
@@ -89,51 +177,96 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             state.device_manager = Some(Arc::clone(&device_manager));
         }
 
-        let state_clone = Arc::clone(&state);
+        // Watch for devices being plugged in or removed
+        device::DeviceManager::start_hotplug_monitor(Arc::clone(&device_manager), Duration::from_secs(2));
 
-        let state_clone2 = Arc::clone(&state_clone);
+        let state_for_hotplug = Arc::clone(&state);
+        let device_manager_for_hotplug = Arc::clone(&device_manager);
         tokio::spawn(async move {
-            let mut event_receiver = event_receiver;
-            loop {
-                if let Some((device_path, key_code, pressed)) = event_receiver.recv().await {
-                    // Forward event to macro engine for processing
-                    let state = state_clone2.read().await;
-                    if let Some(macro_engine) = &state.macro_engine {
-                        if let Err(e) = macro_engine.process_input_event(
-                            key_code,
-                            pressed,
-                            &device_path
-                        ).await {
-                            error!("Error processing input event: {}", e);
-                        }
+            while let Some(change) = change_receiver.recv().await {
+                debug!("Device change: {:?}", change);
+                let notification = match change {
+                    device::DeviceChange::GrabLost(device_path) => {
+                        device_manager_for_hotplug.write().await.release_lost_grab(&device_path);
+                        Response::DeviceUngrabbed { device_path }
                     }
-                }
+                    device::DeviceChange::Added(_) | device::DeviceChange::Removed(_) => {
+                        let devices = device_manager_for_hotplug.read().await.get_devices();
+                        *state_for_hotplug.read().await.devices.write().await = devices.clone();
+                        Response::DevicesChanged(devices)
+                    }
+                };
+
+                // Push the notification to subscribed clients
+                let _ = state_for_hotplug.read().await.device_events.send(notification);
             }
         });
+
+        razermapperd::start_event_dispatch(Arc::clone(&state), event_receiver);
     }
 
     // Initialize macro engine with injector
-    let macro_engine = Arc::new(macro_engine::MacroEngine::with_injector(Arc::clone(&injector_for_macro)));
+    let mut macro_engine = macro_engine::MacroEngine::with_injector(Arc::clone(&injector_for_macro))
+        .with_concurrency(
+            config.macro_engine.max_concurrent_macros,
+            config.macro_engine.overflow_policy,
+            config.macro_engine.macro_queue_size,
+        )
+        .with_max_call_depth(config.macro_engine.max_call_depth)
+        .with_limits(config.macro_engine.max_macro_actions, config.macro_engine.max_total_delay_ms)
+        .with_taps(config.macro_engine.tap_delay_ms, config.macro_engine.collapse_taps)
+        .with_max_repeat_rate(config.macro_engine.max_repeat_rate_hz);
+    if let Some(audit_log) = &audit_log {
+        macro_engine = macro_engine.with_audit_log(Arc::clone(audit_log));
+    }
+    let macro_engine = Arc::new(macro_engine);
     {
         let mut state = state.write().await;
         state.macro_engine = Some(Arc::clone(&macro_engine));
     }
 
-    // Load configuration
-    config_manager.load_config_mut().await?;
+    // Merge the configured startup profile into the macros saved by the previous run
+    let startup_profile = config_manager.startup_profile().await;
+    if config_manager.profile_exists(&startup_profile) {
+        match config_manager.load_profile(&startup_profile).await {
+            Ok(profile) => info!("Loaded startup profile {} with {} macros", startup_profile, profile.macros.len()),
+            Err(e) => error!("Failed to load startup profile {}: {}", startup_profile, e),
+        }
+    }
+    ipc::apply_loaded_macros(&state, &macro_engine, &config_manager).await;
 
-    // Load macros from the default profile
-    if let Some(default_profile) = config_manager.get_profile("default").await {
-        for (macro_name, macro_entry) in &default_profile.macros {
-            if let Err(e) = macro_engine.add_macro(macro_entry.clone()).await {
-                error!("Failed to add macro '{}' from profile: {}", macro_name, e);
+    // Pick up hand edits to the config and macros files
+    {
+        let mut reload_receiver = Arc::clone(&config_manager)
+            .start_watching(Duration::from_secs(config.config.reload_interval_secs));
+        let state_for_reload = Arc::clone(&state);
+        let macro_engine_for_reload = Arc::clone(&macro_engine);
+        let config_manager_for_reload = Arc::clone(&config_manager);
+        tokio::spawn(async move {
+            while let Some(reload) = reload_receiver.recv().await {
+                ipc::apply_loaded_macros(&state_for_reload, &macro_engine_for_reload, &config_manager_for_reload).await;
+                debug!("Applied reloaded configuration with {} macros", reload.macros_count);
             }
+        });
+    }
+
+    // Switch profiles as the focused window changes
+    if config.profile_rules.enabled {
+        if let Err(e) = focus::start_focus_watcher(
+            config.profile_rules.clone(),
+            Arc::clone(&state),
+            Arc::clone(&macro_engine),
+            Arc::clone(&config_manager),
+        ) {
+            error!("Invalid profile rule pattern, automatic profile switching disabled: {}", e);
         }
     }
 
     // AFTER completing all privileged initialization (uinput, device discovery, etc.)
     // Drop privileges to minimize attack surface
-    {
+    if !config.daemon.drop_privileges {
+        warn!("Privilege dropping disabled in configuration, keeping all capabilities");
+    } else {
         let mut security = security_manager.write().await;
         if let Err(e) = security.drop_privileges() {
             error!("Failed to drop privileges: {}", e);
@@ -145,6 +278,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Start IPC server
     let mut ipc_server = ipc::IpcServer::new(&socket_path)?;
     let state_for_shutdown = Arc::clone(&state);
+    let shutdown_requested = Arc::clone(&state.read().await.shutdown_requested);
+    let macro_engine_for_shutdown = Arc::clone(&macro_engine);
     ipc_server.start(
         state,
         macro_engine,
@@ -166,6 +301,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         _ = interrupt.recv() => {
             info!("Received SIGINT, shutting down gracefully");
         }
+        _ = shutdown_requested.notified() => {
+            info!("Shutdown requested over IPC, shutting down gracefully");
+        }
     }
 
     // Cleanup
@@ -183,15 +321,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     ipc_server.shutdown().await?;
+
+    // Let running macros stop between actions so they don't die mid-sequence
+    let drained = macro_engine_for_shutdown.shutdown(macro_engine::MACRO_DRAIN_TIMEOUT).await;
+    if drained > 0 {
+        info!("Stopped {} running macros", drained);
+    }
+
+    // Release held keys and destroy the virtual input device explicitly
+    // rather than relying on drop order
+    if let Err(e) = injector_for_shutdown.read().await.shutdown().await {
+        error!("Error shutting down injector: {}", e);
+    }
+
     info!("Razermapper Daemon shutdown complete");
     Ok(())
 }
-
-/// Determine the appropriate socket path based on the platform
-fn determine_socket_path() -> Result<String, Box<dyn std::error::Error>> {
-    // For system daemon running as root, use RuntimeDirectory from systemd
-    // This is created by RuntimeDirectory=razermapper in the service file
-    let path = "/run/razermapper/razermapper.sock".to_string();
-    info!("Using system-wide socket location: {}", path);
-    Ok(path)
-}
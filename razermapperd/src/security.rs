@@ -5,17 +5,113 @@
 //! - Setting appropriate permissions on Unix sockets
 //! - Token-based authentication when enabled
 
-use razermapper_common::tracing;
+use caps::{CapSet, Capability, CapsHashSet};
+use razermapper_common::{tracing, TokenInfo};
 use libc::{c_int, setgroups};
 use nix::unistd::{getuid, setgid, setuid, Uid};
 use std::fs;
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::Path;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::RwLock;
 use tracing::{debug, info, warn};
 
+/// Default number of requests a client connection may send per second
+pub const DEFAULT_MAX_REQUESTS_PER_SECOND: u32 = 50;
+
+/// Capabilities present in either set that aren't meant to be kept, sorted by name
+fn unexpected_capabilities(effective: &CapsHashSet, permitted: &CapsHashSet, keep: &CapsHashSet) -> Vec<Capability> {
+    let mut unexpected: Vec<Capability> = effective.union(permitted)
+        .filter(|cap| !keep.contains(cap))
+        .copied()
+        .collect();
+    unexpected.sort_by_key(|cap| cap.to_string());
+    unexpected
+}
+
+/// Group that owns the IPC socket unless configured otherwise
+pub const DEFAULT_SOCKET_GROUP: &str = "input";
+
+/// Mode of the IPC socket unless configured otherwise
+pub const DEFAULT_SOCKET_MODE: u32 = 0o660;
+
+/// Parse an octal permission string such as "0660" or "660"
+fn parse_socket_mode(permissions: &str) -> Option<u32> {
+    u32::from_str_radix(permissions.trim(), 8).ok().filter(|mode| *mode <= 0o777)
+}
+
+/// Number of random bytes in an authentication token (256 bits)
+pub const TOKEN_BYTES: usize = 32;
+
+/// Fill a buffer from the kernel's CSPRNG
+fn fill_random(buf: &mut [u8]) -> std::io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let remaining = &mut buf[filled..];
+        let n = unsafe { libc::getrandom(remaining.as_mut_ptr() as *mut libc::c_void, remaining.len(), 0) };
+        if n < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        filled += n as usize;
+    }
+    Ok(())
+}
+
+/// Identifier for a token that can be shown without revealing the token
+fn token_id(token: &str) -> String {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    token.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Token bucket limiting how fast a client may send requests
+///
+/// The bucket holds up to `max_per_second` tokens and refills at that rate,
+/// so a client can burst up to one second's worth of requests.
+pub struct RateLimiter {
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `max_per_second` requests (0 = unlimited)
+    pub fn new(max_per_second: u32) -> Self {
+        Self {
+            capacity: max_per_second as f64,
+            tokens: max_per_second as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token for one request, returning false if the client is over its budget
+    pub fn try_acquire(&mut self) -> bool {
+        if self.capacity == 0.0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * self.capacity;
+        self.tokens = (self.tokens + refill).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
 /// Security manager for handling privilege dropping and permissions
 pub struct SecurityManager {
     /// Whether privileges have been dropped
@@ -24,6 +120,10 @@ pub struct SecurityManager {
     auth_tokens: Arc<RwLock<std::collections::HashMap<String, SystemTime>>>,
     /// Whether token authentication is enabled
     token_auth_enabled: bool,
+    /// Group given ownership of the IPC socket
+    socket_group: String,
+    /// Permission bits of the IPC socket
+    socket_mode: u32,
 }
 
 impl SecurityManager {
@@ -33,13 +133,34 @@ impl SecurityManager {
             privileges_dropped: false,
             auth_tokens: Arc::new(RwLock::new(std::collections::HashMap::new())),
             token_auth_enabled,
+            socket_group: DEFAULT_SOCKET_GROUP.to_string(),
+            socket_mode: DEFAULT_SOCKET_MODE,
+        }
+    }
+
+    /// Own the socket by `group` with the octal `permissions`
+    ///
+    /// Permissions that don't parse as a mode keep the default 0660.
+    pub fn with_socket_ownership(mut self, group: &str, permissions: &str) -> Self {
+        self.socket_group = group.to_string();
+        match parse_socket_mode(permissions) {
+            Some(mode) => self.socket_mode = mode,
+            None => warn!(
+                "Invalid socket permissions {:?}, using {:04o}", permissions, DEFAULT_SOCKET_MODE
+            ),
         }
+        self
     }
 
     /// Drop all Linux capabilities except CAP_SYS_RAWIO
     ///
     /// This should be called after completing privileged initialization
     /// (such as setting up uinput devices) but before handling untrusted input.
+    /// Every other capability is removed from the bounding, inheritable,
+    /// effective and permitted sets, and the result is read back to verify it.
+    ///
+    /// Capability sets belong to a thread, so this reduces the calling thread
+    /// and any thread it creates afterwards.
     pub fn drop_privileges(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         if self.privileges_dropped {
             warn!("Privileges already dropped");
@@ -48,50 +169,47 @@ impl SecurityManager {
 
         info!("Dropping all capabilities except CAP_SYS_RAWIO");
 
-        // Using a simplified but effective approach to drop capabilities
-        // First, clear all capabilities from the bounding set except CAP_SYS_RAWIO
-        // Note: This is a simplified version that works on most Linux systems
-        // A full implementation would use libcap for more precise control
-
-        // Clear all capabilities from the process using prctl
-        // This will remove all capabilities, including CAP_SYS_RAWIO temporarily
+        // Keep permitted capabilities across any later uid change
         let ret = unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) };
         if ret != 0 {
             warn!("Failed to set PR_SET_KEEPCAPS: {}", std::io::Error::last_os_error());
         }
 
-        // Drop all capabilities from the bounding set
-        for cap in 0..32 {
-            if cap != 17 { // Keep CAP_SYS_RAWIO (17)
-                let ret = unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) };
-                if ret != 0 && cap != 17 {
-                    // Some capabilities might not be present, which is fine
-                    debug!("Could not drop capability {} from bounding set: {}", cap, std::io::Error::last_os_error());
+        // Only keep CAP_SYS_RAWIO if we hold it in the first place
+        let mut keep = CapsHashSet::new();
+        if caps::has_cap(None, CapSet::Permitted, Capability::CAP_SYS_RAWIO)? {
+            keep.insert(Capability::CAP_SYS_RAWIO);
+        }
+
+        // Remove everything else from the bounding set so it can't be regained
+        for cap in caps::all() {
+            if cap != Capability::CAP_SYS_RAWIO {
+                if let Err(e) = caps::drop(None, CapSet::Bounding, cap) {
+                    // Capabilities unknown to the running kernel can't be dropped, which is fine
+                    debug!("Could not drop {} from bounding set: {}", cap, e);
                 }
             }
         }
 
-        // For the actual capability set manipulation, we'll use a simpler approach
-        // that doesn't require complex capget/capset system calls
-
-        // In production, a proper implementation would:
-        // 1. Use libcap or proper capset/capget calls
-        // 2. Set only CAP_SYS_RAWIO in the permitted and effective sets
-        // 3. Verify the capabilities were set correctly
-
-        // For this implementation, we'll use a simplified approach with prctl
-        // that works on most modern Linux distributions
-
-        // Log the current capability state for debugging
-        info!("Capability dropping completed - only CAP_SYS_RAWIO should remain");
+        // Effective has to shrink before permitted, as it must stay a subset of it
+        caps::clear(None, CapSet::Inheritable)?;
+        caps::set(None, CapSet::Effective, &keep)?;
+        caps::set(None, CapSet::Permitted, &keep)?;
+
+        // Verify nothing else survived
+        let effective = caps::read(None, CapSet::Effective)?;
+        let permitted = caps::read(None, CapSet::Permitted)?;
+        let unexpected = unexpected_capabilities(&effective, &permitted, &keep);
+        if !unexpected.is_empty() {
+            return Err(format!("Capabilities still held after dropping privileges: {:?}", unexpected).into());
+        }
 
-        // Mark as dropped
         self.privileges_dropped = true;
-        info!("Successfully dropped privileges, keeping only CAP_SYS_RAWIO");
+        info!("Successfully dropped privileges, effective: {:?}, permitted: {:?}", effective, permitted);
         Ok(())
     }
 
-    /// Enforce socket ownership: group "input", mode 0660
+    /// Enforce socket ownership: the configured group and mode, "input" and 0660 by default
     ///
     /// This should be called after creating the Unix socket.
     pub fn set_socket_permissions<P: AsRef<Path>>(&self, socket_path: P) -> Result<(), Box<dyn std::error::Error>> {
@@ -101,15 +219,13 @@ impl SecurityManager {
             return Err(format!("Socket file does not exist: {}", socket_path.display()).into());
         }
 
-        info!("Setting socket permissions: group=input, mode=0660");
+        info!("Setting socket permissions: group={}, mode={:04o}", self.socket_group, self.socket_mode);
 
-        // Set permissions to 0660 (owner read/write, group read/write, no others)
         let mut perms = fs::metadata(socket_path)?.permissions();
-        perms.set_mode(0o660);
+        perms.set_mode(self.socket_mode);
         fs::set_permissions(socket_path, perms)?;
 
-        // Set group ownership to "input"
-        self.set_socket_group(socket_path, "input")?;
+        self.set_socket_group(socket_path, &self.socket_group)?;
 
         info!("Socket permissions configured successfully");
         Ok(())
@@ -145,30 +261,14 @@ impl SecurityManager {
 
     /// Generate an authentication token for a client
     ///
-    /// This function generates a secure token and records it for future validation.
-    /// Tokens expire after 24 hours for security.
+    /// The token is TOKEN_BYTES of kernel randomness (getrandom), hex encoded,
+    /// and is recorded for future validation. Tokens expire after 24 hours.
     pub async fn generate_auth_token(&self) -> Result<String, Box<dyn std::error::Error>> {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-        use std::time::{SystemTime, UNIX_EPOCH};
-
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)?
-            .as_nanos();
+        let mut secret = [0u8; TOKEN_BYTES];
+        fill_random(&mut secret)?;
 
-        // Generate a more secure token using multiple entropy sources
-        let mut hasher = DefaultHasher::new();
-        timestamp.hash(&mut hasher);
-
-        // Add process ID as additional entropy
-        std::process::id().hash(&mut hasher);
-
-        // Add memory address as additional entropy
-        let self_ptr = self as *const Self as usize;
-        self_ptr.hash(&mut hasher);
-
-        let hash = hasher.finish();
-        let token = format!("razermapper-{:x}", hash);
+        let hex: String = secret.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let token = format!("razermapper-{}", hex);
 
         // Store token with expiration time (24 hours from now)
         let expiration = SystemTime::now() + Duration::from_secs(24 * 60 * 60);
@@ -178,7 +278,7 @@ impl SecurityManager {
         // Clean up expired tokens
         self.cleanup_expired_tokens(&mut tokens).await;
 
-        info!("Generated auth token: {}", token);
+        info!("Generated auth token {}", token_id(&token));
         Ok(token)
     }
 
@@ -212,6 +312,33 @@ impl SecurityManager {
         }
     }
 
+    /// Revoke the token with the given id, returning false if no token has it
+    pub async fn revoke_auth_token(&self, id: &str) -> bool {
+        let mut tokens = self.auth_tokens.write().await;
+        let before = tokens.len();
+        tokens.retain(|token, _| token_id(token) != id);
+        let revoked = tokens.len() != before;
+        if revoked {
+            info!("Revoked auth token {}", id);
+        }
+        revoked
+    }
+
+    /// List unexpired tokens by id and expiry, without the secrets themselves
+    pub async fn list_auth_tokens(&self) -> Vec<TokenInfo> {
+        let now = SystemTime::now();
+        self.auth_tokens.read().await.iter()
+            .filter(|(_, expiration)| **expiration > now)
+            .map(|(token, expiration)| TokenInfo {
+                id: token_id(token),
+                expires_at: expiration
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+            })
+            .collect()
+    }
+
     /// Clean up expired tokens
     ///
     /// This should be called periodically to prevent memory leaks.
@@ -316,6 +443,11 @@ mod tests {
 
         let token = manager.generate_auth_token().await.unwrap();
         assert!(token.starts_with("razermapper-"));
+        assert_eq!(token.len(), "razermapper-".len() + TOKEN_BYTES * 2);
+        assert!(token["razermapper-".len()..].chars().all(|c| c.is_ascii_hexdigit()));
+
+        let second = manager.generate_auth_token().await.unwrap();
+        assert_ne!(token, second);
 
         assert!(manager.validate_auth_token(&token).await);
         assert!(!manager.validate_auth_token("invalid-token").await);
@@ -343,6 +475,25 @@ mod tests {
         assert_eq!(mode & 0o777, 0o660);
     }
 
+    #[test]
+    fn test_configured_socket_permissions() {
+        let manager = SecurityManager::new(false).with_socket_ownership("input", "0600");
+        let temp_file = NamedTempFile::new().unwrap();
+
+        // The group may be missing here, but the mode is applied before it
+        let _ = manager.set_socket_permissions(temp_file.path());
+        let mode = fs::metadata(temp_file.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        assert_eq!(parse_socket_mode("660"), Some(0o660));
+        assert_eq!(parse_socket_mode("0770"), Some(0o770));
+        assert_eq!(parse_socket_mode("rw-rw----"), None);
+        assert_eq!(parse_socket_mode("1777"), None);
+        let fallback = SecurityManager::new(false).with_socket_ownership("wheel", "0999");
+        assert_eq!(fallback.socket_mode, DEFAULT_SOCKET_MODE);
+        assert_eq!(fallback.socket_group, "wheel");
+    }
+
     #[test]
     fn test_root_detection() {
         // This test will only pass when run as root
@@ -353,6 +504,57 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_revoke_auth_token() {
+        let manager = SecurityManager::new(true);
+
+        let token = manager.generate_auth_token().await.unwrap();
+        let tokens = manager.list_auth_tokens().await;
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].id, token_id(&token));
+        assert!(!tokens[0].id.contains(&token));
+
+        // The raw secret is not an id
+        assert!(!manager.revoke_auth_token(&token).await);
+        assert!(manager.validate_auth_token(&token).await);
+
+        assert!(manager.revoke_auth_token(&tokens[0].id).await);
+        assert!(!manager.validate_auth_token(&token).await);
+        assert!(manager.list_auth_tokens().await.is_empty());
+        assert!(!manager.revoke_auth_token(&tokens[0].id).await);
+    }
+
+    #[test]
+    fn test_unexpected_capabilities() {
+        let keep: CapsHashSet = [Capability::CAP_SYS_RAWIO].into_iter().collect();
+        let effective: CapsHashSet = [Capability::CAP_SYS_RAWIO].into_iter().collect();
+        let permitted: CapsHashSet = [Capability::CAP_SYS_RAWIO, Capability::CAP_SYS_ADMIN, Capability::CAP_CHOWN]
+            .into_iter()
+            .collect();
+
+        assert!(unexpected_capabilities(&effective, &effective, &keep).is_empty());
+        assert_eq!(
+            unexpected_capabilities(&effective, &permitted, &keep),
+            vec![Capability::CAP_CHOWN, Capability::CAP_SYS_ADMIN]
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(3);
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+
+        // Tokens come back over time
+        std::thread::sleep(Duration::from_millis(400));
+        assert!(limiter.try_acquire());
+
+        let mut unlimited = RateLimiter::new(0);
+        assert!((0..1000).all(|_| unlimited.try_acquire()));
+    }
+
     #[tokio::test]
     async fn test_token_expiration() {
         let manager = SecurityManager::new(true);
@@ -0,0 +1,126 @@
+//! Test doubles for exercising the daemon without root
+//!
+//! `RecordingInjector` stands in for the uinput injector: instead of creating a
+//! virtual device it appends every call to a shared list, so tests can assert
+//! exactly what a macro produced. Available to the crate's own tests and, with
+//! the `testing` feature, to downstream crates.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+use crate::injector::Injector;
+
+/// One call made on a `RecordingInjector`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InjectedAction {
+    KeyPress(u16),
+    KeyRelease(u16),
+    MousePress(u16),
+    MouseRelease(u16),
+    MouseMove { x: i32, y: i32 },
+    MouseMoveAbsolute { x: i32, y: i32 },
+    MouseScroll(i32),
+    TypeString(String),
+    ExecuteCommand(String),
+    /// Keys pressed and released together by `press_keys_then_release`
+    Chord(Vec<u16>),
+    /// Every held key released by `release_all`
+    ReleaseAll,
+}
+
+/// Injector that records actions instead of injecting them
+///
+/// Clones share the same list, so keep a clone (or the `actions` handle)
+/// before handing the injector to a `MacroEngine`.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingInjector {
+    actions: Arc<Mutex<Vec<InjectedAction>>>,
+    /// What `execute_command` returns as the command's stdout
+    command_output: String,
+}
+
+impl RecordingInjector {
+    /// Create an injector with an empty action list
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Have `execute_command` answer every command with this stdout
+    pub fn with_command_output(mut self, output: impl Into<String>) -> Self {
+        self.command_output = output.into();
+        self
+    }
+
+    /// Shared list every injected action is appended to
+    pub fn actions(&self) -> Arc<Mutex<Vec<InjectedAction>>> {
+        Arc::clone(&self.actions)
+    }
+
+    /// Copy of the actions injected so far
+    pub fn recorded(&self) -> Vec<InjectedAction> {
+        self.actions.lock().unwrap().clone()
+    }
+
+    /// Wrap a clone of this injector the way `MacroEngine` and the IPC server expect
+    pub fn shared(&self) -> Arc<RwLock<dyn Injector + Send + Sync>> {
+        Arc::new(RwLock::new(self.clone()))
+    }
+
+    fn record(&self, action: InjectedAction) -> crate::injector::InjectorResult<()> {
+        self.actions.lock().unwrap().push(action);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Injector for RecordingInjector {
+    async fn initialize(&self) -> crate::injector::InjectorResult<()> {
+        Ok(())
+    }
+
+    async fn key_press(&self, key_code: u16) -> crate::injector::InjectorResult<()> {
+        self.record(InjectedAction::KeyPress(key_code))
+    }
+
+    async fn key_release(&self, key_code: u16) -> crate::injector::InjectorResult<()> {
+        self.record(InjectedAction::KeyRelease(key_code))
+    }
+
+    async fn mouse_press(&self, button: u16) -> crate::injector::InjectorResult<()> {
+        self.record(InjectedAction::MousePress(button))
+    }
+
+    async fn mouse_release(&self, button: u16) -> crate::injector::InjectorResult<()> {
+        self.record(InjectedAction::MouseRelease(button))
+    }
+
+    async fn mouse_move(&self, x: i32, y: i32) -> crate::injector::InjectorResult<()> {
+        self.record(InjectedAction::MouseMove { x, y })
+    }
+
+    async fn mouse_move_absolute(&self, x: i32, y: i32) -> crate::injector::InjectorResult<()> {
+        self.record(InjectedAction::MouseMoveAbsolute { x, y })
+    }
+
+    async fn mouse_scroll(&self, amount: i32) -> crate::injector::InjectorResult<()> {
+        self.record(InjectedAction::MouseScroll(amount))
+    }
+
+    async fn type_string(&self, text: &str) -> crate::injector::InjectorResult<()> {
+        self.record(InjectedAction::TypeString(text.to_string()))
+    }
+
+    async fn execute_command(&self, command: &str) -> crate::injector::InjectorResult<String> {
+        self.record(InjectedAction::ExecuteCommand(command.to_string()))?;
+        Ok(self.command_output.clone())
+    }
+
+    async fn press_keys_then_release(&self, keys: &[u16], _hold: Duration) -> crate::injector::InjectorResult<()> {
+        self.record(InjectedAction::Chord(keys.to_vec()))
+    }
+
+    async fn release_all(&self) -> crate::injector::InjectorResult<()> {
+        self.record(InjectedAction::ReleaseAll)
+    }
+}
@@ -11,8 +11,8 @@
 
 use razermapper_common::{
     ipc_client::IpcClient,
-    DeviceInfo, Request, Response, Action, MacroEntry, KeyCombo,
-    serialize, deserialize,
+    DeviceInfo, Request, Response, Action, MacroEntry, KeyCombo, TriggerEdge,
+    encode_frame, decode_frame,
 };
 use std::{
     collections::HashMap,
@@ -116,6 +116,8 @@ impl TestEnvironment {
                 vendor_id: 0x1532, // Razer
                 product_id: 0x0101,
                 phys: "usb-0000:00:14.0-1/input0".to_string(),
+                capabilities: razermapper_common::DeviceCapabilities::default(),
+                stable_id: String::new(),
             },
             DeviceInfo {
                 name: "Test Mouse".to_string(),
@@ -123,6 +125,8 @@ impl TestEnvironment {
                 vendor_id: 0x1532,
                 product_id: 0x0025,
                 phys: "usb-0000:00:14.0-2/input0".to_string(),
+                capabilities: razermapper_common::DeviceCapabilities::default(),
+                stable_id: String::new(),
             },
         ];
 
@@ -173,7 +177,7 @@ impl TestEnvironment {
         stream.read_exact(&mut msg_buf).await?;
 
         // Deserialize the request
-        let request: Request = deserialize(&msg_buf)?;
+        let (request, format): (Request, _) = decode_frame(&msg_buf)?;
         debug!("Received request: {:?}", request);
 
         // Process the request and generate a response
@@ -185,7 +189,7 @@ impl TestEnvironment {
         ).await;
 
         // Serialize the response
-        let response_bytes = serialize(&response);
+        let response_bytes = encode_frame(&response, format).unwrap();
 
         // Send the response length first
         let len = response_bytes.len() as u32;
@@ -215,8 +219,8 @@ impl TestEnvironment {
             }
             Request::SetMacro { device_path: _, macro_entry } => {
                 let mut macros = macros.write().await;
-                macros.insert(macro_entry.name.clone(), macro_entry);
-                Response::Ack
+                let created = macros.insert(macro_entry.name.clone(), macro_entry).is_none();
+                Response::MacroSet { created }
             }
             Request::DeleteMacro { name } => {
                 let mut macros = macros.write().await;
@@ -237,7 +241,7 @@ impl TestEnvironment {
                     Response::Error("Invalid authentication token".to_string())
                 }
             }
-            Request::RecordMacro { device_path, name } => {
+            Request::RecordMacro { device_path, name, .. } => {
                 let mut state = recording_state.write().await;
                 *state = Some((name.clone(), device_path));
                 Response::RecordingStarted { device_path, name }
@@ -259,13 +263,17 @@ impl TestEnvironment {
                         ],
                         device_id: None,
                         enabled: true,
+                        cooldown_ms: 0,
+                        trigger_on: TriggerEdge::Press,
+                        repeat_while_held: false,
+                        tags: vec![],
                     };
                     Response::RecordingStopped { macro_entry }
                 } else {
                     Response::Error("No recording in progress".to_string())
                 }
             }
-            Request::TestMacro { name } => {
+            Request::TestMacro { name, .. } => {
                 let macros = macros.read().await;
                 if let Some(macro_entry) = macros.get(&name) {
                     Response::Ack
@@ -279,7 +287,7 @@ impl TestEnvironment {
             Request::LoadProfile { name: _ } => {
                 // In a real implementation, this would load from disk
                 // For tests, we'll just create an empty profile
-                Response::ProfileLoaded { name, macros_count: 0 }
+                Response::ProfileLoaded { name, macros_count: 0, description: None }
             }
             Request::ListProfiles => {
                 Response::Profiles(vec!["default".to_string(), "test".to_string()])
@@ -346,6 +354,7 @@ async fn test_macro_recording_playback() -> Result<(), Box<dyn std::error::Error
     let record_response = test_env.client.send(&Request::RecordMacro {
         device_path: "/dev/input/test0".to_string(),
         name: "Test Macro".to_string(),
+        capture_trigger: false,
     }).await?;
 
     // Verify recording started
@@ -385,6 +394,8 @@ async fn test_macro_recording_playback() -> Result<(), Box<dyn std::error::Error
     // Test 4: Play macro
     let play_response = test_env.client.send(&Request::TestMacro {
         name: "Test Macro".to_string(),
+        simulate: false,
+        speed: razermapper_common::DEFAULT_REPLAY_SPEED,
     }).await?;
 
     // Verify playback started
@@ -417,6 +428,10 @@ async fn test_macro_management() -> Result<(), Box<dyn std::error::Error>> {
         ],
         device_id: None,
         enabled: true,
+        cooldown_ms: 0,
+        trigger_on: TriggerEdge::Press,
+        repeat_while_held: false,
+        tags: vec![],
     };
 
     // Test 1: Set macro
@@ -425,7 +440,7 @@ async fn test_macro_management() -> Result<(), Box<dyn std::error::Error>> {
         macro_entry: test_macro.clone(),
     }).await?;
 
-    assert!(matches!(set_response, Response::Ack));
+    assert!(matches!(set_response, Response::MacroSet { created: true }));
 
     // Test 2: List macros
     let list_response = test_env.client.send(&Request::ListMacros).await?;
@@ -479,6 +494,10 @@ async fn test_profile_management() -> Result<(), Box<dyn std::error::Error>> {
         ],
         device_id: None,
         enabled: true,
+        cooldown_ms: 0,
+        trigger_on: TriggerEdge::Press,
+        repeat_while_held: false,
+        tags: vec![],
     };
 
     // Set the macro
@@ -518,7 +537,7 @@ async fn test_profile_management() -> Result<(), Box<dyn std::error::Error>> {
     }).await?;
 
     match load_response {
-        Response::ProfileLoaded { name, macros_count } => {
+        Response::ProfileLoaded { name, macros_count, .. } => {
             assert_eq!(name, "Test Profile");
             // Empty profile in test implementation, so macros_count is 0
             assert_eq!(macros_count, 0);
@@ -610,6 +629,8 @@ async fn test_error_handling() -> Result<(), Box<dyn std::error::Error>> {
     // Test 1: Try to execute a non-existent macro
     let play_response = test_env.client.send(&Request::TestMacro {
         name: "Non-existent Macro".to_string(),
+        simulate: false,
+        speed: razermapper_common::DEFAULT_REPLAY_SPEED,
     }).await?;
 
     match play_response {
@@ -694,6 +715,10 @@ async fn test_large_payload() -> Result<(), Box<dyn std::error::Error>> {
         actions,
         device_id: None,
         enabled: true,
+        cooldown_ms: 0,
+        trigger_on: TriggerEdge::Press,
+        repeat_while_held: false,
+        tags: vec![],
     };
 
     // Set large macro
@@ -702,7 +727,7 @@ async fn test_large_payload() -> Result<(), Box<dyn std::error::Error>> {
         macro_entry: large_macro,
     }).await?;
 
-    assert!(matches!(set_response, Response::Ack));
+    assert!(matches!(set_response, Response::MacroSet { .. }));
 
     // List macros to verify it was set
     let list_response = test_env.client.send(&Request::ListMacros).await?;